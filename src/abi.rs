@@ -1,8 +1,33 @@
 use ethabi::param_type::{ParamType, Reader};
 use ethabi::token::{LenientTokenizer, StrictTokenizer, Token, Tokenizer};
-use ethabi::{decode, encode, Contract, Hash};
+use ethabi::{decode, encode, Contract, Event, Hash, Log};
 use ethereum_types::U256;
 use hex::{decode as hex_decode, encode as hex_encode};
+use serde_json::json;
+
+/// Load the contract from an abi file, the entries unknown to ethabi (errors,
+/// receive functions) are skipped. An entry without `type` is a function.
+pub fn load_contract(abi: &[u8]) -> Result<Contract, String> {
+    let entries: Vec<serde_json::Value> =
+        serde_json::from_slice(abi).map_err(|err| err.to_string())?;
+    let entries: Vec<serde_json::Value> = entries
+        .into_iter()
+        .map(|mut entry| {
+            if let Some(object) = entry.as_object_mut() {
+                object.entry("type").or_insert_with(|| json!("function"));
+            }
+            entry
+        })
+        .filter(|entry| {
+            matches!(
+                entry.get("type").and_then(|ty| ty.as_str()),
+                Some("constructor") | Some("function") | Some("event") | Some("fallback")
+            )
+        })
+        .collect();
+    let abi = serde_json::to_vec(&entries).map_err(|err| err.to_string())?;
+    Contract::load(&abi[..]).map_err(|e| format!("{}", e))
+}
 
 fn lower_hex(value: U256) -> String {
     format!("{:x}", value)
@@ -112,7 +137,7 @@ pub fn encode_input(
     lenient: bool,
     constructor: bool,
 ) -> Result<String, String> {
-    let contract = Contract::load(abi).map_err(|e| format!("{}", e))?;
+    let contract = load_contract(abi)?;
     if constructor {
         constructor_encode_input(&contract, function, values, lenient)
     } else {
@@ -174,7 +199,7 @@ pub fn decode_params(types: &[String], data: &str) -> Result<Vec<String>, String
 
 /// According to the given abi file, decode the data
 pub fn decode_input(abi: &[u8], function: &str, data: &str) -> Result<Vec<String>, String> {
-    let contract = Contract::load(abi).map_err(|e| format!("{}", e))?;
+    let contract = load_contract(abi)?;
     let function = contract.function(function).map_err(|e| format!("{}", e))?;
     let tokens = function
         .decode_output(data.as_bytes())
@@ -197,15 +222,38 @@ pub fn decode_input(abi: &[u8], function: &str, data: &str) -> Result<Vec<String
     Ok(result)
 }
 
-/// According to the given abi file, decode the topic
+/// Find the event which emitted the log, `topics[0]` is matched against the
+/// event signatures first, then the anonymous events are tried in turn.
+pub fn find_event<'a>(contract: &'a Contract, topics: &[Hash], data: &[u8]) -> Option<&'a Event> {
+    let parse_ok = |event: &Event| {
+        event
+            .parse_log((topics.to_vec(), data.to_vec()).into())
+            .is_ok()
+    };
+    contract
+        .events()
+        .find(|event| {
+            !event.anonymous && topics.first() == Some(&event.signature()) && parse_ok(event)
+        })
+        .or_else(|| {
+            contract
+                .events()
+                .find(|event| event.anonymous && parse_ok(event))
+        })
+}
+
+/// According to the given abi file, decode the topic, each param as a
+/// `{name: value}` object
 pub fn decode_logs(
     abi: &[u8],
     event: &str,
     topics: &[String],
     data: &str,
-) -> Result<Vec<String>, String> {
-    let contract = Contract::load(abi).map_err(|e| format!("{}", e))?;
-    let event = contract.event(event).map_err(|e| format!("{}", e))?;
+) -> Result<Vec<serde_json::Value>, String> {
+    let contract = load_contract(abi)?;
+    let events = contract
+        .events_by_name(event)
+        .map_err(|e| format!("{}", e))?;
 
     let topics: Vec<Hash> = topics
         .iter()
@@ -213,15 +261,50 @@ pub fn decode_logs(
         .collect::<Result<_, _>>()
         .map_err(|e| format!("{}", e))?;
     let data = hex_decode(data).map_err(|err| err.to_string())?;
-    let decoded = event
-        .parse_log((topics, data).into())
-        .map_err(|e| format!("{}", e))?;
+    // Overloaded events share the name, take the first one matching the log
+    let mut decoded = Err(format!("No event matched: {}", event));
+    for event in events {
+        decoded = event
+            .parse_log((topics.clone(), data.clone()).into())
+            .map_err(|e| format!("{}", e));
+        if decoded.is_ok() {
+            break;
+        }
+    }
+
+    Ok(log_params(decoded?))
+}
 
-    let result = decoded
-        .params
+// Each param of the decoded log as a `{name: value}` object
+fn log_params(log: Log) -> Vec<serde_json::Value> {
+    log.params
         .into_iter()
-        .map(|log_param| format!("{{\"{}\": \"{}\"}}", log_param.name, log_param.value))
-        .collect::<Vec<String>>();
+        .map(|log_param| json!({ log_param.name: log_param.value.to_string() }))
+        .collect()
+}
 
-    Ok(result)
+/// Decode the log by the first contract which contains a matching event, the
+/// event and the decoded params are returned.
+pub fn decode_any_log(
+    contracts: &[Contract],
+    topics: &[String],
+    data: &str,
+) -> Result<Option<(Event, Vec<serde_json::Value>)>, String> {
+    let topic_hashes: Vec<Hash> = topics
+        .iter()
+        .map(|t| t.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("{}", e))?;
+    let raw_data = hex_decode(data).map_err(|err| err.to_string())?;
+    for contract in contracts {
+        if let Some(event) = find_event(contract, &topic_hashes, &raw_data) {
+            // The matched event itself, an overload of the same name may
+            // parse the log too
+            let log = event
+                .parse_log((topic_hashes.clone(), raw_data.clone()).into())
+                .map_err(|e| format!("{}", e))?;
+            return Ok(Some((event.clone(), log_params(log))));
+        }
+    }
+    Ok(None)
 }
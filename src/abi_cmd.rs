@@ -205,10 +205,7 @@ pub fn process(sub_matches: &ArgMatches) -> Result<(), String> {
                     Some(param) => param.map(|s| s.to_owned()).collect::<Vec<String>>(),
                 };
                 let data = m.value_of("data").unwrap();
-                let output = decode_logs(&abi, event, &topic, data)?
-                    .iter()
-                    .map(|value| serde_json::from_str(value).unwrap())
-                    .collect();
+                let output = decode_logs(&abi, event, &topic, data)?;
                 println!(
                     "{}",
                     serde_json::to_string_pretty(&Value::Array(output))
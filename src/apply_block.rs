@@ -0,0 +1,450 @@
+//! Apply a block of signed transactions to the state file: execute them in
+//! order in the block environment of the state, then seal the block and
+//! advance the environment to the next block
+
+use std::fs;
+use std::rc::Rc;
+
+use clap::{App, Arg, ArgMatches};
+use ethereum_types::U256;
+use keccak_hash::keccak;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::block::{apply_rewards, Header};
+use crate::evmc::{parse_revision, Address, Bytes32, EvmcVm, Revision, StatusCode, Uint256};
+use crate::loader::create_vm;
+use crate::receipt::{logs_bloom, push_receipt, Receipt, TransactionReceipt};
+use crate::report::ReportLog;
+use crate::state_db::{load_state, save_state};
+use crate::statetest::parse_hex;
+use crate::transaction::{
+    apply_transaction, decode_transaction, u256_from_bytes, u256_to_bytes, SignedTransaction,
+};
+use crate::trie::{ordered_trie_root, state_root};
+use crate::TestHostContext;
+
+/// The fields of the block environment to override, the others are the ones
+/// of the state file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct BlockEnv {
+    number: Option<u64>,
+    timestamp: Option<u64>,
+    coinbase: Option<Address>,
+    gas_limit: Option<u64>,
+    difficulty: Option<Uint256>,
+    chain_id: Option<u64>,
+    base_fee: Option<Uint256>,
+}
+
+/// The sub command to apply a block
+pub fn sub_command(name: &'static str) -> App<'static, 'static> {
+    App::new(name)
+        .about("Execute the signed transactions as a block, and advance to the next block")
+        .arg(
+            Arg::with_name("input-storage")
+                .long("input-storage")
+                .short("s")
+                .takes_value(true)
+                .help("The storage (accounts) json file, its block is the one to apply"),
+        )
+        .arg(
+            Arg::with_name("output-storage")
+                .long("output-storage")
+                .short("o")
+                .takes_value(true)
+                .help("The storage file to write the state after the block"),
+        )
+        .arg(
+            Arg::with_name("env")
+                .long("env")
+                .takes_value(true)
+                .help("The json file overrides the fields of the block environment"),
+        )
+        .arg(
+            Arg::with_name("tx")
+                .long("tx")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("The signed transactions (hex) in order"),
+        )
+        .arg(
+            Arg::with_name("txs")
+                .long("txs")
+                .takes_value(true)
+                .conflicts_with("tx")
+                .help("The json file of the signed transactions (hex) in order"),
+        )
+        .arg(
+            Arg::with_name("revision")
+                .long("revision")
+                .short("r")
+                .takes_value(true)
+                .default_value("berlin")
+                .help("The EVM revision"),
+        )
+        .arg(
+            Arg::with_name("block-time")
+                .long("block-time")
+                .takes_value(true)
+                .default_value("12")
+                .help("The seconds between this block and the next one"),
+        )
+        .arg(
+            Arg::with_name("no-reward")
+                .long("no-reward")
+                .help("Do not reward the coinbase with the block reward"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print the block and the receipts as json"),
+        )
+}
+
+pub fn process(sub_matches: &ArgMatches) -> Result<(), String> {
+    let vm = Rc::new(create_vm(sub_matches.value_of("vm"))?);
+    let revision = parse_revision(sub_matches.value_of("revision").unwrap())?;
+    let block_time = sub_matches
+        .value_of("block-time")
+        .unwrap()
+        .parse::<u64>()
+        .map_err(|err| format!("Invalid block time: {}", err))?;
+    let mut context: TestHostContext = match sub_matches.value_of("input-storage") {
+        Some(path) => load_state(path)?,
+        None => TestHostContext::new(0, Address::default()),
+    };
+    if let Some(path) = sub_matches.value_of("env") {
+        let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        let env: BlockEnv = serde_json::from_slice(&data).map_err(|err| err.to_string())?;
+        override_env(&mut context, env);
+    }
+    let raw_transactions = match (sub_matches.values_of("tx"), sub_matches.value_of("txs")) {
+        (Some(values), _) => values.map(parse_hex).collect::<Result<Vec<_>, _>>()?,
+        (None, Some(path)) => {
+            let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+            let values: Vec<String> =
+                serde_json::from_slice(&data).map_err(|err| err.to_string())?;
+            values
+                .iter()
+                .map(|value| parse_hex(value))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+        (None, None) => Vec::new(),
+    };
+    let transactions = raw_transactions
+        .iter()
+        .map(|raw| decode_transaction(raw))
+        .collect::<Result<Vec<_>, _>>()?;
+    // The invalid transactions are reported by the block execution
+    let warning = transactions
+        .iter()
+        .find_map(|signed| signed.validate(revision, context.block.chain_id).ok()?);
+    if let Some(warning) = warning {
+        eprintln!("Warning: {}", warning);
+    }
+
+    context.vm = Some(vm.clone());
+    let reward = !sub_matches.is_present("no-reward");
+    let (header, receipts) = apply_block(&vm, &mut context, revision, &transactions, reward)?;
+    advance(&mut context, &header, block_time);
+
+    if sub_matches.is_present("json") {
+        let output = json!({
+            "number": header.number,
+            "hash": Bytes32(header.hash),
+            "parent_hash": Bytes32(header.parent_hash),
+            "state_root": Bytes32(header.state_root),
+            "transactions_root": Bytes32(header.transactions_root),
+            "receipts_root": Bytes32(header.receipts_root),
+            "gas_used": header.gas_used,
+            "receipts": receipts,
+        });
+        println!("{}", serde_json::to_string_pretty(&output).unwrap());
+    } else {
+        for receipt in &receipts {
+            println!(
+                "Receipt(index: {}, hash: {:?}, status: {}, gas used: {}, logs: {})",
+                receipt.transaction_index,
+                receipt.transaction_hash,
+                receipt.status,
+                receipt.gas_used,
+                receipt.logs.len()
+            );
+        }
+        println!(
+            "Block {}: 0x{} (transactions: {}, gas used: {})",
+            header.number,
+            hex::encode(header.hash),
+            receipts.len(),
+            header.gas_used
+        );
+        println!("State root: 0x{}", hex::encode(header.state_root));
+    }
+
+    if let Some(path) = sub_matches.value_of("output-storage") {
+        save_state(path, &mut context)?;
+    }
+    Ok(())
+}
+
+fn override_env(context: &mut TestHostContext, env: BlockEnv) {
+    let block = &mut context.block;
+    block.number = env.number.unwrap_or(block.number);
+    block.timestamp = env.timestamp.unwrap_or(block.timestamp);
+    block.coinbase = env.coinbase.unwrap_or_else(|| block.coinbase.clone());
+    block.gas_limit = env.gas_limit.unwrap_or(block.gas_limit);
+    block.difficulty = env.difficulty.unwrap_or_else(|| block.difficulty.clone());
+    block.chain_id = env.chain_id.unwrap_or(block.chain_id);
+    if env.base_fee.is_some() {
+        block.base_fee = env.base_fee;
+    }
+}
+
+/// Execute the transactions in the block environment of the context, the
+/// context is unchanged if any transaction is invalid. Return the sealed
+/// header and the receipts.
+pub fn apply_block(
+    vm: &EvmcVm,
+    context: &mut TestHostContext,
+    revision: Revision,
+    transactions: &[SignedTransaction],
+    reward: bool,
+) -> Result<(Header, Vec<TransactionReceipt>), String> {
+    let mut builder = BlockBuilder::new(context, revision);
+    for signed in transactions {
+        builder.push(vm, signed)?;
+    }
+    let (block_context, header, receipts) = builder.seal(reward)?;
+    *context = block_context;
+    Ok((header, receipts))
+}
+
+/// The block being built on a copy of the context, the transactions are
+/// executed one by one
+pub struct BlockBuilder {
+    context: TestHostContext,
+    revision: Revision,
+    raw_transactions: Vec<Vec<u8>>,
+    encoded_receipts: Vec<Vec<u8>>,
+    logs: Vec<ReportLog>,
+    receipts: Vec<TransactionReceipt>,
+    gas_used: u64,
+}
+
+impl BlockBuilder {
+    pub fn new(context: &TestHostContext, revision: Revision) -> BlockBuilder {
+        BlockBuilder {
+            context: context.clone(),
+            revision,
+            raw_transactions: Vec::new(),
+            encoded_receipts: Vec::new(),
+            logs: Vec::new(),
+            receipts: Vec::new(),
+            gas_used: 0,
+        }
+    }
+
+    /// The state after the transactions executed so far
+    pub fn context(&self) -> &TestHostContext {
+        &self.context
+    }
+
+    pub fn gas_left(&self) -> u64 {
+        self.context.block.gas_limit.saturating_sub(self.gas_used)
+    }
+
+    /// Execute the transaction, the block is unchanged if it is invalid
+    pub fn push(
+        &mut self,
+        vm: &EvmcVm,
+        signed: &SignedTransaction,
+    ) -> Result<&TransactionReceipt, String> {
+        let tx_hash = hex::encode(signed.hash);
+        signed
+            .validate(self.revision, self.context.block.chain_id)
+            .map_err(|err| format!("transaction 0x{}: {}", tx_hash, err))?;
+        let tx = &signed.transaction;
+        if tx.gas_limit > self.gas_left() {
+            return Err(format!(
+                "transaction 0x{}: the gas limit exceeds the block gas left",
+                tx_hash
+            ));
+        }
+        let result = apply_transaction(vm, &mut self.context, self.revision, tx)
+            .map_err(|err| format!("transaction 0x{}: {}", tx_hash, err))?;
+        self.gas_used += result.gas_used;
+        let byzantium = self.revision as u32 >= Revision::EVMC_BYZANTIUM as u32;
+        let receipt = Receipt {
+            tx_type: signed.tx_type,
+            post_state: if byzantium {
+                None
+            } else {
+                Some(state_root(&self.context)?)
+            },
+            success: result.status == StatusCode::EVMC_SUCCESS,
+            cumulative_gas_used: self.gas_used,
+            logs: result.logs.clone(),
+        };
+        self.raw_transactions.push(signed.raw.clone());
+        self.encoded_receipts.push(receipt.encode());
+        self.logs.extend(result.logs.iter().cloned());
+        let receipt = TransactionReceipt::new(
+            Bytes32(signed.hash),
+            tx.sender.clone(),
+            tx.to.clone(),
+            result.status,
+            result.gas_used,
+            result.created_address,
+            result.logs,
+        );
+        self.receipts
+            .push(push_receipt(&mut self.context, receipt).clone());
+        Ok(self.receipts.last().unwrap())
+    }
+
+    /// Seal the block, return the state after the block, the header and the receipts
+    pub fn seal(
+        self,
+        reward: bool,
+    ) -> Result<(TestHostContext, Header, Vec<TransactionReceipt>), String> {
+        let BlockBuilder {
+            mut context,
+            revision,
+            raw_transactions,
+            encoded_receipts,
+            logs,
+            mut receipts,
+            gas_used,
+        } = self;
+        let block = &context.block;
+        let parent_hash = block
+            .number
+            .checked_sub(1)
+            .and_then(|number| block.block_hashes.get(&number))
+            .map(|hash| hash.0)
+            .unwrap_or_default();
+        let mut header = Header {
+            parent_hash,
+            // keccak256 of the empty uncles list
+            uncles_hash: keccak(rlp::EMPTY_LIST_RLP).0,
+            coinbase: block.coinbase.clone(),
+            state_root: [0u8; 32],
+            transactions_root: ordered_trie_root(raw_transactions),
+            receipts_root: ordered_trie_root(encoded_receipts),
+            logs_bloom: logs_bloom(&logs).to_vec(),
+            difficulty: u256_from_bytes(&block.difficulty.0),
+            number: block.number,
+            gas_limit: block.gas_limit,
+            gas_used,
+            timestamp: block.timestamp,
+            extra_data: Vec::new(),
+            base_fee: block
+                .base_fee
+                .as_ref()
+                .map(|base_fee| u256_from_bytes(&base_fee.0)),
+            withdrawals_root: None,
+            hash: [0u8; 32],
+        };
+        if reward {
+            apply_rewards(&mut context, revision, &header, &[]);
+        }
+        header.state_root = state_root(&context)?;
+        header.hash = keccak(header.encode()).0;
+        for receipt in receipts.iter_mut().chain(context.receipts.iter_mut()) {
+            if receipt.block_number == header.number {
+                receipt.block_hash = Some(Bytes32(header.hash));
+            }
+        }
+        Ok((context, header, receipts))
+    }
+}
+
+/// Move to the next block: the hash of the sealed block is available to
+/// BLOCKHASH, the number and the timestamp are increased, and the base fee is
+/// adjusted to the gas used
+pub fn advance(context: &mut TestHostContext, header: &Header, block_time: u64) {
+    let block = &mut context.block;
+    if let Some(base_fee) = next_base_fee(header) {
+        block.base_fee = Some(Uint256(u256_to_bytes(base_fee)));
+    }
+    block
+        .block_hashes
+        .insert(header.number, Bytes32(header.hash));
+    let number = header.number + 1;
+    block
+        .block_hashes
+        .retain(|hash_number, _| hash_number + 256 >= number);
+    block.number = number;
+    block.timestamp = header.timestamp + block_time;
+}
+
+/// EIP-1559: the base fee of the child block moves by up to 1/8 toward the gas
+/// target (half the gas limit), None before London
+pub fn next_base_fee(header: &Header) -> Option<U256> {
+    const ELASTICITY_MULTIPLIER: u64 = 2;
+    const BASE_FEE_MAX_CHANGE_DENOMINATOR: u64 = 8;
+    let base_fee = header.base_fee?;
+    let gas_target = header.gas_limit / ELASTICITY_MULTIPLIER;
+    if gas_target == 0 || header.gas_used == gas_target {
+        return Some(base_fee);
+    }
+    let change = |gas_delta: u64| {
+        base_fee.saturating_mul(U256::from(gas_delta))
+            / U256::from(gas_target)
+            / U256::from(BASE_FEE_MAX_CHANGE_DENOMINATOR)
+    };
+    if header.gas_used > gas_target {
+        let delta = change(header.gas_used - gas_target).max(U256::one());
+        Some(base_fee.saturating_add(delta))
+    } else {
+        Some(base_fee - change(gas_target - header.gas_used))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(base_fee: Option<u64>, gas_used: u64) -> Header {
+        Header {
+            parent_hash: [0u8; 32],
+            uncles_hash: [0u8; 32],
+            coinbase: Address::default(),
+            state_root: [0u8; 32],
+            transactions_root: [0u8; 32],
+            receipts_root: [0u8; 32],
+            logs_bloom: Vec::new(),
+            difficulty: U256::zero(),
+            number: 1,
+            gas_limit: 30_000_000,
+            gas_used,
+            timestamp: 0,
+            extra_data: Vec::new(),
+            base_fee: base_fee.map(U256::from),
+            withdrawals_root: None,
+            hash: [0u8; 32],
+        }
+    }
+
+    #[test]
+    fn base_fee_update() {
+        let next = |base_fee, gas_used| next_base_fee(&header(Some(base_fee), gas_used));
+        // At the target, full and empty blocks
+        assert_eq!(
+            next(1_000_000_000, 15_000_000),
+            Some(U256::from(1_000_000_000u64))
+        );
+        assert_eq!(
+            next(1_000_000_000, 30_000_000),
+            Some(U256::from(1_125_000_000u64))
+        );
+        assert_eq!(next(1_000_000_000, 0), Some(U256::from(875_000_000u64)));
+        // The increase is at least 1
+        assert_eq!(next(7, 15_000_001), Some(U256::from(8)));
+        assert_eq!(next(7, 14_999_999), Some(U256::from(7)));
+        assert_eq!(next_base_fee(&header(None, 0)), None);
+    }
+}
@@ -0,0 +1,232 @@
+//! Apply a signed (RLP-encoded) transaction to the state file: decode it,
+//! recover the sender, validate it against the state and execute it
+
+use std::rc::Rc;
+
+use clap::{App, Arg, ArgMatches};
+use serde_json::json;
+
+use crate::evmc::{parse_revision, Address, Bytes32, Uint256};
+use crate::loader::create_vm;
+use crate::receipt::{push_receipt, TransactionReceipt};
+use crate::state_db::{load_state, save_state};
+use crate::statetest::parse_hex;
+use crate::transaction::{apply_transaction, decode_transaction, parse_u256, u256_to_bytes};
+use crate::trie::state_root;
+use crate::{JsonBytes, TestHostContext};
+
+/// The sub command to apply a signed transaction
+pub fn sub_command(name: &'static str) -> App<'static, 'static> {
+    App::new(name)
+        .about("Decode, validate and execute a signed transaction (RLP-encoded)")
+        .arg(
+            Arg::with_name("tx")
+                .long("tx")
+                .takes_value(true)
+                .required(true)
+                .help("The signed transaction (hex), as returned by eth_getRawTransactionByHash"),
+        )
+        .arg(
+            Arg::with_name("input-storage")
+                .long("input-storage")
+                .short("s")
+                .takes_value(true)
+                .help("The storage (accounts) json file"),
+        )
+        .arg(
+            Arg::with_name("output-storage")
+                .long("output-storage")
+                .short("o")
+                .takes_value(true)
+                .help("The storage file to write the state after the transaction"),
+        )
+        .arg(
+            Arg::with_name("revision")
+                .long("revision")
+                .short("r")
+                .takes_value(true)
+                .default_value("berlin")
+                .help("The EVM revision"),
+        )
+        .arg(
+            Arg::with_name("chain-id")
+                .long("chain-id")
+                .takes_value(true)
+                .help("The chain id (the one of the storage file by default)"),
+        )
+        .arg(
+            Arg::with_name("base-fee")
+                .long("base-fee")
+                .takes_value(true)
+                .help("The base fee per gas of the block (the one of the storage file by default)"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print the receipt and the output as json"),
+        )
+}
+
+pub fn process(sub_matches: &ArgMatches) -> Result<(), String> {
+    let vm = Rc::new(create_vm(sub_matches.value_of("vm"))?);
+    let revision = parse_revision(sub_matches.value_of("revision").unwrap())?;
+    let raw = parse_hex(sub_matches.value_of("tx").unwrap())?;
+    let mut context: TestHostContext = match sub_matches.value_of("input-storage") {
+        Some(path) => load_state(path)?,
+        None => TestHostContext::new(0, Address::default()),
+    };
+    if let Some(chain_id) = sub_matches.value_of("chain-id") {
+        context.block.chain_id = chain_id
+            .parse()
+            .map_err(|err| format!("{}: {}", chain_id, err))?;
+    }
+    if let Some(base_fee) = sub_matches.value_of("base-fee") {
+        context.block.base_fee = Some(Uint256(u256_to_bytes(parse_u256(base_fee)?)));
+    }
+
+    let signed = decode_transaction(&raw)?;
+    let hash = format!("0x{}", hex::encode(signed.hash));
+    let warning = signed
+        .validate(revision, context.block.chain_id)
+        .map_err(|err| format!("Invalid transaction {}: {}", hash, err))?;
+    if let Some(warning) = warning {
+        eprintln!("Warning: {}", warning);
+    }
+    let tx = &signed.transaction;
+    context.vm = Some(vm.clone());
+    let result = apply_transaction(&vm, &mut context, revision, tx)
+        .map_err(|err| format!("Invalid transaction {}: {}", hash, err))?;
+
+    let receipt = TransactionReceipt::new(
+        Bytes32(signed.hash),
+        tx.sender.clone(),
+        tx.to.clone(),
+        result.status,
+        result.gas_used,
+        result.created_address.clone(),
+        result.logs,
+    );
+    let receipt = push_receipt(&mut context, receipt).clone();
+    let root = state_root(&context)?;
+    if sub_matches.is_present("json") {
+        let output = json!({
+            "receipt": receipt,
+            "output": JsonBytes(result.output),
+            "state_root": Bytes32(root),
+        });
+        println!("{}", serde_json::to_string_pretty(&output).unwrap());
+    } else {
+        println!("Transaction: {} (type: {})", hash, signed.tx_type);
+        println!("Sender: {:?}, nonce: {}", tx.sender, tx.nonce);
+        if let Some(address) = receipt.contract_address.as_ref() {
+            println!("Contract created: {:?}", address);
+        }
+        println!(
+            "Status: {:?}, gas used: {}, logs: {}",
+            result.status,
+            receipt.gas_used,
+            receipt.logs.len()
+        );
+        println!("Output: 0x{}", hex::encode(&result.output));
+        println!("State root: 0x{}", hex::encode(root));
+    }
+
+    if let Some(path) = sub_matches.value_of("output-storage") {
+        save_state(path, &mut context)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use ethereum_types::U256;
+
+    use super::*;
+    use crate::transaction::{
+        balance_of, nonce_of, secret_key_address, set_balance, sign_transaction, Transaction,
+    };
+
+    const SECRET_KEY: &str = "45a915e4d060149eb4365960e6a7a45f334393093061116b197e3240065ff2d8";
+
+    fn temp_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("play-evmone-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn apply_transfer() {
+        let secret_key = hex::decode(SECRET_KEY).unwrap();
+        let sender = secret_key_address(&secret_key).unwrap();
+        let to = Address([0xbb; 20]);
+        let coinbase = Address([0xcc; 20]);
+        let ether = U256::exp10(18);
+
+        let input = temp_path("apply-tx-in");
+        let output = temp_path("apply-tx-out");
+        let mut context = TestHostContext::new(0, Address::default());
+        context.block.chain_id = 1;
+        context.block.coinbase = coinbase.clone();
+        set_balance(&mut context, &sender, ether);
+        save_state(&input, &mut context).unwrap();
+
+        let tx = Transaction {
+            sender: sender.clone(),
+            to: Some(to.clone()),
+            gas_limit: 21000,
+            gas_price: U256::from(10),
+            value: U256::from(1000),
+            ..Default::default()
+        };
+        let raw = sign_transaction(0, Some(1), &tx, &secret_key).unwrap();
+        let matches = sub_command("apply-tx").get_matches_from(vec![
+            "apply-tx",
+            "--tx",
+            &hex::encode(&raw),
+            "--input-storage",
+            &input,
+            "--output-storage",
+            &output,
+        ]);
+        process(&matches).unwrap();
+
+        let context = load_state(&output).unwrap();
+        assert_eq!(nonce_of(&context, &sender), 1);
+        assert_eq!(
+            balance_of(&context, &sender),
+            ether - U256::from(1000 + 21000 * 10)
+        );
+        assert_eq!(balance_of(&context, &to), U256::from(1000));
+        assert_eq!(balance_of(&context, &coinbase), U256::from(21000 * 10));
+
+        // The nonce is used, and the chain id must match
+        let matches = |raw: &[u8], chain_id: &str| {
+            sub_command("apply-tx").get_matches_from(vec![
+                "apply-tx".to_string(),
+                format!("--tx={}", hex::encode(raw)),
+                format!("--input-storage={}", output),
+                format!("--chain-id={}", chain_id),
+            ])
+        };
+        let hash = format!("0x{}", hex::encode(keccak_hash::keccak(&raw).0));
+        assert_eq!(
+            process(&matches(&raw, "1")).unwrap_err(),
+            format!(
+                "Invalid transaction {}: Invalid nonce: expected 1, got 0",
+                hash
+            )
+        );
+        assert_eq!(
+            process(&matches(&raw, "5")).unwrap_err(),
+            format!(
+                "Invalid transaction {}: Invalid chain id: expected 5, got 1",
+                hash
+            )
+        );
+        let _ = fs::remove_file(&input);
+        let _ = fs::remove_file(&output);
+    }
+}
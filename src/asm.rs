@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+
+use ethereum_types::U256;
+
+use crate::evmc::Revision;
+use crate::instructions::{names_table, push_size, OP_PUSH1};
+
+#[derive(Debug, Clone)]
+enum Operand {
+    /// A literal value with the minimal size in bytes it needs
+    Value(Vec<u8>),
+    Label(String),
+}
+
+#[derive(Debug, Clone)]
+enum Statement {
+    Label(String),
+    Instruction(u8),
+    /// PUSH with the explicit size (PUSHn) or the auto size (PUSH)
+    Push {
+        size: Option<usize>,
+        operand: Operand,
+    },
+    Data(Vec<u8>),
+}
+
+/// Assemble the source text into the bytecode.
+///
+/// The syntax is the output of the disassembler: one instruction per line,
+/// `label:` definitions, `.data 0x..` raw bytes and `;` or `//` comments.
+/// `PUSH` without a size picks the smallest PUSHn fits the operand, which can
+/// be a hex (0x..) or decimal number or a label.
+pub fn assemble(source: &str, revision: Revision) -> Result<Vec<u8>, String> {
+    let names = names_table(revision);
+    let opcodes: HashMap<&str, u8> = names
+        .iter()
+        .enumerate()
+        .filter_map(|(opcode, name)| name.map(|name| (name, opcode as u8)))
+        .collect();
+
+    let mut statements = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        parse_line(line, &opcodes, &mut statements)
+            .map_err(|err| format!("line {}: {}", index + 1, err))?;
+    }
+
+    // The size of a PUSH with a label depends on the label offset, so grow
+    // the sizes until all the offsets are stable.
+    let mut label_sizes = vec![1usize; statements.len()];
+    loop {
+        let labels = label_offsets(&statements, &label_sizes)?;
+        let mut changed = false;
+        for (index, statement) in statements.iter().enumerate() {
+            if let Statement::Push {
+                size: None,
+                operand: Operand::Label(label),
+            } = statement
+            {
+                let size = minimal_bytes(labels[label.as_str()] as u64).len();
+                if size > label_sizes[index] {
+                    label_sizes[index] = size;
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            return emit(&statements, &label_sizes, &labels);
+        }
+    }
+}
+
+fn parse_line(
+    line: &str,
+    opcodes: &HashMap<&str, u8>,
+    statements: &mut Vec<Statement>,
+) -> Result<(), String> {
+    let mut line = line;
+    for marker in &[";", "//"] {
+        if let Some(pos) = line.find(marker) {
+            line = &line[..pos];
+        }
+    }
+    let mut line = line.trim();
+    if let Some(pos) = line.find(':') {
+        let label = line[..pos].trim();
+        if !is_label(label) {
+            return Err(format!("Invalid label: {}", label));
+        }
+        statements.push(Statement::Label(label.to_string()));
+        line = line[pos + 1..].trim();
+    }
+    if line.is_empty() {
+        return Ok(());
+    }
+
+    let mut parts = line.split_whitespace();
+    let mnemonic = parts.next().unwrap();
+    let operand = parts.next();
+    if let Some(extra) = parts.next() {
+        return Err(format!("Unexpected token: {}", extra));
+    }
+    let upper = mnemonic.to_uppercase();
+    let statement = if mnemonic == ".data" {
+        let value = operand.ok_or_else(|| ".data requires the bytes".to_string())?;
+        let value = value
+            .strip_prefix("0x")
+            .ok_or_else(|| format!("Invalid data: {}", value))?;
+        Statement::Data(hex::decode(value).map_err(|err| err.to_string())?)
+    } else if upper == "PUSH" {
+        let operand = operand.ok_or_else(|| "PUSH requires an operand".to_string())?;
+        Statement::Push {
+            size: None,
+            operand: parse_operand(operand)?,
+        }
+    } else {
+        let opcode = *opcodes
+            .get(upper.as_str())
+            .ok_or_else(|| format!("Invalid opcode for the revision: {}", mnemonic))?;
+        let size = push_size(opcode);
+        if size > 0 {
+            let operand = operand.ok_or_else(|| format!("{} requires an operand", upper))?;
+            let operand = parse_operand(operand)?;
+            if let Operand::Value(bytes) = &operand {
+                if bytes.len() > size {
+                    return Err(format!("Operand too large for {}", upper));
+                }
+            }
+            Statement::Push {
+                size: Some(size),
+                operand,
+            }
+        } else {
+            if let Some(operand) = operand {
+                return Err(format!("Unexpected operand: {}", operand));
+            }
+            Statement::Instruction(opcode)
+        }
+    };
+    statements.push(statement);
+    Ok(())
+}
+
+fn is_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+fn parse_operand(operand: &str) -> Result<Operand, String> {
+    if let Some(digits) = operand.strip_prefix("0x") {
+        // Keep the leading zeros, `PUSH 0x0001` is a PUSH2
+        let digits = if digits.len() % 2 == 1 {
+            format!("0{}", digits)
+        } else {
+            digits.to_string()
+        };
+        let bytes = hex::decode(&digits).map_err(|err| err.to_string())?;
+        if bytes.is_empty() || bytes.len() > 32 {
+            return Err(format!("Invalid operand: {}", operand));
+        }
+        Ok(Operand::Value(bytes))
+    } else if operand.chars().all(|c| c.is_ascii_digit()) {
+        let value = U256::from_dec_str(operand).map_err(|err| format!("{:?}", err))?;
+        let mut bytes = [0u8; 32];
+        value.to_big_endian(&mut bytes);
+        let start = bytes.iter().position(|b| *b != 0).unwrap_or(31);
+        Ok(Operand::Value(bytes[start..].to_vec()))
+    } else if is_label(operand) {
+        Ok(Operand::Label(operand.to_string()))
+    } else {
+        Err(format!("Invalid operand: {}", operand))
+    }
+}
+
+fn minimal_bytes(value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(7);
+    bytes[start..].to_vec()
+}
+
+fn push_operand_size(statement: &Statement, label_size: usize) -> usize {
+    match statement {
+        Statement::Push {
+            size: Some(size), ..
+        } => *size,
+        Statement::Push {
+            operand: Operand::Value(bytes),
+            ..
+        } => bytes.len(),
+        _ => label_size,
+    }
+}
+
+fn label_offsets<'a>(
+    statements: &'a [Statement],
+    label_sizes: &[usize],
+) -> Result<HashMap<&'a str, usize>, String> {
+    let mut labels = HashMap::new();
+    let mut offset = 0;
+    for (statement, label_size) in statements.iter().zip(label_sizes) {
+        match statement {
+            Statement::Label(label) => {
+                if labels.insert(label.as_str(), offset).is_some() {
+                    return Err(format!("Duplicated label: {}", label));
+                }
+            }
+            Statement::Instruction(_) => offset += 1,
+            Statement::Push { .. } => offset += 1 + push_operand_size(statement, *label_size),
+            Statement::Data(bytes) => offset += bytes.len(),
+        }
+    }
+    for statement in statements {
+        if let Statement::Push {
+            operand: Operand::Label(label),
+            ..
+        } = statement
+        {
+            if !labels.contains_key(label.as_str()) {
+                return Err(format!("Unknown label: {}", label));
+            }
+        }
+    }
+    Ok(labels)
+}
+
+fn emit(
+    statements: &[Statement],
+    label_sizes: &[usize],
+    labels: &HashMap<&str, usize>,
+) -> Result<Vec<u8>, String> {
+    let mut code = Vec::new();
+    for (statement, label_size) in statements.iter().zip(label_sizes) {
+        match statement {
+            Statement::Label(_) => {}
+            Statement::Instruction(opcode) => code.push(*opcode),
+            Statement::Push { operand, .. } => {
+                let size = push_operand_size(statement, *label_size);
+                let bytes = match operand {
+                    Operand::Value(bytes) => bytes.clone(),
+                    Operand::Label(label) => minimal_bytes(labels[label.as_str()] as u64),
+                };
+                if bytes.len() > size {
+                    return Err(format!("Label offset too large for PUSH{}", size));
+                }
+                code.push(OP_PUSH1 + size as u8 - 1);
+                code.extend(vec![0u8; size - bytes.len()]);
+                code.extend(bytes);
+            }
+            Statement::Data(bytes) => code.extend(bytes),
+        }
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::format_code;
+
+    fn round_trip(code: &[u8]) {
+        let text = format_code(code, Revision::EVMC_BERLIN, true);
+        let assembled = assemble(&text, Revision::EVMC_BERLIN).unwrap();
+        assert_eq!(hex::encode(&assembled), hex::encode(code), "{}", text);
+    }
+
+    #[test]
+    fn round_trip_every_opcode() {
+        let mut code = Vec::new();
+        for opcode in 0..=255u8 {
+            code.push(opcode);
+            code.extend((0..push_size(opcode)).map(|index| index as u8 + 1));
+        }
+        round_trip(&code);
+    }
+
+    #[test]
+    fn round_trip_push_with_leading_zeros() {
+        // PUSH2 0x0001, PUSH32 0, JUMPDEST
+        let mut code = vec![0x61, 0x00, 0x01, 0x7f];
+        code.extend([0u8; 32].iter());
+        code.push(0x5b);
+        round_trip(&code);
+    }
+
+    #[test]
+    fn round_trip_truncated_push() {
+        round_trip(&[0x60, 0x01, 0x62, 0xff]);
+    }
+
+    #[test]
+    fn round_trip_metadata() {
+        // {"solc": 0x000811} as CBOR, and its length
+        let mut code = vec![0x60, 0x80, 0x60, 0x40, 0x52, 0x00];
+        code.extend(hex::decode("a164736f6c6343000811000a").unwrap());
+        round_trip(&code);
+    }
+
+    #[test]
+    fn auto_push_size() {
+        let code = assemble("PUSH 0\nPUSH 256\nPUSH 0x0001", Revision::EVMC_BERLIN).unwrap();
+        assert_eq!(hex::encode(code), "6000610100610001");
+    }
+
+    #[test]
+    fn labels() {
+        let source = "    PUSH end\n    JUMP\n    INVALID\nend:\n    JUMPDEST\n    STOP";
+        let code = assemble(source, Revision::EVMC_BERLIN).unwrap();
+        assert_eq!(hex::encode(code), "600456fe5b00");
+    }
+
+    #[test]
+    fn invalid_source() {
+        for source in &["PUSH1 0x0100", "NOPE", "PUSH missing", "a:\na:", "ADD 1"] {
+            assert!(
+                assemble(source, Revision::EVMC_BERLIN).is_err(),
+                "{}",
+                source
+            );
+        }
+    }
+}
@@ -0,0 +1,333 @@
+//! Blocks: header and body decoding, the miner rewards and the withdrawals
+
+use ethereum_types::U256;
+use keccak_hash::keccak;
+use rlp::{Rlp, RlpStream};
+
+use crate::evmc::{Address, Revision};
+use crate::transaction::{
+    balance_of, rlp_at, rlp_bytes, rlp_u64, rlp_word, set_balance, top_level_list, u256_from_bytes,
+    u256_to_bytes,
+};
+use crate::trie::trim_zeros;
+use crate::TestHostContext;
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub parent_hash: [u8; 32],
+    pub uncles_hash: [u8; 32],
+    pub coinbase: Address,
+    pub state_root: [u8; 32],
+    pub transactions_root: [u8; 32],
+    pub receipts_root: [u8; 32],
+    pub logs_bloom: Vec<u8>,
+    pub difficulty: U256,
+    pub number: u64,
+    pub gas_limit: u64,
+    pub gas_used: u64,
+    pub timestamp: u64,
+    pub extra_data: Vec<u8>,
+    /// EIP-1559
+    pub base_fee: Option<U256>,
+    /// EIP-4895
+    pub withdrawals_root: Option<[u8; 32]>,
+    /// keccak256 of the encoded header
+    pub hash: [u8; 32],
+}
+
+impl Header {
+    pub fn decode(rlp: &Rlp) -> Result<Header, String> {
+        let count = rlp.item_count().map_err(|err| err.to_string())?;
+        if !rlp.is_list() || !(15..=17).contains(&count) {
+            return Err(format!("Invalid block header with {} items", count));
+        }
+        let logs_bloom = rlp_bytes(rlp, 6)?;
+        if logs_bloom.len() != 256 {
+            return Err("Invalid logs bloom".to_string());
+        }
+        // The seal is not verified
+        rlp_hash(rlp, 13)?;
+        if rlp_bytes(rlp, 14)?.len() != 8 {
+            return Err("Invalid block nonce".to_string());
+        }
+        Ok(Header {
+            parent_hash: rlp_hash(rlp, 0)?,
+            uncles_hash: rlp_hash(rlp, 1)?,
+            coinbase: rlp_address(rlp, 2)?,
+            state_root: rlp_hash(rlp, 3)?,
+            transactions_root: rlp_hash(rlp, 4)?,
+            receipts_root: rlp_hash(rlp, 5)?,
+            logs_bloom,
+            difficulty: u256_from_bytes(&rlp_word(rlp, 7)?),
+            number: rlp_u64(rlp, 8)?,
+            gas_limit: rlp_u64(rlp, 9)?,
+            gas_used: rlp_u64(rlp, 10)?,
+            timestamp: rlp_u64(rlp, 11)?,
+            extra_data: rlp_bytes(rlp, 12)?,
+            base_fee: if count > 15 {
+                Some(u256_from_bytes(&rlp_word(rlp, 15)?))
+            } else {
+                None
+            },
+            withdrawals_root: if count > 16 {
+                Some(rlp_hash(rlp, 16)?)
+            } else {
+                None
+            },
+            hash: keccak(rlp.as_raw()).0,
+        })
+    }
+
+    /// The rlp of the header fields, the mix hash and the nonce are zero
+    pub fn encode(&self) -> Vec<u8> {
+        let mut stream = RlpStream::new();
+        stream.begin_unbounded_list();
+        stream.append(&&self.parent_hash[..]);
+        stream.append(&&self.uncles_hash[..]);
+        stream.append(&&self.coinbase.0[..]);
+        stream.append(&&self.state_root[..]);
+        stream.append(&&self.transactions_root[..]);
+        stream.append(&&self.receipts_root[..]);
+        stream.append(&self.logs_bloom);
+        stream.append(&trim_zeros(&u256_to_bytes(self.difficulty)));
+        stream.append(&self.number);
+        stream.append(&self.gas_limit);
+        stream.append(&self.gas_used);
+        stream.append(&self.timestamp);
+        stream.append(&self.extra_data);
+        stream.append(&&[0u8; 32][..]);
+        stream.append(&&[0u8; 8][..]);
+        if let Some(base_fee) = self.base_fee {
+            stream.append(&trim_zeros(&u256_to_bytes(base_fee)));
+        }
+        if let Some(root) = self.withdrawals_root.as_ref() {
+            stream.append(&&root[..]);
+        }
+        stream.finalize_unbounded_list();
+        stream.out()
+    }
+}
+
+/// EIP-4895 withdrawal, the amount is in Gwei
+#[derive(Debug, Clone)]
+pub struct Withdrawal {
+    pub address: Address,
+    pub amount: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub header: Header,
+    /// The encoded transactions, as in the transactions trie
+    pub transactions: Vec<Vec<u8>>,
+    pub uncles: Vec<Header>,
+    /// keccak256 of the encoded uncle headers list
+    pub uncles_hash: [u8; 32],
+    pub withdrawals: Option<Vec<Withdrawal>>,
+    /// The encoded withdrawals, as in the withdrawals trie
+    pub encoded_withdrawals: Vec<Vec<u8>>,
+}
+
+impl Block {
+    /// Decode rlp([header, transactions, uncles]) or rlp([header, transactions, uncles, withdrawals])
+    pub fn decode(raw: &[u8]) -> Result<Block, String> {
+        let rlp = top_level_list(raw, 3).or_else(|_| top_level_list(raw, 4))?;
+        let header = Header::decode(&rlp_at(&rlp, 0)?)?;
+
+        let mut transactions = Vec::new();
+        for item in rlp_at(&rlp, 1)?.iter() {
+            if item.is_list() {
+                transactions.push(item.as_raw().to_vec());
+            } else {
+                transactions.push(item.data().map_err(|err| err.to_string())?.to_vec());
+            }
+        }
+        let uncles_rlp = rlp_at(&rlp, 2)?;
+        let uncles = uncles_rlp
+            .iter()
+            .map(|uncle| Header::decode(&uncle))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut encoded_withdrawals = Vec::new();
+        let withdrawals = if rlp.item_count().map_err(|err| err.to_string())? == 4 {
+            let mut withdrawals = Vec::new();
+            for item in rlp_at(&rlp, 3)?.iter() {
+                // The index and the validator index
+                rlp_u64(&item, 0)?;
+                rlp_u64(&item, 1)?;
+                withdrawals.push(Withdrawal {
+                    address: rlp_address(&item, 2)?,
+                    amount: rlp_u64(&item, 3)?,
+                });
+                encoded_withdrawals.push(item.as_raw().to_vec());
+            }
+            Some(withdrawals)
+        } else {
+            None
+        };
+        Ok(Block {
+            header,
+            transactions,
+            uncles,
+            uncles_hash: keccak(uncles_rlp.as_raw()).0,
+            withdrawals,
+            encoded_withdrawals,
+        })
+    }
+}
+
+/// The static block reward of the revision in wei
+pub fn block_reward(revision: Revision) -> U256 {
+    let ether = U256::from(1_000_000_000_000_000_000u64);
+    if revision as u32 >= Revision::EVMC_CONSTANTINOPLE as u32 {
+        ether * U256::from(2)
+    } else if revision as u32 >= Revision::EVMC_BYZANTIUM as u32 {
+        ether * U256::from(3)
+    } else {
+        ether * U256::from(5)
+    }
+}
+
+/// Reward the miner of the block and the miners of the uncles
+pub fn apply_rewards(
+    context: &mut TestHostContext,
+    revision: Revision,
+    header: &Header,
+    uncles: &[Header],
+) {
+    let reward = block_reward(revision);
+    for uncle in uncles {
+        let uncle_reward = reward * U256::from(uncle.number + 8 - header.number) / U256::from(8);
+        let balance = balance_of(context, &uncle.coinbase);
+        set_balance(context, &uncle.coinbase, balance + uncle_reward);
+    }
+    let miner_reward = reward + reward / U256::from(32) * U256::from(uncles.len() as u64);
+    let balance = balance_of(context, &header.coinbase);
+    set_balance(context, &header.coinbase, balance + miner_reward);
+}
+
+/// Credit the withdrawals, the amounts are converted from Gwei to wei
+pub fn apply_withdrawals(context: &mut TestHostContext, withdrawals: &[Withdrawal]) {
+    for withdrawal in withdrawals {
+        let amount = U256::from(withdrawal.amount) * U256::from(1_000_000_000u64);
+        let balance = balance_of(context, &withdrawal.address);
+        set_balance(context, &withdrawal.address, balance + amount);
+    }
+}
+
+fn rlp_hash(rlp: &Rlp, index: usize) -> Result<[u8; 32], String> {
+    let data = rlp_bytes(rlp, index)?;
+    if data.len() != 32 {
+        return Err("Invalid hash".to_string());
+    }
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&data);
+    Ok(hash)
+}
+
+fn rlp_address(rlp: &Rlp, index: usize) -> Result<Address, String> {
+    let data = rlp_bytes(rlp, index)?;
+    if data.len() != 20 {
+        return Err("Invalid address".to_string());
+    }
+    let mut address = Address::default();
+    address.0.copy_from_slice(&data);
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        Header {
+            parent_hash: [1u8; 32],
+            uncles_hash: keccak(rlp::EMPTY_LIST_RLP).0,
+            coinbase: Address([0xcc; 20]),
+            state_root: [2u8; 32],
+            transactions_root: [3u8; 32],
+            receipts_root: [4u8; 32],
+            logs_bloom: vec![0u8; 256],
+            difficulty: U256::from(0x20000),
+            number: 7,
+            gas_limit: 10_000_000,
+            gas_used: 21000,
+            timestamp: 1000,
+            extra_data: b"play-evmone".to_vec(),
+            base_fee: None,
+            withdrawals_root: None,
+            hash: [0u8; 32],
+        }
+    }
+
+    fn round_trip(header: &Header) -> Header {
+        let encoded = header.encode();
+        let decoded = Header::decode(&Rlp::new(&encoded)).unwrap();
+        assert_eq!(decoded.encode(), encoded);
+        assert_eq!(decoded.hash, keccak(&encoded).0);
+        decoded
+    }
+
+    #[test]
+    fn encode_and_decode_header() {
+        let decoded = round_trip(&header());
+        assert_eq!(decoded.coinbase, Address([0xcc; 20]));
+        assert_eq!(decoded.difficulty, U256::from(0x20000));
+        assert_eq!(decoded.number, 7);
+        assert_eq!(decoded.extra_data, b"play-evmone");
+        assert_eq!(decoded.base_fee, None);
+        assert_eq!(decoded.withdrawals_root, None);
+
+        // London, then Shanghai
+        let mut london = header();
+        london.base_fee = Some(U256::from(7));
+        assert_eq!(round_trip(&london).base_fee, Some(U256::from(7)));
+        let mut shanghai = london.clone();
+        shanghai.withdrawals_root = Some([5u8; 32]);
+        let decoded = round_trip(&shanghai);
+        assert_eq!(decoded.base_fee, Some(U256::from(7)));
+        assert_eq!(decoded.withdrawals_root, Some([5u8; 32]));
+    }
+
+    #[test]
+    fn reject_invalid_header() {
+        let mut short_bloom = header();
+        short_bloom.logs_bloom = vec![0u8; 255];
+        let encoded = short_bloom.encode();
+        assert_eq!(
+            Header::decode(&Rlp::new(&encoded)).unwrap_err(),
+            "Invalid logs bloom"
+        );
+
+        let mut stream = RlpStream::new_list(14);
+        for _ in 0..14 {
+            stream.append_empty_data();
+        }
+        let encoded = stream.out();
+        assert_eq!(
+            Header::decode(&Rlp::new(&encoded)).unwrap_err(),
+            "Invalid block header with 14 items"
+        );
+    }
+
+    #[test]
+    fn decode_genesis_block() {
+        let tests: serde_json::Value = serde_json::from_str(include_str!(
+            "../testdata/BlockchainTests/transferInBlock.json"
+        ))
+        .unwrap();
+        let genesis = tests["transferInBlock_Berlin"]["genesisRLP"]
+            .as_str()
+            .unwrap();
+        let raw = hex::decode(genesis.trim_start_matches("0x")).unwrap();
+        let block = Block::decode(&raw).unwrap();
+        assert_eq!(
+            hex::encode(block.header.hash),
+            "52949361323b7bc1082a06d17d95b13b70cfff01fcce3b5fef4f81914a9dd542"
+        );
+        assert_eq!(block.uncles_hash, block.header.uncles_hash);
+        assert!(block.transactions.is_empty() && block.uncles.is_empty());
+        assert!(block.withdrawals.is_none());
+        // The seal is zero, the header is encoded back as it was
+        assert_eq!(block.header.encode(), &raw[3..raw.len() - 2]);
+    }
+}
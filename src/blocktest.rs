@@ -0,0 +1,405 @@
+//! Runner of the BlockchainTests fixtures from ethereum/tests
+//!
+//! The blocks are imported with the NoProof seal engine: the proof of work,
+//! the difficulty and the validity of the uncle headers are not checked.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+use clap::{App, Arg, ArgMatches};
+use ethereum_types::U256;
+use serde::Deserialize;
+
+use crate::block::{apply_rewards, apply_withdrawals, Block, Header};
+use crate::evmc::{Bytes32, EvmcVm, Revision, StatusCode, Uint256};
+use crate::loader::create_vm;
+use crate::receipt::{logs_bloom, Receipt};
+use crate::statetest::{collect_files, fork_revision, parse_hex, pre_state, PreAccount};
+use crate::transaction::{apply_transaction, decode_transaction, u256_to_bytes};
+use crate::trie::{ordered_trie_root, state_root};
+use crate::{BlockContext, TestHostContext};
+
+/// The chain id of the fixtures
+const CHAIN_ID: u64 = 1;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FixtureBlock {
+    rlp: String,
+    #[serde(default)]
+    expect_exception: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BlockchainTest {
+    blocks: Vec<FixtureBlock>,
+    #[serde(rename = "genesisRLP")]
+    genesis_rlp: String,
+    lastblockhash: String,
+    network: String,
+    pre: BTreeMap<String, PreAccount>,
+    #[serde(default)]
+    post_state: Option<BTreeMap<String, PreAccount>>,
+    #[serde(default)]
+    post_state_hash: Option<String>,
+}
+
+// An imported block and the state after it
+struct ChainBlock {
+    header: Header,
+    context: TestHostContext,
+    total_difficulty: U256,
+}
+
+/// The sub command to run the blockchain tests
+pub fn sub_command(name: &'static str) -> App<'static, 'static> {
+    App::new(name)
+        .about("Run the BlockchainTests fixtures (ethereum/tests) and compare the last block and state")
+        .arg(
+            Arg::with_name("path")
+                .long("path")
+                .short("p")
+                .takes_value(true)
+                .required(true)
+                .help("The fixture file or a directory of fixtures"),
+        )
+        .arg(
+            Arg::with_name("fork")
+                .long("fork")
+                .takes_value(true)
+                .multiple(true)
+                .help("Only run the networks (like Istanbul, Berlin)"),
+        )
+        .arg(
+            Arg::with_name("test")
+                .long("test")
+                .short("t")
+                .takes_value(true)
+                .help("Only run the tests whose name contains the text"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .short("v")
+                .help("Print the passed tests too"),
+        )
+}
+
+pub fn process(sub_matches: &ArgMatches) -> Result<(), String> {
+    let forks: Option<Vec<&str>> = sub_matches.values_of("fork").map(|values| values.collect());
+    let filter = sub_matches.value_of("test");
+    let verbose = sub_matches.is_present("verbose");
+    let mut files = Vec::new();
+    collect_files(Path::new(sub_matches.value_of("path").unwrap()), &mut files)?;
+
+    let vm = Rc::new(create_vm(sub_matches.value_of("vm"))?);
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for file in files {
+        let data = fs::read(&file).map_err(|err| format!("{}: {}", file.display(), err))?;
+        let tests: BTreeMap<String, BlockchainTest> = match serde_json::from_slice(&data) {
+            Ok(tests) => tests,
+            Err(err) => {
+                println!("[SKIP] {}: {}", file.display(), err);
+                skipped += 1;
+                continue;
+            }
+        };
+        for (name, test) in tests {
+            if filter.map(|filter| !name.contains(filter)).unwrap_or(false)
+                || forks
+                    .as_ref()
+                    .map(|forks| !forks.contains(&test.network.as_str()))
+                    .unwrap_or(false)
+            {
+                continue;
+            }
+            let revision = match fork_revision(&test.network) {
+                Some(revision) => revision,
+                None => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            match run_test(&vm, &test, revision) {
+                Ok(errors) if errors.is_empty() => {
+                    passed += 1;
+                    if verbose {
+                        println!("[PASS] {}", name);
+                    }
+                }
+                Ok(errors) => {
+                    failed += 1;
+                    println!("[FAIL] {}", name);
+                    for error in errors {
+                        println!("       {}", error);
+                    }
+                }
+                Err(err) => {
+                    failed += 1;
+                    println!("[FAIL] {}\n       {}", name, err);
+                }
+            }
+        }
+    }
+    println!("{} passed, {} failed, {} skipped", passed, failed, skipped);
+    if failed > 0 {
+        return Err(format!("{} tests failed", failed));
+    }
+    Ok(())
+}
+
+// Import the blocks in order, the canonical chain is the one with the most total difficulty
+fn run_test(
+    vm: &Rc<EvmcVm>,
+    test: &BlockchainTest,
+    revision: Revision,
+) -> Result<Vec<String>, String> {
+    let genesis = Block::decode(&parse_hex(&test.genesis_rlp)?)?;
+    let mut context = pre_state(&test.pre)?;
+    context.vm = Some(vm.clone());
+    if state_root(&context)? != genesis.header.state_root {
+        return Err("The pre state does not match the genesis state root".to_string());
+    }
+    let mut best = genesis.header.hash;
+    let mut chain = HashMap::new();
+    chain.insert(
+        best,
+        ChainBlock {
+            total_difficulty: genesis.header.difficulty,
+            header: genesis.header,
+            context,
+        },
+    );
+
+    let mut errors = Vec::new();
+    for (index, fixture) in test.blocks.iter().enumerate() {
+        let imported =
+            parse_hex(&fixture.rlp).and_then(|raw| import_block(vm, revision, &chain, &raw));
+        match (imported, fixture.expect_exception.as_ref()) {
+            (Ok(block), None) => {
+                if block.total_difficulty > chain[&best].total_difficulty {
+                    best = block.header.hash;
+                }
+                chain.insert(block.header.hash, block);
+            }
+            (Ok(_), Some(exception)) => {
+                errors.push(format!(
+                    "block {}: expected exception {}, the block is valid",
+                    index, exception
+                ));
+            }
+            (Err(err), None) => errors.push(format!("block {}: {}", index, err)),
+            (Err(_), Some(_)) => {}
+        }
+    }
+
+    let last = format!("0x{}", hex::encode(best));
+    if last != test.lastblockhash.to_lowercase() {
+        errors.push(format!(
+            "last block: expected {}, got {}",
+            test.lastblockhash, last
+        ));
+    }
+    let expected_root = match (test.post_state.as_ref(), test.post_state_hash.as_ref()) {
+        (Some(post), _) => Some(state_root(&pre_state(post)?)?),
+        (None, Some(hash)) => Some(parse_hash(hash)?),
+        (None, None) => None,
+    };
+    let root = state_root(&chain[&best].context)?;
+    if let Some(expected) = expected_root {
+        if expected != root {
+            errors.push(format!(
+                "state root: expected 0x{}, got 0x{}",
+                hex::encode(expected),
+                hex::encode(root)
+            ));
+        }
+    }
+    Ok(errors)
+}
+
+fn parse_hash(value: &str) -> Result<[u8; 32], String> {
+    let bytes = parse_hex(value)?;
+    if bytes.len() != 32 {
+        return Err(format!("Invalid hash: {}", value));
+    }
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&bytes);
+    Ok(hash)
+}
+
+fn validate_header(header: &Header, parent: &Header) -> Result<(), String> {
+    if header.number != parent.number + 1 {
+        return Err(format!(
+            "Invalid block number {}, the parent is {}",
+            header.number, parent.number
+        ));
+    }
+    if header.timestamp <= parent.timestamp {
+        return Err("The timestamp is not greater than the parent".to_string());
+    }
+    let max_delta = parent.gas_limit / 1024;
+    if header.gas_limit < 5000
+        || header.gas_limit >= parent.gas_limit + max_delta
+        || header.gas_limit + max_delta <= parent.gas_limit
+    {
+        return Err(format!("Invalid gas limit {}", header.gas_limit));
+    }
+    if header.gas_used > header.gas_limit {
+        return Err("The gas used exceeds the gas limit".to_string());
+    }
+    if header.extra_data.len() > 32 {
+        return Err("The extra data is longer than 32 bytes".to_string());
+    }
+    Ok(())
+}
+
+// Execute the block on top of its parent, the roots in the header are checked
+fn import_block(
+    vm: &Rc<EvmcVm>,
+    revision: Revision,
+    chain: &HashMap<[u8; 32], ChainBlock>,
+    raw: &[u8],
+) -> Result<ChainBlock, String> {
+    let block = Block::decode(raw)?;
+    let header = &block.header;
+    let parent = chain
+        .get(&header.parent_hash)
+        .ok_or_else(|| "Unknown parent block".to_string())?;
+    validate_header(header, &parent.header)?;
+    if block.uncles_hash != header.uncles_hash {
+        return Err("Uncles hash mismatch".to_string());
+    }
+    if block.uncles.len() > 2 {
+        return Err("Too many uncles".to_string());
+    }
+    for uncle in &block.uncles {
+        if uncle.number >= header.number || uncle.number + 6 < header.number {
+            return Err(format!("Invalid uncle number {}", uncle.number));
+        }
+    }
+    if ordered_trie_root(block.transactions.clone()) != header.transactions_root {
+        return Err("Transactions root mismatch".to_string());
+    }
+    if let Some(root) = header.withdrawals_root {
+        if ordered_trie_root(block.encoded_withdrawals.clone()) != root {
+            return Err("Withdrawals root mismatch".to_string());
+        }
+    }
+
+    let mut context = parent.context.clone();
+    let mut block_hashes = context.block.block_hashes.clone();
+    block_hashes.insert(parent.header.number, Bytes32(parent.header.hash));
+    block_hashes.retain(|number, _| number + 256 >= header.number);
+    context.block = BlockContext {
+        number: header.number,
+        timestamp: header.timestamp,
+        coinbase: header.coinbase.clone(),
+        gas_limit: header.gas_limit,
+        difficulty: Uint256(u256_to_bytes(header.difficulty)),
+        chain_id: CHAIN_ID,
+        base_fee: header
+            .base_fee
+            .map(|base_fee| Uint256(u256_to_bytes(base_fee))),
+        block_hashes,
+    };
+
+    let mut receipts = Vec::new();
+    let mut cumulative_gas_used = 0;
+    for raw_tx in &block.transactions {
+        let signed = decode_transaction(raw_tx)?;
+        let tx_hash = hex::encode(signed.hash);
+        signed
+            .validate(revision, CHAIN_ID)
+            .map_err(|err| format!("transaction 0x{}: {}", tx_hash, err))?;
+        if cumulative_gas_used + signed.transaction.gas_limit > header.gas_limit {
+            return Err(format!(
+                "transaction 0x{}: the gas limit exceeds the block gas left",
+                tx_hash
+            ));
+        }
+        let result = apply_transaction(vm, &mut context, revision, &signed.transaction)
+            .map_err(|err| format!("transaction 0x{}: {}", tx_hash, err))?;
+        cumulative_gas_used += result.gas_used;
+        let byzantium = revision as u32 >= Revision::EVMC_BYZANTIUM as u32;
+        receipts.push(Receipt {
+            tx_type: signed.tx_type,
+            post_state: if byzantium {
+                None
+            } else {
+                Some(state_root(&context)?)
+            },
+            success: result.status == StatusCode::EVMC_SUCCESS,
+            cumulative_gas_used,
+            logs: result.logs,
+        });
+    }
+    if let Some(withdrawals) = block.withdrawals.as_ref() {
+        apply_withdrawals(&mut context, withdrawals);
+    }
+    apply_rewards(&mut context, revision, header, &block.uncles);
+
+    if cumulative_gas_used != header.gas_used {
+        return Err(format!(
+            "Gas used mismatch: header {}, executed {}",
+            header.gas_used, cumulative_gas_used
+        ));
+    }
+    let logs: Vec<_> = receipts
+        .iter()
+        .flat_map(|receipt| receipt.logs.iter().cloned())
+        .collect();
+    if logs_bloom(&logs)[..] != header.logs_bloom[..] {
+        return Err("Logs bloom mismatch".to_string());
+    }
+    let encoded = receipts.iter().map(Receipt::encode).collect();
+    if ordered_trie_root(encoded) != header.receipts_root {
+        return Err("Receipts root mismatch".to_string());
+    }
+    let root = state_root(&context)?;
+    if root != header.state_root {
+        return Err(format!(
+            "State root mismatch: header 0x{}, executed 0x{}",
+            hex::encode(header.state_root),
+            hex::encode(root)
+        ));
+    }
+    Ok(ChainBlock {
+        total_difficulty: parent.total_difficulty + header.difficulty,
+        header: block.header,
+        context,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> BTreeMap<String, BlockchainTest> {
+        serde_json::from_str(include_str!(
+            "../testdata/BlockchainTests/transferInBlock.json"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn run_transfer_fixture() {
+        let vm = Rc::new(create_vm(None).unwrap());
+        let tests = fixture();
+        let test = &tests["transferInBlock_Berlin"];
+        let revision = fork_revision(&test.network).unwrap();
+        // The second block is rejected by its state root
+        assert_eq!(run_test(&vm, test, revision).unwrap(), Vec::<String>::new());
+
+        let mut tests = fixture();
+        let test = tests.get_mut("transferInBlock_Berlin").unwrap();
+        test.blocks[1].expect_exception = None;
+        test.post_state = None;
+        let errors = run_test(&vm, test, revision).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("block 1: State root mismatch"));
+    }
+}
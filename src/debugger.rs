@@ -0,0 +1,706 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+
+use crate::evmc::Address;
+use crate::host_trace::HostEvent;
+use crate::replay::{
+    apply_memory_write, call_output_range, parse_word, stack_args, write_memory, FrameData, Word,
+    OP_CALL, OP_CALLCODE, OP_CREATE, OP_CREATE2, OP_DELEGATECALL, OP_SSTORE, OP_STATICCALL,
+};
+use crate::trace::{TraceLine, TraceStep};
+use crate::AccountData;
+
+type Storage = HashMap<Address, BTreeMap<Word, Word>>;
+
+const HELP: &str = "Commands:
+  step, s [n]            Execute one instruction (stepping into calls)
+  next, n                Execute until the next instruction of the current frame
+  continue, c            Run until a breakpoint or the end of the execution
+  break, b pc <pc>       Break at the program counter (decimal or 0x hex)
+  break, b op <name>     Break at the opcode (like SSTORE)
+  break, b               List the breakpoints
+  delete, d <id>         Delete a breakpoint
+  info, i                Show the current instruction
+  stack, st              Show the stack (top first)
+  memory, m [off [len]]  Show the memory
+  storage, sto           Show the storage of the current contract
+  frames, bt             Show the call frames
+  help, h                Show this message
+  quit, q                Quit the debugger
+An empty line repeats the last command.
+Memory and storage are rebuilt from the trace, memory written by instructions
+other than MSTORE/MSTORE8/MCOPY/*COPY and call outputs is not shown.";
+
+/// A call made by the contract, as seen by the host
+#[derive(Debug, Clone, Default)]
+struct HostCall {
+    kind: String,
+    destination: Address,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    status: String,
+}
+
+impl HostCall {
+    fn is_create(&self) -> bool {
+        self.kind.contains("CREATE")
+    }
+}
+
+/// A call in progress in the frame
+#[derive(Debug, Clone)]
+struct PendingCall {
+    call: Option<HostCall>,
+    op: u8,
+    return_offset: Option<usize>,
+    return_size: Option<usize>,
+    storage: Storage,
+}
+
+#[derive(Debug, Clone)]
+struct Frame {
+    depth: u32,
+    kind: String,
+    address: Address,
+    code: Vec<u8>,
+    input: Vec<u8>,
+    memory: Vec<u8>,
+    return_data: Vec<u8>,
+    pc: u64,
+    op_name: String,
+    pending: Option<PendingCall>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Breakpoint {
+    Pc(u64),
+    Op(String),
+}
+
+/// Replays the instruction trace of an execution, rebuilding the memory,
+/// storage and call frames along the way.
+pub struct Debugger<'a> {
+    steps: Vec<TraceStep>,
+    calls: Vec<HostCall>,
+    next_call: usize,
+    accounts: &'a HashMap<Address, AccountData>,
+    storage: Storage,
+    frames: Vec<Frame>,
+    position: usize,
+    breakpoints: Vec<Option<Breakpoint>>,
+    summary: String,
+}
+
+impl<'a> Debugger<'a> {
+    /// `accounts` is the state before the execution, `events` are the host
+    /// events recorded during the execution.
+    pub fn new(
+        lines: Vec<TraceLine>,
+        events: &[HostEvent],
+        accounts: &'a HashMap<Address, AccountData>,
+        address: Address,
+        code: Vec<u8>,
+        input: Vec<u8>,
+        summary: String,
+    ) -> Result<Debugger<'a>, String> {
+        let steps: Vec<TraceStep> = lines
+            .into_iter()
+            .filter_map(|line| match line {
+                TraceLine::Step(step) => Some(step),
+                _ => None,
+            })
+            .collect();
+        let first_depth = steps
+            .first()
+            .map(|step| step.depth)
+            .ok_or_else(|| "No instruction executed".to_string())?;
+
+        // The nested calls in the order they started
+        let mut calls: Vec<HostCall> = Vec::new();
+        let mut call_stack = Vec::new();
+        for event in events {
+            match event {
+                HostEvent::CallEnter {
+                    depth,
+                    kind,
+                    destination,
+                    input,
+                    ..
+                } if *depth > 0 => {
+                    call_stack.push(calls.len());
+                    calls.push(HostCall {
+                        kind: kind.clone(),
+                        destination: destination.clone(),
+                        input: input.0.clone(),
+                        ..Default::default()
+                    });
+                }
+                HostEvent::CallExit {
+                    depth,
+                    status,
+                    output,
+                    ..
+                } if *depth > 0 => {
+                    if let Some(index) = call_stack.pop() {
+                        calls[index].output = output.0.clone();
+                        calls[index].status = status.clone();
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let storage = accounts
+            .iter()
+            .map(|(address, account)| {
+                let storage = account
+                    .storage
+                    .iter()
+                    .map(|(key, value)| (key.0, value.data.0))
+                    .collect();
+                (address.clone(), storage)
+            })
+            .collect();
+        let mut debugger = Debugger {
+            steps,
+            calls,
+            next_call: 0,
+            accounts,
+            storage,
+            frames: Vec::new(),
+            position: 0,
+            breakpoints: Vec::new(),
+            summary,
+        };
+        debugger.frames.push(Frame {
+            depth: first_depth,
+            kind: "TOP".to_string(),
+            address,
+            code,
+            input,
+            memory: Vec::new(),
+            return_data: Vec::new(),
+            pc: 0,
+            op_name: String::new(),
+            pending: None,
+        });
+        debugger.sync_frame();
+        Ok(debugger)
+    }
+
+    fn finished(&self) -> bool {
+        self.position >= self.steps.len()
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    // The memory before executing the current step
+    fn sync_frame(&mut self) {
+        if let Some(step) = self.steps.get(self.position).cloned() {
+            let frame = self.frame();
+            frame.memory.resize(step.mem_size as usize, 0);
+            frame.pc = step.pc;
+            frame.op_name = step.op_name;
+        }
+    }
+
+    /// Execute the current step, return false at the end of the trace
+    fn advance(&mut self) -> bool {
+        if self.finished() {
+            return false;
+        }
+        let step = self.steps[self.position].clone();
+        self.execute(&step);
+        self.position += 1;
+        if self.finished() {
+            return false;
+        }
+        let next_depth = self.steps[self.position].depth;
+        let has_pending = self.frame().pending.is_some();
+        if next_depth > step.depth && has_pending {
+            self.enter_call(next_depth);
+        } else {
+            while self.frames.len() > 1 && self.frame().depth > next_depth {
+                self.frames.pop();
+                self.exit_call();
+            }
+            // A call with no instruction executed in it
+            if self.frame().pending.is_some() && next_depth == self.frame().depth {
+                self.exit_call();
+            }
+        }
+        self.sync_frame();
+        true
+    }
+
+    fn execute(&mut self, step: &TraceStep) {
+        match step.op {
+            OP_SSTORE => {
+                let args = stack_args(step);
+                if args.len() >= 2 {
+                    let address = self.frame().address.clone();
+                    self.storage
+                        .entry(address)
+                        .or_default()
+                        .insert(args[0], args[1]);
+                }
+            }
+            OP_CREATE | OP_CREATE2 | OP_CALL | OP_CALLCODE | OP_DELEGATECALL | OP_STATICCALL => {
+                let (return_offset, return_size) = call_output_range(step);
+                let call = self.calls.get(self.next_call).cloned();
+                self.next_call += 1;
+                let storage = self.storage.clone();
+                self.frame().pending = Some(PendingCall {
+                    call,
+                    op: step.op,
+                    return_offset,
+                    return_size,
+                    storage,
+                });
+            }
+            _ => {
+                let accounts = self.accounts;
+                let frame = self.frame();
+                let data = FrameData {
+                    code: &frame.code,
+                    input: &frame.input,
+                    return_data: &frame.return_data,
+                };
+                apply_memory_write(&mut frame.memory, step, &data, |address| {
+                    code_of(accounts, address)
+                });
+            }
+        }
+    }
+
+    fn enter_call(&mut self, depth: u32) {
+        let call = self
+            .frame()
+            .pending
+            .as_ref()
+            .and_then(|pending| pending.call.clone())
+            .unwrap_or_default();
+        let (code, input) = if call.is_create() {
+            (call.input.clone(), Vec::new())
+        } else {
+            (
+                code_of(self.accounts, &call.destination),
+                call.input.clone(),
+            )
+        };
+        self.frames.push(Frame {
+            depth,
+            kind: call.kind.clone(),
+            address: call.destination.clone(),
+            code,
+            input,
+            memory: Vec::new(),
+            return_data: Vec::new(),
+            pc: 0,
+            op_name: String::new(),
+            pending: None,
+        });
+    }
+
+    // Apply the result of the pending call to the current frame
+    fn exit_call(&mut self) {
+        let pending = match self.frame().pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let call = pending.call.unwrap_or_default();
+        if call.status != "EVMC_SUCCESS" {
+            self.storage = pending.storage;
+        }
+        let frame = self.frame();
+        if pending.op == OP_CREATE || pending.op == OP_CREATE2 {
+            frame.return_data = if call.status == "EVMC_REVERT" {
+                call.output
+            } else {
+                Vec::new()
+            };
+        } else {
+            if let (Some(offset), Some(size)) = (pending.return_offset, pending.return_size) {
+                let size = size.min(call.output.len());
+                write_memory(&mut frame.memory, Some(offset), &call.output[..size]);
+            }
+            frame.return_data = call.output;
+        }
+    }
+
+    fn hit_breakpoint(&self) -> Option<usize> {
+        let step = self.steps.get(self.position)?;
+        self.breakpoints
+            .iter()
+            .position(|breakpoint| match breakpoint {
+                Some(Breakpoint::Pc(pc)) => *pc == step.pc,
+                Some(Breakpoint::Op(name)) => name.eq_ignore_ascii_case(&step.op_name),
+                None => false,
+            })
+    }
+
+    fn run_until<F: Fn(&Debugger) -> bool>(&mut self, stop: F) {
+        while self.advance() {
+            if let Some(id) = self.hit_breakpoint() {
+                println!("Breakpoint {} hit", id);
+                return;
+            }
+            if stop(self) {
+                return;
+            }
+        }
+    }
+
+    fn show_current(&self) {
+        match self.steps.get(self.position) {
+            Some(step) => {
+                let frame = self.frames.last().unwrap();
+                println!(
+                    "[{}] {:?} pc: {:#06x} {}  gas: {}, cost: {}",
+                    self.frames.len() - 1,
+                    frame.address,
+                    step.pc,
+                    step.op_name,
+                    step.gas,
+                    step.gas_cost
+                );
+            }
+            None => println!("Execution finished: {}", self.summary),
+        }
+    }
+
+    fn show_stack(&self) {
+        if let Some(step) = self.steps.get(self.position) {
+            for (index, item) in step.stack.iter().rev().enumerate() {
+                println!("{:>4}: 0x{}", index, hex::encode(parse_word(item)));
+            }
+        }
+    }
+
+    fn show_memory(&self, offset: usize, size: Option<usize>) {
+        let memory = &self.frames.last().unwrap().memory;
+        let end = size
+            .map(|size| offset.saturating_add(size))
+            .unwrap_or_else(|| memory.len())
+            .min(memory.len());
+        let mut row = offset;
+        while row < end {
+            let row_end = (row + 32).min(end);
+            println!("{:#06x}: {}", row, hex::encode(&memory[row..row_end]));
+            row = row_end;
+        }
+    }
+
+    fn show_storage(&self) {
+        let address = &self.frames.last().unwrap().address;
+        println!("Storage of {:?}", address);
+        if let Some(storage) = self.storage.get(address) {
+            for (key, value) in storage {
+                println!("  0x{} => 0x{}", hex::encode(key), hex::encode(value));
+            }
+        }
+    }
+
+    fn show_frames(&self) {
+        for (index, frame) in self.frames.iter().enumerate().rev() {
+            println!(
+                "#{} {} {:?} pc: {:#06x} {} (input: {} bytes, code: {} bytes)",
+                index,
+                frame.kind,
+                frame.address,
+                frame.pc,
+                frame.op_name,
+                frame.input.len(),
+                frame.code.len()
+            );
+        }
+    }
+
+    fn command(&mut self, line: &str) -> Result<bool, String> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts.as_slice() {
+            ["step"] | ["s"] => {
+                self.advance();
+                self.show_current();
+            }
+            ["step", count] | ["s", count] => {
+                let count: usize = count.parse().map_err(|_| "Invalid count".to_string())?;
+                for _ in 0..count {
+                    if !self.advance() {
+                        break;
+                    }
+                }
+                self.show_current();
+            }
+            ["next"] | ["n"] => {
+                let depth = self.frames.len();
+                // Stop when back to the current frame (or returned from it)
+                self.run_until(|debugger| debugger.frames.len() <= depth);
+                self.show_current();
+            }
+            ["continue"] | ["c"] => {
+                self.run_until(|_| false);
+                self.show_current();
+            }
+            ["break"] | ["b"] => {
+                for (id, breakpoint) in self.breakpoints.iter().enumerate() {
+                    if let Some(breakpoint) = breakpoint {
+                        println!("{}: {:?}", id, breakpoint);
+                    }
+                }
+            }
+            ["break", "pc", pc] | ["b", "pc", pc] => {
+                let pc = parse_number(pc)?;
+                self.breakpoints.push(Some(Breakpoint::Pc(pc as u64)));
+                println!(
+                    "Breakpoint {} at pc {:#06x}",
+                    self.breakpoints.len() - 1,
+                    pc
+                );
+            }
+            ["break", "op", name] | ["b", "op", name] => {
+                let name = name.to_uppercase();
+                println!("Breakpoint {} at opcode {}", self.breakpoints.len(), name);
+                self.breakpoints.push(Some(Breakpoint::Op(name)));
+            }
+            ["delete", id] | ["d", id] => {
+                let id: usize = id.parse().map_err(|_| "Invalid breakpoint".to_string())?;
+                match self.breakpoints.get_mut(id) {
+                    Some(breakpoint) if breakpoint.is_some() => *breakpoint = None,
+                    _ => return Err(format!("No breakpoint {}", id)),
+                }
+            }
+            ["info"] | ["i"] => self.show_current(),
+            ["stack"] | ["st"] => self.show_stack(),
+            ["memory"] | ["m"] => self.show_memory(0, None),
+            ["memory", offset] | ["m", offset] => self.show_memory(parse_number(offset)?, None),
+            ["memory", offset, size] | ["m", offset, size] => {
+                self.show_memory(parse_number(offset)?, Some(parse_number(size)?))
+            }
+            ["storage"] | ["sto"] => self.show_storage(),
+            ["frames"] | ["bt"] => self.show_frames(),
+            ["help"] | ["h"] => println!("{}", HELP),
+            ["quit"] | ["q"] => return Ok(false),
+            _ => return Err(format!("Unknown command: {} (try help)", line.trim())),
+        }
+        Ok(true)
+    }
+
+    /// Read the commands from stdin until quit or EOF
+    pub fn run(&mut self) -> Result<(), String> {
+        println!(
+            "{} instructions traced, type help for the commands",
+            self.steps.len()
+        );
+        self.show_current();
+        let stdin = io::stdin();
+        let mut last_command = String::new();
+        loop {
+            print!("(debug) ");
+            io::stdout().flush().map_err(|err| err.to_string())?;
+            let mut line = String::new();
+            if stdin
+                .lock()
+                .read_line(&mut line)
+                .map_err(|err| err.to_string())?
+                == 0
+            {
+                println!();
+                return Ok(());
+            }
+            if line.trim().is_empty() {
+                line = last_command.clone();
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            match self.command(&line) {
+                Ok(true) => last_command = line,
+                Ok(false) => return Ok(()),
+                Err(err) => println!("{}", err),
+            }
+        }
+    }
+}
+
+fn parse_number(value: &str) -> Result<usize, String> {
+    match value.strip_prefix("0x") {
+        Some(digits) => usize::from_str_radix(digits, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| format!("Invalid number: {}", value))
+}
+
+fn code_of(accounts: &HashMap<Address, AccountData>, address: &Address) -> Vec<u8> {
+    accounts
+        .get(address)
+        .and_then(|account| account.code.as_ref())
+        .map(|code| code.0.clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evmc::{
+        CallKind, EvmcVm, ExecutionContext, ExecutionMessage, HostContext, HostContextPtr,
+        Revision, StatusCode,
+    };
+    use crate::host_trace::{HostTracer, TraceSink};
+    use crate::{JsonBytes, TestHostContext};
+    use evmc_sys as ffi;
+    use std::ffi::CStr;
+    use std::os::raw::c_char;
+    use std::rc::Rc;
+
+    const CALLER: Address = Address([0xaa; 20]);
+    const CALLEE: Address = Address([0xbb; 20]);
+    static OUTPUT: [u8; 32] = [0x2a; 32];
+
+    fn write_trace(line: &str) {
+        let line = format!("{}\n", line);
+        unsafe { libc::write(libc::STDERR_FILENO, line.as_ptr() as *const _, line.len()) };
+    }
+
+    // A tracing VM running the trace of `CALL STOP` in the caller, with the
+    // callee returning 32 bytes by `RETURN`
+    unsafe extern "C" fn execute(
+        _vm: *mut ffi::evmc_vm,
+        host: *const ffi::evmc_host_interface,
+        context: *mut ffi::evmc_host_context,
+        _revision: ffi::evmc_revision,
+        message: *const ffi::evmc_message,
+        _code: *const u8,
+        _code_size: usize,
+    ) -> ffi::evmc_result {
+        let mut output: &[u8] = &[];
+        if (*message).depth == 0 {
+            write_trace(&format!(
+                r#"{{"pc":0,"op":241,"gas":"0x10000","gasCost":"0x0","memSize":0,"stack":["0x20","0x0","0x0","0x0","0x0","0x{}","0xffff"],"depth":1,"refund":0,"opName":"CALL"}}"#,
+                hex::encode(CALLEE.0)
+            ));
+            let call = ffi::evmc_message {
+                kind: CallKind::EVMC_CALL,
+                flags: 0,
+                depth: 1,
+                gas: 0xffff,
+                destination: CALLEE.into(),
+                sender: CALLER.into(),
+                input_data: [].as_ptr(),
+                input_size: 0,
+                value: Default::default(),
+                create2_salt: Default::default(),
+            };
+            let result = (*host).call.unwrap()(context, &call);
+            assert_eq!(result.status_code, StatusCode::EVMC_SUCCESS);
+            write_trace(
+                r#"{"pc":1,"op":0,"gas":"0x100","gasCost":"0x0","memSize":32,"stack":["0x1"],"depth":1,"refund":0,"opName":"STOP"}"#,
+            );
+        } else {
+            write_trace(
+                r#"{"pc":0,"op":243,"gas":"0xffff","gasCost":"0x0","memSize":32,"stack":["0x20","0x0"],"depth":2,"refund":0,"opName":"RETURN"}"#,
+            );
+            output = &OUTPUT;
+        }
+        ffi::evmc_result {
+            status_code: StatusCode::EVMC_SUCCESS,
+            gas_left: 0,
+            output_data: output.as_ptr(),
+            output_size: output.len(),
+            release: None,
+            create_address: Default::default(),
+            padding: [0u8; 4],
+        }
+    }
+
+    unsafe extern "C" fn set_option(
+        _vm: *mut ffi::evmc_vm,
+        name: *const c_char,
+        _value: *const c_char,
+    ) -> ffi::evmc_set_option_result {
+        match CStr::from_ptr(name).to_str() {
+            Ok("trace") => ffi::evmc_set_option_result::EVMC_SET_OPTION_SUCCESS,
+            _ => ffi::evmc_set_option_result::EVMC_SET_OPTION_INVALID_NAME,
+        }
+    }
+
+    fn tracing_vm() -> Rc<EvmcVm> {
+        let vm = EvmcVm::new(Box::into_raw(Box::new(ffi::evmc_vm {
+            abi_version: 7,
+            name: std::ptr::null(),
+            version: std::ptr::null(),
+            destroy: None,
+            execute: Some(execute),
+            get_capabilities: None,
+            set_option: Some(set_option),
+        })));
+        vm.set_option("trace", "1").unwrap();
+        Rc::new(vm)
+    }
+
+    #[test]
+    fn debug_sub_call() {
+        let vm = tracing_vm();
+        let code = vec![OP_CALL, 0x00];
+        let mut host_context = TestHostContext::new(0, CALLER);
+        host_context.vm = Some(vm.clone());
+        host_context.tracer = Rc::new(HostTracer::recording(TraceSink::Quiet));
+        host_context.set_account_code(&CALLER, Some(JsonBytes(code.clone())));
+        host_context.set_account_code(&CALLEE, Some(JsonBytes(vec![0xf3])));
+        let tracer = host_context.tracer.clone();
+        let before = host_context.accounts.clone();
+
+        host_context.push_frame();
+        let host_context_ptr = HostContextPtr::from(Box::new(host_context));
+        let mut context = ExecutionContext::new(TestHostContext::interface(), host_context_ptr.ptr);
+        let raw_message = ffi::evmc_message {
+            kind: CallKind::EVMC_CALL,
+            flags: 0,
+            depth: 0,
+            gas: 0x10000,
+            destination: CALLER.into(),
+            sender: Address([0x80; 20]).into(),
+            input_data: [].as_ptr(),
+            input_size: 0,
+            value: Default::default(),
+            create2_salt: Default::default(),
+        };
+        let message = ExecutionMessage::from(&raw_message);
+        let (result, lines) = crate::trace::execute_captured(
+            &vm,
+            Revision::EVMC_PETERSBURG,
+            &code,
+            &message,
+            &mut context,
+        )
+        .unwrap();
+        crate::close_top_frame(&context, &result).unwrap();
+        assert_eq!(result.status_code, StatusCode::EVMC_SUCCESS);
+
+        let events = tracer.events.borrow();
+        let mut debugger = Debugger::new(
+            lines,
+            &events,
+            &before,
+            CALLER,
+            code,
+            Vec::new(),
+            "EVMC_SUCCESS".to_string(),
+        )
+        .unwrap();
+        assert_eq!(debugger.steps.len(), 3);
+        // Into the callee, its code comes from the call event
+        assert!(debugger.advance());
+        assert_eq!(debugger.frames.len(), 2);
+        assert_eq!(debugger.frame().address, CALLEE);
+        assert_eq!(debugger.frame().code, vec![0xf3]);
+        // Back in the caller, with the output of the call in its memory
+        assert!(debugger.advance());
+        assert_eq!(debugger.frames.len(), 1);
+        assert_eq!(debugger.frame().pc, 1);
+        assert_eq!(debugger.frame().memory, OUTPUT.to_vec());
+        assert_eq!(debugger.frame().return_data, OUTPUT.to_vec());
+    }
+}
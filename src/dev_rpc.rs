@@ -0,0 +1,453 @@
+//! The dev methods of Hardhat and Anvil: snapshots, mining, time travel and
+//! setting the accounts directly (the hardhat_ and anvil_ names are aliases)
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::evmc::Bytes32;
+use crate::mempool::{Mempool, MiningMode};
+use crate::rpc::{param, param_str, Node, RpcError};
+use crate::statetest::{parse_address, parse_hex, parse_u64, parse_word};
+use crate::transaction::{parse_u256, set_balance};
+use crate::{JsonBytes, TestHostContext};
+
+/// The state saved by evm_snapshot, the backend is restored by its journal
+pub struct Snapshot {
+    journal_count: usize,
+    mempool: Mempool,
+    time_offset: i64,
+    next_timestamp: Option<u64>,
+}
+
+impl Node {
+    pub fn handle_dev(&mut self, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        let name = method
+            .trim_start_matches("hardhat_")
+            .trim_start_matches("anvil_");
+        match name {
+            "evm_snapshot" => {
+                // The backend has the whole state, the entries written
+                // after are journaled
+                self.save()?;
+                let id = self.next_snapshot_id;
+                self.next_snapshot_id += 1;
+                let snapshot = Snapshot {
+                    journal_count: self.db.snapshot()?,
+                    mempool: self.mempool.clone(),
+                    time_offset: self.time_offset,
+                    next_timestamp: self.next_timestamp,
+                };
+                self.snapshots.insert(id, snapshot);
+                Ok(json!(format!("{:#x}", id)))
+            }
+            // The snapshot and the later ones are dropped
+            "evm_revert" => {
+                let id = param_u64(params, 0)?;
+                let snapshot = match self.snapshots.remove(&id) {
+                    Some(snapshot) => snapshot,
+                    None => return Ok(json!(false)),
+                };
+                self.snapshots.retain(|snapshot_id, _| *snapshot_id < id);
+                // The changes not saved are dropped with the loaded entries
+                self.db.revert(snapshot.journal_count)?;
+                let path = self.context.backend_path().map(str::to_string);
+                self.context =
+                    TestHostContext::with_db(Box::new(self.db.clone()), path.as_deref())?;
+                self.mempool = snapshot.mempool;
+                self.time_offset = snapshot.time_offset;
+                self.next_timestamp = snapshot.next_timestamp;
+                Ok(json!(true))
+            }
+            "evm_mine" => {
+                if !params.is_empty() {
+                    self.set_next_timestamp(param_u64(params, 0)?)?;
+                }
+                self.mine()?;
+                Ok(json!("0x0"))
+            }
+            "mine" => {
+                let blocks = match params.first() {
+                    Some(_) => param_u64(params, 0)?,
+                    None => 1,
+                };
+                let interval = match params.get(1) {
+                    Some(_) => param_u64(params, 1)?,
+                    None => 1,
+                };
+                for index in 0..blocks {
+                    if index > 0 {
+                        let previous =
+                            self.context.block.timestamp.checked_sub(1).ok_or_else(|| {
+                                RpcError::invalid_params(
+                                    "The timestamp of the previous block is unknown".to_string(),
+                                )
+                            })?;
+                        let timestamp = previous.checked_add(interval).ok_or_else(|| {
+                            RpcError::invalid_params(format!(
+                                "Timestamp {} + {} overflows",
+                                previous, interval
+                            ))
+                        })?;
+                        if interval == 0 {
+                            // Blocks of the same timestamp, as Hardhat mines them
+                            self.context.block.timestamp = previous;
+                            self.next_timestamp = Some(previous);
+                        } else {
+                            self.set_next_timestamp(timestamp)?;
+                        }
+                    }
+                    self.mine()?;
+                }
+                Ok(Value::Null)
+            }
+            "evm_setAutomine" | "setAutomine" => {
+                let enabled = param(params, 0)?.as_bool().ok_or_else(|| {
+                    RpcError::invalid_params("Parameter 0 must be a boolean".to_string())
+                })?;
+                if enabled {
+                    self.mining = MiningMode::Auto;
+                    // The pending transactions are mined at once
+                    self.mine()?;
+                } else if self.mining == MiningMode::Auto {
+                    self.mining = MiningMode::Manual;
+                }
+                Ok(json!(true))
+            }
+            "getAutomine" => Ok(json!(self.mining == MiningMode::Auto)),
+            // In milliseconds by Hardhat, in seconds by Anvil
+            "evm_setIntervalMining" | "setIntervalMining" => {
+                let interval = param_u64(params, 0)?;
+                let interval = if name == "evm_setIntervalMining" {
+                    Duration::from_millis(interval)
+                } else {
+                    Duration::from_secs(interval)
+                };
+                if interval > Duration::from_secs(0) {
+                    self.mining = MiningMode::Interval(interval);
+                } else if let MiningMode::Interval(_) = self.mining {
+                    self.mining = MiningMode::Manual;
+                }
+                Ok(json!(true))
+            }
+            "dropTransaction" => {
+                let hash = parse_word(param_str(params, 0)?)?;
+                Ok(json!(self.mempool.remove(&hash)))
+            }
+            "evm_increaseTime" => {
+                let seconds = param_u64(params, 0)?;
+                self.time_offset = i64::try_from(seconds)
+                    .ok()
+                    .and_then(|seconds| self.time_offset.checked_add(seconds))
+                    .ok_or_else(|| {
+                        RpcError::invalid_params(format!(
+                            "Increasing the time by {} seconds overflows",
+                            seconds
+                        ))
+                    })?;
+                Ok(json!(self.time_offset.to_string()))
+            }
+            "evm_setNextBlockTimestamp" => {
+                self.set_next_timestamp(param_u64(params, 0)?)?;
+                Ok(Value::Null)
+            }
+            "setBalance" => {
+                let address = parse_address(param_str(params, 0)?)?;
+                let balance = parse_u256(param_str(params, 1)?)?;
+                set_balance(&mut self.context, &address, balance);
+                self.save()?;
+                Ok(json!(true))
+            }
+            "setCode" => {
+                let address = parse_address(param_str(params, 0)?)?;
+                let code = parse_hex(param_str(params, 1)?)?;
+                let code = if code.is_empty() {
+                    None
+                } else {
+                    Some(JsonBytes(code))
+                };
+                self.context.set_account_code(&address, code);
+                self.save()?;
+                Ok(json!(true))
+            }
+            "setStorageAt" => {
+                let address = parse_address(param_str(params, 0)?)?;
+                let key = Bytes32(parse_word(param_str(params, 1)?)?);
+                let value = Bytes32(parse_word(param_str(params, 2)?)?);
+                self.context.load_slot(&address, &key);
+                let value = match self.context.stored_value(&address, &key) {
+                    Some(mut current) => {
+                        current.update_data(value);
+                        current
+                    }
+                    None => crate::Value::new(value),
+                };
+                self.context.set_stored_value(&address, key, value);
+                self.save()?;
+                Ok(json!(true))
+            }
+            "setNonce" => {
+                let address = parse_address(param_str(params, 0)?)?;
+                self.context.account_mut(&address).nonce = param_u64(params, 1)?;
+                self.save()?;
+                Ok(json!(true))
+            }
+            "impersonateAccount" => {
+                let address = parse_address(param_str(params, 0)?)?;
+                self.impersonated.insert(address);
+                Ok(json!(true))
+            }
+            "stopImpersonatingAccount" => {
+                let address = parse_address(param_str(params, 0)?)?;
+                Ok(json!(self.impersonated.remove(&address)))
+            }
+            _ => Err(RpcError::new(
+                -32601,
+                format!("Method not found: {}", method),
+            )),
+        }
+    }
+
+    // The timestamp must be later than the latest block
+    fn set_next_timestamp(&mut self, timestamp: u64) -> Result<(), RpcError> {
+        if timestamp < self.context.block.timestamp {
+            return Err(RpcError::invalid_params(format!(
+                "Timestamp {} is lower than the one of the previous block {}",
+                timestamp,
+                self.context.block.timestamp - 1
+            )));
+        }
+        self.next_timestamp = Some(timestamp);
+        Ok(())
+    }
+}
+
+// A number or a (hex) string
+fn param_u64(params: &[Value], index: usize) -> Result<u64, RpcError> {
+    match param(params, index)? {
+        Value::Number(number) => number.as_u64().ok_or_else(|| {
+            RpcError::invalid_params(format!("Parameter {} must be an unsigned integer", index))
+        }),
+        Value::String(value) => Ok(parse_u64(value)?),
+        _ => Err(RpcError::invalid_params(format!(
+            "Parameter {} must be a number",
+            index
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashSet};
+    use std::fs;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    use crate::evmc::Bytes32;
+    use crate::evmc::{Address, EvmcVm, Revision};
+    use crate::receipt::TransactionReceipt;
+    use crate::state_db::{self, JournalDb, KV_EXTENSION};
+    use crate::transaction::balance_of;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "play-evmone-{}-{}.{}",
+            name,
+            std::process::id(),
+            KV_EXTENSION
+        ));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().to_string()
+    }
+
+    // No transaction is executed, the VM is never called
+    fn node(path: &str) -> Node {
+        let db = JournalDb::new(state_db::open(path).unwrap());
+        Node {
+            context: TestHostContext::with_db(Box::new(db.clone()), Some(path)).unwrap(),
+            db,
+            vm: Rc::new(EvmcVm::new(std::ptr::null_mut())),
+            revision: Revision::EVMC_BERLIN,
+            dev_accounts: Vec::new(),
+            impersonated: HashSet::new(),
+            snapshots: BTreeMap::new(),
+            next_snapshot_id: 1,
+            time_offset: 0,
+            next_timestamp: None,
+            mined: Vec::new(),
+            mempool: Mempool::default(),
+            mining: MiningMode::Manual,
+            last_mined: Instant::now(),
+        }
+    }
+
+    fn call(node: &mut Node, method: &str, params: Value) -> Result<Value, RpcError> {
+        node.handle_dev(method, params.as_array().unwrap())
+    }
+
+    fn balance(node: &Node, address: &Address) -> Option<u64> {
+        node.context
+            .account(address)
+            .map(|_| balance_of(&node.context, address).as_u64())
+    }
+
+    #[test]
+    fn snapshot_and_revert() {
+        let path = temp_path("dev-rpc-revert");
+        let alice = Address([1u8; 20]);
+        let bob = Address([2u8; 20]);
+        {
+            let mut node = node(&path);
+            call(&mut node, "hardhat_setBalance", json!([alice, "0x1"])).unwrap();
+            let first = call(&mut node, "evm_snapshot", json!([])).unwrap();
+            assert_eq!(first, json!("0x1"));
+            call(&mut node, "hardhat_setBalance", json!([alice, "0x2"])).unwrap();
+            call(&mut node, "anvil_setBalance", json!([bob, "0x3"])).unwrap();
+            call(&mut node, "evm_increaseTime", json!([60])).unwrap();
+            let second = call(&mut node, "evm_snapshot", json!([])).unwrap();
+            assert_eq!(second, json!("0x2"));
+
+            assert_eq!(
+                call(&mut node, "evm_revert", json!([first])).unwrap(),
+                json!(true)
+            );
+            assert_eq!(balance(&node, &alice), Some(1));
+            assert_eq!(balance(&node, &bob), None);
+            assert_eq!(node.time_offset, 0);
+            // The later snapshots are dropped with the reverted one
+            assert_eq!(
+                call(&mut node, "evm_revert", json!([second])).unwrap(),
+                json!(false)
+            );
+            assert_eq!(
+                call(&mut node, "evm_revert", json!([first])).unwrap(),
+                json!(false)
+            );
+        }
+        // The backend is restored
+        let node = node(&path);
+        assert_eq!(balance(&node, &alice), Some(1));
+        assert_eq!(balance(&node, &bob), None);
+        drop(node);
+        let _ = fs::remove_file(&path);
+    }
+
+    fn slot(node: &mut Node, address: &Address, key: &Bytes32) -> Option<Bytes32> {
+        node.context.load_slot(address, key);
+        node.context
+            .stored_value(address, key)
+            .map(|value| value.data)
+    }
+
+    #[test]
+    fn revert_after_mine() {
+        let path = temp_path("dev-rpc-revert-mine");
+        let alice = Address([1u8; 20]);
+        let bob = Address([2u8; 20]);
+        let key = Bytes32([1u8; 32]);
+        let receipt = |block_number| TransactionReceipt {
+            transaction_hash: Bytes32([block_number as u8; 32]),
+            transaction_index: 0,
+            block_number,
+            block_hash: None,
+            from: alice.clone(),
+            to: Some(bob.clone()),
+            status: "success".to_string(),
+            success: true,
+            gas_used: 21000,
+            cumulative_gas_used: 21000,
+            contract_address: None,
+            logs: Vec::new(),
+            logs_bloom: JsonBytes(vec![0u8; 256]),
+        };
+        {
+            let mut node = node(&path);
+            call(&mut node, "hardhat_setBalance", json!([alice, "0x1"])).unwrap();
+            call(&mut node, "hardhat_setCode", json!([alice, "0x00"])).unwrap();
+            let value = format!("0x{}", "11".repeat(32));
+            call(
+                &mut node,
+                "hardhat_setStorageAt",
+                json!([alice, key, value]),
+            )
+            .unwrap();
+            let block = node.context.block.clone();
+            node.context.receipts.push(receipt(block.number));
+            call(&mut node, "evm_mine", json!([])).unwrap();
+            let receipts = node.context.receipts.clone();
+
+            let id = call(&mut node, "evm_snapshot", json!([])).unwrap();
+            call(&mut node, "hardhat_setBalance", json!([alice, "0x5"])).unwrap();
+            call(
+                &mut node,
+                "hardhat_setStorageAt",
+                json!([alice, key, "0x0"]),
+            )
+            .unwrap();
+            call(&mut node, "hardhat_setCode", json!([bob, "0x00"])).unwrap();
+            node.context
+                .receipts
+                .push(receipt(node.context.block.number));
+            call(&mut node, "evm_mine", json!([])).unwrap();
+            node.context.remove_account(&alice);
+            call(&mut node, "hardhat_mine", json!(["0x2"])).unwrap();
+            // Not saved yet
+            call(&mut node, "hardhat_setBalance", json!([bob, "0x7"])).unwrap();
+            node.context.account_mut(&bob).nonce = 3;
+
+            assert_eq!(
+                call(&mut node, "evm_revert", json!([id])).unwrap(),
+                json!(true)
+            );
+            assert_eq!(node.context.block.number, block.number + 1);
+            assert_eq!(balance(&node, &alice), Some(1));
+            assert_eq!(slot(&mut node, &alice, &key), Some(Bytes32([0x11; 32])));
+            assert_eq!(node.context.code_of(&alice).unwrap().0, vec![0x00]);
+            assert_eq!(balance(&node, &bob), None);
+            assert_eq!(
+                serde_json::to_value(&node.context.receipts).unwrap(),
+                serde_json::to_value(&receipts).unwrap()
+            );
+        }
+        // The backend is restored
+        let mut node = node(&path);
+        assert_eq!(balance(&node, &alice), Some(1));
+        assert_eq!(slot(&mut node, &alice, &key), Some(Bytes32([0x11; 32])));
+        assert_eq!(balance(&node, &bob), None);
+        assert_eq!(node.context.receipts.len(), 1);
+        drop(node);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn mine_blocks_at_interval() {
+        let path = temp_path("dev-rpc-mine");
+        let mut node = node(&path);
+        let number = node.context.block.number;
+        call(&mut node, "hardhat_mine", json!(["0x3", "0xa"])).unwrap();
+        assert_eq!(node.context.block.number, number + 3);
+        let timestamps: Vec<u64> = node
+            .mined
+            .iter()
+            .map(|(header, _)| header.timestamp)
+            .collect();
+        assert_eq!(timestamps[1], timestamps[0] + 10);
+        assert_eq!(timestamps[2], timestamps[1] + 10);
+
+        // Blocks of the same timestamp
+        node.mined.clear();
+        call(&mut node, "anvil_mine", json!([2, 0])).unwrap();
+        assert_eq!(node.mined[0].0.timestamp, node.mined[1].0.timestamp);
+
+        // evm_mine at a timestamp
+        let next = node.context.block.timestamp + 100;
+        call(&mut node, "evm_mine", json!([next])).unwrap();
+        assert_eq!(node.mined.last().unwrap().0.timestamp, next);
+        let err = call(&mut node, "evm_mine", json!([next - 1])).unwrap_err();
+        assert_eq!(err.code, -32602);
+        drop(node);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,435 @@
+//! Differential execution: run the same create/call on two VMs with identical
+//! contexts and report the divergences of the results, the state changes and
+//! the SSTOREs made (in the reverted frames too)
+
+use std::rc::Rc;
+
+use clap::{App, Arg, ArgMatches};
+use evmc_sys as ffi;
+use serde::Serialize;
+
+use crate::evmc::{
+    parse_revision, Address, CallKind, EvmcVm, ExecutionMessage, Revision, StatusCode,
+};
+use crate::host_trace::{HostEvent, HostTracer, TraceSink};
+use crate::loader::create_vm;
+use crate::report::ExecutionReport;
+use crate::state_db::load_state;
+use crate::{load_binary, TestHostContext};
+
+const DEFAULT_GAS: i64 = 10_000_000;
+
+/// The sub command to compare two VMs
+pub fn sub_command(name: &'static str) -> App<'static, 'static> {
+    App::new(name)
+        .about("Run the same create/call on two VMs and report the divergences")
+        .arg(
+            Arg::with_name("against")
+                .long("against")
+                .takes_value(true)
+                .required(true)
+                .help("The VM to compare with --vm (or the linked evmone): path/to/lib.so[,name=value,...]"),
+        )
+        .arg(
+            Arg::with_name("input-storage")
+                .long("input-storage")
+                .short("s")
+                .takes_value(true)
+                .help("The storage (accounts) json file"),
+        )
+        .arg(
+            Arg::with_name("address")
+                .long("address")
+                .takes_value(true)
+                .required(true)
+                .help("The contract address"),
+        )
+        .arg(
+            Arg::with_name("code")
+                .long("code")
+                .short("c")
+                .takes_value(true)
+                .help("The init code path, create the contract instead of calling it"),
+        )
+        .arg(
+            Arg::with_name("input-data")
+                .long("input-data")
+                .short("i")
+                .takes_value(true)
+                .help("The input data (hex)"),
+        )
+        .arg(
+            Arg::with_name("gas")
+                .long("gas")
+                .takes_value(true)
+                .help("The gas limit (10000000 by default)"),
+        )
+        .arg(
+            Arg::with_name("static")
+                .long("static")
+                .help("Call in the static mode (no state modification allowed)"),
+        )
+        .arg(
+            Arg::with_name("revision")
+                .long("revision")
+                .short("r")
+                .takes_value(true)
+                .default_value("berlin")
+                .help("The EVM revision"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print both reports and the divergences as json"),
+        )
+}
+
+#[derive(Serialize)]
+struct DiffReport<'a> {
+    a: &'a ExecutionReport,
+    b: &'a ExecutionReport,
+    sstores_a: &'a [String],
+    sstores_b: &'a [String],
+    divergences: &'a [String],
+}
+
+pub fn process(sub_matches: &ArgMatches) -> Result<(), String> {
+    let vm_a = Rc::new(create_vm(sub_matches.value_of("vm"))?);
+    let vm_b = Rc::new(create_vm(sub_matches.value_of("against"))?);
+    let revision = parse_revision(sub_matches.value_of("revision").unwrap())?;
+    let destination: Address =
+        serde_json::from_str(&format!("\"{}\"", sub_matches.value_of("address").unwrap()))
+            .map_err(|err| format!("Invalid address: {}", err))?;
+    let context = match sub_matches.value_of("input-storage") {
+        Some(path) => load_state(path)?,
+        None => TestHostContext::new(0, destination.clone()),
+    };
+    let input_data = match sub_matches.value_of("input-data") {
+        Some(data) => hex::decode(data.trim_start_matches("0x")).map_err(|err| err.to_string())?,
+        None => Vec::new(),
+    };
+    let gas = match sub_matches.value_of("gas") {
+        Some(gas) => gas.parse::<i64>().map_err(|err| err.to_string())?,
+        None => DEFAULT_GAS,
+    };
+
+    // The init code with the constructor arguments, or the code of the called account
+    let (kind, code, input) = match sub_matches.value_of("code") {
+        Some(path) => {
+            if context.contract_exists(&destination) {
+                return Err(format!("Contract already exists: {:?}", destination));
+            }
+            let mut code = load_binary(path);
+            code.extend(input_data);
+            (CallKind::EVMC_CREATE, code, Vec::new())
+        }
+        None => {
+            let code = context
+                .code_of(&destination)
+                .map(|code| code.0)
+                .ok_or_else(|| format!("Contract not exists: {:?}", destination))?;
+            (CallKind::EVMC_CALL, code, input_data)
+        }
+    };
+    let flags = if sub_matches.is_present("static") {
+        ffi::evmc_flags::EVMC_STATIC as u32
+    } else {
+        0
+    };
+    let raw_message = ffi::evmc_message {
+        kind,
+        flags,
+        depth: 0,
+        gas,
+        destination: destination.clone().into(),
+        sender: Address([128u8; 20]).into(),
+        input_data: if input.is_empty() {
+            std::ptr::null()
+        } else {
+            input.as_ptr()
+        },
+        input_size: input.len(),
+        value: Default::default(),
+        create2_salt: Default::default(),
+    };
+
+    let (report_a, sstores_a) = execute(&vm_a, &context, revision, &code, &raw_message)?;
+    let (report_b, sstores_b) = execute(&vm_b, &context, revision, &code, &raw_message)?;
+    let mut divergences = compare(&report_a, &report_b);
+    divergences.extend(compare_sstores(&sstores_a, &sstores_b));
+    if sub_matches.is_present("json") {
+        let report = DiffReport {
+            a: &report_a,
+            b: &report_b,
+            sstores_a: &sstores_a,
+            sstores_b: &sstores_b,
+            divergences: &divergences,
+        };
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else if divergences.is_empty() {
+        println!(
+            "No divergence: {} (gas used: {})",
+            report_a.status, report_a.gas_used
+        );
+    } else {
+        for divergence in &divergences {
+            println!("[DIVERGENCE] {}", divergence);
+        }
+    }
+    if !divergences.is_empty() {
+        return Err(format!("{} divergences found", divergences.len()));
+    }
+    Ok(())
+}
+
+// Execute with a clone of the context, the nested calls run on the same VM.
+// The SSTOREs are returned in the executed order, the ones in the reverted
+// frames included.
+fn execute(
+    vm: &Rc<EvmcVm>,
+    context: &TestHostContext,
+    revision: Revision,
+    code: &[u8],
+    raw_message: &ffi::evmc_message,
+) -> Result<(ExecutionReport, Vec<String>), String> {
+    let destination = Address::from(raw_message.destination);
+    let mut context = context.clone();
+    context.current_account = destination.clone();
+    context.vm = Some(vm.clone());
+    context.revision = Some(revision);
+    let tracer = Rc::new(HostTracer::recording(TraceSink::Quiet));
+    context.tracer = tracer.clone();
+    let before = context.clone();
+    let message = ExecutionMessage::from(raw_message);
+    let (result, mut context) = context.execute(vm, revision, code, &message);
+    context.check_reads()?;
+    let success = result.status_code == StatusCode::EVMC_SUCCESS;
+    let created_address = if success && message.is_create() {
+        context.update_code(destination.clone(), result.output_data.clone());
+        Some(destination)
+    } else {
+        None
+    };
+    let report = ExecutionReport::new(
+        &result,
+        message.gas,
+        created_address,
+        None,
+        &tracer,
+        &before,
+        &context,
+    )?;
+    let sstores = tracer
+        .events
+        .borrow()
+        .iter()
+        .filter_map(|event| match event {
+            HostEvent::Sstore {
+                depth,
+                address,
+                key,
+                value,
+                status,
+            } => Some(format!(
+                "[{}] {:?}[{:?}] = {:?} ({})",
+                depth, address, key, value, status
+            )),
+            _ => None,
+        })
+        .collect();
+    Ok((report, sstores))
+}
+
+// The SSTOREs must be the same in the same order, even in the reverted frames
+fn compare_sstores(a: &[String], b: &[String]) -> Vec<String> {
+    let mut divergences = Vec::new();
+    if a.len() != b.len() {
+        divergences.push(format!("sstores: {} vs {}", a.len(), b.len()));
+    }
+    if let Some(index) = a.iter().zip(b.iter()).position(|(a, b)| a != b) {
+        divergences.push(format!("sstore {}: {} vs {}", index, a[index], b[index]));
+    } else if a.len() != b.len() {
+        let (only, extra) = if a.len() > b.len() {
+            ("a", &a[b.len()])
+        } else {
+            ("b", &b[a.len()])
+        };
+        divergences.push(format!(
+            "sstore {} only in {}: {}",
+            a.len().min(b.len()),
+            only,
+            extra
+        ));
+    }
+    divergences
+}
+
+fn compare(a: &ExecutionReport, b: &ExecutionReport) -> Vec<String> {
+    let mut divergences = Vec::new();
+    if a.status != b.status {
+        divergences.push(format!("status: {} vs {}", a.status, b.status));
+    }
+    if a.gas_left != b.gas_left {
+        divergences.push(format!("gas left: {} vs {}", a.gas_left, b.gas_left));
+    }
+    if a.output != b.output {
+        divergences.push(format!("output: 0x{:?} vs 0x{:?}", a.output, b.output));
+    }
+    let writes = |report: &ExecutionReport| {
+        report
+            .state_changes
+            .iter()
+            .map(|change| {
+                format!(
+                    "{:?}[{:?}] = {:?}",
+                    change.address, change.key, change.after
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let (writes_a, writes_b) = (writes(a), writes(b));
+    for write in writes_a.iter().filter(|write| !writes_b.contains(write)) {
+        divergences.push(format!("storage write only in a: {}", write));
+    }
+    for write in writes_b.iter().filter(|write| !writes_a.contains(write)) {
+        divergences.push(format!("storage write only in b: {}", write));
+    }
+    if a.logs.len() != b.logs.len() {
+        divergences.push(format!("logs: {} vs {}", a.logs.len(), b.logs.len()));
+    }
+    for (index, (log_a, log_b)) in a.logs.iter().zip(b.logs.iter()).enumerate() {
+        let (log_a, log_b) = (
+            serde_json::to_string(log_a).unwrap(),
+            serde_json::to_string(log_b).unwrap(),
+        );
+        if log_a != log_b {
+            divergences.push(format!("log {}: {} vs {}", index, log_a, log_b));
+        }
+    }
+    divergences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evmc::Bytes32;
+    use crate::report::{ReportLog, StorageChange};
+    use crate::{JsonBytes, LogEntry};
+
+    fn report() -> ExecutionReport {
+        ExecutionReport {
+            status: "EVMC_SUCCESS".to_string(),
+            success: true,
+            gas_used: 100,
+            gas_left: 900,
+            output: JsonBytes(vec![1, 2]),
+            revert_reason: None,
+            created_address: None,
+            logs: vec![log(1)],
+            state_changes: vec![write(1, 1)],
+            state_root: Bytes32::default(),
+        }
+    }
+
+    fn log(data: u8) -> ReportLog {
+        ReportLog {
+            address: Address([0xaa; 20]),
+            log: LogEntry {
+                data: JsonBytes(vec![data]),
+                topics: vec![Bytes32([7; 32])],
+            },
+        }
+    }
+
+    fn write(key: u8, value: u8) -> StorageChange {
+        StorageChange {
+            address: Address([0xaa; 20]),
+            key: Bytes32([key; 32]),
+            before: Bytes32::default(),
+            after: Bytes32([value; 32]),
+        }
+    }
+
+    #[test]
+    fn compare_same_reports() {
+        assert!(compare(&report(), &report()).is_empty());
+        // Only the written values are compared, not the values before
+        let mut b = report();
+        b.state_changes[0].before = Bytes32([9; 32]);
+        assert!(compare(&report(), &b).is_empty());
+    }
+
+    #[test]
+    fn compare_divergent_reports() {
+        let a = report();
+        let mut b = report();
+        b.status = "EVMC_REVERT".to_string();
+        b.gas_left = 800;
+        b.output = JsonBytes(vec![3]);
+        b.state_changes = vec![write(1, 2), write(2, 2)];
+        b.logs = vec![log(2), log(3)];
+        let key = |key: u8| hex::encode([key; 32]);
+        let address = hex::encode([0xaa; 20]);
+        assert_eq!(
+            compare(&a, &b),
+            vec![
+                "status: EVMC_SUCCESS vs EVMC_REVERT".to_string(),
+                "gas left: 900 vs 800".to_string(),
+                "output: 0x0102 vs 0x03".to_string(),
+                format!(
+                    "storage write only in a: {}[{}] = {}",
+                    address,
+                    key(1),
+                    key(1)
+                ),
+                format!(
+                    "storage write only in b: {}[{}] = {}",
+                    address,
+                    key(1),
+                    key(2)
+                ),
+                format!(
+                    "storage write only in b: {}[{}] = {}",
+                    address,
+                    key(2),
+                    key(2)
+                ),
+                "logs: 1 vs 2".to_string(),
+                format!(
+                    "log 0: {} vs {}",
+                    serde_json::to_string(&a.logs[0]).unwrap(),
+                    serde_json::to_string(&b.logs[0]).unwrap()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn compare_sstores_in_order() {
+        let sstores = |values: &[&str]| {
+            values
+                .iter()
+                .map(|value| value.to_string())
+                .collect::<Vec<_>>()
+        };
+        let a = sstores(&["x = 1", "y = 2"]);
+        assert!(compare_sstores(&a, &a).is_empty());
+        assert_eq!(
+            compare_sstores(&a, &sstores(&["y = 2", "x = 1"])),
+            vec!["sstore 0: x = 1 vs y = 2"]
+        );
+        assert_eq!(
+            compare_sstores(&a, &sstores(&["x = 1", "y = 2", "x = 3"])),
+            vec!["sstores: 2 vs 3", "sstore 2 only in b: x = 3"]
+        );
+        assert_eq!(
+            compare_sstores(&a, &sstores(&["x = 1"])),
+            vec!["sstores: 2 vs 1", "sstore 1 only in a: y = 2"]
+        );
+        // The first difference is reported, not the extra ones
+        assert_eq!(
+            compare_sstores(&a, &sstores(&["x = 2"])),
+            vec!["sstores: 2 vs 1", "sstore 0: x = 1 vs x = 2"]
+        );
+    }
+}
@@ -0,0 +1,320 @@
+use std::fmt::Write;
+
+use crate::evmc::Revision;
+use crate::instructions::{metrics_table, names_table, push_size, OP_JUMPDEST};
+
+/// One disassembled item of the code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Instruction {
+        offset: usize,
+        opcode: u8,
+        name: &'static str,
+        immediate: Vec<u8>,
+    },
+    /// Undefined instructions and truncated PUSH at the end of the code
+    Data { offset: usize, bytes: Vec<u8> },
+}
+
+/// The Solidity metadata appended to the runtime code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+    pub entries: Vec<(String, String)>,
+}
+
+/// Split the trailing Solidity CBOR metadata from the code. The last two bytes
+/// are the big endian length of the CBOR encoded map before them.
+pub fn split_metadata(code: &[u8]) -> (&[u8], Option<Metadata>) {
+    if code.len() < 2 {
+        return (code, None);
+    }
+    let cbor_len = ((code[code.len() - 2] as usize) << 8) | code[code.len() - 1] as usize;
+    if cbor_len == 0 || cbor_len + 2 > code.len() {
+        return (code, None);
+    }
+    let offset = code.len() - 2 - cbor_len;
+    match decode_cbor_map(&code[offset..code.len() - 2]) {
+        Some(entries) if !entries.is_empty() => {
+            let metadata = Metadata {
+                offset,
+                bytes: code[offset..].to_vec(),
+                entries,
+            };
+            (&code[..offset], Some(metadata))
+        }
+        _ => (code, None),
+    }
+}
+
+// Only the subset of CBOR solc emits: a map of text keys to bytes/text/bool values
+fn decode_cbor_map(data: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut pos = 0;
+    let (major, count) = read_cbor_head(data, &mut pos)?;
+    if major != 5 {
+        return None;
+    }
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let (major, len) = read_cbor_head(data, &mut pos)?;
+        if major != 3 {
+            return None;
+        }
+        let key = String::from_utf8(data.get(pos..pos + len)?.to_vec()).ok()?;
+        pos += len;
+        let (major, len) = read_cbor_head(data, &mut pos)?;
+        let value = match major {
+            2 => {
+                let bytes = data.get(pos..pos + len)?;
+                pos += len;
+                if key == "solc" && bytes.len() == 3 {
+                    format!("{}.{}.{}", bytes[0], bytes[1], bytes[2])
+                } else {
+                    format!("0x{}", hex::encode(bytes))
+                }
+            }
+            3 => {
+                let text = String::from_utf8(data.get(pos..pos + len)?.to_vec()).ok()?;
+                pos += len;
+                text
+            }
+            7 if len == 20 => "false".to_string(),
+            7 if len == 21 => "true".to_string(),
+            _ => return None,
+        };
+        entries.push((key, value));
+    }
+    if pos == data.len() {
+        Some(entries)
+    } else {
+        None
+    }
+}
+
+fn read_cbor_head(data: &[u8], pos: &mut usize) -> Option<(u8, usize)> {
+    let head = *data.get(*pos)?;
+    *pos += 1;
+    let major = head >> 5;
+    let len = match head & 0x1f {
+        n @ 0..=23 => n as usize,
+        24 => {
+            let n = *data.get(*pos)? as usize;
+            *pos += 1;
+            n
+        }
+        25 => {
+            let bytes = data.get(*pos..*pos + 2)?;
+            *pos += 2;
+            ((bytes[0] as usize) << 8) | bytes[1] as usize
+        }
+        _ => return None,
+    };
+    Some((major, len))
+}
+
+/// Disassemble the code for the given revision
+pub fn disassemble(code: &[u8], revision: Revision) -> Vec<Item> {
+    let names = names_table(revision);
+    let mut items = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let opcode = code[offset];
+        let size = push_size(opcode);
+        match names[opcode as usize] {
+            Some(name) if offset + size < code.len() => {
+                items.push(Item::Instruction {
+                    offset,
+                    opcode,
+                    name,
+                    immediate: code[offset + 1..offset + 1 + size].to_vec(),
+                });
+                offset += 1 + size;
+            }
+            Some(_) => {
+                // Truncated PUSH at the end of the code
+                items.push(Item::Data {
+                    offset,
+                    bytes: code[offset..].to_vec(),
+                });
+                offset = code.len();
+            }
+            None => {
+                items.push(Item::Data {
+                    offset,
+                    bytes: vec![opcode],
+                });
+                offset += 1;
+            }
+        }
+    }
+    items
+}
+
+/// Format the code as assembly text, it can be assembled back by `asm`
+pub fn format_code(code: &[u8], revision: Revision, with_metrics: bool) -> String {
+    let metrics = metrics_table(revision);
+    let (code, metadata) = split_metadata(code);
+    let mut output = String::new();
+    for item in disassemble(code, revision) {
+        let line = match item {
+            Item::Instruction {
+                offset,
+                opcode,
+                name,
+                immediate,
+            } => {
+                if opcode == OP_JUMPDEST {
+                    writeln!(output, "loc_{:04x}:", offset).unwrap();
+                }
+                let text = if immediate.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{} 0x{}", name, hex::encode(&immediate))
+                };
+                let mut comment = format!("{:04x}", offset);
+                if with_metrics {
+                    let metric = metrics[opcode as usize];
+                    write!(
+                        comment,
+                        "  gas: {}, stack: {} => {:+}",
+                        metric.gas_cost, metric.stack_height_required, metric.stack_height_change
+                    )
+                    .unwrap();
+                }
+                format!("    {:<40} ; {}", text, comment)
+            }
+            Item::Data { offset, bytes } => {
+                let reason = if push_size(bytes[0]) > 0 {
+                    "truncated PUSH"
+                } else {
+                    "undefined instruction"
+                };
+                format!(
+                    "    {:<40} ; {:04x}  {}",
+                    format!(".data 0x{}", hex::encode(&bytes)),
+                    offset,
+                    reason
+                )
+            }
+        };
+        writeln!(output, "{}", line).unwrap();
+    }
+    if let Some(metadata) = metadata {
+        writeln!(output).unwrap();
+        let entries = metadata
+            .entries
+            .iter()
+            .map(|(key, value)| format!("{}: {}", key, value))
+            .collect::<Vec<_>>();
+        writeln!(output, "; Solidity metadata ({})", entries.join(", ")).unwrap();
+        writeln!(
+            output,
+            "    .data 0x{} ; {:04x}",
+            hex::encode(&metadata.bytes),
+            metadata.offset
+        )
+        .unwrap();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The metadata of solc 0.8.7: {"ipfs": <34 bytes>, "solc": 0.8.7}
+    fn solc_metadata() -> Vec<u8> {
+        let mut cbor = vec![0xa2, 0x64];
+        cbor.extend(b"ipfs");
+        cbor.extend(&[0x58, 0x22]);
+        cbor.extend(&[0x12; 34]);
+        cbor.push(0x64);
+        cbor.extend(b"solc");
+        cbor.extend(&[0x43, 0x00, 0x08, 0x07]);
+        let len = cbor.len() as u16;
+        cbor.extend(&len.to_be_bytes());
+        cbor
+    }
+
+    #[test]
+    fn split_solidity_metadata() {
+        let mut code = vec![0x60, 0x80, 0x5b, 0x00];
+        code.extend(solc_metadata());
+        let (runtime, metadata) = split_metadata(&code);
+        assert_eq!(runtime, &[0x60, 0x80, 0x5b, 0x00]);
+        let metadata = metadata.unwrap();
+        assert_eq!(metadata.offset, 4);
+        assert_eq!(metadata.bytes, &code[4..]);
+        assert_eq!(
+            metadata.entries,
+            vec![
+                ("ipfs".to_string(), format!("0x{}", "12".repeat(34))),
+                ("solc".to_string(), "0.8.7".to_string()),
+            ]
+        );
+
+        // The length points to a text, not a map
+        let code = [0x60, 0x01, 0x00, 0x02];
+        assert_eq!(split_metadata(&code), (&code[..], None));
+        // A byte left after the map
+        let mut code = solc_metadata();
+        let len = code.len();
+        code.insert(len - 2, 0x00);
+        code[len] += 1;
+        assert_eq!(split_metadata(&code).1, None);
+        // Longer than the code
+        assert_eq!(split_metadata(&[0x00, 0x10]).1, None);
+    }
+
+    #[test]
+    fn truncated_push_and_undefined_as_data() {
+        let items = disassemble(&[0x60, 0x01, 0x0c, 0x61, 0x02], Revision::EVMC_BERLIN);
+        assert_eq!(
+            items,
+            vec![
+                Item::Instruction {
+                    offset: 0,
+                    opcode: 0x60,
+                    name: "PUSH1",
+                    immediate: vec![0x01],
+                },
+                Item::Data {
+                    offset: 2,
+                    bytes: vec![0x0c],
+                },
+                Item::Data {
+                    offset: 3,
+                    bytes: vec![0x61, 0x02],
+                },
+            ]
+        );
+        let text = format_code(&[0x0c, 0x61, 0x02], Revision::EVMC_BERLIN, false);
+        assert!(text.contains(".data 0x0c"));
+        assert!(text.contains("undefined instruction"));
+        assert!(text.contains(".data 0x6102"));
+        assert!(text.contains("truncated PUSH"));
+    }
+
+    #[test]
+    fn undefined_by_revision() {
+        // SHL since Constantinople
+        let shl = |revision| match &disassemble(&[0x1b], revision)[0] {
+            Item::Instruction { name, .. } => Some(*name),
+            Item::Data { .. } => None,
+        };
+        assert_eq!(shl(Revision::EVMC_BYZANTIUM), None);
+        assert_eq!(shl(Revision::EVMC_CONSTANTINOPLE), Some("SHL"));
+    }
+
+    #[test]
+    fn mark_jump_destinations() {
+        // The 0x5b pushed is not a JUMPDEST
+        let text = format_code(&[0x60, 0x5b, 0x5b, 0x00], Revision::EVMC_BERLIN, true);
+        assert!(!text.contains("loc_0001:"));
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1], "loc_0002:");
+        assert!(lines[2].trim_start().starts_with("JUMPDEST"));
+        assert!(lines[2].ends_with("0002  gas: 1, stack: 0 => +0"));
+    }
+}
@@ -1,9 +1,10 @@
 use std::alloc::{dealloc, Layout};
+use std::ffi::CString;
 use std::fmt;
 use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
 use std::ptr;
-use std::slice::from_raw_parts;
+use std::slice::{from_raw_parts, from_raw_parts_mut};
 
 use evmc_sys as ffi;
 use hex;
@@ -23,9 +24,26 @@ pub type StorageStatus = ffi::evmc_storage_status;
 /// EVMC VM revision.
 pub type Revision = ffi::evmc_revision;
 
+/// Parse the revision name (like "istanbul" or "tangerine-whistle")
+pub fn parse_revision(name: &str) -> Result<Revision, String> {
+    match name.to_lowercase().replace('_', "-").as_str() {
+        "frontier" => Ok(Revision::EVMC_FRONTIER),
+        "homestead" => Ok(Revision::EVMC_HOMESTEAD),
+        "tangerine-whistle" => Ok(Revision::EVMC_TANGERINE_WHISTLE),
+        "spurious-dragon" => Ok(Revision::EVMC_SPURIOUS_DRAGON),
+        "byzantium" => Ok(Revision::EVMC_BYZANTIUM),
+        "constantinople" => Ok(Revision::EVMC_CONSTANTINOPLE),
+        "petersburg" => Ok(Revision::EVMC_PETERSBURG),
+        "istanbul" => Ok(Revision::EVMC_ISTANBUL),
+        "berlin" => Ok(Revision::EVMC_BERLIN),
+        _ => Err(format!("Invalid revision: {}", name)),
+    }
+}
+
 pub type TxContext = ffi::evmc_tx_context;
 pub type HostInterface = ffi::evmc_host_interface;
 
+#[derive(Debug)]
 pub struct EvmcVm {
     pub instance: *mut ffi::evmc_vm,
 }
@@ -35,6 +53,28 @@ impl EvmcVm {
         EvmcVm { instance }
     }
 
+    pub fn set_option(&self, name: &str, value: &str) -> Result<(), String> {
+        let c_name = CString::new(name).map_err(|err| err.to_string())?;
+        let c_value = CString::new(value).map_err(|err| err.to_string())?;
+        let result = unsafe {
+            match (*self.instance).set_option {
+                Some(set_option_fn) => {
+                    set_option_fn(self.instance, c_name.as_ptr(), c_value.as_ptr())
+                }
+                None => ffi::evmc_set_option_result::EVMC_SET_OPTION_INVALID_NAME,
+            }
+        };
+        match result {
+            ffi::evmc_set_option_result::EVMC_SET_OPTION_SUCCESS => Ok(()),
+            ffi::evmc_set_option_result::EVMC_SET_OPTION_INVALID_NAME => {
+                Err(format!("VM option not supported: {}", name))
+            }
+            ffi::evmc_set_option_result::EVMC_SET_OPTION_INVALID_VALUE => {
+                Err(format!("Invalid value for VM option {}: {}", name, value))
+            }
+        }
+    }
+
     pub fn execute(
         &self,
         revision: Revision,
@@ -384,7 +424,7 @@ pub trait HostContext {
     fn call(&mut self, msg: ExecutionMessage) -> ExecutionResult;
     fn selfdestruct(&mut self, address: &Address, beneficiary: &Address);
     fn emit_log(&mut self, address: &Address, data: &[u8], topics: &[Bytes32]);
-    fn copy_code(&mut self, address: &Address, code_offset: usize, buffer: &[u8]) -> usize;
+    fn copy_code(&mut self, address: &Address, code_offset: usize, buffer: &mut [u8]) -> usize;
     fn get_code_size(&mut self, address: &Address) -> usize;
     fn get_code_hash(&mut self, address: &Address) -> Bytes32;
     fn get_block_hash(&mut self, number: u64) -> Bytes32;
@@ -508,7 +548,7 @@ pub fn get_interface<T: HostContext>() -> ffi::evmc_host_interface {
         buffer_size: usize,
     ) -> usize {
         let address = Address::from(*address);
-        let buffer: &[u8] = from_raw_parts(buffer_data, buffer_size);
+        let buffer: &mut [u8] = from_raw_parts_mut(buffer_data, buffer_size);
         HostContextWrapper::<T>::from(context).copy_code(&address, code_offset, buffer)
     }
 
@@ -0,0 +1,464 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::FromRawFd;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::evmc::{Address, Bytes32, Uint256};
+use crate::JsonBytes;
+
+/// A host interaction made by the VM (or by the CLI on behalf of the VM)
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "op", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HostEvent {
+    TxContext {
+        depth: u32,
+    },
+    AccountExists {
+        depth: u32,
+        address: Address,
+        exists: bool,
+    },
+    Sload {
+        depth: u32,
+        address: Address,
+        key: Bytes32,
+        value: Bytes32,
+    },
+    Sstore {
+        depth: u32,
+        address: Address,
+        key: Bytes32,
+        value: Bytes32,
+        status: String,
+    },
+    Balance {
+        depth: u32,
+        address: Address,
+        balance: Uint256,
+    },
+    CallEnter {
+        depth: u32,
+        kind: String,
+        sender: Address,
+        destination: Address,
+        gas: i64,
+        value: Uint256,
+        input: JsonBytes,
+    },
+    CallExit {
+        depth: u32,
+        status: String,
+        gas_left: i64,
+        gas_used: i64,
+        output: JsonBytes,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        revert_reason: Option<String>,
+    },
+    Create {
+        depth: u32,
+        address: Address,
+        code_size: usize,
+    },
+    Log {
+        depth: u32,
+        address: Address,
+        topics: Vec<Bytes32>,
+        data: JsonBytes,
+    },
+    Selfdestruct {
+        depth: u32,
+        address: Address,
+        beneficiary: Address,
+    },
+    CopyCode {
+        depth: u32,
+        address: Address,
+        code_offset: usize,
+        buffer_size: usize,
+        copied: usize,
+    },
+    CodeSize {
+        depth: u32,
+        address: Address,
+        size: usize,
+    },
+    CodeHash {
+        depth: u32,
+        address: Address,
+        hash: Bytes32,
+    },
+    BlockHash {
+        depth: u32,
+        number: u64,
+        hash: Bytes32,
+    },
+}
+
+impl fmt::Display for HostEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostEvent::TxContext { depth } => write!(f, "[{}] TX_CONTEXT", depth),
+            HostEvent::AccountExists {
+                depth,
+                address,
+                exists,
+            } => write!(
+                f,
+                "[{}] ACCOUNT_EXISTS(address: {:?}) => {}",
+                depth, address, exists
+            ),
+            HostEvent::Sload {
+                depth,
+                address,
+                key,
+                value,
+            } => write!(
+                f,
+                "[{}] SLOAD(address: {:?}, key: {:?}) => {:?}",
+                depth, address, key, value
+            ),
+            HostEvent::Sstore {
+                depth,
+                address,
+                key,
+                value,
+                status,
+            } => write!(
+                f,
+                "[{}] SSTORE(address: {:?}, key: {:?}, value: {:?}) => {}",
+                depth, address, key, value, status
+            ),
+            HostEvent::Balance {
+                depth,
+                address,
+                balance,
+            } => write!(
+                f,
+                "[{}] BALANCE(address: {:?}) => {:?}",
+                depth, address, balance
+            ),
+            HostEvent::CallEnter {
+                depth,
+                kind,
+                sender,
+                destination,
+                gas,
+                value,
+                input,
+            } => write!(
+                f,
+                "[{}] CALL_ENTER(kind: {}, sender: {:?}, destination: {:?}, gas: {}, value: {:?}, input: {:?})",
+                depth, kind, sender, destination, gas, value, input
+            ),
+            HostEvent::CallExit {
+                depth,
+                status,
+                gas_left,
+                gas_used,
+                output,
+                revert_reason,
+            } => {
+                write!(
+                    f,
+                    "[{}] CALL_EXIT(status: {}, gas_left: {}, gas_used: {}, output: {:?})",
+                    depth, status, gas_left, gas_used, output
+                )?;
+                if let Some(reason) = revert_reason {
+                    write!(f, " reverted: {}", reason)?;
+                }
+                Ok(())
+            }
+            HostEvent::Create {
+                depth,
+                address,
+                code_size,
+            } => write!(
+                f,
+                "[{}] CREATE(address: {:?}, code_size: {})",
+                depth, address, code_size
+            ),
+            HostEvent::Log {
+                depth,
+                address,
+                topics,
+                data,
+            } => write!(
+                f,
+                "[{}] LOG(address: {:?}, topics: {:?}, data: {:?})",
+                depth, address, topics, data
+            ),
+            HostEvent::Selfdestruct {
+                depth,
+                address,
+                beneficiary,
+            } => write!(
+                f,
+                "[{}] SELFDESTRUCT(address: {:?}, beneficiary: {:?})",
+                depth, address, beneficiary
+            ),
+            HostEvent::CopyCode {
+                depth,
+                address,
+                code_offset,
+                buffer_size,
+                copied,
+            } => write!(
+                f,
+                "[{}] COPY_CODE(address: {:?}, code_offset: {}, buffer_size: {}) => {}",
+                depth, address, code_offset, buffer_size, copied
+            ),
+            HostEvent::CodeSize {
+                depth,
+                address,
+                size,
+            } => write!(
+                f,
+                "[{}] CODE_SIZE(address: {:?}) => {}",
+                depth, address, size
+            ),
+            HostEvent::CodeHash {
+                depth,
+                address,
+                hash,
+            } => write!(
+                f,
+                "[{}] CODE_HASH(address: {:?}) => {:?}",
+                depth, address, hash
+            ),
+            HostEvent::BlockHash {
+                depth,
+                number,
+                hash,
+            } => write!(
+                f,
+                "[{}] BLOCK_HASH(number: {}) => {:?}",
+                depth, number, hash
+            ),
+        }
+    }
+}
+
+/// Where the host events go
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceSink {
+    #[default]
+    Quiet,
+    Human,
+    Json,
+}
+
+impl FromStr for TraceSink {
+    type Err = String;
+    fn from_str(s: &str) -> Result<TraceSink, String> {
+        match s {
+            "quiet" => Ok(TraceSink::Quiet),
+            "human" => Ok(TraceSink::Human),
+            "json" => Ok(TraceSink::Json),
+            _ => Err(format!("Invalid host trace sink: {}", s)),
+        }
+    }
+}
+
+/// Emits the host events to stderr through the sink, and records them if a
+/// consumer asked for them
+#[derive(Debug, Default)]
+pub struct HostTracer {
+    pub sink: TraceSink,
+    pub events: RefCell<Vec<HostEvent>>,
+    // The events are recorded, false for the tracers living across the
+    // transactions so they do not grow
+    keep: bool,
+    // A duplicate of stderr taken at the creation, so the events are not
+    // caught when stderr is redirected to capture the VM trace
+    stderr: Option<File>,
+}
+
+impl HostTracer {
+    /// Emit the events only
+    pub fn new(sink: TraceSink) -> HostTracer {
+        let stderr = match sink {
+            TraceSink::Quiet => None,
+            _ => {
+                let fd = unsafe { libc::dup(libc::STDERR_FILENO) };
+                if fd < 0 {
+                    None
+                } else {
+                    Some(unsafe { File::from_raw_fd(fd) })
+                }
+            }
+        };
+        HostTracer {
+            sink,
+            events: RefCell::new(Vec::new()),
+            keep: false,
+            stderr,
+        }
+    }
+
+    /// Emit and record the events, for the execution of one message or
+    /// transaction
+    pub fn recording(sink: TraceSink) -> HostTracer {
+        HostTracer {
+            keep: true,
+            ..HostTracer::new(sink)
+        }
+    }
+
+    pub fn record(&self, event: HostEvent) {
+        let line = match self.sink {
+            TraceSink::Quiet => None,
+            TraceSink::Human => Some(event.to_string()),
+            TraceSink::Json => Some(serde_json::to_string(&event).unwrap()),
+        };
+        if let Some(line) = line {
+            match self.stderr.as_ref() {
+                Some(mut stderr) => {
+                    let _ = writeln!(stderr, "{}", line);
+                }
+                None => eprintln!("{}", line),
+            }
+        }
+        if self.keep {
+            self.events.borrow_mut().push(event);
+        }
+    }
+
+    /// The events in the recorded order, without the ones of the failed call
+    /// frames (their state changes and logs were reverted)
+    pub fn committed_events(&self) -> Vec<HostEvent> {
+        let mut frames: Vec<Vec<HostEvent>> = vec![Vec::new()];
+        for event in self.events.borrow().iter() {
+            match event {
+                HostEvent::CallEnter { .. } => frames.push(vec![event.clone()]),
+                HostEvent::CallExit { status, .. } if frames.len() > 1 => {
+                    let mut frame = frames.pop().unwrap();
+                    if status != "EVMC_SUCCESS" {
+                        // Keep the call enter only
+                        frame.truncate(1);
+                    }
+                    frame.push(event.clone());
+                    frames.last_mut().unwrap().extend(frame);
+                }
+                _ => frames.last_mut().unwrap().push(event.clone()),
+            }
+        }
+        frames.into_iter().flatten().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enter(depth: u32) -> HostEvent {
+        HostEvent::CallEnter {
+            depth,
+            kind: "EVMC_CALL".to_string(),
+            sender: Address::default(),
+            destination: Address([depth as u8; 20]),
+            gas: 100_000,
+            value: Uint256::default(),
+            input: JsonBytes(Vec::new()),
+        }
+    }
+
+    fn exit(depth: u32, status: &str) -> HostEvent {
+        HostEvent::CallExit {
+            depth,
+            status: status.to_string(),
+            gas_left: 0,
+            gas_used: 100_000,
+            output: JsonBytes(Vec::new()),
+            revert_reason: None,
+        }
+    }
+
+    fn sstore(depth: u32) -> HostEvent {
+        HostEvent::Sstore {
+            depth,
+            address: Address([depth as u8; 20]),
+            key: Bytes32::default(),
+            value: Bytes32([depth as u8; 32]),
+            status: "EVMC_STORAGE_ADDED".to_string(),
+        }
+    }
+
+    // The op and the depth of each committed event
+    fn committed(events: Vec<HostEvent>) -> Vec<String> {
+        let tracer = HostTracer::recording(TraceSink::Quiet);
+        for event in events {
+            tracer.record(event);
+        }
+        tracer
+            .committed_events()
+            .iter()
+            .map(|event| {
+                let event = serde_json::to_value(event).unwrap();
+                format!("{} {}", event["op"].as_str().unwrap(), event["depth"])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drop_events_of_reverted_frames() {
+        // The successful inner frame is reverted with its caller
+        let events = vec![
+            sstore(0),
+            enter(1),
+            sstore(1),
+            enter(2),
+            sstore(2),
+            exit(2, "EVMC_SUCCESS"),
+            exit(1, "EVMC_REVERT"),
+            sstore(0),
+        ];
+        assert_eq!(
+            committed(events),
+            vec!["SSTORE 0", "CALL_ENTER 1", "CALL_EXIT 1", "SSTORE 0"]
+        );
+
+        // The reverted inner frame only
+        let events = vec![
+            enter(1),
+            sstore(1),
+            enter(2),
+            sstore(2),
+            exit(2, "EVMC_OUT_OF_GAS"),
+            sstore(1),
+            exit(1, "EVMC_SUCCESS"),
+        ];
+        assert_eq!(
+            committed(events),
+            vec![
+                "CALL_ENTER 1",
+                "SSTORE 1",
+                "CALL_ENTER 2",
+                "CALL_EXIT 2",
+                "SSTORE 1",
+                "CALL_EXIT 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_unmatched_call_exit() {
+        // The exit of the top level call has no enter, the events before it
+        // are kept
+        let events = vec![sstore(0), exit(0, "EVMC_REVERT"), sstore(0)];
+        assert_eq!(
+            committed(events),
+            vec!["SSTORE 0", "CALL_EXIT 0", "SSTORE 0"]
+        );
+
+        // The enter of an unfinished call is kept with its events
+        let events = vec![sstore(0), enter(1), sstore(1)];
+        assert_eq!(
+            committed(events),
+            vec!["SSTORE 0", "CALL_ENTER 1", "SSTORE 1"]
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! A minimal HTTP/1.1 server side: read one request, write one response and
+//! close the connection
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// The header names are lowercase
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|value| value.as_str())
+    }
+}
+
+/// Read the request line, the headers and the body (by Content-Length)
+pub fn read_request(stream: &TcpStream) -> Result<Request, String> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|err| err.to_string())?;
+    let mut parts = line.split_whitespace();
+    let method = match (parts.next(), parts.next()) {
+        (Some(method), Some(_path)) => method.to_string(),
+        _ => return Err(format!("Invalid request line: {:?}", line)),
+    };
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(|err| err.to_string())? == 0 {
+            return Err("Connection closed in the headers".to_string());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(pos) = line.find(':') {
+            headers.insert(
+                line[..pos].trim().to_lowercase(),
+                line[pos + 1..].trim().to_string(),
+            );
+        }
+    }
+    let length = match headers.get("content-length") {
+        Some(length) => length
+            .parse::<usize>()
+            .map_err(|err| format!("Invalid Content-Length: {}", err))?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(format!("Request body too large: {} bytes", length));
+    }
+    let mut body = vec![0u8; length];
+    reader
+        .read_exact(&mut body)
+        .map_err(|err| err.to_string())?;
+    Ok(Request {
+        method,
+        headers,
+        body,
+    })
+}
+
+/// Write the response, CORS is allowed for the browser clients
+pub fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), String> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: POST, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream
+        .write_all(head.as_bytes())
+        .and_then(|_| stream.write_all(body))
+        .and_then(|_| stream.flush())
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Send the bytes to a local server, return the request it reads
+    fn send(bytes: &'static [u8]) -> Result<Request, String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // The client closes the connection after writing
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(bytes).unwrap();
+        });
+        let (stream, _) = listener.accept().unwrap();
+        client.join().unwrap();
+        read_request(&stream)
+    }
+
+    #[test]
+    fn read_post_request() {
+        let request = send(
+            b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: 7\r\n\r\n{\"a\":1}",
+        )
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        assert_eq!(request.body, b"{\"a\":1}");
+    }
+
+    #[test]
+    fn reject_invalid_requests() {
+        assert!(send(b"GARBAGE\r\n\r\n").is_err());
+        assert!(send(b"POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n").is_err());
+        assert!(send(b"POST / HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n").is_err());
+        assert!(send(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort").is_err());
+    }
+
+    #[test]
+    fn write_json_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut response = String::new();
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        write_response(&mut stream, "200 OK", "application/json", b"{}").unwrap();
+        drop(stream);
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 2\r\n"));
+        assert!(response.ends_with("\r\n\r\n{}"));
+    }
+}
@@ -0,0 +1,265 @@
+//! EVM instruction tables, ported from include/evmc/instructions.h
+
+use crate::evmc::Revision;
+
+/// Metrics for an EVM 1 instruction (`evmc_instruction_metrics`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionMetrics {
+    /// The instruction gas cost, -1 for undefined instructions
+    pub gas_cost: i16,
+    /// The minimum number of the EVM stack items required for the instruction
+    pub stack_height_required: i8,
+    /// The EVM stack height change caused by the instruction execution
+    pub stack_height_change: i8,
+}
+
+const UNDEFINED: InstructionMetrics = InstructionMetrics {
+    gas_cost: -1,
+    stack_height_required: 0,
+    stack_height_change: 0,
+};
+
+pub const OP_JUMPDEST: u8 = 0x5b;
+pub const OP_PUSH1: u8 = 0x60;
+pub const OP_PUSH32: u8 = 0x7f;
+
+// (opcode, name, introduced in, gas cost in that revision, stack required, stack change)
+const BASE_INSTRUCTIONS: &[(u8, &str, Revision, i16, i8, i8)] = &[
+    (0x00, "STOP", Revision::EVMC_FRONTIER, 0, 0, 0),
+    (0x01, "ADD", Revision::EVMC_FRONTIER, 3, 2, -1),
+    (0x02, "MUL", Revision::EVMC_FRONTIER, 5, 2, -1),
+    (0x03, "SUB", Revision::EVMC_FRONTIER, 3, 2, -1),
+    (0x04, "DIV", Revision::EVMC_FRONTIER, 5, 2, -1),
+    (0x05, "SDIV", Revision::EVMC_FRONTIER, 5, 2, -1),
+    (0x06, "MOD", Revision::EVMC_FRONTIER, 5, 2, -1),
+    (0x07, "SMOD", Revision::EVMC_FRONTIER, 5, 2, -1),
+    (0x08, "ADDMOD", Revision::EVMC_FRONTIER, 8, 3, -2),
+    (0x09, "MULMOD", Revision::EVMC_FRONTIER, 8, 3, -2),
+    (0x0a, "EXP", Revision::EVMC_FRONTIER, 10, 2, -1),
+    (0x0b, "SIGNEXTEND", Revision::EVMC_FRONTIER, 5, 2, -1),
+    (0x10, "LT", Revision::EVMC_FRONTIER, 3, 2, -1),
+    (0x11, "GT", Revision::EVMC_FRONTIER, 3, 2, -1),
+    (0x12, "SLT", Revision::EVMC_FRONTIER, 3, 2, -1),
+    (0x13, "SGT", Revision::EVMC_FRONTIER, 3, 2, -1),
+    (0x14, "EQ", Revision::EVMC_FRONTIER, 3, 2, -1),
+    (0x15, "ISZERO", Revision::EVMC_FRONTIER, 3, 1, 0),
+    (0x16, "AND", Revision::EVMC_FRONTIER, 3, 2, -1),
+    (0x17, "OR", Revision::EVMC_FRONTIER, 3, 2, -1),
+    (0x18, "XOR", Revision::EVMC_FRONTIER, 3, 2, -1),
+    (0x19, "NOT", Revision::EVMC_FRONTIER, 3, 1, 0),
+    (0x1a, "BYTE", Revision::EVMC_FRONTIER, 3, 2, -1),
+    (0x1b, "SHL", Revision::EVMC_CONSTANTINOPLE, 3, 2, -1),
+    (0x1c, "SHR", Revision::EVMC_CONSTANTINOPLE, 3, 2, -1),
+    (0x1d, "SAR", Revision::EVMC_CONSTANTINOPLE, 3, 2, -1),
+    (0x20, "SHA3", Revision::EVMC_FRONTIER, 30, 2, -1),
+    (0x30, "ADDRESS", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x31, "BALANCE", Revision::EVMC_FRONTIER, 20, 1, 0),
+    (0x32, "ORIGIN", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x33, "CALLER", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x34, "CALLVALUE", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x35, "CALLDATALOAD", Revision::EVMC_FRONTIER, 3, 1, 0),
+    (0x36, "CALLDATASIZE", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x37, "CALLDATACOPY", Revision::EVMC_FRONTIER, 3, 3, -3),
+    (0x38, "CODESIZE", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x39, "CODECOPY", Revision::EVMC_FRONTIER, 3, 3, -3),
+    (0x3a, "GASPRICE", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x3b, "EXTCODESIZE", Revision::EVMC_FRONTIER, 20, 1, 0),
+    (0x3c, "EXTCODECOPY", Revision::EVMC_FRONTIER, 20, 4, -4),
+    (0x3d, "RETURNDATASIZE", Revision::EVMC_BYZANTIUM, 2, 0, 1),
+    (0x3e, "RETURNDATACOPY", Revision::EVMC_BYZANTIUM, 3, 3, -3),
+    (
+        0x3f,
+        "EXTCODEHASH",
+        Revision::EVMC_CONSTANTINOPLE,
+        400,
+        1,
+        0,
+    ),
+    (0x40, "BLOCKHASH", Revision::EVMC_FRONTIER, 20, 1, 0),
+    (0x41, "COINBASE", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x42, "TIMESTAMP", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x43, "NUMBER", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x44, "DIFFICULTY", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x45, "GASLIMIT", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x46, "CHAINID", Revision::EVMC_ISTANBUL, 2, 0, 1),
+    (0x47, "SELFBALANCE", Revision::EVMC_ISTANBUL, 5, 0, 1),
+    (0x50, "POP", Revision::EVMC_FRONTIER, 2, 1, -1),
+    (0x51, "MLOAD", Revision::EVMC_FRONTIER, 3, 1, 0),
+    (0x52, "MSTORE", Revision::EVMC_FRONTIER, 3, 2, -2),
+    (0x53, "MSTORE8", Revision::EVMC_FRONTIER, 3, 2, -2),
+    (0x54, "SLOAD", Revision::EVMC_FRONTIER, 50, 1, 0),
+    (0x55, "SSTORE", Revision::EVMC_FRONTIER, 0, 2, -2),
+    (0x56, "JUMP", Revision::EVMC_FRONTIER, 8, 1, -1),
+    (0x57, "JUMPI", Revision::EVMC_FRONTIER, 10, 2, -2),
+    (0x58, "PC", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x59, "MSIZE", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x5a, "GAS", Revision::EVMC_FRONTIER, 2, 0, 1),
+    (0x5b, "JUMPDEST", Revision::EVMC_FRONTIER, 1, 0, 0),
+    (0xf0, "CREATE", Revision::EVMC_FRONTIER, 32000, 3, -2),
+    (0xf1, "CALL", Revision::EVMC_FRONTIER, 40, 7, -6),
+    (0xf2, "CALLCODE", Revision::EVMC_FRONTIER, 40, 7, -6),
+    (0xf3, "RETURN", Revision::EVMC_FRONTIER, 0, 2, -2),
+    (0xf4, "DELEGATECALL", Revision::EVMC_HOMESTEAD, 40, 6, -5),
+    (0xf5, "CREATE2", Revision::EVMC_CONSTANTINOPLE, 32000, 4, -3),
+    (0xfa, "STATICCALL", Revision::EVMC_BYZANTIUM, 700, 6, -5),
+    (0xfd, "REVERT", Revision::EVMC_BYZANTIUM, 0, 2, -2),
+    (0xfe, "INVALID", Revision::EVMC_FRONTIER, 0, 0, 0),
+    (0xff, "SELFDESTRUCT", Revision::EVMC_FRONTIER, 0, 1, -1),
+];
+
+// Gas cost changes: (opcode, since revision, new gas cost)
+const GAS_CHANGES: &[(u8, Revision, i16)] = &[
+    (0x31, Revision::EVMC_TANGERINE_WHISTLE, 400),
+    (0x3b, Revision::EVMC_TANGERINE_WHISTLE, 700),
+    (0x3c, Revision::EVMC_TANGERINE_WHISTLE, 700),
+    (0x54, Revision::EVMC_TANGERINE_WHISTLE, 200),
+    (0xf1, Revision::EVMC_TANGERINE_WHISTLE, 700),
+    (0xf2, Revision::EVMC_TANGERINE_WHISTLE, 700),
+    (0xf4, Revision::EVMC_TANGERINE_WHISTLE, 700),
+    (0xff, Revision::EVMC_TANGERINE_WHISTLE, 5000),
+    (0x31, Revision::EVMC_ISTANBUL, 700),
+    (0x3f, Revision::EVMC_ISTANBUL, 700),
+    (0x54, Revision::EVMC_ISTANBUL, 800),
+];
+
+const PUSH_NAMES: [&str; 32] = [
+    "PUSH1", "PUSH2", "PUSH3", "PUSH4", "PUSH5", "PUSH6", "PUSH7", "PUSH8", "PUSH9", "PUSH10",
+    "PUSH11", "PUSH12", "PUSH13", "PUSH14", "PUSH15", "PUSH16", "PUSH17", "PUSH18", "PUSH19",
+    "PUSH20", "PUSH21", "PUSH22", "PUSH23", "PUSH24", "PUSH25", "PUSH26", "PUSH27", "PUSH28",
+    "PUSH29", "PUSH30", "PUSH31", "PUSH32",
+];
+const DUP_NAMES: [&str; 16] = [
+    "DUP1", "DUP2", "DUP3", "DUP4", "DUP5", "DUP6", "DUP7", "DUP8", "DUP9", "DUP10", "DUP11",
+    "DUP12", "DUP13", "DUP14", "DUP15", "DUP16",
+];
+const SWAP_NAMES: [&str; 16] = [
+    "SWAP1", "SWAP2", "SWAP3", "SWAP4", "SWAP5", "SWAP6", "SWAP7", "SWAP8", "SWAP9", "SWAP10",
+    "SWAP11", "SWAP12", "SWAP13", "SWAP14", "SWAP15", "SWAP16",
+];
+const LOG_NAMES: [&str; 5] = ["LOG0", "LOG1", "LOG2", "LOG3", "LOG4"];
+
+/// The table of the EVM 1 instruction names, `None` for undefined instructions
+/// (`evmc_get_instruction_names_table`)
+pub fn names_table(revision: Revision) -> [Option<&'static str>; 256] {
+    let mut names = [None; 256];
+    for &(opcode, name, since, ..) in BASE_INSTRUCTIONS {
+        if revision as u32 >= since as u32 {
+            names[opcode as usize] = Some(name);
+        }
+    }
+    for i in 0..32 {
+        names[OP_PUSH1 as usize + i] = Some(PUSH_NAMES[i]);
+    }
+    for i in 0..16 {
+        names[0x80 + i] = Some(DUP_NAMES[i]);
+        names[0x90 + i] = Some(SWAP_NAMES[i]);
+    }
+    for i in 0..5 {
+        names[0xa0 + i] = Some(LOG_NAMES[i]);
+    }
+    names
+}
+
+/// The table of the EVM 1 instructions metrics (`evmc_get_instruction_metrics_table`)
+pub fn metrics_table(revision: Revision) -> [InstructionMetrics; 256] {
+    let mut metrics = [UNDEFINED; 256];
+    for &(opcode, _, since, gas_cost, required, change) in BASE_INSTRUCTIONS {
+        if revision as u32 >= since as u32 {
+            metrics[opcode as usize] = InstructionMetrics {
+                gas_cost,
+                stack_height_required: required,
+                stack_height_change: change,
+            };
+        }
+    }
+    for &(opcode, since, gas_cost) in GAS_CHANGES {
+        if revision as u32 >= since as u32 {
+            metrics[opcode as usize].gas_cost = gas_cost;
+        }
+    }
+    for i in 0..32 {
+        metrics[OP_PUSH1 as usize + i] = InstructionMetrics {
+            gas_cost: 3,
+            stack_height_required: 0,
+            stack_height_change: 1,
+        };
+    }
+    for i in 0..16 {
+        metrics[0x80 + i] = InstructionMetrics {
+            gas_cost: 3,
+            stack_height_required: i as i8 + 1,
+            stack_height_change: 1,
+        };
+        metrics[0x90 + i] = InstructionMetrics {
+            gas_cost: 3,
+            stack_height_required: i as i8 + 2,
+            stack_height_change: 0,
+        };
+    }
+    for i in 0..5 {
+        metrics[0xa0 + i] = InstructionMetrics {
+            gas_cost: 375 * (i as i16 + 1),
+            stack_height_required: i as i8 + 2,
+            stack_height_change: -(i as i8 + 2),
+        };
+    }
+    metrics
+}
+
+/// The number of immediate bytes following the instruction
+pub fn push_size(opcode: u8) -> usize {
+    if (OP_PUSH1..=OP_PUSH32).contains(&opcode) {
+        (opcode - OP_PUSH1) as usize + 1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_by_revision() {
+        assert_eq!(names_table(Revision::EVMC_FRONTIER)[0xf4], None);
+        assert_eq!(
+            names_table(Revision::EVMC_HOMESTEAD)[0xf4],
+            Some("DELEGATECALL")
+        );
+        assert_eq!(names_table(Revision::EVMC_PETERSBURG)[0x46], None);
+        assert_eq!(names_table(Revision::EVMC_ISTANBUL)[0x46], Some("CHAINID"));
+        let names = names_table(Revision::EVMC_BERLIN);
+        assert_eq!(names[0x0c], None);
+        assert_eq!(names[OP_PUSH32 as usize], Some("PUSH32"));
+        assert_eq!(names[0x8f], Some("DUP16"));
+        assert_eq!(names[0xa4], Some("LOG4"));
+    }
+
+    #[test]
+    fn metrics_by_revision() {
+        let sload = |revision| metrics_table(revision)[0x54].gas_cost;
+        assert_eq!(sload(Revision::EVMC_FRONTIER), 50);
+        assert_eq!(sload(Revision::EVMC_TANGERINE_WHISTLE), 200);
+        assert_eq!(sload(Revision::EVMC_ISTANBUL), 800);
+        let metrics = metrics_table(Revision::EVMC_BYZANTIUM);
+        assert_eq!(metrics[0x1b], UNDEFINED);
+        assert_eq!(
+            metrics[0x9f],
+            InstructionMetrics {
+                gas_cost: 3,
+                stack_height_required: 17,
+                stack_height_change: 0,
+            }
+        );
+        assert_eq!(metrics[0xa2].gas_cost, 1125);
+        assert_eq!(metrics[0xa2].stack_height_change, -4);
+    }
+
+    #[test]
+    fn push_sizes() {
+        assert_eq!(push_size(OP_PUSH1), 1);
+        assert_eq!(push_size(OP_PUSH32), 32);
+        assert_eq!(push_size(OP_JUMPDEST), 0);
+        assert_eq!(push_size(0x80), 0);
+    }
+}
@@ -0,0 +1,357 @@
+//! A minimal embedded key-value store: an append-only log of the puts and the
+//! deletes, with the offsets of the live values indexed in memory. Each flush
+//! appends a commit record, the records after the last one are discarded on
+//! open. The log is compacted when the obsolete records outweigh the live ones.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use keccak_hash::keccak;
+
+const TAG_PUT: u8 = 1;
+const TAG_DELETE: u8 = 0;
+const TAG_COMMIT: u8 = 2;
+// tag, key length, value length, value digest
+const HEADER_SIZE: u64 = 1 + 4 + 4 + 8;
+const MIN_COMPACT_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    offset: u64,
+    length: u32,
+    // The first 8 bytes of keccak256(value), to skip reading the stored value
+    // of a changed one
+    digest: u64,
+}
+
+pub struct KvStore {
+    path: PathBuf,
+    file: RefCell<File>,
+    index: BTreeMap<Vec<u8>, Entry>,
+    // The size of the log and of the live records in it
+    size: u64,
+    live_size: u64,
+    // The size of the log at the last commit
+    committed: u64,
+}
+
+impl KvStore {
+    /// Open the store, it is created if not exists. The records after the last
+    /// commit (the changes of a crashed process) are discarded. The store is
+    /// locked until it is dropped.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<KvStore, String> {
+        let path = path.as_ref().to_path_buf();
+        let error = |err: std::io::Error| format!("{}: {}", path.display(), err);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(error)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Err(format!("{}: locked by another process", path.display()));
+        }
+        let file_size = file.metadata().map_err(error)?.len();
+        let mut index = BTreeMap::new();
+        let mut size = 0;
+        let mut live_size = 0;
+        let mut committed = 0;
+        // The puts (with their entries) and the deletes since the last commit
+        let mut pending = Vec::new();
+        let mut reader = BufReader::new(&file);
+        while size + HEADER_SIZE <= file_size {
+            let mut header = [0u8; HEADER_SIZE as usize];
+            reader.read_exact(&mut header).map_err(error)?;
+            let (tag, key_length, length, digest) = parse_header(&header);
+            let record_size = HEADER_SIZE + key_length as u64 + length as u64;
+            if tag > TAG_COMMIT || size + record_size > file_size {
+                break;
+            }
+            let mut key = vec![0u8; key_length as usize];
+            reader.read_exact(&mut key).map_err(error)?;
+            reader.seek_relative(length as i64).map_err(error)?;
+            let entry = Entry {
+                offset: size + HEADER_SIZE + key_length as u64,
+                length,
+                digest,
+            };
+            size += record_size;
+            match tag {
+                TAG_PUT => pending.push((key, Some(entry))),
+                TAG_DELETE => pending.push((key, None)),
+                _ => {
+                    for (key, entry) in pending.drain(..) {
+                        if let Some(old) = index.remove(&key) {
+                            live_size -= record_size_of(&key, &old);
+                        }
+                        if let Some(entry) = entry {
+                            live_size += record_size_of(&key, &entry);
+                            index.insert(key, entry);
+                        }
+                    }
+                    committed = size;
+                }
+            }
+        }
+        drop(reader);
+        if committed < file_size {
+            file.set_len(committed).map_err(error)?;
+        }
+        Ok(KvStore {
+            path,
+            file: RefCell::new(file),
+            index,
+            size: committed,
+            live_size,
+            committed,
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        match self.index.get(key) {
+            Some(entry) => self.read_value(entry).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Append the value, nothing is written if it is unchanged
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        let digest = digest(value);
+        if let Some(entry) = self.index.get(key) {
+            if entry.length as usize == value.len()
+                && entry.digest == digest
+                && self.read_value(entry)? == value
+            {
+                return Ok(());
+            }
+        }
+        let offset = self.append(TAG_PUT, key, value, digest)?;
+        let entry = Entry {
+            offset,
+            length: value.len() as u32,
+            digest,
+        };
+        if let Some(old) = self.index.insert(key.to_vec(), entry) {
+            self.live_size -= record_size_of(key, &old);
+        }
+        self.live_size += record_size_of(key, &entry);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), String> {
+        if let Some(old) = self.index.remove(key) {
+            self.live_size -= record_size_of(key, &old);
+            self.append(TAG_DELETE, key, &[], 0)?;
+        }
+        Ok(())
+    }
+
+    pub fn keys_with_prefix(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
+        self.index
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Commit the changes and sync the log to the disk, compact it first if it
+    /// is mostly obsolete
+    pub fn flush(&mut self) -> Result<(), String> {
+        if self.size > MIN_COMPACT_SIZE && self.size > self.live_size * 2 {
+            self.compact()?;
+        } else if self.size > self.committed {
+            self.append(TAG_COMMIT, &[], &[], 0)?;
+            self.committed = self.size;
+        }
+        self.file
+            .borrow_mut()
+            .sync_data()
+            .map_err(|err| format!("{}: {}", self.path.display(), err))
+    }
+
+    // Rewrite the live records to a new log, and replace the old one
+    fn compact(&mut self) -> Result<(), String> {
+        let compact_path = self.path.with_extension("compact");
+        let _ = fs::remove_file(&compact_path);
+        let mut compacted = KvStore::open(&compact_path)?;
+        for (key, entry) in &self.index {
+            let value = self.read_value(entry)?;
+            compacted.put(key, &value)?;
+        }
+        compacted.flush()?;
+        drop(compacted);
+        fs::rename(&compact_path, &self.path)
+            .map_err(|err| format!("{}: {}", self.path.display(), err))?;
+        *self = KvStore::open(&self.path)?;
+        Ok(())
+    }
+
+    fn read_value(&self, entry: &Entry) -> Result<Vec<u8>, String> {
+        let mut file = self.file.borrow_mut();
+        let mut value = vec![0u8; entry.length as usize];
+        file.seek(SeekFrom::Start(entry.offset))
+            .and_then(|_| file.read_exact(&mut value))
+            .map_err(|err| match err.kind() {
+                ErrorKind::UnexpectedEof => format!("{}: truncated value", self.path.display()),
+                _ => format!("{}: {}", self.path.display(), err),
+            })?;
+        Ok(value)
+    }
+
+    // Return the offset of the value
+    fn append(&mut self, tag: u8, key: &[u8], value: &[u8], digest: u64) -> Result<u64, String> {
+        let mut record = Vec::with_capacity(HEADER_SIZE as usize + key.len() + value.len());
+        record.push(tag);
+        record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        record.extend_from_slice(&digest.to_le_bytes());
+        record.extend_from_slice(key);
+        record.extend_from_slice(value);
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(self.size))
+            .and_then(|_| file.write_all(&record))
+            .map_err(|err| format!("{}: {}", self.path.display(), err))?;
+        let offset = self.size + HEADER_SIZE + key.len() as u64;
+        self.size += record.len() as u64;
+        Ok(offset)
+    }
+}
+
+fn parse_header(header: &[u8; HEADER_SIZE as usize]) -> (u8, u32, u32, u64) {
+    let mut key_length = [0u8; 4];
+    let mut length = [0u8; 4];
+    let mut digest = [0u8; 8];
+    key_length.copy_from_slice(&header[1..5]);
+    length.copy_from_slice(&header[5..9]);
+    digest.copy_from_slice(&header[9..17]);
+    (
+        header[0],
+        u32::from_le_bytes(key_length),
+        u32::from_le_bytes(length),
+        u64::from_le_bytes(digest),
+    )
+}
+
+fn record_size_of(key: &[u8], entry: &Entry) -> u64 {
+    HEADER_SIZE + key.len() as u64 + entry.length as u64
+}
+
+fn digest(value: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&keccak(value).0[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("play-evmone-{}-{}.kv", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn reopen_append_log() {
+        let path = temp_path("kv-log");
+        {
+            let mut store = KvStore::open(&path).unwrap();
+            store.put(b"a1", b"one").unwrap();
+            store.put(b"a2", b"two").unwrap();
+            store.put(b"b1", b"three").unwrap();
+            store.put(b"a1", b"uno").unwrap();
+            store.delete(b"a2").unwrap();
+            let size = store.size;
+            // An unchanged value is not appended again
+            store.put(b"b1", b"three").unwrap();
+            assert_eq!(store.size, size);
+            store.flush().unwrap();
+        }
+        let store = KvStore::open(&path).unwrap();
+        assert_eq!(store.get(b"a1").unwrap(), Some(b"uno".to_vec()));
+        assert_eq!(store.get(b"a2").unwrap(), None);
+        assert_eq!(store.get(b"b1").unwrap(), Some(b"three".to_vec()));
+        assert_eq!(store.keys_with_prefix(b"a"), vec![b"a1".to_vec()]);
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn discard_uncommitted_records() {
+        let path = temp_path("kv-crash");
+        let size = {
+            let mut store = KvStore::open(&path).unwrap();
+            store.put(b"a", b"one").unwrap();
+            store.flush().unwrap();
+            let size = store.size;
+            // A crash before the commit, in the middle of the last record
+            store.put(b"b", b"two").unwrap();
+            store.delete(b"a").unwrap();
+            store.put(b"c", b"three").unwrap();
+            size
+        };
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(fs::metadata(&path).unwrap().len() - 1)
+            .unwrap();
+        drop(file);
+        {
+            let mut store = KvStore::open(&path).unwrap();
+            assert_eq!(store.size, size);
+            assert_eq!(fs::metadata(&path).unwrap().len(), size);
+            assert_eq!(store.get(b"a").unwrap(), Some(b"one".to_vec()));
+            assert_eq!(store.get(b"b").unwrap(), None);
+            store.put(b"d", b"four").unwrap();
+            store.flush().unwrap();
+        }
+        let store = KvStore::open(&path).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(store.get(b"d").unwrap(), Some(b"four".to_vec()));
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lock_while_open() {
+        let path = temp_path("kv-lock");
+        let store = KvStore::open(&path).unwrap();
+        assert!(KvStore::open(&path).is_err());
+        drop(store);
+        assert!(KvStore::open(&path).is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact_obsolete_records() {
+        let path = temp_path("kv-compact");
+        let value = |byte: u8| vec![byte; 64 * 1024];
+        {
+            let mut store = KvStore::open(&path).unwrap();
+            store.put(b"kept", b"value").unwrap();
+            for byte in 0..20 {
+                store.put(b"overwritten", &value(byte)).unwrap();
+            }
+            store.put(b"deleted", &value(0)).unwrap();
+            store.delete(b"deleted").unwrap();
+            assert!(store.size > MIN_COMPACT_SIZE);
+            store.flush().unwrap();
+            // The live records and the commit
+            assert_eq!(store.size, store.live_size + HEADER_SIZE);
+            assert_eq!(fs::metadata(&path).unwrap().len(), store.size);
+            assert_eq!(store.get(b"overwritten").unwrap(), Some(value(19)));
+        }
+        let store = KvStore::open(&path).unwrap();
+        assert_eq!(store.get(b"kept").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.get(b"overwritten").unwrap(), Some(value(19)));
+        assert_eq!(store.get(b"deleted").unwrap(), None);
+        assert!(!path.with_extension("compact").exists());
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! Load EVMC VMs from shared libraries, the same way as the EVMC loader
+//! (include/evmc/loader.h) does
+
+use std::ffi::{CStr, CString};
+use std::path::Path;
+
+use evmc_sys as ffi;
+
+use crate::evmc::EvmcVm;
+use crate::evmc_create_evmone;
+
+type CreateFn = unsafe extern "C" fn() -> *mut ffi::evmc_vm;
+
+/// Load the VM by the config (`<path> ("," <option-name> ["=" <option-value>])*`),
+/// the linked evmone is used if no config is given
+pub fn create_vm(config: Option<&str>) -> Result<EvmcVm, String> {
+    match config {
+        Some(config) => load_and_configure(config),
+        None => Ok(EvmcVm::new(unsafe { evmc_create_evmone() })),
+    }
+}
+
+/// Load the VM and set the options in order
+pub fn load_and_configure(config: &str) -> Result<EvmcVm, String> {
+    let (path, options) = split_config(config);
+    let vm = load_and_create(path)?;
+    for (name, value) in options {
+        if let Err(err) = vm.set_option(name, value) {
+            destroy(&vm);
+            return Err(format!("{}: {}", path, err));
+        }
+    }
+    Ok(vm)
+}
+
+// "lib.so,a=1,b" => ("lib.so", [("a", "1"), ("b", "")])
+fn split_config(config: &str) -> (&str, Vec<(&str, &str)>) {
+    let mut parts = config.split(',');
+    let path = parts.next().unwrap_or("");
+    let options = parts
+        .map(|option| match option.find('=') {
+            Some(pos) => (&option[..pos], &option[pos + 1..]),
+            None => (option, ""),
+        })
+        .collect();
+    (path, options)
+}
+
+/// Load the library and create the VM, the ABI version must be the one of evmc-sys
+pub fn load_and_create(path: &str) -> Result<EvmcVm, String> {
+    let create_fn = load(path)?;
+    let instance = unsafe { create_fn() };
+    if instance.is_null() {
+        return Err(format!("{}: the VM creation failed", path));
+    }
+    let vm = EvmcVm::new(instance);
+    let abi_version = unsafe { (*instance).abi_version };
+    if abi_version != ffi::EVMC_ABI_VERSION as i32 {
+        destroy(&vm);
+        return Err(format!(
+            "{}: EVMC ABI version {} of the VM is incompatible with {}",
+            path,
+            abi_version,
+            ffi::EVMC_ABI_VERSION
+        ));
+    }
+    Ok(vm)
+}
+
+/// Open the library and find `evmc_create_<base name>` or `evmc_create`
+pub fn load(path: &str) -> Result<CreateFn, String> {
+    if path.is_empty() {
+        return Err("The VM path is empty".to_string());
+    }
+    let c_path = CString::new(path).map_err(|err| err.to_string())?;
+    let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_LAZY) };
+    if handle.is_null() {
+        return Err(format!("Can not open {}: {}", path, dl_error()));
+    }
+    for name in &[
+        format!("evmc_create_{}", base_name(path)),
+        "evmc_create".to_string(),
+    ] {
+        let c_name = CString::new(name.as_str()).map_err(|err| err.to_string())?;
+        let symbol = unsafe { libc::dlsym(handle, c_name.as_ptr()) };
+        if !symbol.is_null() {
+            return Ok(unsafe { std::mem::transmute::<*mut libc::c_void, CreateFn>(symbol) });
+        }
+    }
+    Err(format!(
+        "EVMC create function not found in {}: {}",
+        path,
+        dl_error()
+    ))
+}
+
+// "/ethereum/libexample-interpreter.so.1.0" => "example_interpreter"
+fn base_name(path: &str) -> String {
+    let file_name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path);
+    let name = file_name.strip_prefix("lib").unwrap_or(file_name);
+    let name = name.split('.').next().unwrap_or(name);
+    name.replace('-', "_")
+}
+
+fn dl_error() -> String {
+    let message = unsafe { libc::dlerror() };
+    if message.is_null() {
+        "unknown error".to_string()
+    } else {
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+fn destroy(vm: &EvmcVm) {
+    unsafe {
+        if let Some(destroy_fn) = (*vm.instance).destroy {
+            destroy_fn(vm.instance);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_names() {
+        assert_eq!(
+            base_name("/ethereum/libexample-interpreter.so.1.0"),
+            "example_interpreter"
+        );
+        assert_eq!(base_name("libevmone.so"), "evmone");
+        assert_eq!(base_name("./evmone.so"), "evmone");
+        assert_eq!(base_name("vm"), "vm");
+    }
+
+    #[test]
+    fn split_options() {
+        assert_eq!(split_config("lib.so"), ("lib.so", vec![]));
+        assert_eq!(
+            split_config("lib.so,O=0,trace,a=b=c"),
+            ("lib.so", vec![("O", "0"), ("trace", ""), ("a", "b=c")])
+        );
+        assert_eq!(split_config(",O=0"), ("", vec![("O", "0")]));
+    }
+
+    #[test]
+    fn load_errors() {
+        assert_eq!(load("").err().unwrap(), "The VM path is empty");
+        let err = load("/nonexistent/libvm.so").err().unwrap();
+        assert!(
+            err.starts_with("Can not open /nonexistent/libvm.so: "),
+            "{}",
+            err
+        );
+        let err = load("libc.so.6").err().unwrap();
+        assert!(
+            err.starts_with("EVMC create function not found in libc.so.6: "),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn load_evmc_create_of_base_name() {
+        // The path of the linked evmone library
+        let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+        let address = evmc_create_evmone as CreateFn as *const libc::c_void;
+        let found = unsafe { libc::dladdr(address, &mut info) };
+        assert_ne!(found, 0);
+        let path = unsafe { CStr::from_ptr(info.dli_fname) }
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let create_fn = load(&path).unwrap();
+        assert_eq!(create_fn as usize, evmc_create_evmone as CreateFn as usize);
+        assert!(load_and_create(&path).is_ok());
+        assert_eq!(
+            load_and_configure(&format!("{},no-such-option=1", path))
+                .err()
+                .unwrap(),
+            format!("{}: VM option not supported: no-such-option", path)
+        );
+    }
+}
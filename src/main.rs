@@ -1,10 +1,42 @@
 mod abi;
 mod abi_cmd;
+mod apply_block;
+mod apply_tx;
+mod asm;
+mod block;
+mod blocktest;
+mod debugger;
+mod dev_rpc;
+mod diff;
+mod disasm;
 mod evmc;
+mod host_trace;
+mod http;
+mod instructions;
+mod kv_store;
+mod loader;
+mod mempool;
+mod overlay;
+mod proof;
+mod pubsub;
+mod receipt;
+mod replay;
+mod report;
+mod revert;
+mod rpc;
+mod scenario;
+mod state_db;
+mod statetest;
+mod trace;
+mod transaction;
+mod trie;
+mod tx_cmd;
+mod ws;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::fs;
+use std::rc::Rc;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 use evmc::{
@@ -13,7 +45,14 @@ use evmc::{
     StatusCode, StorageStatus, TxContext, Uint256,
 };
 use evmc_sys as ffi;
+use host_trace::{HostEvent, HostTracer, TraceSink};
+use keccak_hash::keccak;
+use overlay::Overlay;
+use receipt::TransactionReceipt;
+use report::ExecutionReport;
+use revert::CustomError;
 use serde::{Deserialize, Serialize};
+use state_db::Backend;
 
 #[link(name = "evmone")]
 extern "C" {
@@ -41,18 +80,60 @@ fn main() -> Result<(), String> {
         .long("input-storage")
         .short("s")
         .takes_value(true)
-        .help("The storage to run the contract");
+        .help("The storage to run the contract (a .kv path is a key-value store)");
     let arg_output_storage = Arg::with_name("output-storage")
         .long("output-storage")
         .short("o")
         .takes_value(true)
-        .help("The storage after run the contract");
+        .help("The storage after run the contract (a .kv path is a key-value store)");
     let arg_address = Arg::with_name("address")
         .long("address")
         .takes_value(true)
         .required(true)
         .help("The account address");
+    let arg_abi = Arg::with_name("abi")
+        .long("abi")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("The ABI json file of the contract");
+    let arg_host_trace = Arg::with_name("host-trace")
+        .long("host-trace")
+        .takes_value(true)
+        .possible_values(&["quiet", "human", "json"])
+        .default_value("human")
+        .help("How to print the host interactions (to stderr)");
+    let arg_json = Arg::with_name("json")
+        .long("json")
+        .help("Print the execution result as a JSON document");
+    let arg_trace = Arg::with_name("trace")
+        .long("trace")
+        .help("Print the EIP-3155 instruction trace (to stderr), the VM must support tracing");
+    let arg_trace_storage = Arg::with_name("trace-storage")
+        .long("trace-storage")
+        .requires("trace")
+        .help("Include the storage of the executing contract in the trace");
+    let arg_trace_memory = Arg::with_name("trace-memory")
+        .long("trace-memory")
+        .requires("trace")
+        .help("Include the memory in the trace");
+    let arg_static = Arg::with_name("static")
+        .long("static")
+        .help("Call with static mode");
+    let arg_revision = Arg::with_name("revision")
+        .long("revision")
+        .short("r")
+        .takes_value(true)
+        .default_value("berlin")
+        .help("The EVM revision of the instruction set");
     let global_matches = App::new("Play evmone")
+        .arg(
+            Arg::with_name("vm")
+                .long("vm")
+                .takes_value(true)
+                .global(true)
+                .help("Load the EVMC VM from the library: path/to/lib.so[,name=value,...] (the linked evmone by default)"),
+        )
         .subcommand(
             SubCommand::with_name("list")
                 .about("List all accounts")
@@ -70,6 +151,46 @@ fn main() -> Result<(), String> {
                 .arg(arg_input_storage.clone())
                 .arg(arg_address.clone()),
         )
+        .subcommand(
+            SubCommand::with_name("state-root")
+                .about("Compute the state root (Merkle Patricia Trie) of the accounts")
+                .arg(arg_input_storage.clone())
+                .arg(
+                    Arg::with_name("accounts")
+                        .long("accounts")
+                        .help("Also print the storage root and code hash of every account"),
+                )
+                .arg(arg_json.clone().help("Print the roots as a JSON document")),
+        )
+        .subcommand(
+            SubCommand::with_name("receipt")
+                .about("Show a transaction receipt (the last one by default)")
+                .arg(arg_input_storage.clone())
+                .arg(
+                    Arg::with_name("hash")
+                        .long("hash")
+                        .takes_value(true)
+                        .help("The transaction hash"),
+                )
+                .arg(
+                    Arg::with_name("index")
+                        .long("index")
+                        .takes_value(true)
+                        .conflicts_with("hash")
+                        .help("The position of the receipt in the state file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("logs")
+                .about("Decode the logs of an account (or all accounts) by event ABIs")
+                .arg(arg_input_storage.clone())
+                .arg(arg_address.clone().required(false))
+                .arg(
+                    arg_abi
+                        .clone()
+                        .help("The ABI json file contains the events"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("create")
                 .about("Create contract by code and input")
@@ -88,7 +209,17 @@ fn main() -> Result<(), String> {
                         .help("The input data file for the contract"),
                 )
                 .arg(arg_input_storage.clone())
-                .arg(arg_output_storage.clone()),
+                .arg(arg_output_storage.clone())
+                .arg(
+                    arg_abi
+                        .clone()
+                        .help("The ABI json file contains the custom errors"),
+                )
+                .arg(arg_host_trace.clone())
+                .arg(arg_json.clone())
+                .arg(arg_trace.clone())
+                .arg(arg_trace_storage.clone())
+                .arg(arg_trace_memory.clone()),
         )
         .subcommand(
             SubCommand::with_name("call")
@@ -96,39 +227,155 @@ fn main() -> Result<(), String> {
                 .arg(arg_address.clone().required(true))
                 .arg(arg_input_data.clone())
                 .arg(arg_input_storage.clone().required(true))
+                .arg(arg_output_storage.clone())
+                .arg(
+                    arg_abi
+                        .clone()
+                        .help("The ABI json file contains the custom errors"),
+                )
+                .arg(arg_host_trace)
+                .arg(arg_json)
+                .arg(arg_trace)
+                .arg(arg_trace_storage)
+                .arg(arg_trace_memory)
+                .arg(arg_static.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Call a contract and step through the execution (the VM must support tracing)")
+                .arg(arg_address.clone().required(true))
+                .arg(arg_input_data.clone())
+                .arg(arg_input_storage.clone().required(true))
                 .arg(arg_output_storage)
+                .arg(arg_abi.help("The ABI json file contains the custom errors"))
+                .arg(arg_static),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Disassemble the binary code")
+                .arg(
+                    Arg::with_name("code")
+                        .long("code")
+                        .short("c")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The binary code path"),
+                )
+                .arg(arg_revision.clone())
+                .arg(
+                    Arg::with_name("metrics")
+                        .long("metrics")
+                        .help("Show the gas cost and stack requirement of the instructions"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("asm")
+                .about("Assemble the mnemonic source into binary code")
+                .arg(
+                    Arg::with_name("source")
+                        .long("source")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The assembly source path"),
+                )
+                .arg(arg_revision)
                 .arg(
-                    Arg::with_name("static")
-                        .long("static")
-                        .help("Call with static mode"),
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("The binary code path (print to stdout by default)"),
                 ),
         )
+        .subcommand(scenario::sub_command("run-scenario"))
+        .subcommand(statetest::sub_command("statetest"))
+        .subcommand(blocktest::sub_command("blocktest"))
+        .subcommand(diff::sub_command("diff"))
+        .subcommand(proof::sub_command("proof"))
+        .subcommand(apply_tx::sub_command("apply-tx"))
+        .subcommand(apply_block::sub_command("apply-block"))
+        .subcommand(tx_cmd::sub_command("tx"))
+        .subcommand(rpc::sub_command("serve"))
         .subcommand(abi_cmd::sub_command("ethabi"))
         .get_matches();
 
     if let Some(sub_matches) = global_matches.subcommand_matches("ethabi") {
         return abi_cmd::process(sub_matches);
     }
+    if let Some(sub_matches) = global_matches.subcommand_matches("run-scenario") {
+        return scenario::process(sub_matches);
+    }
+    if let Some(sub_matches) = global_matches.subcommand_matches("statetest") {
+        return statetest::process(sub_matches);
+    }
+    if let Some(sub_matches) = global_matches.subcommand_matches("blocktest") {
+        return blocktest::process(sub_matches);
+    }
+    if let Some(sub_matches) = global_matches.subcommand_matches("diff") {
+        return diff::process(sub_matches);
+    }
+    if let Some(sub_matches) = global_matches.subcommand_matches("proof") {
+        return proof::process(sub_matches);
+    }
+    if let Some(sub_matches) = global_matches.subcommand_matches("apply-tx") {
+        return apply_tx::process(sub_matches);
+    }
+    if let Some(sub_matches) = global_matches.subcommand_matches("apply-block") {
+        return apply_block::process(sub_matches);
+    }
+    if let Some(sub_matches) = global_matches.subcommand_matches("tx") {
+        return tx_cmd::process(sub_matches);
+    }
+    if let Some(sub_matches) = global_matches.subcommand_matches("serve") {
+        return rpc::process(sub_matches);
+    }
+    if let Some(sub_matches) = global_matches.subcommand_matches("disasm") {
+        let code = sub_matches.value_of("code").map(load_binary).unwrap();
+        let revision = evmc::parse_revision(sub_matches.value_of("revision").unwrap())?;
+        print!(
+            "{}",
+            disasm::format_code(&code, revision, sub_matches.is_present("metrics"))
+        );
+        return Ok(());
+    }
+    if let Some(sub_matches) = global_matches.subcommand_matches("asm") {
+        let source = fs::read_to_string(sub_matches.value_of("source").unwrap())
+            .map_err(|err| err.to_string())?;
+        let revision = evmc::parse_revision(sub_matches.value_of("revision").unwrap())?;
+        let code = asm::assemble(&source, revision)?;
+        match sub_matches.value_of("output") {
+            Some(path) => fs::write(path, hex::encode(&code)).map_err(|err| err.to_string())?,
+            None => println!("{}", hex::encode(&code)),
+        }
+        return Ok(());
+    }
 
     let get_context = |matches: &ArgMatches, destination, required| -> Result<_, String> {
         if required && matches.value_of("input-storage").is_none() {
             return Err("<input-storage> is required!".to_string());
         }
-        let host_context: TestHostContext = matches
-            .value_of("input-storage")
-            .map(|path| {
-                println!("Load context from: {}", path);
-                let json_string = String::from_utf8(fs::read(path).unwrap()).unwrap();
-                serde_json::from_slice(json_string.trim().as_bytes()).unwrap()
-            })
-            .unwrap_or_else(|| {
-                println!("New context for: {:?}", destination);
+        let verbose = !matches.is_present("json");
+        let mut host_context = match matches.value_of("input-storage") {
+            Some(path) => {
+                if verbose {
+                    println!("Load context from: {}", path);
+                }
+                state_db::load_state(path)?
+            }
+            None => {
+                if verbose {
+                    println!("New context for: {:?}", destination);
+                }
                 TestHostContext::new(0, destination)
-            });
+            }
+        };
+        if let Some(sink) = matches.value_of("host-trace") {
+            host_context.tracer = Rc::new(HostTracer::recording(sink.parse::<TraceSink>()?));
+        }
         Ok(host_context)
     };
 
-    let vm = EvmcVm::new(unsafe { evmc_create_evmone() });
+    let vm = Rc::new(loader::create_vm(global_matches.value_of("vm"))?);
     match global_matches.subcommand() {
         ("create", Some(sub_matches)) => {
             let value = Uint256([3u8; 32]);
@@ -136,10 +383,21 @@ fn main() -> Result<(), String> {
                 .value_of("address")
                 .map(|s| serde_json::from_str(format!("\"{}\"", s).as_str()).unwrap())
                 .unwrap();
-            let host_context = get_context(sub_matches, destination.clone(), false)?;
+            let mut host_context = get_context(sub_matches, destination.clone(), false)?;
             if host_context.contract_exists(&destination) {
                 return Err(format!("Contract already exists: {:?}", destination));
             }
+            host_context.custom_errors = Rc::new(load_custom_errors(sub_matches)?);
+            // The nested frames are traced too
+            let vm = if sub_matches.is_present("trace") {
+                trace::tracing_vm(global_matches.value_of("vm"))?
+            } else {
+                vm
+            };
+            host_context.vm = Some(vm.clone());
+            let tracer = host_context.tracer.clone();
+            let before = host_context.clone();
+            let json = sub_matches.is_present("json");
             let host_context_ptr = HostContextPtr::from(Box::new(host_context));
             let mut context =
                 ExecutionContext::new(TestHostContext::interface(), host_context_ptr.ptr);
@@ -149,9 +407,11 @@ fn main() -> Result<(), String> {
                 .map(|s| hex::decode(s).unwrap())
                 .unwrap_or_default();
 
-            println!("address: {:?}", destination);
-            println!("code: {}", hex::encode(&code));
-            println!("input-data: {}", hex::encode(&input_data));
+            if !json {
+                println!("address: {:?}", destination);
+                println!("code: {}", hex::encode(&code));
+                println!("input-data: {}", hex::encode(&input_data));
+            }
             code.extend(input_data);
 
             let raw_message = ffi::evmc_message {
@@ -168,35 +428,104 @@ fn main() -> Result<(), String> {
             };
             let message = ExecutionMessage::from(&raw_message);
 
-            let result = vm.execute(Revision::EVMC_MAX_REVISION, &code, &message, &mut context);
-            println!("Execution result: {:#?}\n", result);
+            tracer.record(HostEvent::CallEnter {
+                depth: 0,
+                kind: format!("{:?}", message.kind),
+                sender: Address::from(message.sender),
+                destination: destination.clone(),
+                gas: message.gas,
+                value: Uint256::from(message.value),
+                input: JsonBytes(code.clone()),
+            });
+            let result = execute_traced(
+                sub_matches,
+                &vm,
+                Revision::EVMC_MAX_REVISION,
+                &code,
+                &message,
+                &mut context,
+            )?;
+            if !json {
+                println!("Execution result: {:#?}\n", result);
+            }
 
             assert_eq!(result.create_address, Address::default());
             let mut wrapper = HostContextWrapper::from(context.context);
             let context: &mut TestHostContext = &mut wrapper;
-            if result.status_code == StatusCode::EVMC_SUCCESS && message.is_create() {
-                context.update_code(destination, result.output_data);
+            let revert_reason = revert::revert_reason(&result, &context.custom_errors);
+            if !json {
+                if let Some(reason) = revert_reason.as_ref() {
+                    println!("Revert reason (depth: 0): {}", reason);
+                }
+            }
+            tracer.record(HostEvent::CallExit {
+                depth: 0,
+                status: format!("{:?}", result.status_code),
+                gas_left: result.gas_left,
+                gas_used: message.gas - result.gas_left,
+                output: JsonBytes(result.output_data.clone()),
+                revert_reason: revert_reason.clone(),
+            });
+            let success = result.status_code == StatusCode::EVMC_SUCCESS;
+            if success && message.is_create() {
+                tracer.record(HostEvent::Create {
+                    depth: 0,
+                    address: destination.clone(),
+                    code_size: result.output_data.len(),
+                });
+                context.update_code(destination.clone(), result.output_data.clone());
+            }
+            record_receipt(context, &tracer, &message, &result, &code, json);
+            if !json {
+                println!("State root: 0x{}", hex::encode(trie::state_root(context)?));
+            }
+            if json {
+                let created_address = if success { Some(destination) } else { None };
+                let report = ExecutionReport::new(
+                    &result,
+                    message.gas,
+                    created_address,
+                    revert_reason,
+                    &tracer,
+                    &before,
+                    context,
+                )?;
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
             }
 
             if let Some(output_storage_path) = sub_matches.value_of("output-storage") {
-                let data = serde_json::to_string_pretty(context).unwrap();
-                fs::write(output_storage_path, data.as_bytes()).unwrap();
+                state_db::save_state(output_storage_path, context)?;
+            }
+            if !success {
+                return Err(format!("Execution failed: {:?}", result.status_code));
             }
         }
-        ("call", Some(sub_matches)) => {
+        ("call", Some(sub_matches)) | ("debug", Some(sub_matches)) => {
+            let debug = global_matches.subcommand_name() == Some("debug");
             let value = Uint256([0u8; 32]);
             let destination: Address = sub_matches
                 .value_of("address")
                 .map(|s| serde_json::from_str(format!("\"{}\"", s).as_str()).unwrap())
                 .unwrap();
-            let host_context = get_context(sub_matches, destination.clone(), true)?;
-            let code = host_context
-                .accounts
-                .get(&destination)
-                .unwrap()
-                .code
-                .clone()
-                .unwrap();
+            let mut host_context = get_context(sub_matches, destination.clone(), true)?;
+            host_context.custom_errors = Rc::new(load_custom_errors(sub_matches)?);
+            // The nested frames are traced too
+            let vm = if debug || sub_matches.is_present("trace") {
+                trace::tracing_vm(global_matches.value_of("vm"))?
+            } else {
+                vm
+            };
+            host_context.vm = Some(vm.clone());
+            // The debugger shows the whole storage of the contracts, and
+            // follows the nested calls with the host events
+            if debug {
+                host_context.load_all()?;
+                host_context.tracer = Rc::new(HostTracer::recording(TraceSink::Quiet));
+            }
+            let tracer = host_context.tracer.clone();
+            let before = host_context.clone();
+            let json = sub_matches.is_present("json");
+            let code = host_context.code_of(&destination).unwrap();
             let host_context_ptr = HostContextPtr::from(Box::new(host_context));
             let mut context =
                 ExecutionContext::new(TestHostContext::interface(), host_context_ptr.ptr);
@@ -206,9 +535,11 @@ fn main() -> Result<(), String> {
                 .map(|s| hex::decode(s).unwrap())
                 .unwrap_or_default();
 
-            println!("address: {:?}", destination);
-            println!("code: {}", hex::encode(&code.0));
-            println!("input-data: {}", hex::encode(&input_data));
+            if !json {
+                println!("address: {:?}", destination);
+                println!("code: {}", hex::encode(&code.0));
+                println!("input-data: {}", hex::encode(&input_data));
+            }
             let is_static = sub_matches.is_present("static");
             let mut flags: u32 = 0;
             unsafe {
@@ -231,25 +562,107 @@ fn main() -> Result<(), String> {
             };
             let message = ExecutionMessage::from(&raw_message);
 
-            let result = vm.execute(Revision::EVMC_MAX_REVISION, &code.0, &message, &mut context);
-            println!("Execution result: {:#?}\n", result);
+            tracer.record(HostEvent::CallEnter {
+                depth: 0,
+                kind: format!("{:?}", message.kind),
+                sender: Address::from(message.sender),
+                destination: destination.clone(),
+                gas: message.gas,
+                value: Uint256::from(message.value),
+                input: JsonBytes(input_data.clone()),
+            });
+            let (result, trace_lines) = if debug {
+                HostContextWrapper::<TestHostContext>::from(context.context).push_frame();
+                let (result, lines) = trace::execute_captured(
+                    &vm,
+                    Revision::EVMC_MAX_REVISION,
+                    &code.0,
+                    &message,
+                    &mut context,
+                )?;
+                close_top_frame(&context, &result)?;
+                (result, lines)
+            } else {
+                let result = execute_traced(
+                    sub_matches,
+                    &vm,
+                    Revision::EVMC_MAX_REVISION,
+                    &code.0,
+                    &message,
+                    &mut context,
+                )?;
+                (result, Vec::new())
+            };
+            if !json {
+                println!("Execution result: {:#?}\n", result);
+            }
 
             assert_eq!(result.create_address, Address::default());
             let mut wrapper = HostContextWrapper::from(context.context);
             let context: &mut TestHostContext = &mut wrapper;
-            if result.status_code == StatusCode::EVMC_SUCCESS
+            let revert_reason = revert::revert_reason(&result, &context.custom_errors);
+            if !json {
+                if let Some(reason) = revert_reason.as_ref() {
+                    println!("Revert reason (depth: 0): {}", reason);
+                }
+            }
+            tracer.record(HostEvent::CallExit {
+                depth: 0,
+                status: format!("{:?}", result.status_code),
+                gas_left: result.gas_left,
+                gas_used: message.gas - result.gas_left,
+                output: JsonBytes(result.output_data.clone()),
+                revert_reason: revert_reason.clone(),
+            });
+            if debug {
+                let mut summary = format!("{:?}", result.status_code);
+                if let Some(reason) = revert_reason.as_ref() {
+                    summary = format!("{} ({})", summary, reason);
+                }
+                debugger::Debugger::new(
+                    trace_lines,
+                    &tracer.events.borrow(),
+                    &before.accounts,
+                    destination.clone(),
+                    code.0.clone(),
+                    input_data.clone(),
+                    summary,
+                )?
+                .run()?;
+            }
+            let success = result.status_code == StatusCode::EVMC_SUCCESS;
+            if success
                 && (message.kind == CallKind::EVMC_CREATE || message.kind == CallKind::EVMC_CREATE2)
             {
-                context.update_code(destination, result.output_data);
+                context.update_code(destination.clone(), result.output_data.clone());
+            }
+            record_receipt(context, &tracer, &message, &result, &input_data, json);
+            if !json {
+                println!("State root: 0x{}", hex::encode(trie::state_root(context)?));
+            }
+            if json {
+                let report = ExecutionReport::new(
+                    &result,
+                    message.gas,
+                    None,
+                    revert_reason,
+                    &tracer,
+                    &before,
+                    context,
+                )?;
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
             }
 
             if let Some(output_storage_path) = sub_matches.value_of("output-storage") {
-                let data = serde_json::to_string_pretty(context).unwrap();
-                fs::write(output_storage_path, data.as_bytes()).unwrap();
+                state_db::save_state(output_storage_path, context)?;
+            }
+            if !success {
+                return Err(format!("Execution failed: {:?}", result.status_code));
             }
         }
         ("list", Some(sub_matches)) => {
-            let host_context = get_context(sub_matches, Default::default(), true)?;
+            let mut host_context = get_context(sub_matches, Default::default(), true)?;
+            host_context.load_all()?;
             for (address, account) in host_context.accounts {
                 println!(
                     "Account(address: {:?}, code: {:?}, nonce: {})",
@@ -260,27 +673,150 @@ fn main() -> Result<(), String> {
             }
         }
         ("show", Some(sub_matches)) => {
-            let host_context = get_context(sub_matches, Default::default(), true)?;
+            let mut host_context = get_context(sub_matches, Default::default(), true)?;
             let destination: Address = sub_matches
                 .value_of("address")
                 .map(|s| serde_json::from_str(format!("\"{}\"", s).as_str()).unwrap())
                 .unwrap();
+            host_context.load_storage(&destination)?;
             if let Some(account) = host_context.accounts.get(&destination) {
                 println!("{}", serde_json::to_string_pretty(account).unwrap());
             } else {
                 return Err(format!("Account not exists: {:?}", destination));
             }
         }
+        ("state-root", Some(sub_matches)) => {
+            let mut host_context = get_context(sub_matches, Default::default(), true)?;
+            host_context.load_all()?;
+            let mut addresses: Vec<&Address> = host_context.accounts.keys().collect();
+            addresses.sort_by_key(|address| address.0);
+            let accounts: Vec<serde_json::Value> = addresses
+                .into_iter()
+                .map(|address| {
+                    let account = &host_context.accounts[address];
+                    serde_json::json!({
+                        "address": address,
+                        "storage_root": Bytes32(trie::storage_root(account)),
+                        "code_hash": Bytes32(trie::code_hash(account)),
+                    })
+                })
+                .collect();
+            let state_root = Bytes32(trie::state_root(&host_context)?);
+            if sub_matches.is_present("json") {
+                let mut output = serde_json::json!({ "state_root": state_root });
+                if sub_matches.is_present("accounts") {
+                    output["accounts"] = serde_json::Value::Array(accounts);
+                }
+                println!("{}", serde_json::to_string_pretty(&output).unwrap());
+            } else {
+                if sub_matches.is_present("accounts") {
+                    for account in accounts {
+                        println!(
+                            "Account(address: {}, storage_root: {}, code_hash: {})",
+                            account["address"].as_str().unwrap(),
+                            account["storage_root"].as_str().unwrap(),
+                            account["code_hash"].as_str().unwrap(),
+                        );
+                    }
+                }
+                println!("State root: 0x{}", hex::encode(state_root.0));
+            }
+        }
+        ("receipt", Some(sub_matches)) => {
+            let host_context = get_context(sub_matches, Default::default(), true)?;
+            let receipts = &host_context.receipts;
+            let receipt = if let Some(hash) = sub_matches.value_of("hash") {
+                let hash: Bytes32 = serde_json::from_str(format!("\"{}\"", hash).as_str())
+                    .map_err(|err| format!("Invalid hash: {}", err))?;
+                receipts
+                    .iter()
+                    .find(|receipt| receipt.transaction_hash == hash)
+                    .ok_or_else(|| format!("Receipt not exists: {:?}", hash))?
+            } else if let Some(index) = sub_matches.value_of("index") {
+                let index = index.parse::<usize>().map_err(|err| err.to_string())?;
+                receipts
+                    .get(index)
+                    .ok_or_else(|| format!("Receipt not exists: {}", index))?
+            } else {
+                receipts
+                    .last()
+                    .ok_or_else(|| "No receipts in the state file".to_string())?
+            };
+            println!("{}", serde_json::to_string_pretty(receipt).unwrap());
+        }
+        ("logs", Some(sub_matches)) => {
+            let mut host_context = get_context(sub_matches, Default::default(), true)?;
+            // Parse each abi file once, not for each log
+            let contracts = match sub_matches.values_of("abi") {
+                None => Vec::new(),
+                Some(paths) => paths
+                    .map(|path| {
+                        fs::read(path)
+                            .map_err(|err| err.to_string())
+                            .and_then(|abi| abi::load_contract(&abi))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            };
+            let accounts: Vec<&AccountData> = match sub_matches.value_of("address") {
+                Some(s) => {
+                    let destination: Address =
+                        serde_json::from_str(format!("\"{}\"", s).as_str()).unwrap();
+                    host_context.load_account(&destination);
+                    host_context.check_reads()?;
+                    let account = host_context
+                        .accounts
+                        .get(&destination)
+                        .ok_or_else(|| format!("Account not exists: {:?}", destination))?;
+                    vec![account]
+                }
+                None => {
+                    host_context.load_all()?;
+                    host_context.accounts.values().collect()
+                }
+            };
+            for account in accounts {
+                for (index, log) in account.logs.iter().enumerate() {
+                    let topics: Vec<String> = log
+                        .topics
+                        .iter()
+                        .map(|topic| format!("{:?}", topic))
+                        .collect();
+                    let data = hex::encode(&log.data.0);
+                    match abi::decode_any_log(&contracts, &topics, &data)? {
+                        Some((event, params)) => {
+                            let kind = if event.anonymous { " (anonymous)" } else { "" };
+                            println!(
+                                "Log(address: {:?}, index: {}, event: {}{})\n{}",
+                                account.address,
+                                index,
+                                event.name,
+                                kind,
+                                serde_json::to_string_pretty(&serde_json::Value::Array(params))
+                                    .map_err(|err| err.to_string())?,
+                            );
+                        }
+                        None => {
+                            println!(
+                                "Log(address: {:?}, index: {}, event: <unknown>)\n{}",
+                                account.address,
+                                index,
+                                serde_json::to_string_pretty(log).unwrap(),
+                            );
+                        }
+                    }
+                }
+            }
+        }
         ("remove", Some(sub_matches)) => {
             let mut host_context = get_context(sub_matches, Default::default(), true)?;
             let destination: Address = sub_matches
                 .value_of("address")
                 .map(|s| serde_json::from_str(format!("\"{}\"", s).as_str()).unwrap())
                 .unwrap();
-            if let Some(account) = host_context.accounts.remove(&destination) {
+            host_context.load_storage(&destination)?;
+            if let Some(account) = host_context.remove_account(&destination) {
                 let path = sub_matches.value_of("input-storage").unwrap();
-                let data = serde_json::to_string_pretty(&host_context).unwrap();
-                fs::write(path, data.as_bytes()).unwrap();
+                state_db::save_state(path, &mut host_context)?;
                 println!(
                     "[Account removed]: {:?}\n{}",
                     destination,
@@ -298,6 +834,97 @@ fn main() -> Result<(), String> {
     Ok(())
 }
 
+/// Execute the code, print the instruction trace when `--trace` is given (the
+/// VM must be a tracing one then)
+fn execute_traced(
+    matches: &ArgMatches,
+    vm: &EvmcVm,
+    revision: Revision,
+    code: &[u8],
+    message: &ExecutionMessage,
+    context: &mut ExecutionContext,
+) -> Result<ExecutionResult, String> {
+    HostContextWrapper::<TestHostContext>::from(context.context).push_frame();
+    if !matches.is_present("trace") {
+        let result = vm.execute(revision, code, message, context);
+        close_top_frame(context, &result)?;
+        return Ok(result);
+    }
+    let (result, mut lines) = trace::execute_captured(vm, revision, code, message, context)?;
+    close_top_frame(context, &result)?;
+    if matches.is_present("trace-storage") {
+        trace::fill_storage(&mut lines);
+    }
+    if matches.is_present("trace-memory") {
+        let wrapper = HostContextWrapper::<TestHostContext>::from(context.context);
+        let events = wrapper.tracer.events.borrow();
+        trace::fill_memory(&mut lines, &events, |address| {
+            wrapper
+                .code_of(address)
+                .map(|code| code.0)
+                .unwrap_or_default()
+        });
+    }
+    for line in &lines {
+        eprintln!("{}", line.to_line());
+    }
+    Ok(result)
+}
+
+// Keep the changes of the top level frame if the execution succeeded
+fn close_top_frame(context: &ExecutionContext, result: &ExecutionResult) -> Result<(), String> {
+    let mut wrapper = HostContextWrapper::<TestHostContext>::from(context.context);
+    let success = result.status_code == StatusCode::EVMC_SUCCESS && !wrapper.has_read_error();
+    wrapper.pop_frame(success);
+    wrapper.check_reads()
+}
+
+// The nonce as the create address expects it
+fn nonce_u256(nonce: u64) -> Uint256 {
+    let mut data = [0u8; 32];
+    data[0..8].copy_from_slice(&nonce.to_le_bytes());
+    Uint256(data)
+}
+
+// A read of the backend failed during the execution, the state it saw is
+// wrong so the frame fails without gas left
+fn fail_on_read_error(result: &mut ExecutionResult) {
+    result.status_code = StatusCode::EVMC_INTERNAL_ERROR;
+    result.gas_left = 0;
+}
+
+// Store the receipt of the top level create/call
+fn record_receipt(
+    context: &mut TestHostContext,
+    tracer: &HostTracer,
+    message: &ExecutionMessage,
+    result: &ExecutionResult,
+    input: &[u8],
+    json: bool,
+) {
+    let receipt = receipt::record(context, tracer, message, result, input);
+    if !json {
+        println!(
+            "Receipt: 0x{} (block: {}, index: {}, logs: {})",
+            hex::encode(receipt.transaction_hash.0),
+            receipt.block_number,
+            receipt.transaction_index,
+            receipt.logs.len()
+        );
+    }
+}
+
+fn load_custom_errors(matches: &ArgMatches) -> Result<Vec<CustomError>, String> {
+    let mut errors = Vec::new();
+    if let Some(paths) = matches.values_of("abi") {
+        for path in paths {
+            let abi = fs::read(path).map_err(|err| err.to_string())?;
+            errors.extend(revert::load_errors(&abi)?);
+        }
+    }
+    Ok(errors)
+}
+
 fn load_binary(path: &str) -> Vec<u8> {
     hex::decode(
         String::from_utf8(fs::read(path).unwrap())
@@ -367,24 +994,69 @@ pub struct AccountData {
     code: Option<JsonBytes>,
     storage: HashMap<Bytes32, Value>,
     logs: Vec<LogEntry>,
+    #[serde(default)]
+    balance: Uint256,
 }
 
 impl AccountData {
     pub fn new(address: Address) -> AccountData {
-        println!("AccountData::new({:?})", address);
         AccountData {
             nonce: 0,
             address,
             code: None,
             storage: HashMap::default(),
             logs: Vec::new(),
+            balance: Uint256::default(),
+        }
+    }
+}
+
+/// The block the transactions executed in
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BlockContext {
+    pub number: u64,
+    pub timestamp: u64,
+    pub coinbase: Address,
+    pub gas_limit: u64,
+    pub difficulty: Uint256,
+    pub chain_id: u64,
+    /// The EIP-1559 base fee per gas, burned from the fee paid to the coinbase
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_fee: Option<Uint256>,
+    /// The hashes of the recent blocks for BLOCKHASH
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub block_hashes: BTreeMap<u64, Bytes32>,
+}
+
+impl Default for BlockContext {
+    fn default() -> BlockContext {
+        BlockContext {
+            number: 1,
+            timestamp: 1,
+            coinbase: Address::default(),
+            gas_limit: 666_666_666,
+            difficulty: Uint256::default(),
+            chain_id: 0,
+            base_fee: None,
+            block_hashes: BTreeMap::new(),
         }
     }
+}
 
-    fn nonce_u256(&self) -> Uint256 {
-        let mut data = [0u8; 32];
-        data[0..8].copy_from_slice(&self.nonce.to_le_bytes());
-        Uint256(data)
+/// The transaction being executed
+#[derive(Clone, Debug)]
+pub struct TxEnv {
+    pub origin: Address,
+    pub gas_price: Uint256,
+}
+
+impl Default for TxEnv {
+    fn default() -> TxEnv {
+        TxEnv {
+            origin: Address([128u8; 20]),
+            gas_price: Uint256::default(),
+        }
     }
 }
 
@@ -395,6 +1067,32 @@ pub struct TestHostContext {
     pub current_account: Address,
     pub accounts: HashMap<Address, AccountData>,
     pub destructed_accounts: Vec<Address>,
+    #[serde(default)]
+    pub block: BlockContext,
+    #[serde(skip)]
+    pub tx: TxEnv,
+    // The revision of the nested calls, Petersburg if not set
+    #[serde(skip)]
+    pub revision: Option<Revision>,
+    // Custom errors used to decode the revert reason
+    #[serde(skip)]
+    pub custom_errors: Rc<Vec<CustomError>>,
+    // Shared by all the call frames
+    #[serde(skip)]
+    pub tracer: Rc<HostTracer>,
+    // The VM to execute the nested calls
+    #[serde(skip)]
+    pub vm: Option<Rc<EvmcVm>>,
+    // The receipts of the executed transactions in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub receipts: Vec<TransactionReceipt>,
+    // The changes of the nested call frames, innermost last
+    #[serde(skip)]
+    pub overlays: Vec<Overlay>,
+    // The backend of the accounts not loaded yet, None if they are all in
+    // the accounts
+    #[serde(skip)]
+    pub backend: Option<Backend>,
 }
 
 impl TestHostContext {
@@ -404,46 +1102,53 @@ impl TestHostContext {
             current_account,
             accounts: HashMap::default(),
             destructed_accounts: Vec::new(),
+            block: BlockContext::default(),
+            tx: TxEnv::default(),
+            revision: None,
+            custom_errors: Rc::default(),
+            tracer: Rc::default(),
+            vm: None,
+            receipts: Vec::new(),
+            overlays: Vec::new(),
+            backend: None,
         }
     }
 
-    pub fn contract_exists(&self, address: &Address) -> bool {
-        self.accounts
-            .get(address)
-            .map(|account| account.code.is_some())
-            .unwrap_or(false)
+    pub fn trace(&self, event: HostEvent) {
+        self.tracer.record(event);
     }
 
-    pub fn update_code(&mut self, address: Address, code: Vec<u8>) {
-        // println!(">> before update_code context: {:#?}", self);
-        let account = self
-            .accounts
-            .entry(address.clone())
-            .or_insert_with(|| AccountData::new(address));
-        account.code = Some(JsonBytes(code));
-        // println!(">> after update_code context: {:#?}", self);
-    }
-
-    // We assume the `other` account always have latest state
-    pub fn update(&mut self, other: &TestHostContext) {
-        if other.destructed_accounts.len() < self.destructed_accounts.len() {
-            panic!(
-                "other destructed_accounts length invalid ({} < {})",
-                other.destructed_accounts.len(),
-                self.destructed_accounts.len()
-            );
-        }
-        for address in &other.destructed_accounts {
-            if other.accounts.contains_key(address) {
-                panic!(
-                    "Invalid state for context, address={:?}",
-                    other.current_account
-                );
-            }
+    /// Execute a top level message with this context, return the result and
+    /// the context after the execution. The changes are dropped if it failed.
+    /// The execution fails if a read of the backend failed, the error is
+    /// returned by the next save.
+    pub fn execute(
+        mut self,
+        vm: &EvmcVm,
+        revision: Revision,
+        code: &[u8],
+        message: &ExecutionMessage,
+    ) -> (ExecutionResult, TestHostContext) {
+        self.push_frame();
+        let host_context_ptr = HostContextPtr::from(Box::new(self));
+        let mut context = ExecutionContext::new(TestHostContext::interface(), host_context_ptr.ptr);
+        let mut result = vm.execute(revision, code, message, &mut context);
+        let mut wrapper: HostContextWrapper<TestHostContext> =
+            HostContextWrapper::from(context.context);
+        let mut host_context = std::mem::take(&mut *wrapper);
+        if host_context.has_read_error() {
+            fail_on_read_error(&mut result);
         }
+        host_context.pop_frame(result.status_code == StatusCode::EVMC_SUCCESS);
+        (result, host_context)
+    }
 
-        self.accounts = other.accounts.clone();
-        self.destructed_accounts = other.destructed_accounts.clone();
+    pub fn contract_exists(&self, address: &Address) -> bool {
+        self.code_of(address).is_some()
+    }
+
+    pub fn update_code(&mut self, address: Address, code: Vec<u8>) {
+        self.write_code(&address, JsonBytes(code));
     }
 }
 
@@ -453,78 +1158,116 @@ impl HostContext for TestHostContext {
     }
 
     fn get_tx_context(&mut self) -> TxContext {
-        println!("get_tx_context()");
+        self.trace(HostEvent::TxContext { depth: self.depth });
+        let mut chain_id = Uint256::default();
+        chain_id.0[24..].copy_from_slice(&self.block.chain_id.to_be_bytes());
         TxContext {
-            tx_gas_price: Uint256::default().into(),
-            tx_origin: Address([128u8; 20]).into(),
-            block_coinbase: Address::default().into(),
-            block_number: 1,
-            block_timestamp: 1,
-            block_gas_limit: 666_666_666,
-            block_difficulty: Uint256::default().into(),
-            chain_id: Uint256::default().into(),
+            tx_gas_price: self.tx.gas_price.clone().into(),
+            tx_origin: self.tx.origin.clone().into(),
+            block_coinbase: self.block.coinbase.clone().into(),
+            block_number: self.block.number as i64,
+            block_timestamp: self.block.timestamp as i64,
+            block_gas_limit: self.block.gas_limit as i64,
+            block_difficulty: self.block.difficulty.clone().into(),
+            chain_id: chain_id.into(),
         }
     }
 
     fn account_exists(&mut self, address: &Address) -> bool {
-        println!("account_exists(address: {:?})", address);
-        true
+        self.load_account(address);
+        // An empty account does not exist since Spurious Dragon (EIP-161)
+        let revision = self.revision.unwrap_or(Revision::EVMC_PETERSBURG);
+        let exists = self.has_account(address)
+            && ((revision as u32) < Revision::EVMC_SPURIOUS_DRAGON as u32
+                || !self.is_empty_account(address));
+        self.trace(HostEvent::AccountExists {
+            depth: self.depth,
+            address: address.clone(),
+            exists,
+        });
+        exists
     }
 
     fn get_storage(&mut self, address: &Address, key: &Bytes32) -> Bytes32 {
-        println!("get(address: {:?}, key: {:?})", address, key);
-        self.accounts
-            .get(address)
-            .and_then(|account| account.storage.get(key))
-            .map(|value| value.data.clone())
-            .unwrap_or_default()
+        self.load_slot(address, key);
+        let value = self
+            .storage_value(address, key)
+            .map(|value| value.data)
+            .unwrap_or_default();
+        self.trace(HostEvent::Sload {
+            depth: self.depth,
+            address: address.clone(),
+            key: key.clone(),
+            value: value.clone(),
+        });
+        value
     }
 
     fn set_storage(&mut self, address: Address, key: Bytes32, value: Bytes32) -> StorageStatus {
-        // println!(">> before set_storage context: {:#?}", self);
-        println!(
-            "set(address: {:?}, key: {:?}), value: {:?}, contains_address: {}",
+        self.load_slot(&address, &key);
+        // The original value is the one before the top level execution, below
+        // all the frames
+        let original = self
+            .stored_value(&address, &key)
+            .map(|value| value.data)
+            .unwrap_or_default();
+        let current = self.storage_value(&address, &key);
+        let current_data = current
+            .as_ref()
+            .map(|current| current.data.clone())
+            .unwrap_or_default();
+        let updated = match current {
+            Some(mut current) => {
+                current.update_data(value.clone());
+                current
+            }
+            None => Value::new(value.clone()),
+        };
+        self.write_storage(&address, key.clone(), updated);
+
+        let zero = Bytes32::default();
+        let status = if current_data == value {
+            StorageStatus::EVMC_STORAGE_UNCHANGED
+        } else if original != current_data {
+            StorageStatus::EVMC_STORAGE_MODIFIED_AGAIN
+        } else if original == zero {
+            StorageStatus::EVMC_STORAGE_ADDED
+        } else if value == zero {
+            StorageStatus::EVMC_STORAGE_DELETED
+        } else {
+            StorageStatus::EVMC_STORAGE_MODIFIED
+        };
+        self.trace(HostEvent::Sstore {
+            depth: self.depth,
             address,
             key,
             value,
-            self.accounts.contains_key(&address)
-        );
-        let (modify_time, changed) = {
-            let val = self
-                .accounts
-                .entry(address.clone())
-                .or_insert_with(|| AccountData::new(address))
-                .storage
-                .entry(key)
-                .or_insert_with(|| Value::new(value.clone()));
-            let changed = val.update_data(value);
-            (val.modify_time, changed)
-        };
-        // println!(">> after set_storage context: {:#?}", self);
-
-        match (modify_time, changed) {
-            (0, true) => panic!("Invalid storage value data"),
-            (0, false) => StorageStatus::EVMC_STORAGE_ADDED,
-            (1, true) => StorageStatus::EVMC_STORAGE_MODIFIED,
-            (_, true) => StorageStatus::EVMC_STORAGE_MODIFIED_AGAIN,
-            (_, false) => StorageStatus::EVMC_STORAGE_UNCHANGED,
-        }
+            status: format!("{:?}", status),
+        });
+        status
     }
 
     fn get_balance(&mut self, address: &Address) -> Uint256 {
-        println!("get_balance(address: {:?})", address);
-        Uint256::default()
+        self.load_account(address);
+        let balance = self.account_balance(address);
+        self.trace(HostEvent::Balance {
+            depth: self.depth,
+            address: address.clone(),
+            balance: balance.clone(),
+        });
+        balance
     }
 
     fn call(&mut self, message: ExecutionMessage) -> ExecutionResult {
-        println!("call(message: {:?})", message);
         let sender = Address::from(message.inner.sender);
-        let sender_nonce = self
-            .accounts
-            .entry(sender.clone())
-            .or_insert_with(|| AccountData::new(sender))
-            .nonce_u256();
-        let (destination, _code_hash) = message.destination(sender_nonce);
+        self.touch_account(&sender);
+        // The creator nonce is bumped in the caller frame, kept even if the
+        // create fails
+        let sender_nonce = self.account_nonce(&sender);
+        if message.is_create() {
+            self.write_nonce(&sender, sender_nonce + 1);
+        }
+        let (destination, _code_hash) = message.destination(nonce_u256(sender_nonce));
         let mut message_inner = *message.inner;
         let message = {
             message_inner.destination = destination.clone().into();
@@ -532,90 +1275,173 @@ impl HostContext for TestHostContext {
                 inner: &message_inner,
             }
         };
-        println!("call destination: {:?}", destination);
+        // Calling an account without code executes nothing
         let code = if message.is_create() {
             message.input_data().to_vec()
-        } else if let Some(account) = self.accounts.get(&destination) {
-            if let Some(code) = account.code.as_ref() {
-                code.clone().0
-            } else {
-                panic!("No code found form account: {:?}", destination);
-            }
         } else {
-            panic!("Not such account: {:?}", destination);
-        };
-        println!("code: {}", hex::encode(&code));
-        println!("input-data: {}", hex::encode(&message.input_data()));
-
-        let host_context = {
-            let mut context = self.clone();
-            context.depth = message.depth as u32 + 1;
-            context.current_account = destination.clone();
-            Box::new(context)
+            self.code_of(&destination)
+                .map(|code| code.0)
+                .unwrap_or_default()
         };
+        self.trace(HostEvent::CallEnter {
+            depth: message.depth as u32,
+            kind: format!("{:?}", message.kind),
+            sender: Address::from(message.sender),
+            destination: destination.clone(),
+            gas: message.gas,
+            value: Uint256::from(message.value),
+            input: JsonBytes(message.input_data().to_vec()),
+        });
+
+        let vm = self
+            .vm
+            .clone()
+            .unwrap_or_else(|| Rc::new(EvmcVm::new(unsafe { evmc_create_evmone() })));
+        let revision = self.revision.unwrap_or(Revision::EVMC_PETERSBURG);
+        // The state is moved into the frame and back when it returns, the
+        // value is moved in the frame
+        let mut host_context =
+            Box::new(self.enter_frame(message.depth as u32, destination.clone()));
+        if message.kind == CallKind::EVMC_CALL || message.is_create() {
+            // The called account is created even if empty before Spurious
+            // Dragon
+            if (revision as u32) < Revision::EVMC_SPURIOUS_DRAGON as u32 {
+                host_context.touch_account(&destination);
+            }
+            host_context.transfer(&sender, &destination, &Uint256::from(message.value));
+        }
         let host_context_ptr = HostContextPtr::from(host_context);
         let mut context = ExecutionContext::new(TestHostContext::interface(), host_context_ptr.ptr);
-        let vm = EvmcVm::new(unsafe { evmc_create_evmone() });
-        let mut result = vm.execute(Revision::EVMC_PETERSBURG, &code, &message, &mut context);
-        println!("Execution result: {:#?}\n", result);
-
+        let mut result = vm.execute(revision, &code, &message, &mut context);
+        // The backend is in the frame until it exits
         let mut wrapper = HostContextWrapper::from(context.context);
         let context: &mut TestHostContext = &mut wrapper;
-        if result.status_code == StatusCode::EVMC_SUCCESS && message.is_create() {
+        if context.has_read_error() {
+            fail_on_read_error(&mut result);
+        }
+        self.trace(HostEvent::CallExit {
+            depth: message.depth as u32,
+            status: format!("{:?}", result.status_code),
+            gas_left: result.gas_left,
+            gas_used: message.gas - result.gas_left,
+            output: JsonBytes(result.output_data.clone()),
+            revert_reason: revert::revert_reason(&result, &self.custom_errors),
+        });
+
+        let success = result.status_code == StatusCode::EVMC_SUCCESS;
+        if success && message.is_create() {
+            self.trace(HostEvent::Create {
+                depth: message.depth as u32,
+                address: destination.clone(),
+                code_size: result.output_data.len(),
+            });
             context.update_code(destination.clone(), result.output_data.clone());
         }
         result.create_address = destination;
-        self.update(context);
+        self.exit_frame(context, success);
         result
     }
 
     fn selfdestruct(&mut self, address: &Address, beneficiary: &Address) {
-        self.destructed_accounts.push(address.clone());
-        println!(
-            "emit_log(address: {:?}, beneficiary: {:?})",
-            address, beneficiary
-        );
+        // The balance moves in the frame, it is burned if the beneficiary is
+        // the destructed account. The account is removed at the end of the
+        // transaction.
+        self.load_account(beneficiary);
+        let revision = self.revision.unwrap_or(Revision::EVMC_PETERSBURG);
+        if (revision as u32) < Revision::EVMC_SPURIOUS_DRAGON as u32 {
+            self.touch_account(beneficiary);
+        }
+        let balance = self.account_balance(address);
+        self.transfer(address, beneficiary, &balance);
+        self.write_balance(address, Uint256::default());
+        self.push_destructed(address);
+        self.trace(HostEvent::Selfdestruct {
+            depth: self.depth,
+            address: address.clone(),
+            beneficiary: beneficiary.clone(),
+        });
     }
 
     fn emit_log(&mut self, address: &Address, data: &[u8], topics: &[Bytes32]) {
-        println!(
-            "emit_log(address: {:?}, data: {}, topics: {:?})",
+        self.trace(HostEvent::Log {
+            depth: self.depth,
+            address: address.clone(),
+            topics: topics.to_vec(),
+            data: JsonBytes(data.to_vec()),
+        });
+        self.push_log(
             address,
-            hex::encode(data),
-            topics
-        );
-        self.accounts
-            .entry(address.clone())
-            .or_insert_with(|| AccountData::new(address.clone()))
-            .logs
-            .push(LogEntry {
+            LogEntry {
                 data: JsonBytes(data.to_vec()),
                 topics: topics.to_vec(),
-            });
+            },
+        );
     }
 
-    fn copy_code(&mut self, address: &Address, code_offset: usize, buffer: &[u8]) -> usize {
-        println!(
-            "copy_code(address: {:?}, code_offset: {:?}, buffer: {})",
-            address,
+    fn copy_code(&mut self, address: &Address, code_offset: usize, buffer: &mut [u8]) -> usize {
+        self.load_account(address);
+        let code = self.code_of(address).map(|code| code.0).unwrap_or_default();
+        let copied = if code_offset < code.len() {
+            let len = buffer.len().min(code.len() - code_offset);
+            buffer[..len].copy_from_slice(&code[code_offset..code_offset + len]);
+            len
+        } else {
+            0
+        };
+        self.trace(HostEvent::CopyCode {
+            depth: self.depth,
+            address: address.clone(),
             code_offset,
-            hex::encode(buffer)
-        );
-        0
+            buffer_size: buffer.len(),
+            copied,
+        });
+        copied
     }
 
     fn get_code_size(&mut self, address: &Address) -> usize {
-        println!("get_code_size(address: {:?})", address);
-        0
+        self.load_account(address);
+        let size = self
+            .code_of(address)
+            .map(|code| code.0.len())
+            .unwrap_or_default();
+        self.trace(HostEvent::CodeSize {
+            depth: self.depth,
+            address: address.clone(),
+            size,
+        });
+        size
     }
 
     fn get_code_hash(&mut self, address: &Address) -> Bytes32 {
-        println!("get_code_hash(address: {:?})", address);
-        Bytes32::default()
+        self.load_account(address);
+        // Zero for an account not existing, the hash of the empty code otherwise
+        let code = self.code_of(address);
+        let hash = if code.is_some() || self.account(address).is_some() {
+            Bytes32(keccak(code.map(|code| code.0).unwrap_or_default()).0)
+        } else {
+            Bytes32::default()
+        };
+        self.trace(HostEvent::CodeHash {
+            depth: self.depth,
+            address: address.clone(),
+            hash: hash.clone(),
+        });
+        hash
     }
 
     fn get_block_hash(&mut self, number: u64) -> Bytes32 {
-        println!("get_block_hash(number: {:?})", number);
-        Bytes32::default()
+        let hash = self
+            .block
+            .block_hashes
+            .get(&number)
+            .filter(|_| number < self.block.number && number + 256 >= self.block.number)
+            .cloned()
+            .unwrap_or_default();
+        self.trace(HostEvent::BlockHash {
+            depth: self.depth,
+            number,
+            hash: hash.clone(),
+        });
+        hash
     }
 }
@@ -0,0 +1,345 @@
+//! The pending transactions of the node: ordered by nonce for each sender, and
+//! by the effective gas price across the senders when a block is built
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+use ethereum_types::U256;
+
+use crate::apply_block::BlockBuilder;
+use crate::evmc::{Address, EvmcVm};
+use crate::transaction::{base_fee, nonce_of, SignedTransaction, Transaction};
+use crate::TestHostContext;
+
+/// The percentage a replacement must raise the fees by
+const PRICE_BUMP: u64 = 10;
+/// The most transactions kept for a sender
+const MAX_PER_SENDER: usize = 64;
+/// The most transactions kept in the pool
+const MAX_TRANSACTIONS: usize = 4096;
+
+/// When the blocks are mined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MiningMode {
+    /// Mine a block for every transaction sent
+    Auto,
+    /// Mine a block (possibly empty) every interval
+    Interval(Duration),
+    /// Only mine by evm_mine
+    Manual,
+}
+
+#[derive(Default, Clone)]
+pub struct Mempool {
+    // The transactions of each sender by nonce
+    senders: HashMap<Address, BTreeMap<u64, SignedTransaction>>,
+}
+
+impl Mempool {
+    /// Add the transaction, the one with the same sender and nonce is replaced
+    /// if the fees are raised enough. A new transaction is rejected when the
+    /// sender or the pool has too many.
+    pub fn add(&mut self, signed: SignedTransaction) -> Result<(), String> {
+        let tx = &signed.transaction;
+        let total: usize = self.senders.values().map(BTreeMap::len).sum();
+        let transactions = self.senders.entry(tx.sender.clone()).or_default();
+        if let Some(current) = transactions.get(&tx.nonce) {
+            if current.hash == signed.hash {
+                return Err("already known".to_string());
+            }
+            let current = &current.transaction;
+            // Saturated, the price of a replacement can not exceed U256::MAX
+            let bumped = |fee: U256| {
+                let increase = match fee.checked_mul(U256::from(PRICE_BUMP)) {
+                    Some(product) => product / U256::from(100),
+                    None => fee / U256::from(100) * U256::from(PRICE_BUMP),
+                };
+                fee.saturating_add(increase)
+            };
+            // The fee must be raised even if the bump is rounded down to zero
+            // or saturated
+            let raised = |fee: U256, current: U256| fee > current && fee >= bumped(current);
+            let priority_fee = |tx: &Transaction| tx.priority_fee.unwrap_or(tx.gas_price);
+            if !raised(tx.gas_price, current.gas_price)
+                || !raised(priority_fee(tx), priority_fee(current))
+            {
+                return Err(format!(
+                    "replacement transaction underpriced: the fees must be {}% higher",
+                    PRICE_BUMP
+                ));
+            }
+        } else if transactions.len() >= MAX_PER_SENDER {
+            return Err(format!(
+                "too many transactions of the sender: at most {}",
+                MAX_PER_SENDER
+            ));
+        } else if total >= MAX_TRANSACTIONS {
+            return Err(format!(
+                "txpool is full: at most {} transactions",
+                MAX_TRANSACTIONS
+            ));
+        }
+        transactions.insert(tx.nonce, signed);
+        Ok(())
+    }
+
+    /// Remove the transaction by hash, return true if it is found
+    pub fn remove(&mut self, hash: &[u8; 32]) -> bool {
+        let mut found = false;
+        for transactions in self.senders.values_mut() {
+            let count = transactions.len();
+            transactions.retain(|_, signed| &signed.hash != hash);
+            found |= transactions.len() != count;
+        }
+        self.senders
+            .retain(|_, transactions| !transactions.is_empty());
+        found
+    }
+
+    /// The next nonce of the sender after the pending transactions
+    pub fn next_nonce(&self, context: &TestHostContext, sender: &Address) -> u64 {
+        let mut nonce = nonce_of(context, sender);
+        if let Some(transactions) = self.senders.get(sender) {
+            while transactions.contains_key(&nonce) {
+                nonce += 1;
+            }
+        }
+        nonce
+    }
+
+    /// The number of the pending (executable) and the queued (after a nonce
+    /// gap) transactions, the stale ones are not counted
+    pub fn status(&self, context: &TestHostContext) -> (usize, usize) {
+        let mut pending = 0;
+        let mut total = 0;
+        for (sender, transactions) in &self.senders {
+            let nonce = nonce_of(context, sender);
+            pending += (self.next_nonce(context, sender) - nonce) as usize;
+            total += transactions.range(nonce..).count();
+        }
+        (pending, total - pending)
+    }
+
+    /// Fill the block with the executable transactions, see `next_executable`.
+    /// The invalid transactions are dropped with the later ones of the sender
+    /// (they can not be executed after the nonce gap), return their hashes
+    /// and errors.
+    pub fn fill_block(
+        &mut self,
+        vm: &EvmcVm,
+        builder: &mut BlockBuilder,
+    ) -> Vec<([u8; 32], String)> {
+        let base_fee = base_fee(builder.context());
+        let mut dropped = Vec::new();
+        // The senders whose next transaction does not fit in the block
+        let mut skipped = HashSet::new();
+        loop {
+            // The replaced or mined transactions are stale
+            for (sender, transactions) in self.senders.iter_mut() {
+                let nonce = nonce_of(builder.context(), sender);
+                transactions.retain(|tx_nonce, _| *tx_nonce >= nonce);
+            }
+            self.senders
+                .retain(|_, transactions| !transactions.is_empty());
+
+            let next = self
+                .next_executable(builder.context(), base_fee, &skipped)
+                .cloned();
+            let signed = match next {
+                Some(signed) => signed,
+                None => break,
+            };
+            let sender = signed.transaction.sender.clone();
+            if signed.transaction.gas_limit > builder.gas_left() {
+                skipped.insert(sender);
+                continue;
+            }
+            let nonce = signed.transaction.nonce;
+            let transactions = match self.senders.get_mut(&sender) {
+                Some(transactions) => transactions,
+                None => continue,
+            };
+            match builder.push(vm, &signed) {
+                Ok(_) => {
+                    transactions.remove(&nonce);
+                }
+                Err(err) => {
+                    dropped.push((signed.hash, err));
+                    transactions.remove(&nonce);
+                    for (later_nonce, later) in transactions.split_off(&(nonce + 1)) {
+                        let err = format!(
+                            "transaction 0x{}: nonce {} after the dropped nonce {}",
+                            hex::encode(later.hash),
+                            later_nonce,
+                            nonce
+                        );
+                        dropped.push((later.hash, err));
+                    }
+                }
+            }
+        }
+        dropped
+    }
+
+    /// The executable transaction with the highest effective gas price among
+    /// the next transactions of the senders (by hash if the prices are
+    /// equal), the fee cap must cover the base fee
+    fn next_executable(
+        &self,
+        context: &TestHostContext,
+        base_fee: U256,
+        skipped: &HashSet<Address>,
+    ) -> Option<&SignedTransaction> {
+        self.senders
+            .iter()
+            .filter(|(sender, _)| !skipped.contains(*sender))
+            .filter_map(|(sender, transactions)| transactions.get(&nonce_of(context, sender)))
+            .filter(|signed| signed.transaction.gas_price >= base_fee)
+            .max_by_key(|signed| {
+                let price = signed.transaction.effective_gas_price(base_fee);
+                (price, Reverse(signed.hash))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed(sender: u8, nonce: u64, gas_price: u64) -> SignedTransaction {
+        let mut hash = [0u8; 32];
+        hash[0] = sender;
+        hash[8..16].copy_from_slice(&nonce.to_be_bytes());
+        hash[16..24].copy_from_slice(&gas_price.to_be_bytes());
+        SignedTransaction {
+            tx_type: 0,
+            chain_id: None,
+            transaction: Transaction {
+                sender: Address([sender; 20]),
+                nonce,
+                gas_limit: 21000,
+                gas_price: U256::from(gas_price),
+                ..Default::default()
+            },
+            s: [0u8; 32],
+            hash,
+            raw: Vec::new(),
+        }
+    }
+
+    // The order the transactions are taken in, as mined by `fill_block`
+    fn mining_order(mempool: &mut Mempool, context: &mut TestHostContext) -> Vec<(u8, u64)> {
+        let mut order = Vec::new();
+        while let Some(next) = mempool
+            .next_executable(context, U256::zero(), &HashSet::new())
+            .cloned()
+        {
+            let sender = next.transaction.sender.clone();
+            order.push((sender.0[0], next.transaction.nonce));
+            context.account_mut(&sender).nonce += 1;
+            mempool.remove(&next.hash);
+        }
+        order
+    }
+
+    #[test]
+    fn order_by_nonce_and_price() {
+        let mut context = TestHostContext::new(0, Address::default());
+        let mut mempool = Mempool::default();
+        // The nonces of a sender are in order whatever their prices
+        mempool.add(signed(1, 1, 50)).unwrap();
+        mempool.add(signed(1, 0, 10)).unwrap();
+        mempool.add(signed(2, 0, 20)).unwrap();
+        mempool.add(signed(2, 1, 5)).unwrap();
+        assert_eq!(
+            mining_order(&mut mempool, &mut context),
+            vec![(2, 0), (1, 0), (1, 1), (2, 1)]
+        );
+    }
+
+    #[test]
+    fn below_base_fee_not_executable() {
+        let context = TestHostContext::new(0, Address::default());
+        let mut mempool = Mempool::default();
+        mempool.add(signed(1, 0, 10)).unwrap();
+        let skipped = HashSet::new();
+        assert!(mempool
+            .next_executable(&context, U256::from(11), &skipped)
+            .is_none());
+        assert!(mempool
+            .next_executable(&context, U256::from(10), &skipped)
+            .is_some());
+    }
+
+    #[test]
+    fn replace_with_higher_fees() {
+        let mut mempool = Mempool::default();
+        mempool.add(signed(1, 0, 100)).unwrap();
+        assert_eq!(mempool.add(signed(1, 0, 100)).unwrap_err(), "already known");
+        let err = mempool.add(signed(1, 0, 109)).unwrap_err();
+        assert!(err.starts_with("replacement transaction underpriced"));
+        mempool.add(signed(1, 0, 110)).unwrap();
+        assert!(!mempool.remove(&signed(1, 0, 100).hash));
+        assert!(mempool.remove(&signed(1, 0, 110).hash));
+
+        // The bump of a low price is rounded down to zero
+        mempool.add(signed(2, 0, 5)).unwrap();
+        let mut same_price = signed(2, 0, 5);
+        same_price.hash[31] = 1;
+        let err = mempool.add(same_price).unwrap_err();
+        assert!(err.starts_with("replacement transaction underpriced"));
+        mempool.add(signed(2, 0, 6)).unwrap();
+
+        // The bump of the highest price is saturated, it can not be raised
+        let mut max = signed(3, 0, 0);
+        max.transaction.gas_price = U256::max_value();
+        mempool.add(max.clone()).unwrap();
+        let mut replacement = max.clone();
+        replacement.hash[31] = 1;
+        let err = mempool.add(replacement).unwrap_err();
+        assert!(err.starts_with("replacement transaction underpriced"));
+    }
+
+    #[test]
+    fn limit_per_sender_and_in_total() {
+        let mut mempool = Mempool::default();
+        for nonce in 0..MAX_PER_SENDER as u64 {
+            mempool.add(signed(1, nonce, 10)).unwrap();
+        }
+        let err = mempool
+            .add(signed(1, MAX_PER_SENDER as u64, 10))
+            .unwrap_err();
+        assert!(err.starts_with("too many transactions of the sender"));
+        // A replacement is not limited
+        mempool.add(signed(1, 0, 11)).unwrap();
+
+        for sender in 2..=(MAX_TRANSACTIONS / MAX_PER_SENDER) as u8 {
+            for nonce in 0..MAX_PER_SENDER as u64 {
+                mempool.add(signed(sender, nonce, 10)).unwrap();
+            }
+        }
+        let err = mempool.add(signed(255, 0, 10)).unwrap_err();
+        assert!(err.starts_with("txpool is full"));
+        mempool.add(signed(2, 0, 11)).unwrap();
+    }
+
+    #[test]
+    fn pending_and_queued() {
+        let mut context = TestHostContext::new(0, Address::default());
+        context.account_mut(&Address([1u8; 20])).nonce = 3;
+        let mut mempool = Mempool::default();
+        // Stale, pending, pending, queued after the gap at 5
+        for nonce in &[2, 3, 4, 6] {
+            mempool.add(signed(1, *nonce, 10)).unwrap();
+        }
+        mempool.add(signed(2, 1, 10)).unwrap();
+        assert_eq!(mempool.next_nonce(&context, &Address([1u8; 20])), 5);
+        assert_eq!(mempool.next_nonce(&context, &Address([2u8; 20])), 0);
+        assert_eq!(mempool.status(&context), (2, 2));
+
+        mempool.add(signed(1, 5, 10)).unwrap();
+        assert_eq!(mempool.next_nonce(&context, &Address([1u8; 20])), 7);
+        assert_eq!(mempool.status(&context), (4, 1));
+    }
+}
@@ -0,0 +1,473 @@
+//! The changes of the call frames over the state: each frame records only the
+//! entries it touched, merged into its caller when the call succeeds and
+//! dropped otherwise. The top level execution has a frame too, its changes are
+//! written to the state when it succeeds.
+
+use std::collections::HashMap;
+use std::mem;
+
+use crate::evmc::{Address, Bytes32, Uint256};
+use crate::transaction::{u256_from_bytes, u256_to_bytes};
+use crate::{JsonBytes, LogEntry, TestHostContext, Value};
+
+/// The touched entries of an account
+#[derive(Default, Clone, Debug)]
+struct AccountOverlay {
+    balance: Option<Uint256>,
+    nonce: Option<u64>,
+    code: Option<JsonBytes>,
+    storage: HashMap<Bytes32, Value>,
+    logs: Vec<LogEntry>,
+}
+
+/// The changes of a call frame, an account is created when merged if it is
+/// touched
+#[derive(Default, Clone, Debug)]
+pub struct Overlay {
+    accounts: HashMap<Address, AccountOverlay>,
+    destructed_accounts: Vec<Address>,
+}
+
+impl Overlay {
+    fn merge(&mut self, other: Overlay) {
+        for (address, changes) in other.accounts {
+            let account = self.accounts.entry(address).or_default();
+            if changes.balance.is_some() {
+                account.balance = changes.balance;
+            }
+            if changes.nonce.is_some() {
+                account.nonce = changes.nonce;
+            }
+            if changes.code.is_some() {
+                account.code = changes.code;
+            }
+            account.storage.extend(changes.storage);
+            account.logs.extend(changes.logs);
+        }
+        self.destructed_accounts.extend(other.destructed_accounts);
+    }
+}
+
+impl TestHostContext {
+    /// Open the frame of a top level execution
+    pub fn push_frame(&mut self) {
+        self.overlays.push(Overlay::default());
+    }
+
+    /// Close the innermost frame, its changes are kept if it succeeded
+    pub fn pop_frame(&mut self, success: bool) {
+        let overlay = self
+            .overlays
+            .pop()
+            .expect("The overlay of the call frame is missing");
+        if !success {
+            return;
+        }
+        match self.overlays.last_mut() {
+            Some(caller) => caller.merge(overlay),
+            None => {
+                for (address, changes) in overlay.accounts {
+                    self.touch_account(&address);
+                    if let Some(balance) = changes.balance {
+                        self.account_mut(&address).balance = balance;
+                    }
+                    if let Some(nonce) = changes.nonce {
+                        self.account_mut(&address).nonce = nonce;
+                    }
+                    if let Some(code) = changes.code {
+                        self.set_account_code(&address, Some(code));
+                    }
+                    for (key, value) in changes.storage {
+                        self.set_stored_value(&address, key, value);
+                    }
+                    if !changes.logs.is_empty() {
+                        self.account_mut(&address).logs.extend(changes.logs);
+                    }
+                }
+                self.destructed_accounts.extend(overlay.destructed_accounts);
+            }
+        }
+    }
+
+    /// The context of a nested call frame, the state is moved into it with a
+    /// new overlay on top
+    pub fn enter_frame(&mut self, depth: u32, current_account: Address) -> TestHostContext {
+        let mut overlays = mem::take(&mut self.overlays);
+        overlays.push(Overlay::default());
+        TestHostContext {
+            depth,
+            current_account,
+            accounts: mem::take(&mut self.accounts),
+            destructed_accounts: mem::take(&mut self.destructed_accounts),
+            block: mem::take(&mut self.block),
+            tx: self.tx.clone(),
+            revision: self.revision,
+            custom_errors: self.custom_errors.clone(),
+            tracer: self.tracer.clone(),
+            vm: self.vm.clone(),
+            receipts: mem::take(&mut self.receipts),
+            overlays,
+            backend: mem::take(&mut self.backend),
+        }
+    }
+
+    /// Move the state back from the frame, its overlay is merged if the call
+    /// succeeded
+    pub fn exit_frame(&mut self, frame: &mut TestHostContext, success: bool) {
+        self.accounts = mem::take(&mut frame.accounts);
+        self.destructed_accounts = mem::take(&mut frame.destructed_accounts);
+        self.block = mem::take(&mut frame.block);
+        self.receipts = mem::take(&mut frame.receipts);
+        self.overlays = mem::take(&mut frame.overlays);
+        self.backend = mem::take(&mut frame.backend);
+        self.pop_frame(success);
+    }
+
+    // The entry of the innermost frame touched it
+    fn overlay_entry<T, F>(&self, address: &Address, entry: F) -> Option<&T>
+    where
+        F: Fn(&AccountOverlay) -> Option<&T>,
+    {
+        self.overlays
+            .iter()
+            .rev()
+            .find_map(|overlay| overlay.accounts.get(address).and_then(&entry))
+    }
+
+    pub fn storage_value(&self, address: &Address, key: &Bytes32) -> Option<Value> {
+        match self.overlay_entry(address, |account| account.storage.get(key)) {
+            Some(value) => Some(value.clone()),
+            None => self.stored_value(address, key),
+        }
+    }
+
+    pub fn code_of(&self, address: &Address) -> Option<JsonBytes> {
+        match self.overlay_entry(address, |account| account.code.as_ref()) {
+            Some(code) => Some(code.clone()),
+            None => self
+                .account(address)
+                .and_then(|account| account.code.as_ref().cloned()),
+        }
+    }
+
+    pub fn account_balance(&self, address: &Address) -> Uint256 {
+        match self.overlay_entry(address, |account| account.balance.as_ref()) {
+            Some(balance) => balance.clone(),
+            None => self
+                .account(address)
+                .map(|account| account.balance.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn account_nonce(&self, address: &Address) -> u64 {
+        match self.overlay_entry(address, |account| account.nonce.as_ref()) {
+            Some(nonce) => *nonce,
+            None => self
+                .account(address)
+                .map(|account| account.nonce)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The account is in the state: loaded, created or touched by a frame
+    pub fn has_account(&self, address: &Address) -> bool {
+        self.overlays
+            .iter()
+            .any(|overlay| overlay.accounts.contains_key(address))
+            || self.account(address).is_some()
+    }
+
+    /// No nonce, balance or code (EIP-161)
+    pub fn is_empty_account(&self, address: &Address) -> bool {
+        self.account_nonce(address) == 0
+            && u256_from_bytes(&self.account_balance(address).0).is_zero()
+            && self
+                .code_of(address)
+                .map(|code| code.0.is_empty())
+                .unwrap_or(true)
+    }
+
+    /// Create the account if not exists
+    pub fn touch_account(&mut self, address: &Address) {
+        self.load_account(address);
+        let exists = self.accounts.contains_key(address)
+            || self
+                .overlays
+                .iter()
+                .any(|overlay| overlay.accounts.contains_key(address));
+        if !exists {
+            match self.overlays.last_mut() {
+                Some(overlay) => {
+                    overlay.accounts.entry(address.clone()).or_default();
+                }
+                None => {
+                    self.account_mut(address);
+                }
+            }
+        }
+    }
+
+    pub fn write_balance(&mut self, address: &Address, balance: Uint256) {
+        match self.overlays.last_mut() {
+            Some(overlay) => {
+                overlay.accounts.entry(address.clone()).or_default().balance = Some(balance);
+            }
+            None => self.account_mut(address).balance = balance,
+        }
+    }
+
+    pub fn write_nonce(&mut self, address: &Address, nonce: u64) {
+        match self.overlays.last_mut() {
+            Some(overlay) => {
+                overlay.accounts.entry(address.clone()).or_default().nonce = Some(nonce);
+            }
+            None => self.account_mut(address).nonce = nonce,
+        }
+    }
+
+    /// Move the value of a call, the VM checked the balance of the sender
+    pub fn transfer(&mut self, from: &Address, to: &Address, value: &Uint256) {
+        let value = u256_from_bytes(&value.0);
+        if value.is_zero() || from == to {
+            return;
+        }
+        let balance = u256_from_bytes(&self.account_balance(from).0);
+        self.write_balance(from, Uint256(u256_to_bytes(balance.saturating_sub(value))));
+        let balance = u256_from_bytes(&self.account_balance(to).0);
+        self.write_balance(to, Uint256(u256_to_bytes(balance + value)));
+    }
+
+    pub fn write_storage(&mut self, address: &Address, key: Bytes32, value: Value) {
+        match self.overlays.last_mut() {
+            Some(overlay) => {
+                overlay
+                    .accounts
+                    .entry(address.clone())
+                    .or_default()
+                    .storage
+                    .insert(key, value);
+            }
+            None => self.set_stored_value(address, key, value),
+        }
+    }
+
+    pub fn write_code(&mut self, address: &Address, code: JsonBytes) {
+        match self.overlays.last_mut() {
+            Some(overlay) => {
+                overlay.accounts.entry(address.clone()).or_default().code = Some(code);
+            }
+            None => self.set_account_code(address, Some(code)),
+        }
+    }
+
+    pub fn push_log(&mut self, address: &Address, log: LogEntry) {
+        match self.overlays.last_mut() {
+            Some(overlay) => {
+                overlay
+                    .accounts
+                    .entry(address.clone())
+                    .or_default()
+                    .logs
+                    .push(log);
+            }
+            None => self.account_mut(address).logs.push(log),
+        }
+    }
+
+    pub fn push_destructed(&mut self, address: &Address) {
+        match self.overlays.last_mut() {
+            Some(overlay) => overlay.destructed_accounts.push(address.clone()),
+            None => self.destructed_accounts.push(address.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethereum_types::U256;
+
+    fn word(byte: u8) -> Bytes32 {
+        let mut word = Bytes32::default();
+        word.0[31] = byte;
+        word
+    }
+
+    fn log(byte: u8) -> LogEntry {
+        LogEntry {
+            data: JsonBytes(vec![byte]),
+            topics: Vec::new(),
+        }
+    }
+
+    fn stored(context: &TestHostContext, address: &Address, key: &Bytes32) -> Option<Bytes32> {
+        context.storage_value(address, key).map(|value| value.data)
+    }
+
+    #[test]
+    fn revert_drops_changes() {
+        let alice = Address([1u8; 20]);
+        let mut context = TestHostContext::new(0, alice.clone());
+        context.push_frame();
+        context.write_storage(&alice, word(1), Value::new(word(10)));
+        context.write_code(&alice, JsonBytes(vec![0x00]));
+        context.write_nonce(&alice, 1);
+        context.push_log(&alice, log(1));
+        context.push_destructed(&alice);
+        assert_eq!(stored(&context, &alice, &word(1)), Some(word(10)));
+        assert_eq!(context.account_nonce(&alice), 1);
+        context.pop_frame(false);
+        assert_eq!(stored(&context, &alice, &word(1)), None);
+        assert_eq!(context.code_of(&alice), None);
+        assert_eq!(context.account_nonce(&alice), 0);
+        assert!(context.accounts.is_empty());
+        assert!(context.destructed_accounts.is_empty());
+    }
+
+    #[test]
+    fn merge_into_caller() {
+        let alice = Address([1u8; 20]);
+        let bob = Address([2u8; 20]);
+        let mut context = TestHostContext::new(0, alice.clone());
+        context.account_mut(&alice).balance = Uint256(u256_to_bytes(U256::from(100)));
+        context.push_frame();
+        let mut frame = context.enter_frame(1, bob.clone());
+        frame.transfer(&alice, &bob, &Uint256(u256_to_bytes(U256::from(30))));
+        frame.write_storage(&bob, word(1), Value::new(word(10)));
+        frame.push_log(&bob, log(1));
+        frame.push_destructed(&bob);
+        context.exit_frame(&mut frame, true);
+        // Still revertible with the caller
+        assert!(!context.accounts.contains_key(&bob));
+        assert_eq!(stored(&context, &bob, &word(1)), Some(word(10)));
+        context.pop_frame(true);
+        assert_eq!(
+            u256_from_bytes(&context.accounts[&alice].balance.0),
+            U256::from(70)
+        );
+        assert_eq!(
+            u256_from_bytes(&context.accounts[&bob].balance.0),
+            U256::from(30)
+        );
+        assert_eq!(context.accounts[&bob].logs.len(), 1);
+        assert_eq!(stored(&context, &bob, &word(1)), Some(word(10)));
+        assert_eq!(context.destructed_accounts, vec![bob]);
+    }
+
+    #[test]
+    fn storage_status_from_original_value() {
+        use crate::evmc::{HostContext, StorageStatus};
+
+        let alice = Address([1u8; 20]);
+        let mut context = TestHostContext::new(0, alice.clone());
+        context.set_stored_value(&alice, word(1), Value::new(word(1)));
+        context.set_stored_value(&alice, word(3), Value::new(word(3)));
+        context.push_frame();
+        let set = |context: &mut TestHostContext, key: u8, value: u8| {
+            context.set_storage(alice.clone(), word(key), word(value))
+        };
+        assert_eq!(
+            set(&mut context, 1, 2),
+            StorageStatus::EVMC_STORAGE_MODIFIED
+        );
+        assert_eq!(
+            set(&mut context, 1, 2),
+            StorageStatus::EVMC_STORAGE_UNCHANGED
+        );
+        assert_eq!(
+            set(&mut context, 2, 0),
+            StorageStatus::EVMC_STORAGE_UNCHANGED
+        );
+        assert_eq!(set(&mut context, 2, 5), StorageStatus::EVMC_STORAGE_ADDED);
+        assert_eq!(set(&mut context, 3, 0), StorageStatus::EVMC_STORAGE_DELETED);
+        // The slots changed by the caller frame are dirty in the nested one
+        let mut frame = context.enter_frame(1, alice.clone());
+        assert_eq!(
+            set(&mut frame, 1, 1),
+            StorageStatus::EVMC_STORAGE_MODIFIED_AGAIN
+        );
+        assert_eq!(
+            set(&mut frame, 3, 3),
+            StorageStatus::EVMC_STORAGE_MODIFIED_AGAIN
+        );
+        context.exit_frame(&mut frame, true);
+        context.pop_frame(true);
+        // The next execution starts from the written values
+        context.push_frame();
+        assert_eq!(
+            set(&mut context, 2, 6),
+            StorageStatus::EVMC_STORAGE_MODIFIED
+        );
+    }
+
+    #[test]
+    fn empty_account_exists_before_spurious_dragon() {
+        use crate::evmc::{HostContext, Revision};
+
+        let alice = Address([1u8; 20]);
+        let empty = Address([2u8; 20]);
+        let mut context = TestHostContext::new(0, alice.clone());
+        context.account_mut(&empty);
+        context.push_frame();
+        context.write_nonce(&alice, 1);
+        for (revision, empty_exists) in &[
+            (Revision::EVMC_FRONTIER, true),
+            (Revision::EVMC_SPURIOUS_DRAGON, false),
+        ] {
+            context.revision = Some(*revision);
+            assert!(context.account_exists(&alice));
+            assert_eq!(context.account_exists(&empty), *empty_exists);
+            assert!(!context.account_exists(&Address([3u8; 20])));
+        }
+    }
+
+    #[test]
+    fn selfdestruct_moves_balance_in_frame() {
+        use crate::evmc::HostContext;
+
+        let alice = Address([1u8; 20]);
+        let bob = Address([2u8; 20]);
+        let balance = |context: &TestHostContext, address: &Address| {
+            u256_from_bytes(&context.account_balance(address).0)
+        };
+        let mut context = TestHostContext::new(0, alice.clone());
+        context.account_mut(&alice).balance = Uint256(u256_to_bytes(U256::from(100)));
+        context.account_mut(&bob).balance = Uint256(u256_to_bytes(U256::from(5)));
+        context.push_frame();
+        let mut frame = context.enter_frame(1, alice.clone());
+        frame.selfdestruct(&alice, &bob);
+        assert_eq!(balance(&frame, &alice), U256::zero());
+        assert_eq!(balance(&frame, &bob), U256::from(105));
+        context.exit_frame(&mut frame, false);
+        assert_eq!(balance(&context, &alice), U256::from(100));
+        assert!(context.overlays[0].destructed_accounts.is_empty());
+
+        // The balance is burned if the beneficiary is the destructed account
+        context.selfdestruct(&bob, &bob);
+        context.pop_frame(true);
+        assert_eq!(context.accounts[&bob].balance, Uint256::default());
+        assert_eq!(context.destructed_accounts, vec![bob]);
+    }
+
+    #[test]
+    fn nested_reads_top_to_bottom() {
+        let alice = Address([1u8; 20]);
+        let mut context = TestHostContext::new(0, alice.clone());
+        context.set_stored_value(&alice, word(1), Value::new(word(1)));
+        context.set_stored_value(&alice, word(2), Value::new(word(2)));
+        context.push_frame();
+        context.write_storage(&alice, word(1), Value::new(word(11)));
+        context.write_nonce(&alice, 1);
+        let mut frame = context.enter_frame(1, alice.clone());
+        frame.write_storage(&alice, word(1), Value::new(word(21)));
+        assert_eq!(stored(&frame, &alice, &word(1)), Some(word(21)));
+        assert_eq!(stored(&frame, &alice, &word(2)), Some(word(2)));
+        assert_eq!(frame.account_nonce(&alice), 1);
+        context.exit_frame(&mut frame, false);
+        assert_eq!(stored(&context, &alice, &word(1)), Some(word(11)));
+        context.pop_frame(true);
+        assert_eq!(stored(&context, &alice, &word(1)), Some(word(11)));
+        assert_eq!(context.accounts[&alice].nonce, 1);
+    }
+}
@@ -0,0 +1,325 @@
+//! Merkle proofs of the accounts and storage slots in the eth_getProof shape,
+//! and the verifier of such proofs
+
+use std::fs;
+
+use clap::{App, Arg, ArgMatches};
+use rlp::Rlp;
+use serde::{Deserialize, Serialize};
+
+use crate::evmc::{Address, Bytes32};
+use crate::state_db::load_state;
+use crate::transaction::{parse_u256, u256_to_bytes};
+use crate::trie::{
+    code_hash, secure_trie_proof, state_items, state_root, storage_items, storage_root, trim_zeros,
+    verify_secure_proof, EMPTY_ROOT,
+};
+use crate::{AccountData, JsonBytes, TestHostContext};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageProof {
+    pub key: Bytes32,
+    /// The value as a hex quantity
+    pub value: String,
+    pub proof: Vec<JsonBytes>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountProof {
+    pub address: Address,
+    pub account_proof: Vec<JsonBytes>,
+    /// The balance as a hex quantity
+    pub balance: String,
+    pub code_hash: Bytes32,
+    /// The nonce as a hex quantity
+    pub nonce: String,
+    pub storage_hash: Bytes32,
+    pub storage_proof: Vec<StorageProof>,
+}
+
+/// The sub command to build or verify the proofs
+pub fn sub_command(name: &'static str) -> App<'static, 'static> {
+    App::new(name)
+        .about("Build the account and storage proofs (eth_getProof), or verify them")
+        .arg(
+            Arg::with_name("input-storage")
+                .long("input-storage")
+                .short("s")
+                .takes_value(true)
+                .required_unless("verify")
+                .help("The storage (accounts) json file"),
+        )
+        .arg(
+            Arg::with_name("address")
+                .long("address")
+                .takes_value(true)
+                .required_unless("verify")
+                .help("The address of the account"),
+        )
+        .arg(
+            Arg::with_name("slot")
+                .long("slot")
+                .takes_value(true)
+                .multiple(true)
+                .help("The storage slots (hex or decimal) to prove"),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .takes_value(true)
+                .conflicts_with_all(&["address", "slot"])
+                .help("Verify the proof json file against the state root"),
+        )
+        .arg(
+            Arg::with_name("root").long("root").takes_value(true).help(
+                "The state root to verify against (computed from --input-storage by default)",
+            ),
+        )
+}
+
+pub fn process(sub_matches: &ArgMatches) -> Result<(), String> {
+    let context = match sub_matches.value_of("input-storage") {
+        Some(path) => Some(load_state(path)?),
+        None => None,
+    };
+
+    if let Some(path) = sub_matches.value_of("verify") {
+        let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        let proof: AccountProof = serde_json::from_slice(&data).map_err(|err| err.to_string())?;
+        let root = match (sub_matches.value_of("root"), context.as_ref()) {
+            (Some(root), _) => u256_to_bytes(parse_u256(root)?),
+            (None, Some(context)) => state_root(context)?,
+            (None, None) => return Err("<root> or <input-storage> is required".to_string()),
+        };
+        verify(&root, &proof)?;
+        println!(
+            "Valid proof of {:?} ({} storage slots) against state root 0x{}",
+            proof.address,
+            proof.storage_proof.len(),
+            hex::encode(root)
+        );
+        return Ok(());
+    }
+
+    let mut context = context.unwrap();
+    let address: Address =
+        serde_json::from_str(&format!("\"{}\"", sub_matches.value_of("address").unwrap()))
+            .map_err(|err| format!("Invalid address: {}", err))?;
+    context.load_storage(&address)?;
+    let slots = match sub_matches.values_of("slot") {
+        Some(values) => values
+            .map(|value| parse_u256(value).map(|slot| Bytes32(u256_to_bytes(slot))))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new(),
+    };
+    let proof = build(&context, &address, &slots)?;
+    println!("{}", serde_json::to_string_pretty(&proof).unwrap());
+    Ok(())
+}
+
+/// Build the proofs of the account and its storage slots, the storage of the
+/// account must be loaded
+pub fn build(
+    context: &TestHostContext,
+    address: &Address,
+    slots: &[Bytes32],
+) -> Result<AccountProof, String> {
+    let empty = AccountData::new(address.clone());
+    let account = context.accounts.get(address).unwrap_or(&empty);
+    let to_json = |nodes: Vec<Vec<u8>>| nodes.into_iter().map(JsonBytes).collect();
+    let storage_proof = slots
+        .iter()
+        .map(|slot| {
+            let value = account
+                .storage
+                .get(slot)
+                .map(|value| value.data.0)
+                .unwrap_or_default();
+            StorageProof {
+                key: slot.clone(),
+                value: quantity(&value),
+                proof: to_json(secure_trie_proof(storage_items(account), &slot.0)),
+            }
+        })
+        .collect();
+    Ok(AccountProof {
+        address: address.clone(),
+        account_proof: to_json(secure_trie_proof(state_items(context)?, &address.0)),
+        balance: quantity(&account.balance.0),
+        code_hash: Bytes32(code_hash(account)),
+        nonce: format!("{:#x}", account.nonce),
+        storage_hash: Bytes32(storage_root(account)),
+        storage_proof,
+    })
+}
+
+/// Verify the account proof against the state root, and the storage proofs
+/// against the storage hash of the account
+pub fn verify(root: &[u8; 32], proof: &AccountProof) -> Result<(), String> {
+    let nodes = |proof: &[JsonBytes]| proof.iter().map(|node| node.0.clone()).collect::<Vec<_>>();
+    let account = verify_secure_proof(root, &proof.address.0, &nodes(&proof.account_proof))?;
+    let fields = match account {
+        Some(account) => {
+            let rlp = Rlp::new(&account);
+            let field = |index| -> Result<Vec<u8>, String> {
+                rlp.val_at(index).map_err(|err| err.to_string())
+            };
+            (field(0)?, field(1)?, field(2)?, field(3)?)
+        }
+        // Not in the trie, the account is empty
+        None => (
+            Vec::new(),
+            Vec::new(),
+            EMPTY_ROOT.to_vec(),
+            code_hash(&AccountData::new(proof.address.clone())).to_vec(),
+        ),
+    };
+    check_quantity("nonce", &proof.nonce, &fields.0)?;
+    check_quantity("balance", &proof.balance, &fields.1)?;
+    if fields.2 != proof.storage_hash.0 {
+        return Err(format!(
+            "Storage hash mismatch: proof 0x{}, trie 0x{}",
+            hex::encode(proof.storage_hash.0),
+            hex::encode(&fields.2)
+        ));
+    }
+    if fields.3 != proof.code_hash.0 {
+        return Err(format!(
+            "Code hash mismatch: proof 0x{}, trie 0x{}",
+            hex::encode(proof.code_hash.0),
+            hex::encode(&fields.3)
+        ));
+    }
+
+    for slot in &proof.storage_proof {
+        let value = verify_secure_proof(&proof.storage_hash.0, &slot.key.0, &nodes(&slot.proof))?;
+        let value = match value {
+            Some(value) => Rlp::new(&value)
+                .as_val::<Vec<u8>>()
+                .map_err(|err| err.to_string())?,
+            None => Vec::new(),
+        };
+        check_quantity(&format!("slot {:?}", slot.key), &slot.value, &value)
+            .map_err(|err| format!("Invalid storage proof: {}", err))?;
+    }
+    Ok(())
+}
+
+/// The hex quantity of the big endian number, 0x0 for zero
+pub fn quantity(bytes: &[u8]) -> String {
+    let hex = hex::encode(trim_zeros(bytes));
+    let digits = hex.trim_start_matches('0');
+    if digits.is_empty() {
+        "0x0".to_string()
+    } else {
+        format!("0x{}", digits)
+    }
+}
+
+fn check_quantity(name: &str, expected: &str, bytes: &[u8]) -> Result<(), String> {
+    let expected = u256_to_bytes(parse_u256(expected)?);
+    if trim_zeros(&expected) != trim_zeros(bytes) {
+        return Err(format!(
+            "{} mismatch: proof {}, trie {}",
+            name,
+            quantity(&expected),
+            quantity(bytes)
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evmc::Uint256;
+    use crate::Value;
+
+    fn slot(byte: u8) -> Bytes32 {
+        let mut slot = Bytes32::default();
+        slot.0[31] = byte;
+        slot
+    }
+
+    fn context() -> TestHostContext {
+        let mut context = TestHostContext::new(0, Address::default());
+        for byte in 1..=3u8 {
+            let address = Address([byte; 20]);
+            let mut account = AccountData::new(address.clone());
+            account.nonce = byte as u64;
+            account.balance = Uint256([byte; 32]);
+            if byte == 2 {
+                account.code = Some(JsonBytes(vec![0x60, 0x00]));
+                for key in 1..=20u8 {
+                    let value = Value {
+                        data: slot(key * 3),
+                        modify_time: 0,
+                    };
+                    account.storage.insert(slot(key), value);
+                }
+            }
+            context.accounts.insert(address, account);
+        }
+        context
+    }
+
+    #[test]
+    fn build_and_verify() {
+        let context = context();
+        let root = state_root(&context).unwrap();
+
+        let address = Address([2; 20]);
+        let proof = build(&context, &address, &[slot(7), slot(0), slot(99)]).unwrap();
+        assert_eq!(proof.nonce, "0x2");
+        assert_eq!(proof.balance, format!("0x2{}", "02".repeat(31)));
+        let values: Vec<_> = proof
+            .storage_proof
+            .iter()
+            .map(|slot| &slot.value[..])
+            .collect();
+        assert_eq!(values, ["0x15", "0x0", "0x0"]);
+        verify(&root, &proof).unwrap();
+
+        // An absent account, with an absent slot
+        let proof = build(&context, &Address([9; 20]), &[slot(1)]).unwrap();
+        assert_eq!((&proof.nonce[..], &proof.balance[..]), ("0x0", "0x0"));
+        assert_eq!(proof.storage_hash.0, EMPTY_ROOT);
+        assert!(proof.storage_proof[0].proof.is_empty());
+        verify(&root, &proof).unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_proof() {
+        let context = context();
+        let root = state_root(&context).unwrap();
+        let proof = build(&context, &Address([2; 20]), &[slot(7), slot(0)]).unwrap();
+
+        let mut invalid = proof.clone();
+        invalid.nonce = "0x3".to_string();
+        assert_eq!(
+            verify(&root, &invalid).unwrap_err(),
+            "nonce mismatch: proof 0x3, trie 0x2"
+        );
+
+        let mut invalid = proof.clone();
+        invalid.storage_proof[1].value = "0x1".to_string();
+        assert_eq!(
+            verify(&root, &invalid).unwrap_err(),
+            format!(
+                "Invalid storage proof: slot {:?} mismatch: proof 0x1, trie 0x0",
+                slot(0)
+            )
+        );
+
+        // The absent account is not accepted against the proof nodes of another one
+        let mut invalid = proof.clone();
+        invalid.address = Address([9; 20]);
+        assert!(verify(&root, &invalid).is_err());
+
+        let mut other = context;
+        other.accounts.remove(&Address([1; 20]));
+        assert!(verify(&state_root(&other).unwrap(), &proof).is_err());
+    }
+}
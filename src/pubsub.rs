@@ -0,0 +1,332 @@
+//! eth_subscribe and eth_unsubscribe over WebSocket: the newHeads and logs
+//! subscriptions are notified of the blocks mined by the node
+
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+use crate::block::Header;
+use crate::evmc::Bytes32;
+use crate::proof::quantity;
+use crate::receipt::TransactionReceipt;
+use crate::rpc::{handle_batch, log_json, param_str, response, LogFilter, Node, RpcError};
+use crate::transaction::u256_to_bytes;
+use crate::ws::Connection;
+
+pub enum Subscription {
+    NewHeads,
+    Logs(LogFilter),
+}
+
+// The subscriptions are bound to the connection
+struct Subscriber {
+    connection: Connection,
+    subscriptions: BTreeMap<String, Subscription>,
+}
+
+/// The WebSocket connections and their subscriptions
+#[derive(Default)]
+pub struct Subscribers {
+    subscribers: Vec<Subscriber>,
+    next_id: u64,
+}
+
+impl Subscribers {
+    pub fn add(&mut self, connection: Connection) {
+        self.subscribers.push(Subscriber {
+            connection,
+            subscriptions: BTreeMap::new(),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    /// Handle the received messages
+    pub fn poll(&mut self, node: &mut Node) {
+        for subscriber in self.subscribers.iter_mut() {
+            let messages = match subscriber.connection.read_messages() {
+                Ok(messages) => messages,
+                Err(err) => {
+                    eprintln!("[ERROR] {}", err);
+                    subscriber.connection.closed = true;
+                    continue;
+                }
+            };
+            for message in messages {
+                let next_id = &mut self.next_id;
+                let response = handle_batch(message.as_bytes(), |request| {
+                    subscriber.handle_request(node, next_id, request)
+                });
+                if let Err(err) = subscriber.connection.send_text(&response) {
+                    eprintln!("[ERROR] {}", err);
+                    break;
+                }
+            }
+        }
+        self.subscribers
+            .retain(|subscriber| !subscriber.connection.closed);
+    }
+
+    /// Notify the subscriptions of the mined block
+    pub fn notify(&mut self, header: &Header, receipts: &[TransactionReceipt]) {
+        let head = header_json(header);
+        for subscriber in self.subscribers.iter_mut() {
+            let mut notifications = Vec::new();
+            for (id, subscription) in &subscriber.subscriptions {
+                match subscription {
+                    Subscription::NewHeads => notifications.push(notification(id, head.clone())),
+                    Subscription::Logs(filter) => {
+                        for receipt in receipts {
+                            for log in receipt.logs.iter().filter(|log| filter.matches(log)) {
+                                notifications.push(notification(id, log_json(receipt, log)));
+                            }
+                        }
+                    }
+                }
+            }
+            for notification in notifications {
+                if let Err(err) = subscriber.connection.send_text(&notification.to_string()) {
+                    eprintln!("[ERROR] {}", err);
+                    break;
+                }
+            }
+        }
+        self.subscribers
+            .retain(|subscriber| !subscriber.connection.closed);
+    }
+}
+
+impl Subscriber {
+    // The other methods are handled by the node
+    fn handle_request(&mut self, node: &mut Node, next_id: &mut u64, request: &Value) -> Value {
+        let method = request.get("method").and_then(Value::as_str);
+        if method != Some("eth_subscribe") && method != Some("eth_unsubscribe") {
+            return node.handle_request(request);
+        }
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let params = request
+            .get("params")
+            .and_then(Value::as_array)
+            .map(|params| params.as_slice())
+            .unwrap_or(&[]);
+        let result = if method == Some("eth_subscribe") {
+            self.subscribe(node, next_id, params)
+        } else {
+            param_str(params, 0).map(|id| json!(self.subscriptions.remove(id).is_some()))
+        };
+        response(id, result)
+    }
+
+    fn subscribe(
+        &mut self,
+        node: &Node,
+        next_id: &mut u64,
+        params: &[Value],
+    ) -> Result<Value, RpcError> {
+        let subscription = match param_str(params, 0)? {
+            "newHeads" => Subscription::NewHeads,
+            "logs" => {
+                let filter = params.get(1).unwrap_or(&Value::Null);
+                Subscription::Logs(LogFilter::parse(filter, node.latest_block())?)
+            }
+            kind => {
+                return Err(RpcError::invalid_params(format!(
+                    "Unsupported subscription: {}",
+                    kind
+                )))
+            }
+        };
+        *next_id += 1;
+        let id = format!("{:#x}", next_id);
+        self.subscriptions.insert(id.clone(), subscription);
+        Ok(json!(id))
+    }
+}
+
+fn notification(id: &str, result: Value) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "eth_subscription",
+        "params": { "subscription": id, "result": result },
+    })
+}
+
+pub fn header_json(header: &Header) -> Value {
+    let mut head = json!({
+        "number": format!("{:#x}", header.number),
+        "hash": Bytes32(header.hash),
+        "parentHash": Bytes32(header.parent_hash),
+        "sha3Uncles": Bytes32(header.uncles_hash),
+        "miner": header.coinbase,
+        "stateRoot": Bytes32(header.state_root),
+        "transactionsRoot": Bytes32(header.transactions_root),
+        "receiptsRoot": Bytes32(header.receipts_root),
+        "logsBloom": format!("0x{}", hex::encode(&header.logs_bloom)),
+        "difficulty": quantity(&u256_to_bytes(header.difficulty)),
+        "gasLimit": format!("{:#x}", header.gas_limit),
+        "gasUsed": format!("{:#x}", header.gas_used),
+        "timestamp": format!("{:#x}", header.timestamp),
+        "extraData": format!("0x{}", hex::encode(&header.extra_data)),
+        "mixHash": Bytes32([0u8; 32]),
+        "nonce": "0x0000000000000000",
+    });
+    if let Some(base_fee) = header.base_fee {
+        head["baseFeePerGas"] = json!(quantity(&u256_to_bytes(base_fee)));
+    }
+    head
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{TcpListener, TcpStream};
+
+    use ethereum_types::U256;
+
+    use crate::evmc::Address;
+    use crate::http::Request;
+    use crate::receipt::ReceiptLog;
+    use crate::JsonBytes;
+
+    // The subscriber of the upgraded connection, and the client stream after
+    // the handshake response
+    fn subscriber() -> (Subscriber, BufReader<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut headers = HashMap::new();
+        headers.insert(
+            "sec-websocket-key".to_string(),
+            "dGhlIHNhbXBsZSBub25jZQ==".to_string(),
+        );
+        let request = Request {
+            method: "GET".to_string(),
+            headers,
+            body: Vec::new(),
+        };
+        let subscriber = Subscriber {
+            connection: Connection::accept(stream, &request).unwrap(),
+            subscriptions: BTreeMap::new(),
+        };
+        let mut client = BufReader::new(client);
+        loop {
+            let mut line = String::new();
+            client.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+        }
+        (subscriber, client)
+    }
+
+    // A text frame of the server, not masked
+    fn read_text(client: &mut BufReader<TcpStream>) -> Value {
+        let mut head = [0u8; 2];
+        client.read_exact(&mut head).unwrap();
+        assert_eq!(head[0], 0x81);
+        let length = match head[1] {
+            126 => {
+                let mut bytes = [0u8; 2];
+                client.read_exact(&mut bytes).unwrap();
+                u16::from_be_bytes(bytes) as usize
+            }
+            length => length as usize,
+        };
+        let mut payload = vec![0u8; length];
+        client.read_exact(&mut payload).unwrap();
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    fn word(byte: u8) -> Bytes32 {
+        let mut word = Bytes32::default();
+        word.0[31] = byte;
+        word
+    }
+
+    fn log(address: u8, topics: &[u8], log_index: u64) -> ReceiptLog {
+        ReceiptLog {
+            address: Address([address; 20]),
+            topics: topics.iter().map(|topic| word(*topic)).collect(),
+            data: JsonBytes(Vec::new()),
+            log_index,
+        }
+    }
+
+    fn header() -> Header {
+        Header {
+            parent_hash: [0u8; 32],
+            uncles_hash: [0u8; 32],
+            coinbase: Address::default(),
+            state_root: [0u8; 32],
+            transactions_root: [0u8; 32],
+            receipts_root: [0u8; 32],
+            logs_bloom: vec![0u8; 256],
+            difficulty: U256::zero(),
+            number: 1,
+            gas_limit: 30_000_000,
+            gas_used: 0,
+            timestamp: 0,
+            extra_data: Vec::new(),
+            base_fee: None,
+            withdrawals_root: None,
+            hash: [0u8; 32],
+        }
+    }
+
+    #[test]
+    fn notify_matching_logs() {
+        let (mut subscriber, mut client) = subscriber();
+        // The logs of the address 1, with any first topic and the topic 7 or 8 next
+        let filter = json!({
+            "address": Address([1u8; 20]),
+            "topics": [null, [word(7), word(8)]],
+        });
+        let filter = LogFilter::parse(&filter, 0).unwrap();
+        subscriber
+            .subscriptions
+            .insert("0x1".to_string(), Subscription::Logs(filter));
+        let mut subscribers = Subscribers::default();
+        subscribers.subscribers.push(subscriber);
+
+        let receipt = TransactionReceipt {
+            transaction_hash: Bytes32::default(),
+            transaction_index: 0,
+            block_number: 1,
+            block_hash: None,
+            from: Address::default(),
+            to: None,
+            status: "EVMC_SUCCESS".to_string(),
+            success: true,
+            gas_used: 0,
+            cumulative_gas_used: 0,
+            contract_address: None,
+            logs: vec![
+                log(1, &[5, 7], 0),
+                log(2, &[5, 7], 1),
+                log(1, &[5, 9], 2),
+                log(1, &[5], 3),
+                log(1, &[6, 8, 10], 4),
+            ],
+            logs_bloom: JsonBytes(vec![0u8; 256]),
+        };
+        subscribers.notify(&header(), &[receipt]);
+
+        for log_index in &["0x0", "0x4"] {
+            let notification = read_text(&mut client);
+            assert_eq!(notification["method"], json!("eth_subscription"));
+            assert_eq!(notification["params"]["subscription"], json!("0x1"));
+            assert_eq!(
+                notification["params"]["result"]["logIndex"],
+                json!(log_index)
+            );
+        }
+        // Nothing else is sent
+        client.get_ref().set_nonblocking(true).unwrap();
+        let mut rest = [0u8; 1];
+        assert!(client.read(&mut rest).is_err());
+    }
+}
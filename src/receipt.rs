@@ -0,0 +1,382 @@
+//! Transaction receipts and the logs bloom
+
+use keccak_hash::keccak;
+use rlp::RlpStream;
+use serde::{Deserialize, Serialize};
+
+use crate::evmc::{Address, Bytes32, ExecutionMessage, ExecutionResult, StatusCode};
+use crate::host_trace::HostTracer;
+use crate::report::ReportLog;
+use crate::transaction::collect_logs;
+use crate::{JsonBytes, TestHostContext};
+
+/// The receipt of a `create`/`call` (or transaction) stored in the state file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionReceipt {
+    pub transaction_hash: Bytes32,
+    /// The index of the transaction in the block
+    pub transaction_index: u64,
+    pub block_number: u64,
+    /// Set when the block is sealed by `apply-block`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_hash: Option<Bytes32>,
+    pub from: Address,
+    pub to: Option<Address>,
+    pub status: String,
+    pub success: bool,
+    /// The gas used, with the intrinsic gas of a transaction (a `create`/`call`
+    /// message has none)
+    pub gas_used: u64,
+    /// The gas used by this and the previous transactions of the block
+    pub cumulative_gas_used: u64,
+    pub contract_address: Option<Address>,
+    pub logs: Vec<ReceiptLog>,
+    pub logs_bloom: JsonBytes,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiptLog {
+    pub address: Address,
+    pub topics: Vec<Bytes32>,
+    pub data: JsonBytes,
+    /// The index of the log in the block
+    pub log_index: u64,
+}
+
+impl TransactionReceipt {
+    /// The receipt of the transaction, the position in the block is set by `push_receipt`
+    pub fn new(
+        transaction_hash: Bytes32,
+        from: Address,
+        to: Option<Address>,
+        status: StatusCode,
+        gas_used: u64,
+        contract_address: Option<Address>,
+        logs: Vec<ReportLog>,
+    ) -> TransactionReceipt {
+        let success = status == StatusCode::EVMC_SUCCESS;
+        TransactionReceipt {
+            transaction_hash,
+            transaction_index: 0,
+            block_number: 0,
+            block_hash: None,
+            from,
+            to,
+            status: format!("{:?}", status),
+            success,
+            gas_used,
+            cumulative_gas_used: gas_used,
+            contract_address: if success { contract_address } else { None },
+            logs_bloom: JsonBytes(logs_bloom(&logs).to_vec()),
+            logs: logs
+                .into_iter()
+                .map(|log| ReceiptLog {
+                    address: log.address,
+                    topics: log.log.topics,
+                    data: log.log.data,
+                    log_index: 0,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Append the receipt after the receipts of the current block
+pub fn push_receipt(
+    context: &mut TestHostContext,
+    mut receipt: TransactionReceipt,
+) -> &TransactionReceipt {
+    let number = context.block.number;
+    let block_receipts = context
+        .receipts
+        .iter()
+        .filter(|receipt| receipt.block_number == number);
+    let (mut index, mut gas_used, mut log_index) = (0, 0, 0);
+    for previous in block_receipts {
+        index += 1;
+        gas_used = previous.cumulative_gas_used;
+        log_index += previous.logs.len() as u64;
+    }
+    receipt.block_number = number;
+    receipt.transaction_index = index;
+    receipt.cumulative_gas_used = gas_used + receipt.gas_used;
+    for log in receipt.logs.iter_mut() {
+        log.log_index = log_index;
+        log_index += 1;
+    }
+    context.receipts.push(receipt);
+    context.receipts.last().unwrap()
+}
+
+/// Store the receipt of the top level message executed with the tracer, the
+/// logs of a failed execution are dropped. The message is not a transaction:
+/// the gas used is the execution gas only and the state is left as it is, the
+/// hash is derived from the position of the receipt.
+///
+/// No block is mined for the message: the receipt is appended to the current
+/// block of the state file, as the next transaction of it, until `apply-block`
+/// (or the RPC node) moves to the next block.
+pub fn record<'a>(
+    context: &'a mut TestHostContext,
+    tracer: &HostTracer,
+    message: &ExecutionMessage,
+    result: &ExecutionResult,
+    input: &[u8],
+) -> &'a TransactionReceipt {
+    let logs = if result.status_code == StatusCode::EVMC_SUCCESS {
+        collect_logs(tracer)
+    } else {
+        Vec::new()
+    };
+    let sender = Address::from(message.sender);
+    let destination = Address::from(message.destination);
+    let (to, contract_address) = if message.is_create() {
+        (None, Some(destination))
+    } else {
+        (Some(destination), None)
+    };
+    let hash = message_hash(
+        message,
+        input,
+        context.block.number,
+        context.receipts.len() as u64,
+    );
+    let gas_used = (message.gas - result.gas_left) as u64;
+    let receipt = TransactionReceipt::new(
+        hash,
+        sender,
+        to,
+        result.status_code,
+        gas_used,
+        contract_address,
+        logs,
+    );
+    push_receipt(context, receipt)
+}
+
+/// The hash identifying a `create`/`call` message,
+/// keccak(rlp([block number, receipt index, gas, to, value, input, from]))
+/// where the index is the one of its receipt in the state file
+pub fn message_hash(
+    message: &ExecutionMessage,
+    input: &[u8],
+    block_number: u64,
+    receipt_index: u64,
+) -> Bytes32 {
+    let mut stream = RlpStream::new_list(7);
+    stream.append(&block_number);
+    stream.append(&receipt_index);
+    stream.append(&(message.gas as u64));
+    if message.is_create() {
+        stream.append_empty_data();
+    } else {
+        stream.append(&&message.destination.bytes[..]);
+    }
+    stream.append(&&message.value.bytes[..]);
+    stream.append(&input);
+    stream.append(&&message.sender.bytes[..]);
+    Bytes32(keccak(stream.out()).0)
+}
+
+/// The receipt as committed in the receipts trie
+#[derive(Debug, Clone)]
+pub struct Receipt {
+    /// The type of the transaction, 0 for legacy transactions
+    pub tx_type: u8,
+    /// The intermediate state root before Byzantium
+    pub post_state: Option<[u8; 32]>,
+    pub success: bool,
+    pub cumulative_gas_used: u64,
+    pub logs: Vec<ReportLog>,
+}
+
+impl Receipt {
+    pub fn bloom(&self) -> [u8; 256] {
+        logs_bloom(&self.logs)
+    }
+
+    /// rlp([status or post state, cumulative gas, bloom, logs]), typed
+    /// receipts are prefixed by the transaction type (EIP-2718)
+    pub fn encode(&self) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(4);
+        match self.post_state.as_ref() {
+            Some(root) => stream.append(&&root[..]),
+            None if self.success => stream.append(&1u8),
+            None => stream.append_empty_data(),
+        };
+        stream.append(&self.cumulative_gas_used);
+        stream.append(&&self.bloom()[..]);
+        append_logs(&mut stream, &self.logs);
+        let mut encoded = Vec::new();
+        if self.tx_type != 0 {
+            encoded.push(self.tx_type);
+        }
+        encoded.extend(stream.out());
+        encoded
+    }
+}
+
+/// Append the logs as rlp([[address, [topics], data], ..])
+pub fn append_logs(stream: &mut RlpStream, logs: &[ReportLog]) {
+    stream.begin_list(logs.len());
+    for log in logs {
+        stream.begin_list(3);
+        stream.append(&&log.address.0[..]);
+        stream.begin_list(log.log.topics.len());
+        for topic in &log.log.topics {
+            stream.append(&&topic.0[..]);
+        }
+        stream.append(&log.log.data.0);
+    }
+}
+
+/// The 2048 bits bloom filter of the addresses and topics of the logs
+pub fn logs_bloom(logs: &[ReportLog]) -> [u8; 256] {
+    let mut bloom = [0u8; 256];
+    for log in logs {
+        accrue(&mut bloom, &log.address.0);
+        for topic in &log.log.topics {
+            accrue(&mut bloom, &topic.0);
+        }
+    }
+    bloom
+}
+
+// Set the 3 bits selected by the first 6 bytes of the hash
+fn accrue(bloom: &mut [u8; 256], input: &[u8]) {
+    let hash = keccak(input).0;
+    for pair in hash[..6].chunks(2) {
+        let bit = ((pair[0] as usize) << 8 | pair[1] as usize) & 2047;
+        bloom[255 - bit / 8] |= 1 << (bit % 8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evmc::{CallKind, Uint256};
+    use crate::host_trace::TraceSink;
+    use crate::LogEntry;
+    use evmc_sys as ffi;
+
+    fn log(address: &str, topics: &[&str]) -> ReportLog {
+        let mut log_address = Address::default();
+        log_address
+            .0
+            .copy_from_slice(&hex::decode(address).unwrap());
+        ReportLog {
+            address: log_address,
+            log: LogEntry {
+                data: JsonBytes(Vec::new()),
+                topics: topics
+                    .iter()
+                    .map(|topic| {
+                        let mut word = Bytes32::default();
+                        word.0.copy_from_slice(&hex::decode(topic).unwrap());
+                        word
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    fn receipt(gas_used: u64, logs: usize) -> TransactionReceipt {
+        let logs = (0..logs)
+            .map(|_| log("ef2d6d194084c2de36e0dabfce45d046b37d1106", &[]))
+            .collect();
+        TransactionReceipt::new(
+            Bytes32::default(),
+            Address::default(),
+            None,
+            StatusCode::EVMC_SUCCESS,
+            gas_used,
+            None,
+            logs,
+        )
+    }
+
+    #[test]
+    fn bloom_of_mainnet_log() {
+        let logs = vec![log(
+            "ef2d6d194084c2de36e0dabfce45d046b37d1106",
+            &["02c69be41d0b7e40352fc85be1cd65eb03d40ef8427a0ca4596b1ead9a00e9fc"],
+        )];
+        let expected = hex::decode(
+            "00000000000000000000000000000000000000001000000000000000000000000000000000000000\
+             00000000000000000000000000000000000000000000000000000000000000000000000000000000\
+             00000000000000000000000000000000000000020200000000000000000000000000000000000000\
+             00000008000000001000000000000000000000000000000000000000000000000000001000000000\
+             00000000000000000000000000000000000000000000000000000000000000000000000000000000\
+             00000000000000000000000000000000000000000000000000000000000000000000000000000000\
+             00000000000000000000000000000000",
+        )
+        .unwrap();
+        assert_eq!(logs_bloom(&logs).to_vec(), expected);
+        assert_eq!(logs_bloom(&[]).to_vec(), vec![0u8; 256]);
+    }
+
+    #[test]
+    fn cumulative_gas_and_log_index() {
+        let mut context = TestHostContext::new(0, Address::default());
+        push_receipt(&mut context, receipt(21000, 2));
+        let second = push_receipt(&mut context, receipt(30000, 1));
+        assert_eq!(second.transaction_index, 1);
+        assert_eq!(second.cumulative_gas_used, 51000);
+        assert_eq!(second.logs[0].log_index, 2);
+
+        context.block.number += 1;
+        let next_block = push_receipt(&mut context, receipt(25000, 1));
+        assert_eq!(next_block.transaction_index, 0);
+        assert_eq!(next_block.cumulative_gas_used, 25000);
+        assert_eq!(next_block.logs[0].log_index, 0);
+        let first = &context.receipts[0];
+        assert_eq!(
+            first
+                .logs
+                .iter()
+                .map(|log| log.log_index)
+                .collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+    }
+
+    #[test]
+    fn message_hash_of_position() {
+        let raw_message = ffi::evmc_message {
+            kind: CallKind::EVMC_CALL,
+            flags: 0,
+            depth: 0,
+            gas: 100_000,
+            destination: Address([1u8; 20]).into(),
+            sender: Address([2u8; 20]).into(),
+            input_data: std::ptr::null(),
+            input_size: 0,
+            value: Uint256::default().into(),
+            create2_salt: Bytes32::default().into(),
+        };
+        let message = ExecutionMessage::from(&raw_message);
+        let hash = message_hash(&message, &[1, 2], 1, 0);
+        assert_eq!(message_hash(&message, &[1, 2], 1, 0), hash);
+        assert_ne!(message_hash(&message, &[1, 2], 1, 1), hash);
+        assert_ne!(message_hash(&message, &[1, 2], 2, 0), hash);
+        assert_ne!(message_hash(&message, &[1, 3], 1, 0), hash);
+
+        // The receipts of the same message differ, the state is unchanged
+        let mut context = TestHostContext::new(0, Address::default());
+        let tracer = HostTracer::recording(TraceSink::Quiet);
+        let result = ExecutionResult {
+            status_code: StatusCode::EVMC_SUCCESS,
+            gas_left: 60_000,
+            output_data: Vec::new(),
+            release: None,
+            create_address: Address::default(),
+            padding: [0u8; 4],
+        };
+        let first = record(&mut context, &tracer, &message, &result, &[1, 2]).clone();
+        let second = record(&mut context, &tracer, &message, &result, &[1, 2]);
+        assert_ne!(first.transaction_hash, second.transaction_hash);
+        assert_eq!(second.gas_used, 40_000);
+        assert_eq!(second.cumulative_gas_used, 80_000);
+        assert!(context.accounts.is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+//! The replay of the instruction trace shared by the trace output and the
+//! debugger: the VM tracer reports the stack and the memory size only, the
+//! memory is rebuilt from the instructions writing it.
+
+use crate::evmc::Address;
+use crate::trace::TraceStep;
+
+pub type Word = [u8; 32];
+
+pub const OP_CALLDATACOPY: u8 = 0x37;
+pub const OP_CODECOPY: u8 = 0x39;
+pub const OP_EXTCODECOPY: u8 = 0x3c;
+pub const OP_RETURNDATACOPY: u8 = 0x3e;
+pub const OP_MSTORE: u8 = 0x52;
+pub const OP_MSTORE8: u8 = 0x53;
+pub const OP_SLOAD: u8 = 0x54;
+pub const OP_SSTORE: u8 = 0x55;
+pub const OP_MCOPY: u8 = 0x5e;
+pub const OP_CREATE: u8 = 0xf0;
+pub const OP_CALL: u8 = 0xf1;
+pub const OP_CALLCODE: u8 = 0xf2;
+pub const OP_DELEGATECALL: u8 = 0xf4;
+pub const OP_CREATE2: u8 = 0xf5;
+pub const OP_STATICCALL: u8 = 0xfa;
+
+/// The data of a call frame the instructions copy into its memory
+pub struct FrameData<'a> {
+    pub code: &'a [u8],
+    pub input: &'a [u8],
+    pub return_data: &'a [u8],
+}
+
+/// The stack items of the step, the top first
+pub fn stack_args(step: &TraceStep) -> Vec<Word> {
+    step.stack
+        .iter()
+        .rev()
+        .map(|item| parse_word(item))
+        .collect()
+}
+
+/// Apply the memory write of the step. The output of a call is written when
+/// the call returns, see `call_output_range`.
+pub fn apply_memory_write<F: Fn(&Address) -> Vec<u8>>(
+    memory: &mut Vec<u8>,
+    step: &TraceStep,
+    data: &FrameData,
+    code_of: F,
+) {
+    let args = stack_args(step);
+    let arg = |index: usize| args.get(index).cloned().unwrap_or_default();
+    let arg_usize = |index: usize| word_to_usize(&arg(index));
+    match step.op {
+        OP_MSTORE => write_memory(memory, arg_usize(0), &arg(1)),
+        OP_MSTORE8 => write_memory(memory, arg_usize(0), &arg(1)[31..]),
+        OP_MCOPY => {
+            let copied = copy_data(memory, arg_usize(1), arg_usize(2));
+            write_memory(memory, arg_usize(0), &copied);
+        }
+        OP_CALLDATACOPY | OP_CODECOPY | OP_RETURNDATACOPY => {
+            let source = match step.op {
+                OP_CALLDATACOPY => data.input,
+                OP_CODECOPY => data.code,
+                _ => data.return_data,
+            };
+            let copied = copy_data(source, arg_usize(1), arg_usize(2));
+            write_memory(memory, arg_usize(0), &copied);
+        }
+        OP_EXTCODECOPY => {
+            let mut address = Address::default();
+            address.0.copy_from_slice(&arg(0)[12..]);
+            let copied = copy_data(&code_of(&address), arg_usize(2), arg_usize(3));
+            write_memory(memory, arg_usize(1), &copied);
+        }
+        _ => {}
+    }
+}
+
+/// The memory (offset, size) the call writes its output to, nothing for the
+/// creates
+pub fn call_output_range(step: &TraceStep) -> (Option<usize>, Option<usize>) {
+    let args = stack_args(step);
+    let arg_usize = |index: usize| args.get(index).and_then(word_to_usize);
+    match step.op {
+        OP_CALL | OP_CALLCODE => (arg_usize(5), arg_usize(6)),
+        OP_DELEGATECALL | OP_STATICCALL => (arg_usize(4), arg_usize(5)),
+        _ => (Some(0), Some(0)),
+    }
+}
+
+/// Stack items are written as 0x prefixed hex without the leading zeros
+pub fn parse_word(item: &str) -> Word {
+    let digits = item.trim_start_matches("0x");
+    let digits = if digits.len() % 2 == 1 {
+        format!("0{}", digits)
+    } else {
+        digits.to_string()
+    };
+    let bytes = hex::decode(digits).unwrap_or_default();
+    let mut word = Word::default();
+    let len = bytes.len().min(32);
+    word[32 - len..].copy_from_slice(&bytes[bytes.len() - len..]);
+    word
+}
+
+/// None if the value is too large to be a memory offset or size
+pub fn word_to_usize(word: &Word) -> Option<usize> {
+    if word[..24].iter().any(|b| *b != 0) {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&word[24..]);
+    let value = u64::from_be_bytes(bytes);
+    if value > u32::MAX as u64 {
+        None
+    } else {
+        Some(value as usize)
+    }
+}
+
+/// `size` bytes of the source from the offset, zero padded
+pub fn copy_data(source: &[u8], offset: Option<usize>, size: Option<usize>) -> Vec<u8> {
+    let size = match size {
+        Some(size) => size,
+        None => return Vec::new(),
+    };
+    let mut data = vec![0u8; size];
+    if let Some(offset) = offset {
+        if offset < source.len() {
+            let len = size.min(source.len() - offset);
+            data[..len].copy_from_slice(&source[offset..offset + len]);
+        }
+    }
+    data
+}
+
+/// Write the data at the offset, the memory is expanded to hold it
+pub fn write_memory(memory: &mut Vec<u8>, offset: Option<usize>, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    if let Some(offset) = offset {
+        if memory.len() < offset + data.len() {
+            memory.resize(offset + data.len(), 0);
+        }
+        memory[offset..offset + data.len()].copy_from_slice(data);
+    }
+}
@@ -0,0 +1,135 @@
+use serde::Serialize;
+
+use crate::evmc::{Address, Bytes32, ExecutionResult, StatusCode};
+use crate::host_trace::HostTracer;
+use crate::transaction::collect_logs;
+use crate::trie::state_root;
+use crate::{JsonBytes, LogEntry, TestHostContext};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportLog {
+    pub address: Address,
+    #[serde(flatten)]
+    pub log: LogEntry,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageChange {
+    pub address: Address,
+    pub key: Bytes32,
+    pub before: Bytes32,
+    pub after: Bytes32,
+}
+
+/// The machine readable result of a `create` or `call`
+#[derive(Debug, Clone, Serialize)]
+pub struct ExecutionReport {
+    pub status: String,
+    pub success: bool,
+    pub gas_used: i64,
+    pub gas_left: i64,
+    pub output: JsonBytes,
+    pub revert_reason: Option<String>,
+    pub created_address: Option<Address>,
+    pub logs: Vec<ReportLog>,
+    pub state_changes: Vec<StorageChange>,
+    /// The state root of the accounts after the execution
+    pub state_root: Bytes32,
+}
+
+impl ExecutionReport {
+    /// Build the report by comparing the context before the execution with
+    /// the context after the execution, the accounts changed are the ones
+    /// loaded after. The logs are the ones recorded by the tracer, in the
+    /// emitted order.
+    pub fn new(
+        result: &ExecutionResult,
+        gas_limit: i64,
+        created_address: Option<Address>,
+        revert_reason: Option<String>,
+        tracer: &HostTracer,
+        before: &TestHostContext,
+        after: &TestHostContext,
+    ) -> Result<ExecutionReport, String> {
+        let success = result.status_code == StatusCode::EVMC_SUCCESS;
+        let logs = if success {
+            collect_logs(tracer)
+        } else {
+            Vec::new()
+        };
+        let mut state_changes = Vec::new();
+        let mut addresses: Vec<&Address> = after.accounts.keys().collect();
+        addresses.sort_by_key(|address| address.0);
+        for address in addresses {
+            let account = &after.accounts[address];
+            let mut keys: Vec<&Bytes32> = account.storage.keys().collect();
+            keys.sort_by_key(|key| key.0);
+            for key in keys {
+                let value = &account.storage[key].data;
+                let old_value = before
+                    .stored_value(address, key)
+                    .map(|value| value.data)
+                    .unwrap_or_default();
+                if &old_value != value {
+                    state_changes.push(StorageChange {
+                        address: address.clone(),
+                        key: key.clone(),
+                        before: old_value,
+                        after: value.clone(),
+                    });
+                }
+            }
+        }
+        Ok(ExecutionReport {
+            status: format!("{:?}", result.status_code),
+            success,
+            gas_used: gas_limit - result.gas_left,
+            gas_left: result.gas_left,
+            output: JsonBytes(result.output_data.clone()),
+            revert_reason,
+            created_address,
+            logs,
+            state_changes,
+            state_root: Bytes32(state_root(after)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host_trace::{HostEvent, TraceSink};
+
+    #[test]
+    fn logs_in_emitted_order() {
+        let tracer = HostTracer::recording(TraceSink::Quiet);
+        // Emitted by the accounts in the reverse order of their addresses
+        for byte in &[2u8, 1u8, 2u8] {
+            tracer.record(HostEvent::Log {
+                depth: 0,
+                address: Address([*byte; 20]),
+                topics: Vec::new(),
+                data: JsonBytes(vec![*byte]),
+            });
+        }
+        let mut result = ExecutionResult {
+            status_code: StatusCode::EVMC_SUCCESS,
+            gas_left: 10,
+            output_data: Vec::new(),
+            release: None,
+            create_address: Address::default(),
+            padding: [0u8; 4],
+        };
+        let context = TestHostContext::new(0, Address::default());
+        let report =
+            ExecutionReport::new(&result, 100, None, None, &tracer, &context, &context).unwrap();
+        let addresses: Vec<u8> = report.logs.iter().map(|log| log.address.0[0]).collect();
+        assert_eq!(addresses, vec![2, 1, 2]);
+        assert_eq!(report.gas_used, 90);
+
+        result.status_code = StatusCode::EVMC_REVERT;
+        let report =
+            ExecutionReport::new(&result, 100, None, None, &tracer, &context, &context).unwrap();
+        assert!(report.logs.is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+use ethabi::param_type::{ParamType, Reader};
+use ethabi::{decode, Token};
+use keccak_hash::keccak;
+use serde_json::Value;
+
+use crate::evmc::{ExecutionResult, StatusCode};
+
+/// Selector of `Error(string)`
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+/// Selector of `Panic(uint256)`
+const PANIC_SELECTOR: [u8; 4] = [0x4e, 0x48, 0x7b, 0x71];
+
+/// A custom error (`error Name(...)`) defined in the contract abi
+#[derive(Debug, Clone)]
+pub struct CustomError {
+    pub name: String,
+    pub inputs: Vec<(String, ParamType)>,
+    pub selector: [u8; 4],
+}
+
+/// Load all the custom errors from an abi file
+pub fn load_errors(abi: &[u8]) -> Result<Vec<CustomError>, String> {
+    let entries: Vec<Value> = serde_json::from_slice(abi).map_err(|err| err.to_string())?;
+    let mut errors = Vec::new();
+    for entry in entries {
+        if entry.get("type").and_then(Value::as_str) != Some("error") {
+            continue;
+        }
+        let name = entry
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| "Error without name in abi".to_string())?
+            .to_string();
+        let mut inputs = Vec::new();
+        let mut types = Vec::new();
+        if let Some(params) = entry.get("inputs").and_then(Value::as_array) {
+            for param in params {
+                let param_name = param
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string();
+                let type_name = canonical_type(param)?;
+                let kind = Reader::read(&type_name).map_err(|e| format!("{}", e))?;
+                inputs.push((param_name, kind));
+                types.push(type_name);
+            }
+        }
+        let signature = format!("{}({})", name, types.join(","));
+        let mut selector = [0u8; 4];
+        selector.copy_from_slice(&keccak(signature.as_bytes()).0[0..4]);
+        errors.push(CustomError {
+            name,
+            inputs,
+            selector,
+        });
+    }
+    Ok(errors)
+}
+
+// The type used in signature, tuples are expanded from their components
+fn canonical_type(param: &Value) -> Result<String, String> {
+    let type_name = param
+        .get("type")
+        .and_then(Value::as_str)
+        .ok_or_else(|| "Param without type in abi".to_string())?;
+    if let Some(suffix) = type_name.strip_prefix("tuple") {
+        let components = param
+            .get("components")
+            .and_then(Value::as_array)
+            .ok_or_else(|| "Tuple without components in abi".to_string())?
+            .iter()
+            .map(canonical_type)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(format!("({}){}", components.join(","), suffix))
+    } else {
+        Ok(type_name.to_string())
+    }
+}
+
+/// The meaning of the panic code emitted by solidity compiler
+pub fn panic_reason(code: u8) -> &'static str {
+    match code {
+        0x00 => "generic compiler inserted panic",
+        0x01 => "assertion failed",
+        0x11 => "arithmetic underflow or overflow",
+        0x12 => "division or modulo by zero",
+        0x21 => "conversion into invalid enum value",
+        0x22 => "access to incorrectly encoded storage byte array",
+        0x31 => "pop() on an empty array",
+        0x32 => "array index out of bounds",
+        0x41 => "too much memory allocated",
+        0x51 => "call to zero-initialized internal function",
+        _ => "unknown panic code",
+    }
+}
+
+/// Decode the revert data, return `None` when it is empty or unknown
+pub fn decode_revert(output: &[u8], errors: &[CustomError]) -> Option<String> {
+    if output.len() < 4 {
+        return None;
+    }
+    let (selector, data) = output.split_at(4);
+    if selector == ERROR_SELECTOR {
+        match decode(&[ParamType::String], data).ok()?.pop()? {
+            Token::String(message) => Some(format!("Error({:?})", message)),
+            _ => None,
+        }
+    } else if selector == PANIC_SELECTOR {
+        if data.len() != 32 || data[0..31].iter().any(|byte| *byte != 0) {
+            return Some(format!(
+                "Panic(0x{}: unknown panic code)",
+                hex::encode(data)
+            ));
+        }
+        Some(format!(
+            "Panic(0x{:02x}: {})",
+            data[31],
+            panic_reason(data[31])
+        ))
+    } else {
+        let error = errors.iter().find(|error| error.selector == selector)?;
+        let types: Vec<ParamType> = error.inputs.iter().map(|(_, kind)| kind.clone()).collect();
+        let tokens = decode(&types, data).ok()?;
+        let params = error
+            .inputs
+            .iter()
+            .zip(tokens.iter())
+            .map(|((name, _), token)| format!("{}: {}", name, token))
+            .collect::<Vec<String>>();
+        Some(format!("{}({})", error.name, params.join(", ")))
+    }
+}
+
+/// The decoded revert reason of a reverted execution
+pub fn revert_reason(result: &ExecutionResult, errors: &[CustomError]) -> Option<String> {
+    if result.status_code != StatusCode::EVMC_REVERT {
+        return None;
+    }
+    decode_revert(&result.output_data, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethabi::{encode, Address, Uint as U256};
+    use serde_json::json;
+
+    fn with_selector(selector: [u8; 4], tokens: &[Token]) -> Vec<u8> {
+        let mut output = selector.to_vec();
+        output.extend(encode(tokens));
+        output
+    }
+
+    fn abi() -> Vec<u8> {
+        serde_json::to_vec(&json!([
+            { "type": "function", "name": "run", "inputs": [], "outputs": [] },
+            {
+                "type": "error",
+                "name": "Failed",
+                "inputs": [
+                    { "name": "code", "type": "uint256" },
+                    {
+                        "name": "info",
+                        "type": "tuple",
+                        "components": [
+                            { "name": "who", "type": "address" },
+                            { "name": "amount", "type": "uint256" }
+                        ]
+                    }
+                ]
+            }
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn decode_error_and_panic() {
+        let output = with_selector(ERROR_SELECTOR, &[Token::String("boom".to_string())]);
+        assert_eq!(decode_revert(&output, &[]).unwrap(), "Error(\"boom\")");
+
+        let output = with_selector(PANIC_SELECTOR, &[Token::Uint(U256::from(0x11))]);
+        assert_eq!(
+            decode_revert(&output, &[]).unwrap(),
+            "Panic(0x11: arithmetic underflow or overflow)"
+        );
+        let output = with_selector(PANIC_SELECTOR, &[Token::Uint(U256::from(0x1234))]);
+        assert_eq!(
+            decode_revert(&output, &[]).unwrap(),
+            format!("Panic(0x{:064x}: unknown panic code)", 0x1234)
+        );
+    }
+
+    #[test]
+    fn decode_custom_error_with_tuple() {
+        let param = json!({
+            "type": "tuple[]",
+            "components": [{ "type": "address" }, { "type": "uint256" }]
+        });
+        assert_eq!(canonical_type(&param).unwrap(), "(address,uint256)[]");
+
+        let errors = load_errors(&abi()).unwrap();
+        assert_eq!(errors.len(), 1);
+        // keccak("Failed(uint256,(address,uint256))")
+        assert_eq!(errors[0].selector, [0xc8, 0xce, 0xbf, 0xd1]);
+        let output = with_selector(
+            errors[0].selector,
+            &[
+                Token::Uint(U256::from(7)),
+                Token::Tuple(vec![
+                    Token::Address(Address::repeat_byte(0x11)),
+                    Token::Uint(U256::from(42)),
+                ]),
+            ],
+        );
+        assert_eq!(
+            decode_revert(&output, &errors).unwrap(),
+            format!("Failed(code: 7, info: ({},2a))", "11".repeat(20))
+        );
+        // Unknown without the abi
+        assert!(decode_revert(&output, &[]).is_none());
+    }
+
+    #[test]
+    fn truncated_revert_data() {
+        assert!(decode_revert(&[], &[]).is_none());
+        assert!(decode_revert(&ERROR_SELECTOR[..3], &[]).is_none());
+        let output = with_selector(ERROR_SELECTOR, &[Token::String("boom".to_string())]);
+        assert!(decode_revert(&output[..output.len() - 32], &[]).is_none());
+
+        let output = with_selector(PANIC_SELECTOR, &[Token::Uint(U256::from(0x11))]);
+        assert_eq!(
+            decode_revert(&output[..20], &[]).unwrap(),
+            format!(
+                "Panic(0x{}: unknown panic code)",
+                hex::encode(&output[4..20])
+            )
+        );
+
+        let errors = load_errors(&abi()).unwrap();
+        let output = with_selector(errors[0].selector, &[Token::Uint(U256::from(7))]);
+        assert!(decode_revert(&output, &errors).is_none());
+    }
+}
@@ -0,0 +1,1121 @@
+//! Ethereum JSON-RPC over HTTP and WebSocket backed by the state file, the
+//! transactions are mined from the mempool by automine, interval or evm_mine
+
+use std::collections::{BTreeMap, HashSet};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::{App, Arg, ArgMatches};
+use ethereum_types::U256;
+use evmc_sys as ffi;
+use keccak_hash::keccak;
+use serde_json::{json, Value};
+
+use crate::apply_block::{advance, BlockBuilder};
+use crate::block::Header;
+use crate::dev_rpc::Snapshot;
+use crate::evmc::{
+    parse_revision, Address, Bytes32, CallKind, EvmcVm, ExecutionMessage, Revision, StatusCode,
+    Uint256,
+};
+use crate::http::{read_request, write_response, Request};
+use crate::loader::create_vm;
+use crate::mempool::{Mempool, MiningMode};
+use crate::proof::quantity;
+use crate::pubsub::Subscribers;
+use crate::receipt::{ReceiptLog, TransactionReceipt};
+use crate::state_db::{self, JournalDb, MemoryDb};
+use crate::statetest::{parse_address, parse_hex, parse_u64, parse_word};
+use crate::transaction::{
+    balance_of, base_fee, create_address, decode_transaction, impersonated_transaction, nonce_of,
+    parse_u256, secret_key_address, set_balance, sign_transaction, u256_to_bytes,
+    SignedTransaction, Transaction,
+};
+use crate::ws::{is_upgrade, Connection};
+use crate::{revert, TestHostContext, TxEnv};
+
+const DEFAULT_GAS: u64 = 10_000_000;
+/// The chain id of the dev chain if the state file has none
+const DEV_CHAIN_ID: u64 = 31337;
+/// The time to read a request, or to write a response
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// The most connections whose request is read at once
+const MAX_CONNECTIONS: usize = 64;
+/// How often the WebSocket connections are polled
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A JSON-RPC error object
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: String) -> RpcError {
+        RpcError {
+            code,
+            message,
+            data: None,
+        }
+    }
+
+    pub fn invalid_params(message: String) -> RpcError {
+        RpcError::new(-32602, message)
+    }
+}
+
+impl From<String> for RpcError {
+    fn from(message: String) -> RpcError {
+        RpcError::new(-32000, message)
+    }
+}
+
+/// The sub command to serve the JSON-RPC
+pub fn sub_command(name: &'static str) -> App<'static, 'static> {
+    App::new(name)
+        .about("Serve the Ethereum JSON-RPC over HTTP and WebSocket, the state is persisted to the storage file")
+        .arg(
+            Arg::with_name("input-storage")
+                .long("input-storage")
+                .short("s")
+                .takes_value(true)
+                .help("The storage (accounts) json file or .kv key-value store, created if not exists"),
+        )
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .takes_value(true)
+                .default_value("127.0.0.1")
+                .help("The address to listen on"),
+        )
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .short("p")
+                .takes_value(true)
+                .default_value("8545")
+                .help("The port to listen on"),
+        )
+        .arg(
+            Arg::with_name("revision")
+                .long("revision")
+                .short("r")
+                .takes_value(true)
+                .default_value("berlin")
+                .help("The EVM revision"),
+        )
+        .arg(
+            Arg::with_name("chain-id")
+                .long("chain-id")
+                .takes_value(true)
+                .help("The chain id (the one of the storage file, or 31337 by default)"),
+        )
+        .arg(
+            Arg::with_name("mining")
+                .long("mining")
+                .takes_value(true)
+                .possible_values(&["auto", "interval", "manual"])
+                .default_value("auto")
+                .help("Mine a block for every transaction, every block time, or only by evm_mine"),
+        )
+        .arg(
+            Arg::with_name("block-time")
+                .long("block-time")
+                .takes_value(true)
+                .default_value("12")
+                .help("The seconds between the blocks of the interval mining"),
+        )
+        .arg(
+            Arg::with_name("accounts")
+                .long("accounts")
+                .takes_value(true)
+                .default_value("10")
+                .help("The number of the unlocked dev accounts"),
+        )
+        .arg(
+            Arg::with_name("private-key")
+                .long("private-key")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Unlock the account of the private key (hex)"),
+        )
+        .arg(
+            Arg::with_name("balance")
+                .long("balance")
+                .takes_value(true)
+                .default_value("10000000000000000000000")
+                .help("The balance (wei) of the new dev accounts"),
+        )
+}
+
+pub fn process(sub_matches: &ArgMatches) -> Result<(), String> {
+    let vm = Rc::new(create_vm(sub_matches.value_of("vm"))?);
+    let revision = parse_revision(sub_matches.value_of("revision").unwrap())?;
+    let path = sub_matches.value_of("input-storage");
+    let db = JournalDb::new(match path {
+        Some(path) => state_db::open(path)?,
+        None => Box::new(MemoryDb::default()),
+    });
+    let mut context = TestHostContext::with_db(Box::new(db.clone()), path)?;
+    match sub_matches.value_of("chain-id") {
+        Some(chain_id) => context.block.chain_id = parse_u64(chain_id)?,
+        None if context.block.chain_id == 0 => context.block.chain_id = DEV_CHAIN_ID,
+        None => {}
+    }
+
+    // The dev accounts are derived from their index
+    let count = parse_u64(sub_matches.value_of("accounts").unwrap())?;
+    let mut keys: Vec<Vec<u8>> = (0..count)
+        .map(|index| {
+            keccak(format!("play-evmone dev account {}", index))
+                .0
+                .to_vec()
+        })
+        .collect();
+    if let Some(values) = sub_matches.values_of("private-key") {
+        for value in values {
+            keys.push(parse_hex(value)?);
+        }
+    }
+    let balance = parse_u256(sub_matches.value_of("balance").unwrap())?;
+    let mining = match sub_matches.value_of("mining").unwrap() {
+        "auto" => MiningMode::Auto,
+        "interval" => {
+            let block_time = parse_u64(sub_matches.value_of("block-time").unwrap())?;
+            MiningMode::Interval(Duration::from_secs(block_time.max(1)))
+        }
+        _ => MiningMode::Manual,
+    };
+    let mut dev_accounts = Vec::new();
+    for (index, key) in keys.into_iter().enumerate() {
+        let address = secret_key_address(&key)?;
+        if context.account(&address).is_none() {
+            set_balance(&mut context, &address, balance);
+        }
+        println!(
+            "Account #{}: 0x{:?} (private key: 0x{})",
+            index,
+            address,
+            hex::encode(&key)
+        );
+        dev_accounts.push((address, key));
+    }
+
+    let mut node = Node {
+        context,
+        db,
+        vm,
+        revision,
+        dev_accounts,
+        impersonated: HashSet::new(),
+        snapshots: BTreeMap::new(),
+        next_snapshot_id: 1,
+        time_offset: 0,
+        next_timestamp: None,
+        mined: Vec::new(),
+        mempool: Mempool::default(),
+        mining,
+        last_mined: Instant::now(),
+    };
+    node.save()?;
+    let address = format!(
+        "{}:{}",
+        sub_matches.value_of("host").unwrap(),
+        sub_matches.value_of("port").unwrap()
+    );
+    let listener = TcpListener::bind(&address).map_err(|err| format!("{}: {}", address, err))?;
+    println!("Listening on http://{} and ws://{}", address, address);
+
+    // The connections are accepted by a thread, the requests are read and the
+    // responses written by a thread of each connection, so a slow client does
+    // not stall the others. The node handles the requests one by one, it
+    // waits for them unless the WebSocket connections are polled or a block
+    // is mined on the interval.
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || accept_connections(listener, sender));
+    let mut subscribers = Subscribers::default();
+    loop {
+        let wait = if !subscribers.is_empty() {
+            Some(POLL_INTERVAL)
+        } else if let MiningMode::Interval(interval) = node.mining {
+            Some(interval.saturating_sub(node.last_mined.elapsed()))
+        } else {
+            None
+        };
+        let received = match wait {
+            Some(wait) => receiver.recv_timeout(wait).ok(),
+            None => receiver.recv().ok(),
+        };
+        let requests = received
+            .into_iter()
+            .chain(std::iter::from_fn(|| receiver.try_recv().ok()));
+        for (stream, request) in requests {
+            match request.and_then(|request| serve_request(&mut node, stream, &request)) {
+                Ok(Some(connection)) => subscribers.add(connection),
+                Ok(None) => {}
+                Err(err) => eprintln!("[ERROR] {}", err),
+            }
+        }
+        subscribers.poll(&mut node);
+        if let Err(err) = node.mine_on_interval() {
+            eprintln!("[ERROR] {}", err);
+        }
+        for (header, receipts) in node.mined.drain(..) {
+            subscribers.notify(&header, &receipts);
+        }
+    }
+}
+
+type Received = (TcpStream, Result<Request, String>);
+
+// Read the request of each connection by its own thread, the connections over
+// the limit are closed at once
+fn accept_connections(listener: TcpListener, sender: mpsc::Sender<Received>) {
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("[ERROR] {}", err);
+                continue;
+            }
+        };
+        if open.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+            eprintln!(
+                "[ERROR] Too many connections: at most {} are read at once",
+                MAX_CONNECTIONS
+            );
+            continue;
+        }
+        open.fetch_add(1, Ordering::SeqCst);
+        let open = open.clone();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let request = stream
+                .set_read_timeout(Some(IO_TIMEOUT))
+                .and_then(|_| stream.set_write_timeout(Some(IO_TIMEOUT)))
+                .map_err(|err| err.to_string())
+                .and_then(|_| read_request(&stream));
+            open.fetch_sub(1, Ordering::SeqCst);
+            let _ = sender.send((stream, request));
+        });
+    }
+}
+
+// Serve the HTTP request, or return the connection upgraded to WebSocket
+fn serve_request(
+    node: &mut Node,
+    stream: TcpStream,
+    request: &Request,
+) -> Result<Option<Connection>, String> {
+    match request.method.as_str() {
+        "GET" if is_upgrade(request) => return Connection::accept(stream, request).map(Some),
+        "OPTIONS" => respond(stream, "204 No Content", "text/plain", Vec::new()),
+        "POST" => {
+            let response = node.handle_body(&request.body);
+            respond(stream, "200 OK", "application/json", response.into_bytes())
+        }
+        _ => respond(
+            stream,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"Only POST and WebSocket are supported".to_vec(),
+        ),
+    }
+    Ok(None)
+}
+
+// Write the response by the thread of the connection
+fn respond(mut stream: TcpStream, status: &'static str, content_type: &'static str, body: Vec<u8>) {
+    thread::spawn(move || {
+        if let Err(err) = write_response(&mut stream, status, content_type, &body) {
+            eprintln!("[ERROR] {}", err);
+        }
+    });
+}
+
+/// The chain state served by the JSON-RPC
+pub struct Node {
+    pub context: TestHostContext,
+    /// The backend of the context, journaled for the snapshots
+    pub db: JournalDb,
+    pub vm: Rc<EvmcVm>,
+    pub revision: Revision,
+    /// The unlocked accounts and their private keys
+    pub dev_accounts: Vec<(Address, Vec<u8>)>,
+    /// The accounts to send transactions from without their keys
+    pub impersonated: HashSet<Address>,
+    /// The states saved by evm_snapshot by id
+    pub snapshots: BTreeMap<u64, Snapshot>,
+    pub next_snapshot_id: u64,
+    /// The seconds added to the current time by evm_increaseTime
+    pub time_offset: i64,
+    /// The timestamp of the next block set by evm_setNextBlockTimestamp
+    pub next_timestamp: Option<u64>,
+    /// The blocks mined since the subscribers were notified
+    pub mined: Vec<(Header, Vec<TransactionReceipt>)>,
+    /// The transactions waiting to be mined
+    pub mempool: Mempool,
+    pub mining: MiningMode,
+    pub last_mined: Instant,
+}
+
+impl Node {
+    /// Handle the JSON-RPC request (or batch), return the response
+    pub fn handle_body(&mut self, body: &[u8]) -> String {
+        handle_batch(body, |request| self.handle_request(request))
+    }
+
+    pub fn handle_request(&mut self, request: &Value) -> Value {
+        dispatch(request, |method, params| self.handle(method, params))
+    }
+
+    pub fn handle(&mut self, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        match method {
+            "eth_chainId" => Ok(json!(format!("{:#x}", self.context.block.chain_id))),
+            "net_version" => Ok(json!(self.context.block.chain_id.to_string())),
+            "eth_blockNumber" => Ok(json!(format!("{:#x}", self.latest_block()))),
+            "eth_accounts" => Ok(json!(self
+                .dev_accounts
+                .iter()
+                .map(|(address, _)| address)
+                .collect::<Vec<_>>())),
+            "eth_getBalance" => {
+                let address = parse_address(param_str(params, 0)?)?;
+                self.check_block(params.get(1))?;
+                let balance = balance_of(&self.context, &address);
+                Ok(json!(quantity(&u256_to_bytes(balance))))
+            }
+            "eth_getTransactionCount" => {
+                let address = parse_address(param_str(params, 0)?)?;
+                let nonce = match params.get(1).and_then(Value::as_str) {
+                    Some("pending") => self.mempool.next_nonce(&self.context, &address),
+                    _ => {
+                        self.check_block(params.get(1))?;
+                        nonce_of(&self.context, &address)
+                    }
+                };
+                Ok(json!(format!("{:#x}", nonce)))
+            }
+            "eth_getCode" => {
+                let address = parse_address(param_str(params, 0)?)?;
+                self.check_block(params.get(1))?;
+                let code = self
+                    .context
+                    .code_of(&address)
+                    .map(|code| code.0)
+                    .unwrap_or_default();
+                Ok(json!(format!("0x{}", hex::encode(code))))
+            }
+            "eth_getStorageAt" => {
+                let address = parse_address(param_str(params, 0)?)?;
+                let key = Bytes32(parse_word(param_str(params, 1)?)?);
+                self.check_block(params.get(2))?;
+                let value = self
+                    .context
+                    .stored_value(&address, &key)
+                    .map(|value| value.data)
+                    .unwrap_or_default();
+                Ok(json!(value))
+            }
+            "eth_call" => {
+                self.check_block(params.get(1))?;
+                self.call(param(params, 0)?)
+            }
+            "eth_sendRawTransaction" => {
+                let raw = parse_hex(param_str(params, 0)?)?;
+                self.submit(decode_transaction(&raw)?)
+            }
+            "eth_sendTransaction" => {
+                let signed = self.sign(param(params, 0)?)?;
+                self.submit(signed)
+            }
+            "txpool_status" => {
+                let (pending, queued) = self.mempool.status(&self.context);
+                Ok(json!({
+                    "pending": format!("{:#x}", pending),
+                    "queued": format!("{:#x}", queued),
+                }))
+            }
+            "eth_getTransactionReceipt" => {
+                let hash = Bytes32(parse_word(param_str(params, 0)?)?);
+                Ok(self
+                    .context
+                    .receipts
+                    .iter()
+                    .find(|receipt| receipt.transaction_hash == hash)
+                    .map(receipt_json)
+                    .unwrap_or(Value::Null))
+            }
+            "eth_getLogs" => self.get_logs(param(params, 0)?),
+            method
+                if method.starts_with("evm_")
+                    || method.starts_with("hardhat_")
+                    || method.starts_with("anvil_") =>
+            {
+                self.handle_dev(method, params)
+            }
+            _ => Err(RpcError::new(
+                -32601,
+                format!("Method not found: {}", method),
+            )),
+        }
+    }
+
+    /// The number of the last mined block, the block of the context is the pending one
+    pub fn latest_block(&self) -> u64 {
+        self.context.block.number.saturating_sub(1)
+    }
+
+    /// Only the latest state is kept
+    fn check_block(&self, block: Option<&Value>) -> Result<(), RpcError> {
+        match block.and_then(Value::as_str) {
+            None | Some("latest") | Some("pending") => Ok(()),
+            Some(tag) => {
+                if block_number(tag, self.latest_block())? == self.latest_block() {
+                    Ok(())
+                } else {
+                    Err(RpcError::from(format!(
+                        "The state of block {} is not available, only the latest state is kept",
+                        tag
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Write the changes since the last save to the backend
+    pub fn save(&mut self) -> Result<(), String> {
+        self.context.save_changes()
+    }
+
+    /// Mine the pending transactions in a new block, the timestamp is the
+    /// current time (moved by evm_increaseTime) unless the pending block is
+    /// later. Return the header and the dropped invalid transactions.
+    #[allow(clippy::type_complexity)]
+    pub fn mine(&mut self) -> Result<(Header, Vec<([u8; 32], String)>), String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or_default();
+        let timestamp = match self.next_timestamp.take() {
+            Some(timestamp) => {
+                self.time_offset = (timestamp as i64).saturating_sub(now);
+                timestamp
+            }
+            None => now.saturating_add(self.time_offset).max(0) as u64,
+        };
+        let block = &mut self.context.block;
+        block.timestamp = block.timestamp.max(timestamp);
+        let mut builder = BlockBuilder::new(&self.context, self.revision);
+        let dropped = self.mempool.fill_block(&self.vm, &mut builder);
+        for (_, err) in &dropped {
+            eprintln!("[ERROR] Dropped {}", err);
+        }
+        let (context, header, receipts) = builder.seal(false)?;
+        self.context = context;
+        advance(&mut self.context, &header, 1);
+        self.last_mined = Instant::now();
+        self.save()?;
+        self.mined.push((header.clone(), receipts));
+        Ok((header, dropped))
+    }
+
+    /// Mine a block if the interval has passed since the last one
+    pub fn mine_on_interval(&mut self) -> Result<(), String> {
+        if let MiningMode::Interval(interval) = self.mining {
+            if self.last_mined.elapsed() >= interval {
+                self.mine()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Add the transaction to the mempool, it is mined at once by automine
+    // unless it is queued after a nonce gap
+    fn submit(&mut self, signed: SignedTransaction) -> Result<Value, RpcError> {
+        let hash = signed.hash;
+        if let Some(warning) = signed.validate(self.revision, self.context.block.chain_id)? {
+            eprintln!("[WARN] transaction 0x{}: {}", hex::encode(hash), warning);
+        }
+        let tx = &signed.transaction;
+        let nonce = nonce_of(&self.context, &tx.sender);
+        if tx.nonce < nonce {
+            return Err(RpcError::from(format!(
+                "Nonce too low: expected at least {}, got {}",
+                nonce, tx.nonce
+            )));
+        }
+        if tx.gas_limit > self.context.block.gas_limit {
+            return Err(RpcError::from(format!(
+                "Gas limit {} exceeds the block gas limit {}",
+                tx.gas_limit, self.context.block.gas_limit
+            )));
+        }
+        self.mempool.add(signed)?;
+        let (pending, _) = self.mempool.status(&self.context);
+        if self.mining == MiningMode::Auto && pending > 0 {
+            let (_, dropped) = self.mine()?;
+            if let Some((_, err)) = dropped.into_iter().find(|(dropped, _)| dropped == &hash) {
+                return Err(RpcError::from(err));
+            }
+        }
+        Ok(json!(Bytes32(hash)))
+    }
+
+    // Sign the transaction object with the key of an unlocked account, the
+    // transactions of the impersonated accounts are not signed
+    fn sign(&self, object: &Value) -> Result<SignedTransaction, RpcError> {
+        let from = parse_address(field(object, "from").ok_or_else(|| {
+            RpcError::invalid_params("The from address is required".to_string())
+        })?)?;
+        let mut tx = self.transaction(object)?;
+        tx.nonce = match field(object, "nonce") {
+            Some(nonce) => parse_u64(nonce)?,
+            None => self.mempool.next_nonce(&self.context, &from),
+        };
+        let tx_type = if tx.priority_fee.is_some() { 2 } else { 0 };
+        let chain_id = Some(self.context.block.chain_id);
+        if self.impersonated.contains(&from) {
+            tx.sender = from;
+            return Ok(impersonated_transaction(tx_type, chain_id, &tx));
+        }
+        let key = self
+            .dev_accounts
+            .iter()
+            .find(|(address, _)| address == &from)
+            .map(|(_, key)| key)
+            .ok_or_else(|| RpcError::from(format!("Account 0x{:?} is not unlocked", from)))?;
+        let raw = sign_transaction(tx_type, chain_id, &tx, key)?;
+        Ok(decode_transaction(&raw)?)
+    }
+
+    // The transaction of the call object, without the sender and the nonce
+    fn transaction(&self, object: &Value) -> Result<Transaction, RpcError> {
+        let u256_field = |name: &str| -> Result<Option<U256>, String> {
+            field(object, name).map(parse_u256).transpose()
+        };
+        let (gas_price, priority_fee) = match u256_field("maxFeePerGas")? {
+            Some(max_fee) => (
+                max_fee,
+                Some(u256_field("maxPriorityFeePerGas")?.unwrap_or_else(U256::zero)),
+            ),
+            None => (
+                u256_field("gasPrice")?.unwrap_or_else(|| base_fee(&self.context)),
+                None,
+            ),
+        };
+        let data = field(object, "input").or_else(|| field(object, "data"));
+        // The gas of the EVMC message is signed
+        let gas_limit = match field(object, "gas") {
+            Some(gas) => parse_u64(gas)?,
+            None => DEFAULT_GAS.min(self.context.block.gas_limit),
+        };
+        if gas_limit > i64::MAX as u64 {
+            return Err(RpcError::invalid_params(format!(
+                "The gas {} exceeds the maximum {}",
+                gas_limit,
+                i64::MAX
+            )));
+        }
+        Ok(Transaction {
+            sender: Address::default(),
+            to: field(object, "to").map(parse_address).transpose()?,
+            nonce: 0,
+            gas_limit,
+            gas_price,
+            value: u256_field("value")?.unwrap_or_else(U256::zero),
+            data: data.map(parse_hex).transpose()?.unwrap_or_default(),
+            access_list: Vec::new(),
+            priority_fee,
+        })
+    }
+
+    // Execute the call in a frame which is discarded, the state is unchanged
+    fn call(&mut self, object: &Value) -> Result<Value, RpcError> {
+        let from = match field(object, "from") {
+            Some(from) => parse_address(from)?,
+            None => Address::default(),
+        };
+        let tx = self.transaction(object)?;
+        let (kind, destination, code, input) = match tx.to.as_ref() {
+            Some(to) => {
+                let code = self
+                    .context
+                    .code_of(to)
+                    .map(|code| code.0)
+                    .unwrap_or_default();
+                (CallKind::EVMC_CALL, to.clone(), code, tx.data.clone())
+            }
+            None => {
+                let address = create_address(&from, nonce_of(&self.context, &from));
+                (CallKind::EVMC_CREATE, address, tx.data.clone(), Vec::new())
+            }
+        };
+        if code.is_empty() {
+            return Ok(json!("0x"));
+        }
+        let gas_price = tx.effective_gas_price(base_fee(&self.context));
+        let mut context = std::mem::take(&mut self.context);
+        let saved = (
+            context.current_account.clone(),
+            context.vm.take(),
+            context.revision,
+            context.tracer.clone(),
+            context.tx.clone(),
+        );
+        context.current_account = destination.clone();
+        context.vm = Some(self.vm.clone());
+        context.revision = Some(self.revision);
+        context.tracer = Rc::default();
+        context.tx = TxEnv {
+            origin: from.clone(),
+            gas_price: Uint256(u256_to_bytes(gas_price)),
+        };
+        let raw_message = ffi::evmc_message {
+            kind,
+            flags: 0,
+            depth: 0,
+            gas: tx.gas_limit as i64,
+            destination: destination.into(),
+            sender: from.into(),
+            input_data: if input.is_empty() {
+                std::ptr::null()
+            } else {
+                input.as_ptr()
+            },
+            input_size: input.len(),
+            value: Uint256(u256_to_bytes(tx.value)).into(),
+            create2_salt: Default::default(),
+        };
+        let message = ExecutionMessage::from(&raw_message);
+        context.push_frame();
+        let (result, mut context) = context.execute(&self.vm, self.revision, &code, &message);
+        context.pop_frame(false);
+        let (current_account, vm, revision, tracer, tx) = saved;
+        context.current_account = current_account;
+        context.vm = vm;
+        context.revision = revision;
+        context.tracer = tracer;
+        context.tx = tx;
+        self.context = context;
+        self.context.check_reads()?;
+        let output = format!("0x{}", hex::encode(&result.output_data));
+        match result.status_code {
+            StatusCode::EVMC_SUCCESS => Ok(json!(output)),
+            StatusCode::EVMC_REVERT => {
+                let message = match revert::revert_reason(&result, &[]) {
+                    Some(reason) => format!("execution reverted: {}", reason),
+                    None => "execution reverted".to_string(),
+                };
+                Err(RpcError {
+                    code: 3,
+                    message,
+                    data: Some(json!(output)),
+                })
+            }
+            status => Err(RpcError::from(format!("execution failed: {:?}", status))),
+        }
+    }
+
+    fn get_logs(&self, filter: &Value) -> Result<Value, RpcError> {
+        let filter = LogFilter::parse(filter, self.latest_block())?;
+        let mut logs = Vec::new();
+        for receipt in &self.context.receipts {
+            let in_block = match filter.block_hash.as_ref() {
+                Some(hash) => receipt.block_hash.as_ref() == Some(hash),
+                None => {
+                    receipt.block_number >= filter.from_block
+                        && receipt.block_number <= filter.to_block
+                }
+            };
+            // The receipts of the pending block are not included
+            if !in_block || receipt.block_hash.is_none() {
+                continue;
+            }
+            for log in &receipt.logs {
+                if filter.matches(log) {
+                    logs.push(log_json(receipt, log));
+                }
+            }
+        }
+        Ok(Value::Array(logs))
+    }
+}
+
+/// The filter of eth_getLogs and the logs subscriptions
+pub struct LogFilter {
+    pub block_hash: Option<Bytes32>,
+    pub from_block: u64,
+    pub to_block: u64,
+    /// Any of the addresses, all the addresses if empty
+    pub addresses: Vec<Address>,
+    /// Any of the topics at each position, an empty list matches any topic
+    pub topics: Vec<Vec<Bytes32>>,
+}
+
+impl LogFilter {
+    pub fn parse(filter: &Value, latest: u64) -> Result<LogFilter, RpcError> {
+        let block_hash = field(filter, "blockHash")
+            .map(|hash| parse_word(hash).map(Bytes32))
+            .transpose()?;
+        let from_block = match field(filter, "fromBlock") {
+            Some(tag) => block_number(tag, latest)?,
+            None => latest,
+        };
+        let to_block = match field(filter, "toBlock") {
+            Some(tag) => block_number(tag, latest)?,
+            None => latest,
+        };
+        let addresses = match filter.get("address") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(|value| parse_address(value.as_str().unwrap_or_default()))
+                .collect::<Result<Vec<_>, _>>()?,
+            Some(value) => vec![parse_address(value.as_str().unwrap_or_default())?],
+        };
+        let topics = match filter.get("topics") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(topic_filter)
+                .collect::<Result<Vec<_>, _>>()?,
+            Some(_) => {
+                return Err(RpcError::invalid_params(
+                    "The topics must be an array".to_string(),
+                ))
+            }
+        };
+        Ok(LogFilter {
+            block_hash,
+            from_block,
+            to_block,
+            addresses,
+            topics,
+        })
+    }
+
+    /// Match the address and the topics of the log
+    pub fn matches(&self, log: &ReceiptLog) -> bool {
+        let address_matched = self.addresses.is_empty() || self.addresses.contains(&log.address);
+        address_matched
+            && self.topics.iter().enumerate().all(|(index, expected)| {
+                expected.is_empty()
+                    || log
+                        .topics
+                        .get(index)
+                        .map(|topic| expected.contains(topic))
+                        .unwrap_or(false)
+            })
+    }
+}
+
+/// Parse the request (or batch) and handle each request
+pub fn handle_batch<F>(body: &[u8], mut handle: F) -> String
+where
+    F: FnMut(&Value) -> Value,
+{
+    let response = match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(requests)) if requests.is_empty() => response(
+            Value::Null,
+            Err(RpcError::new(-32600, "Empty batch".to_string())),
+        ),
+        Ok(Value::Array(requests)) => Value::Array(requests.iter().map(&mut handle).collect()),
+        Ok(request) => handle(&request),
+        Err(err) => response(
+            Value::Null,
+            Err(RpcError::new(-32700, format!("Parse error: {}", err))),
+        ),
+    };
+    response.to_string()
+}
+
+/// Check the request object and pass its method and params to the handler
+pub fn dispatch<F>(request: &Value, handle: F) -> Value
+where
+    F: FnOnce(&str, &[Value]) -> Result<Value, RpcError>,
+{
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method,
+        None => {
+            let error = RpcError::new(-32600, "Invalid request".to_string());
+            return response(id, Err(error));
+        }
+    };
+    let params = match request.get("params") {
+        Some(Value::Array(params)) => params.as_slice(),
+        None | Some(Value::Null) => &[],
+        Some(_) => {
+            let error = RpcError::invalid_params("The params must be an array".to_string());
+            return response(id, Err(error));
+        }
+    };
+    response(id, handle(method, params))
+}
+
+pub fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(err) => {
+            let mut error = json!({ "code": err.code, "message": err.message });
+            if let Some(data) = err.data {
+                error["data"] = data;
+            }
+            json!({ "jsonrpc": "2.0", "id": id, "error": error })
+        }
+    }
+}
+
+pub fn param(params: &[Value], index: usize) -> Result<&Value, RpcError> {
+    params
+        .get(index)
+        .ok_or_else(|| RpcError::invalid_params(format!("Missing parameter {}", index)))
+}
+
+pub fn param_str(params: &[Value], index: usize) -> Result<&str, RpcError> {
+    param(params, index)?
+        .as_str()
+        .ok_or_else(|| RpcError::invalid_params(format!("Parameter {} must be a string", index)))
+}
+
+fn field<'a>(object: &'a Value, name: &str) -> Option<&'a str> {
+    object.get(name).and_then(Value::as_str)
+}
+
+// The number of the block tag, the pending block is the one after the latest
+fn block_number(tag: &str, latest: u64) -> Result<u64, String> {
+    match tag {
+        "latest" | "safe" | "finalized" => Ok(latest),
+        "pending" => Ok(latest + 1),
+        "earliest" => Ok(0),
+        number => parse_u64(number),
+    }
+}
+
+// null (any topic), a topic or a list of topics
+fn topic_filter(value: &Value) -> Result<Vec<Bytes32>, String> {
+    match value {
+        Value::Null => Ok(Vec::new()),
+        Value::String(topic) => Ok(vec![Bytes32(parse_word(topic)?)]),
+        Value::Array(topics) => topics
+            .iter()
+            .map(|topic| parse_word(topic.as_str().unwrap_or_default()).map(Bytes32))
+            .collect(),
+        _ => Err("Invalid topic filter".to_string()),
+    }
+}
+
+pub fn receipt_json(receipt: &TransactionReceipt) -> Value {
+    json!({
+        "transactionHash": receipt.transaction_hash,
+        "transactionIndex": format!("{:#x}", receipt.transaction_index),
+        "blockHash": receipt.block_hash,
+        "blockNumber": format!("{:#x}", receipt.block_number),
+        "from": receipt.from,
+        "to": receipt.to,
+        "cumulativeGasUsed": format!("{:#x}", receipt.cumulative_gas_used),
+        "gasUsed": format!("{:#x}", receipt.gas_used),
+        "contractAddress": receipt.contract_address,
+        "logs": receipt
+            .logs
+            .iter()
+            .map(|log| log_json(receipt, log))
+            .collect::<Vec<_>>(),
+        "logsBloom": receipt.logs_bloom,
+        "status": if receipt.success { "0x1" } else { "0x0" },
+    })
+}
+
+pub fn log_json(receipt: &TransactionReceipt, log: &ReceiptLog) -> Value {
+    json!({
+        "address": log.address,
+        "topics": log.topics,
+        "data": log.data,
+        "blockNumber": format!("{:#x}", receipt.block_number),
+        "blockHash": receipt.block_hash,
+        "transactionHash": receipt.transaction_hash,
+        "transactionIndex": format!("{:#x}", receipt.transaction_index),
+        "logIndex": format!("{:#x}", log.log_index),
+        "removed": false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Echo the params of "echo", fail the other methods
+    fn handle(method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        match method {
+            "echo" => Ok(Value::Array(params.to_vec())),
+            "first" => param_str(params, 0).map(|value| json!(value)),
+            "fail" => Err(format!("Failed: {}", params.len()).into()),
+            _ => Err(RpcError::new(
+                -32601,
+                format!("Method not found: {}", method),
+            )),
+        }
+    }
+
+    fn handle_body(body: &str) -> Value {
+        let response = handle_batch(body.as_bytes(), |request| dispatch(request, handle));
+        serde_json::from_str(&response).unwrap()
+    }
+
+    fn error_code(response: &Value) -> i64 {
+        response["error"]["code"].as_i64().unwrap()
+    }
+
+    const OUTPUT: [u8; 32] = [0x2a; 32];
+
+    // A VM storing 0x2a in the slot 1 of the called account, it returns the
+    // 32 bytes of 0x2a
+    unsafe extern "C" fn sstore_execute(
+        _vm: *mut ffi::evmc_vm,
+        host: *const ffi::evmc_host_interface,
+        context: *mut ffi::evmc_host_context,
+        _revision: ffi::evmc_revision,
+        message: *const ffi::evmc_message,
+        _code: *const u8,
+        _code_size: usize,
+    ) -> ffi::evmc_result {
+        let set_storage = (*host).set_storage.unwrap();
+        let mut key = [0u8; 32];
+        key[31] = 1;
+        set_storage(
+            context,
+            &(*message).destination,
+            &Bytes32(key).into(),
+            &Bytes32(OUTPUT).into(),
+        );
+        ffi::evmc_result {
+            status_code: StatusCode::EVMC_SUCCESS,
+            gas_left: (*message).gas,
+            output_data: OUTPUT.as_ptr(),
+            output_size: OUTPUT.len(),
+            release: None,
+            create_address: Address::default().into(),
+            padding: [0u8; 4],
+        }
+    }
+
+    #[test]
+    fn call_leaves_state_unchanged() {
+        let contract = Address([2u8; 20]);
+        let db = JournalDb::new(Box::new(MemoryDb::default()));
+        let mut context = TestHostContext::with_db(Box::new(db.clone()), None).unwrap();
+        context.update_code(contract.clone(), vec![0x00]);
+        let mut node = Node {
+            context,
+            db,
+            vm: Rc::new(EvmcVm::new(Box::into_raw(Box::new(ffi::evmc_vm {
+                abi_version: 7,
+                name: std::ptr::null(),
+                version: std::ptr::null(),
+                destroy: None,
+                execute: Some(sstore_execute),
+                get_capabilities: None,
+                set_option: None,
+            })))),
+            revision: Revision::EVMC_BERLIN,
+            dev_accounts: Vec::new(),
+            impersonated: HashSet::new(),
+            snapshots: BTreeMap::new(),
+            next_snapshot_id: 1,
+            time_offset: 0,
+            next_timestamp: None,
+            mined: Vec::new(),
+            mempool: Mempool::default(),
+            mining: MiningMode::Manual,
+            last_mined: Instant::now(),
+        };
+        let call = json!([{ "to": format!("0x{:?}", contract), "gas": "0x10000" }]);
+        let output = node.handle("eth_call", call.as_array().unwrap()).unwrap();
+        assert_eq!(output, json!(format!("0x{}", hex::encode(OUTPUT))));
+
+        let mut key = [0u8; 32];
+        key[31] = 1;
+        assert!(node.context.overlays.is_empty());
+        assert!(node
+            .context
+            .storage_value(&contract, &Bytes32(key))
+            .is_none());
+        assert!(node.context.vm.is_none());
+        assert!(node.context.revision.is_none());
+    }
+
+    #[test]
+    fn dispatch_request() {
+        let response = handle_body(r#"{"jsonrpc":"2.0","id":7,"method":"echo","params":[1,"a"]}"#);
+        assert_eq!(
+            response,
+            json!({ "jsonrpc": "2.0", "id": 7, "result": [1, "a"] })
+        );
+        // The params may be left out
+        let response = handle_body(r#"{"jsonrpc":"2.0","id":"x","method":"echo"}"#);
+        assert_eq!(response["id"], json!("x"));
+        assert_eq!(response["result"], json!([]));
+    }
+
+    #[test]
+    fn batch_in_order() {
+        let response = handle_body(
+            r#"[
+                {"jsonrpc":"2.0","id":1,"method":"echo","params":[1]},
+                {"jsonrpc":"2.0","id":2,"method":"fail","params":[]},
+                {"jsonrpc":"2.0","id":3,"method":"echo","params":[3]}
+            ]"#,
+        );
+        let responses = response.as_array().unwrap();
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0]["result"], json!([1]));
+        assert_eq!(responses[1]["id"], json!(2));
+        assert_eq!(error_code(&responses[1]), -32000);
+        assert_eq!(responses[2]["result"], json!([3]));
+
+        let response = handle_body("[]");
+        assert_eq!(error_code(&response), -32600);
+    }
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(error_code(&handle_body("{")), -32700);
+        assert_eq!(handle_body("{")["id"], Value::Null);
+        assert_eq!(
+            error_code(&handle_body(r#"{"jsonrpc":"2.0","id":1}"#)),
+            -32600
+        );
+        assert_eq!(
+            error_code(&handle_body(
+                r#"{"jsonrpc":"2.0","id":1,"method":"echo","params":{}}"#
+            )),
+            -32602
+        );
+        assert_eq!(
+            error_code(&handle_body(
+                r#"{"jsonrpc":"2.0","id":1,"method":"first","params":[1]}"#
+            )),
+            -32602
+        );
+        assert_eq!(
+            error_code(&handle_body(
+                r#"{"jsonrpc":"2.0","id":1,"method":"nope","params":[]}"#
+            )),
+            -32601
+        );
+
+        let mut error = RpcError::new(3, "execution reverted".to_string());
+        error.data = Some(json!("0x01"));
+        let response = response(json!(1), Err(error));
+        assert_eq!(response["error"]["data"], json!("0x01"));
+    }
+}
@@ -0,0 +1,770 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use clap::{App, Arg, ArgMatches};
+use ethabi::{Contract, Hash, RawLog, Token};
+use evmc_sys as ffi;
+use serde::Deserialize;
+
+use crate::abi::{
+    constructor_encode_input, contract_encode_input, decode_any_log, load_contract, parse_tokens,
+};
+use crate::evmc::{
+    Address, Bytes32, CallKind, EvmcVm, ExecutionMessage, Revision, StatusCode, Uint256,
+};
+use crate::host_trace::{HostTracer, TraceSink};
+use crate::loader::create_vm;
+use crate::state_db::{load_state, save_state};
+use crate::statetest::parse_word;
+use crate::transaction::collect_logs;
+use crate::{revert, TestHostContext};
+
+const DEFAULT_GAS: i64 = 10_000_000;
+const DEFAULT_SENDER: Address = Address([128u8; 20]);
+const SECONDS_PER_BLOCK: u64 = 15;
+
+/// A list of steps run one after another on the same state
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    /// The state to start with, an empty state if not given
+    #[serde(default)]
+    pub input_storage: Option<String>,
+    /// Where to save the state after all the steps
+    #[serde(default)]
+    pub output_storage: Option<String>,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Step {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub action: Action,
+    #[serde(default)]
+    pub expect: Expect,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Deploy(Deploy),
+    Call(Call),
+    AdvanceBlock(AdvanceBlock),
+    SetBalance(SetBalance),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Deploy {
+    pub address: Address,
+    /// Path of the hex encoded code
+    pub code: String,
+    #[serde(default)]
+    pub abi: Option<String>,
+    /// The constructor arguments
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub sender: Option<Address>,
+    #[serde(default)]
+    pub value: Option<String>,
+    #[serde(default)]
+    pub gas: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Call {
+    pub address: Address,
+    #[serde(default)]
+    pub abi: Option<String>,
+    /// The function to call, encoded with the arguments by the ABI
+    #[serde(default)]
+    pub function: Option<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// The raw input data, used when no function given
+    #[serde(default)]
+    pub input: Option<String>,
+    #[serde(default)]
+    pub sender: Option<Address>,
+    #[serde(default)]
+    pub value: Option<String>,
+    #[serde(default)]
+    pub gas: Option<i64>,
+    #[serde(default, rename = "static")]
+    pub is_static: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdvanceBlock {
+    #[serde(default = "default_blocks")]
+    pub blocks: u64,
+    /// The seconds passed, 15 seconds per block by default
+    #[serde(default)]
+    pub seconds: Option<u64>,
+}
+
+fn default_blocks() -> u64 {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetBalance {
+    pub address: Address,
+    pub balance: String,
+}
+
+/// The expectations of a step, the status is expected to be success by default
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expect {
+    /// success, revert, failure or the EVMC status code (like EVMC_OUT_OF_GAS)
+    #[serde(default)]
+    pub status: Option<String>,
+    /// The decoded outputs of the function, or the raw output without function
+    #[serde(default)]
+    pub output: Option<Vec<String>>,
+    #[serde(default)]
+    pub revert_reason: Option<String>,
+    /// The storage (key => value) of the contract after the step
+    #[serde(default)]
+    pub storage: Option<HashMap<String, String>>,
+    /// All the logs emitted in the step
+    #[serde(default)]
+    pub logs: Option<Vec<ExpectLog>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpectLog {
+    #[serde(default)]
+    pub address: Option<Address>,
+    pub event: String,
+    #[serde(default)]
+    pub params: Option<Vec<String>>,
+}
+
+/// What happened in a call or deploy step
+struct Outcome {
+    status: StatusCode,
+    output: Vec<u8>,
+    revert_reason: Option<String>,
+    address: Address,
+    contract: Option<Contract>,
+    function: Option<String>,
+    logs: Vec<(Address, Vec<Bytes32>, Vec<u8>)>,
+}
+
+/// Run scenario sub command
+pub fn sub_command(name: &'static str) -> App<'static, 'static> {
+    App::new(name)
+        .about("Run the steps in a scenario file (JSON or YAML) and check the expectations")
+        .arg(
+            Arg::with_name("file")
+                .long("file")
+                .short("f")
+                .takes_value(true)
+                .required(true)
+                .help("The scenario file path"),
+        )
+        .arg(
+            Arg::with_name("input-storage")
+                .long("input-storage")
+                .short("s")
+                .takes_value(true)
+                .help("The storage to start with (overrides the scenario)"),
+        )
+        .arg(
+            Arg::with_name("output-storage")
+                .long("output-storage")
+                .short("o")
+                .takes_value(true)
+                .help("The storage after all the steps (overrides the scenario)"),
+        )
+}
+
+pub fn process(sub_matches: &ArgMatches) -> Result<(), String> {
+    let path = Path::new(sub_matches.value_of("file").unwrap());
+    let scenario = load_scenario(path)?;
+    let base = path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .to_path_buf();
+    let resolve = |file: &str| base.join(file);
+
+    let input_storage = sub_matches
+        .value_of("input-storage")
+        .map(PathBuf::from)
+        .or_else(|| scenario.input_storage.as_deref().map(resolve));
+    let mut context = match input_storage {
+        Some(path) => load_state(&path.to_string_lossy())?,
+        None => TestHostContext::new(0, Address::default()),
+    };
+    let vm = Rc::new(create_vm(sub_matches.value_of("vm"))?);
+    context.vm = Some(vm.clone());
+
+    // The logs are decoded by every ABI loaded so far
+    let mut abis = Vec::new();
+    let mut failed = 0;
+    for (index, step) in scenario.steps.iter().enumerate() {
+        let name = step.name.clone().unwrap_or_else(|| step.action.describe());
+        let errors = match run_step(&vm, &mut context, step, &base, &mut abis) {
+            Ok(errors) => errors,
+            Err(err) => vec![err],
+        };
+        if errors.is_empty() {
+            println!("[PASS] {}. {}", index + 1, name);
+        } else {
+            failed += 1;
+            println!("[FAIL] {}. {}", index + 1, name);
+            for error in errors {
+                println!("       {}", error);
+            }
+        }
+    }
+    println!(
+        "{} steps, {} passed, {} failed",
+        scenario.steps.len(),
+        scenario.steps.len() - failed,
+        failed
+    );
+
+    let output_storage = sub_matches
+        .value_of("output-storage")
+        .map(PathBuf::from)
+        .or_else(|| scenario.output_storage.as_deref().map(resolve));
+    if let Some(path) = output_storage {
+        save_state(&path.to_string_lossy(), &mut context)?;
+    }
+    if failed > 0 {
+        return Err(format!("{} steps failed", failed));
+    }
+    Ok(())
+}
+
+fn load_scenario(path: &Path) -> Result<Scenario, String> {
+    let data = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&data).map_err(|err| err.to_string()),
+        _ => serde_json::from_str(&data).map_err(|err| err.to_string()),
+    }
+}
+
+impl Action {
+    fn describe(&self) -> String {
+        match self {
+            Action::Deploy(deploy) => format!("deploy {} at {:?}", deploy.code, deploy.address),
+            Action::Call(call) => match call.function.as_ref() {
+                Some(function) => format!(
+                    "call {}({}) on {:?}",
+                    function,
+                    call.args.join(", "),
+                    call.address
+                ),
+                None => format!("call {:?}", call.address),
+            },
+            Action::AdvanceBlock(advance) => format!("advance {} blocks", advance.blocks),
+            Action::SetBalance(set) => {
+                format!("set balance of {:?} to {}", set.address, set.balance)
+            }
+        }
+    }
+}
+
+/// Run the step on the context, return the failed expectations
+fn run_step(
+    vm: &EvmcVm,
+    context: &mut TestHostContext,
+    step: &Step,
+    base: &Path,
+    abis: &mut Vec<Contract>,
+) -> Result<Vec<String>, String> {
+    let outcome = match &step.action {
+        Action::Deploy(deploy) => deploy_contract(vm, context, deploy, base, abis)?,
+        Action::Call(call) => call_contract(vm, context, call, base, abis)?,
+        Action::AdvanceBlock(advance) => {
+            context.block.number += advance.blocks;
+            context.block.timestamp += advance
+                .seconds
+                .unwrap_or(advance.blocks * SECONDS_PER_BLOCK);
+            return Ok(Vec::new());
+        }
+        Action::SetBalance(set) => {
+            let balance = Uint256(parse_word(&set.balance)?);
+            context.account_mut(&set.address).balance = balance;
+            return Ok(Vec::new());
+        }
+    };
+    check_outcome(context, &outcome, &step.expect, abis)
+}
+
+fn deploy_contract(
+    vm: &EvmcVm,
+    context: &mut TestHostContext,
+    deploy: &Deploy,
+    base: &Path,
+    abis: &mut Vec<Contract>,
+) -> Result<Outcome, String> {
+    if context.contract_exists(&deploy.address) {
+        return Err(format!("Contract already exists: {:?}", deploy.address));
+    }
+    let code_path = base.join(&deploy.code);
+    let code = fs::read_to_string(&code_path)
+        .map_err(|err| format!("{}: {}", code_path.display(), err))?;
+    let code = code.trim().trim_start_matches("0x");
+    let (contract, errors) = load_abi(deploy.abi.as_deref(), base, abis)?;
+    let code = match contract.as_ref() {
+        Some(contract) if contract.constructor.is_some() => {
+            constructor_encode_input(contract, code, &deploy.args, true)?
+        }
+        _ if deploy.args.is_empty() => code.to_string(),
+        _ => return Err("Constructor arguments given without constructor ABI".to_string()),
+    };
+    let code = hex::decode(code).map_err(|err| err.to_string())?;
+    let message = new_message(
+        CallKind::EVMC_CREATE,
+        &deploy.address,
+        deploy.sender.as_ref(),
+        deploy.value.as_deref(),
+        deploy.gas,
+        &[],
+        false,
+    )?;
+    let mut outcome = execute(vm, context, &code, &message, errors)?;
+    if outcome.status == StatusCode::EVMC_SUCCESS {
+        context.update_code(deploy.address.clone(), outcome.output.clone());
+    }
+    outcome.contract = contract;
+    Ok(outcome)
+}
+
+fn call_contract(
+    vm: &EvmcVm,
+    context: &mut TestHostContext,
+    call: &Call,
+    base: &Path,
+    abis: &mut Vec<Contract>,
+) -> Result<Outcome, String> {
+    let code = context
+        .code_of(&call.address)
+        .ok_or_else(|| format!("No contract found at {:?}", call.address))?;
+    let (contract, errors) = load_abi(call.abi.as_deref(), base, abis)?;
+    let input = match (call.function.as_ref(), call.input.as_ref()) {
+        (Some(function), _) => {
+            let contract = contract
+                .as_ref()
+                .ok_or_else(|| "The ABI is required to call a function".to_string())?;
+            contract_encode_input(contract, function, &call.args, true)?
+        }
+        (None, Some(input)) => input.trim_start_matches("0x").to_string(),
+        (None, None) => String::new(),
+    };
+    let input = hex::decode(input).map_err(|err| err.to_string())?;
+    let message = new_message(
+        CallKind::EVMC_CALL,
+        &call.address,
+        call.sender.as_ref(),
+        call.value.as_deref(),
+        call.gas,
+        &input,
+        call.is_static,
+    )?;
+    let mut outcome = execute(vm, context, &code.0, &message, errors)?;
+    outcome.contract = contract;
+    outcome.function = call.function.clone();
+    Ok(outcome)
+}
+
+// Load the ABI file, it is added to the loaded ones
+fn load_abi(
+    abi: Option<&str>,
+    base: &Path,
+    abis: &mut Vec<Contract>,
+) -> Result<(Option<Contract>, Vec<revert::CustomError>), String> {
+    match abi {
+        Some(abi) => {
+            let path = base.join(abi);
+            let data = fs::read(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+            let contract = load_contract(&data)?;
+            if !abis.contains(&contract) {
+                abis.push(contract.clone());
+            }
+            Ok((Some(contract), revert::load_errors(&data)?))
+        }
+        None => Ok((None, Vec::new())),
+    }
+}
+
+fn new_message(
+    kind: CallKind,
+    destination: &Address,
+    sender: Option<&Address>,
+    value: Option<&str>,
+    gas: Option<i64>,
+    input: &[u8],
+    is_static: bool,
+) -> Result<ffi::evmc_message, String> {
+    let value = match value {
+        Some(value) => Uint256(parse_word(value)?),
+        None => Uint256::default(),
+    };
+    let flags = if is_static {
+        ffi::evmc_flags::EVMC_STATIC as u32
+    } else {
+        0
+    };
+    Ok(ffi::evmc_message {
+        kind,
+        flags,
+        depth: 0,
+        gas: gas.unwrap_or(DEFAULT_GAS),
+        destination: destination.clone().into(),
+        sender: sender.cloned().unwrap_or(DEFAULT_SENDER).into(),
+        input_data: if input.is_empty() {
+            std::ptr::null()
+        } else {
+            input.as_ptr()
+        },
+        input_size: input.len(),
+        value: value.into(),
+        create2_salt: Default::default(),
+    })
+}
+
+// Execute the message, the changes of a failed execution are dropped with its
+// frame
+fn execute(
+    vm: &EvmcVm,
+    context: &mut TestHostContext,
+    code: &[u8],
+    raw_message: &ffi::evmc_message,
+    errors: Vec<revert::CustomError>,
+) -> Result<Outcome, String> {
+    let mut step_context = std::mem::take(context);
+    step_context.current_account = Address::from(raw_message.destination);
+    step_context.custom_errors = Rc::new(errors);
+    let tracer = Rc::new(HostTracer::recording(TraceSink::Quiet));
+    step_context.tracer = tracer.clone();
+    let message = ExecutionMessage::from(raw_message);
+    let (result, step_context) =
+        step_context.execute(vm, Revision::EVMC_MAX_REVISION, code, &message);
+    *context = step_context;
+    let revert_reason = revert::revert_reason(&result, &context.custom_errors);
+    context.custom_errors = Rc::default();
+    context.tracer = Rc::default();
+    context.check_reads()?;
+    // The steps are not transactions, no receipt is stored
+    let logs = if result.status_code == StatusCode::EVMC_SUCCESS {
+        collect_logs(&tracer)
+            .into_iter()
+            .map(|log| (log.address, log.log.topics, log.log.data.0))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    Ok(Outcome {
+        status: result.status_code,
+        output: result.output_data.clone(),
+        revert_reason,
+        address: Address::from(raw_message.destination),
+        contract: None,
+        function: None,
+        logs,
+    })
+}
+
+fn check_outcome(
+    context: &TestHostContext,
+    outcome: &Outcome,
+    expect: &Expect,
+    abis: &[Contract],
+) -> Result<Vec<String>, String> {
+    let mut errors = Vec::new();
+    let status = format!("{:?}", outcome.status);
+    let expected_status = expect.status.as_deref().unwrap_or("success");
+    let status_matched = match expected_status {
+        "success" => outcome.status == StatusCode::EVMC_SUCCESS,
+        "revert" => outcome.status == StatusCode::EVMC_REVERT,
+        "failure" => outcome.status != StatusCode::EVMC_SUCCESS,
+        name => name == status,
+    };
+    if !status_matched {
+        let mut error = format!("status: expected {}, got {}", expected_status, status);
+        if let Some(reason) = outcome.revert_reason.as_ref() {
+            error = format!("{} ({})", error, reason);
+        }
+        errors.push(error);
+    }
+
+    if let Some(expected) = expect.revert_reason.as_ref() {
+        if outcome.revert_reason.as_ref() != Some(expected) {
+            errors.push(format!(
+                "revert reason: expected {}, got {}",
+                expected,
+                outcome.revert_reason.as_deref().unwrap_or("<none>")
+            ));
+        }
+    }
+
+    if let Some(expected) = expect.output.as_ref() {
+        match (outcome.contract.as_ref(), outcome.function.as_ref()) {
+            (Some(contract), Some(function)) => {
+                let function = contract.function(function).map_err(|err| err.to_string())?;
+                let actual = function
+                    .decode_output(&outcome.output)
+                    .map_err(|err| format!("output: {}", err))?;
+                let params: Vec<_> = function
+                    .outputs
+                    .iter()
+                    .map(|param| param.kind.clone())
+                    .zip(expected.iter().map(|value| value as &str))
+                    .collect();
+                if params.len() != expected.len() {
+                    errors.push(format!(
+                        "output: expected {} values, the function returns {}",
+                        expected.len(),
+                        function.outputs.len()
+                    ));
+                } else if parse_tokens(&params, true)? != actual {
+                    errors.push(format!(
+                        "output: expected [{}], got [{}]",
+                        expected.join(", "),
+                        format_tokens(&actual)
+                    ));
+                }
+            }
+            _ => {
+                let actual = format!("0x{}", hex::encode(&outcome.output));
+                let expected = expected.join("");
+                if expected.to_lowercase() != actual {
+                    errors.push(format!("output: expected {}, got {}", expected, actual));
+                }
+            }
+        }
+    }
+
+    if let Some(expected) = expect.storage.as_ref() {
+        let mut keys: Vec<&String> = expected.keys().collect();
+        keys.sort();
+        for key in keys {
+            let key_word = Bytes32(parse_word(key)?);
+            let value = Bytes32(parse_word(&expected[key])?);
+            let actual = context
+                .stored_value(&outcome.address, &key_word)
+                .map(|value| value.data)
+                .unwrap_or_default();
+            if actual != value {
+                errors.push(format!(
+                    "storage[{}]: expected {:#?}, got {:#?}",
+                    key, value, actual
+                ));
+            }
+        }
+    }
+
+    if let Some(expected) = expect.logs.as_ref() {
+        if expected.len() != outcome.logs.len() {
+            errors.push(format!(
+                "logs: expected {} logs, got {}",
+                expected.len(),
+                outcome.logs.len()
+            ));
+        } else {
+            for (index, (expected, (address, topics, data))) in
+                expected.iter().zip(outcome.logs.iter()).enumerate()
+            {
+                if let Some(error) = check_log(abis, expected, address, topics, data)? {
+                    errors.push(format!("logs[{}]: {}", index, error));
+                }
+            }
+        }
+    }
+    Ok(errors)
+}
+
+// The event is found in all the loaded ABIs, the log may be emitted by a
+// contract called by the step
+fn check_log(
+    abis: &[Contract],
+    expected: &ExpectLog,
+    address: &Address,
+    topics: &[Bytes32],
+    data: &[u8],
+) -> Result<Option<String>, String> {
+    if let Some(expected_address) = expected.address.as_ref() {
+        if expected_address != address {
+            return Ok(Some(format!(
+                "expected address {:?}, got {:?}",
+                expected_address, address
+            )));
+        }
+    }
+    if abis.is_empty() {
+        return Err("The ABI is required to check the logs".to_string());
+    }
+    let topic_strings: Vec<String> = topics.iter().map(|topic| format!("{:?}", topic)).collect();
+    let event = match decode_any_log(abis, &topic_strings, &hex::encode(data))? {
+        Some((event, _)) => event,
+        None => {
+            return Ok(Some(format!(
+                "expected {}, got an unknown event",
+                expected.event
+            )))
+        }
+    };
+    if event.name != expected.event {
+        return Ok(Some(format!(
+            "expected {}, got {}",
+            expected.event, event.name
+        )));
+    }
+    if let Some(params) = expected.params.as_ref() {
+        let hashes: Vec<Hash> = topics
+            .iter()
+            .map(|topic| Hash::from_slice(&topic.0))
+            .collect();
+        let log = event
+            .parse_log(RawLog {
+                topics: hashes,
+                data: data.to_vec(),
+            })
+            .map_err(|err| err.to_string())?;
+        let actual: Vec<Token> = log.params.into_iter().map(|param| param.value).collect();
+        let kinds: Vec<_> = event
+            .inputs
+            .iter()
+            .map(|param| param.kind.clone())
+            .zip(params.iter().map(|value| value as &str))
+            .collect();
+        if kinds.len() != params.len() || parse_tokens(&kinds, true)? != actual {
+            return Ok(Some(format!(
+                "{}: expected [{}], got [{}]",
+                event.name,
+                params.join(", "),
+                format_tokens(&actual)
+            )));
+        }
+    }
+    Ok(None)
+}
+
+fn format_tokens(tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .map(|token| token.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keccak_hash::keccak;
+
+    fn word_at(data: &[u8], offset: usize) -> Bytes32 {
+        let mut word = [0u8; 32];
+        word.copy_from_slice(&data[offset..offset + 32]);
+        Bytes32(word)
+    }
+
+    fn result(status_code: StatusCode, gas: i64, output: Vec<u8>) -> ffi::evmc_result {
+        let output: &'static [u8] = Box::leak(output.into_boxed_slice());
+        ffi::evmc_result {
+            status_code,
+            gas_left: gas,
+            output_data: output.as_ptr(),
+            output_size: output.len(),
+            release: None,
+            create_address: Address::default().into(),
+            padding: [0u8; 4],
+        }
+    }
+
+    // The Storage contract: the constructor stores its argument in the slot
+    // 0, set(value) stores the value in the slot 1, emits Stored(value) and
+    // returns it, it reverts with Error("zero") if the value is zero
+    unsafe extern "C" fn storage_execute(
+        _vm: *mut ffi::evmc_vm,
+        host: *const ffi::evmc_host_interface,
+        context: *mut ffi::evmc_host_context,
+        _revision: ffi::evmc_revision,
+        message: *const ffi::evmc_message,
+        code: *const u8,
+        code_size: usize,
+    ) -> ffi::evmc_result {
+        let message = &*message;
+        let set_storage = (*host).set_storage.unwrap();
+        let mut key = Bytes32::default();
+        if message.kind == CallKind::EVMC_CREATE {
+            let code = std::slice::from_raw_parts(code, code_size);
+            let initial = word_at(code, code.len() - 32);
+            set_storage(context, &message.destination, &key.into(), &initial.into());
+            return result(StatusCode::EVMC_SUCCESS, message.gas, vec![0x00]);
+        }
+        let input = std::slice::from_raw_parts(message.input_data, message.input_size);
+        let value = word_at(input, 4);
+        if value == Bytes32::default() {
+            let reason = ethabi::encode(&[Token::String("zero".to_string())]);
+            let output = [&[0x08, 0xc3, 0x79, 0xa0][..], &reason].concat();
+            return result(StatusCode::EVMC_REVERT, message.gas, output);
+        }
+        key.0[31] = 1;
+        set_storage(
+            context,
+            &message.destination,
+            &key.into(),
+            &value.clone().into(),
+        );
+        let topic = Bytes32(keccak("Stored(uint256)").0);
+        let emit_log = (*host).emit_log.unwrap();
+        emit_log(
+            context,
+            &message.destination,
+            value.0.as_ptr(),
+            32,
+            &topic.into(),
+            1,
+        );
+        result(StatusCode::EVMC_SUCCESS, message.gas, value.0.to_vec())
+    }
+
+    #[test]
+    fn run_storage_scenario() {
+        let base = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/scenario");
+        let scenario = load_scenario(&base.join("storage.json")).unwrap();
+        let vm = EvmcVm::new(Box::into_raw(Box::new(ffi::evmc_vm {
+            abi_version: 7,
+            name: std::ptr::null(),
+            version: std::ptr::null(),
+            destroy: None,
+            execute: Some(storage_execute),
+            get_capabilities: None,
+            set_option: None,
+        })));
+        let mut context = TestHostContext::new(0, Address::default());
+        let mut abis = Vec::new();
+        let errors: Vec<Vec<String>> = scenario
+            .steps
+            .iter()
+            .map(|step| run_step(&vm, &mut context, step, &base, &mut abis).unwrap())
+            .collect();
+        assert_eq!(abis.len(), 1);
+        assert!(errors[0].is_empty(), "{:?}", errors[0]);
+        assert!(errors[1].is_empty(), "{:?}", errors[1]);
+        assert_eq!(
+            errors[2],
+            vec![
+                "output: expected [8], got [7]",
+                "logs[0]: Stored: expected [8], got [7]"
+            ]
+        );
+        assert!(errors[3].is_empty(), "{:?}", errors[3]);
+    }
+}
@@ -0,0 +1,1551 @@
+//! The storage backends of the state: the accounts, their code and storage,
+//! the receipts, and the environment (the rest of the context: the block…).
+//! A path ending with `.kv` is a key-value store, any other one a JSON file.
+//! The context over a backend loads the accounts and the slots on first
+//! access, and only writes back the entries changed since the last save.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::evmc::{Address, Bytes32, Uint256};
+use crate::kv_store::KvStore;
+use crate::receipt::TransactionReceipt;
+use crate::{AccountData, JsonBytes, LogEntry, TestHostContext, Value};
+
+pub const KV_EXTENSION: &str = "kv";
+
+/// The account without the code and the storage
+#[derive(Default, Serialize, Deserialize, Clone, Debug)]
+pub struct AccountInfo {
+    pub nonce: u64,
+    pub balance: Uint256,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub logs: Vec<LogEntry>,
+}
+
+impl AccountInfo {
+    fn of(account: &AccountData) -> AccountInfo {
+        AccountInfo {
+            nonce: account.nonce,
+            balance: account.balance.clone(),
+            logs: account.logs.clone(),
+        }
+    }
+}
+
+pub trait StateDb {
+    fn account(&self, address: &Address) -> Result<Option<AccountInfo>, String>;
+    fn set_account(&mut self, address: &Address, info: &AccountInfo) -> Result<(), String>;
+    /// Remove the account with its code and storage
+    fn remove_account(&mut self, address: &Address) -> Result<(), String>;
+    fn addresses(&self) -> Result<Vec<Address>, String>;
+
+    fn code(&self, address: &Address) -> Result<Option<Vec<u8>>, String>;
+    fn set_code(&mut self, address: &Address, code: Option<&[u8]>) -> Result<(), String>;
+
+    fn storage(&self, address: &Address, key: &Bytes32) -> Result<Option<Value>, String>;
+    /// Set the slot, remove it if the value is None
+    fn set_storage(
+        &mut self,
+        address: &Address,
+        key: &Bytes32,
+        value: Option<&Value>,
+    ) -> Result<(), String>;
+    fn storage_keys(&self, address: &Address) -> Result<Vec<Bytes32>, String>;
+
+    fn receipt_count(&self) -> Result<usize, String>;
+    fn receipts(&self) -> Result<Vec<TransactionReceipt>, String>;
+    /// Set the receipt at the index (at most the count), remove it and the
+    /// following ones if None
+    fn set_receipt(
+        &mut self,
+        index: usize,
+        receipt: Option<&TransactionReceipt>,
+    ) -> Result<(), String>;
+
+    /// The context without the accounts and the receipts as JSON
+    fn environment(&self) -> Result<Option<Vec<u8>>, String>;
+    fn set_environment(&mut self, data: &[u8]) -> Result<(), String>;
+
+    /// Write the changes to the disk
+    fn flush(&mut self) -> Result<(), String>;
+}
+
+/// The backend under the accounts of a context: the accounts of the context
+/// are the ones loaded or changed, and the changes since the last save are
+/// tracked by key
+#[derive(Clone)]
+pub struct Backend {
+    // Shared by the call frames and the copies of the context
+    db: Rc<RefCell<Box<dyn StateDb>>>,
+    path: Option<String>,
+    // The accounts read from the backend (the absent ones too) and the slots
+    loaded: HashSet<Address>,
+    loaded_slots: HashSet<(Address, Bytes32)>,
+    // The accounts with all their slots in the context: loaded, created or
+    // removed
+    complete_storage: HashSet<Address>,
+    // The changes since the last save
+    dirty: HashSet<Address>,
+    dirty_code: HashSet<Address>,
+    dirty_slots: HashSet<(Address, Bytes32)>,
+    removed: HashSet<Address>,
+    saved_receipts: usize,
+    // The first read failed in the host callbacks, they can not return it.
+    // The execution fails and the state is not saved.
+    read_error: Rc<RefCell<Option<String>>>,
+    // The encoded accounts of the backend for the state root, until they are
+    // written
+    encoded_accounts: Rc<RefCell<HashMap<Address, Option<Vec<u8>>>>>,
+}
+
+impl fmt::Debug for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Backend")
+            .field("path", &self.path)
+            .field("loaded", &self.loaded.len())
+            .field("dirty", &(self.dirty.len() + self.dirty_slots.len()))
+            .finish()
+    }
+}
+
+impl Backend {
+    // The value of the read, the default one if it failed
+    fn read_or_default<T: Default>(&self, result: Result<T, String>) -> T {
+        result.unwrap_or_else(|err| {
+            self.read_error.borrow_mut().get_or_insert(err);
+            T::default()
+        })
+    }
+
+    // The account without its storage
+    fn read_account(&self, address: &Address) -> Result<Option<AccountData>, String> {
+        let db = self.db.borrow();
+        let info = match db.account(address)? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        let mut account = AccountData::new(address.clone());
+        account.nonce = info.nonce;
+        account.balance = info.balance;
+        account.logs = info.logs;
+        account.code = db.code(address)?.map(JsonBytes);
+        Ok(Some(account))
+    }
+
+    // Add the slots not loaded yet to the account
+    fn read_storage(&self, address: &Address, account: &mut AccountData) -> Result<(), String> {
+        if self.complete_storage.contains(address) {
+            return Ok(());
+        }
+        let db = self.db.borrow();
+        for key in db.storage_keys(address)? {
+            if self.loaded_slots.contains(&(address.clone(), key.clone())) {
+                continue;
+            }
+            if let Some(value) = db.storage(address, &key)? {
+                account.storage.entry(key).or_insert(value);
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&mut self, context: &TestHostContext) -> Result<(), String> {
+        // The changes may be built on the default value of a failed read
+        if let Some(err) = self.read_error.borrow_mut().take() {
+            return Err(format!("Failed to read the state: {}", err));
+        }
+        let mut db = self.db.borrow_mut();
+        let mut encoded_accounts = self.encoded_accounts.borrow_mut();
+        let written = self
+            .removed
+            .iter()
+            .chain(&self.dirty)
+            .chain(&self.dirty_code)
+            .chain(self.dirty_slots.iter().map(|(address, _)| address));
+        for address in written {
+            encoded_accounts.remove(address);
+        }
+        for address in &self.removed {
+            db.remove_account(address)?;
+        }
+        for address in &self.dirty {
+            if let Some(account) = context.accounts.get(address) {
+                db.set_account(address, &AccountInfo::of(account))?;
+            }
+        }
+        for address in &self.dirty_code {
+            if let Some(account) = context.accounts.get(address) {
+                db.set_code(address, account.code.as_ref().map(|code| &code.0[..]))?;
+            }
+        }
+        for (address, key) in &self.dirty_slots {
+            if let Some(account) = context.accounts.get(address) {
+                db.set_storage(address, key, account.storage.get(key))?;
+            }
+        }
+        // The receipts of the last block saved are written again, they
+        // get the block hash when the block is sealed
+        let receipts = &context.receipts;
+        let saved = self.saved_receipts.min(receipts.len());
+        let from = match saved.checked_sub(1) {
+            Some(last) => receipts[..saved]
+                .iter()
+                .rposition(|receipt| receipt.block_number != receipts[last].block_number)
+                .map_or(0, |index| index + 1),
+            None => 0,
+        };
+        for (index, receipt) in receipts.iter().enumerate().skip(from) {
+            db.set_receipt(index, Some(receipt))?;
+        }
+        db.set_environment(&environment_json(context))?;
+        self.saved_receipts = context.receipts.len();
+        self.dirty.clear();
+        self.dirty_code.clear();
+        self.dirty_slots.clear();
+        self.removed.clear();
+        db.flush()
+    }
+}
+
+impl TestHostContext {
+    /// The context over the backend: the environment and the receipts are
+    /// read, the accounts are loaded on first access
+    pub fn with_db(db: Box<dyn StateDb>, path: Option<&str>) -> Result<TestHostContext, String> {
+        let mut context = match db.environment()? {
+            Some(data) => serde_json::from_slice(&data).map_err(|err| err.to_string())?,
+            None => TestHostContext::new(0, Address::default()),
+        };
+        // The receipts were in the environment before they had their own keys
+        let saved_receipts = db.receipt_count()?;
+        if saved_receipts > 0 {
+            context.receipts = db.receipts()?;
+        }
+        context.backend = Some(Backend {
+            db: Rc::new(RefCell::new(db)),
+            path: path.map(str::to_string),
+            loaded: HashSet::new(),
+            loaded_slots: HashSet::new(),
+            complete_storage: HashSet::new(),
+            dirty: HashSet::new(),
+            dirty_code: HashSet::new(),
+            dirty_slots: HashSet::new(),
+            removed: HashSet::new(),
+            saved_receipts,
+            read_error: Rc::new(RefCell::new(None)),
+            encoded_accounts: Rc::new(RefCell::new(HashMap::new())),
+        });
+        Ok(context)
+    }
+
+    /// The account in the context or read from the backend, the slots not
+    /// loaded are not in its storage
+    pub fn account(&self, address: &Address) -> Option<Cow<'_, AccountData>> {
+        if let Some(account) = self.accounts.get(address) {
+            return Some(Cow::Borrowed(account));
+        }
+        match &self.backend {
+            Some(backend) if !backend.loaded.contains(address) => backend
+                .read_or_default(backend.read_account(address))
+                .map(Cow::Owned),
+            _ => None,
+        }
+    }
+
+    /// The slot in the context or read from the backend
+    pub fn stored_value(&self, address: &Address, key: &Bytes32) -> Option<Value> {
+        let value = self
+            .accounts
+            .get(address)
+            .and_then(|account| account.storage.get(key));
+        if value.is_some() {
+            return value.cloned();
+        }
+        let backend = self.backend.as_ref()?;
+        if backend.complete_storage.contains(address)
+            || backend
+                .loaded_slots
+                .contains(&(address.clone(), key.clone()))
+        {
+            return None;
+        }
+        backend.read_or_default(backend.db.borrow().storage(address, key))
+    }
+
+    /// Load the account (without its storage) from the backend
+    pub fn load_account(&mut self, address: &Address) {
+        if self.accounts.contains_key(address) {
+            return;
+        }
+        if let Some(backend) = self.backend.as_mut() {
+            if backend.loaded.insert(address.clone()) {
+                if let Some(account) = backend.read_or_default(backend.read_account(address)) {
+                    self.accounts.insert(address.clone(), account);
+                }
+            }
+        }
+    }
+
+    /// Load the slot of the account from the backend
+    pub fn load_slot(&mut self, address: &Address, key: &Bytes32) {
+        self.load_account(address);
+        let (backend, account) = match (self.backend.as_mut(), self.accounts.get_mut(address)) {
+            (Some(backend), Some(account)) => (backend, account),
+            _ => return,
+        };
+        if backend.complete_storage.contains(address)
+            || !backend.loaded_slots.insert((address.clone(), key.clone()))
+        {
+            return;
+        }
+        let value = backend.read_or_default(backend.db.borrow().storage(address, key));
+        if let Some(value) = value {
+            account.storage.entry(key.clone()).or_insert(value);
+        }
+    }
+
+    /// Load the account with all its slots from the backend
+    pub fn load_storage(&mut self, address: &Address) -> Result<(), String> {
+        self.load_account(address);
+        if let (Some(backend), Some(account)) =
+            (self.backend.as_mut(), self.accounts.get_mut(address))
+        {
+            backend.read_storage(address, account)?;
+            backend.complete_storage.insert(address.clone());
+        }
+        Ok(())
+    }
+
+    /// Load every account with its storage, for the commands over the whole
+    /// state
+    pub fn load_all(&mut self) -> Result<(), String> {
+        let addresses = match &self.backend {
+            Some(backend) => backend.db.borrow().addresses()?,
+            None => return Ok(()),
+        };
+        for address in addresses {
+            self.load_account(&address);
+        }
+        let addresses: Vec<Address> = self.accounts.keys().cloned().collect();
+        for address in addresses {
+            self.load_storage(&address)?;
+        }
+        Ok(())
+    }
+
+    /// A read of the backend by the host failed, the execution must fail
+    pub fn has_read_error(&self) -> bool {
+        self.backend
+            .as_ref()
+            .map(|backend| backend.read_error.borrow().is_some())
+            .unwrap_or(false)
+    }
+
+    /// The first read of the backend failed since the last check
+    pub fn check_reads(&self) -> Result<(), String> {
+        let err = match &self.backend {
+            Some(backend) => backend.read_error.borrow_mut().take(),
+            None => None,
+        };
+        match err {
+            Some(err) => Err(format!("Failed to read the state: {}", err)),
+            None => Ok(()),
+        }
+    }
+
+    /// Visit every account with all its storage, the entries not loaded are
+    /// read from the backend but not kept
+    pub fn visit_accounts<F: FnMut(&Address, &AccountData)>(
+        &self,
+        mut visit: F,
+    ) -> Result<(), String> {
+        self.visit_loaded_accounts(&mut visit)?;
+        let backend = match &self.backend {
+            Some(backend) => backend,
+            None => return Ok(()),
+        };
+        for address in self.unloaded_addresses()? {
+            if let Some(mut account) = backend.read_account(&address)? {
+                backend.read_storage(&address, &mut account)?;
+                visit(&address, &account);
+            }
+        }
+        Ok(())
+    }
+
+    /// Visit the accounts of the context with all their storage
+    pub fn visit_loaded_accounts<F: FnMut(&Address, &AccountData)>(
+        &self,
+        mut visit: F,
+    ) -> Result<(), String> {
+        for (address, account) in &self.accounts {
+            match &self.backend {
+                Some(backend) if !backend.complete_storage.contains(address) => {
+                    let mut account = account.clone();
+                    backend.read_storage(address, &mut account)?;
+                    visit(address, &account);
+                }
+                _ => visit(address, account),
+            }
+        }
+        Ok(())
+    }
+
+    /// The encoded accounts of the backend not loaded in the context, they
+    /// are read once and kept until they are written
+    pub fn encoded_backend_accounts<F: Fn(&AccountData) -> Vec<u8>>(
+        &self,
+        encode: F,
+    ) -> Result<Vec<(Address, Vec<u8>)>, String> {
+        let backend = match &self.backend {
+            Some(backend) => backend,
+            None => return Ok(Vec::new()),
+        };
+        let mut encoded_accounts = backend.encoded_accounts.borrow_mut();
+        let mut items = Vec::new();
+        for address in self.unloaded_addresses()? {
+            if !encoded_accounts.contains_key(&address) {
+                let encoded = match backend.read_account(&address)? {
+                    Some(mut account) => {
+                        backend.read_storage(&address, &mut account)?;
+                        Some(encode(&account))
+                    }
+                    None => None,
+                };
+                encoded_accounts.insert(address.clone(), encoded);
+            }
+            if let Some(Some(encoded)) = encoded_accounts.get(&address) {
+                items.push((address, encoded.clone()));
+            }
+        }
+        Ok(items)
+    }
+
+    // The accounts of the backend not loaded in the context
+    fn unloaded_addresses(&self) -> Result<Vec<Address>, String> {
+        let backend = match &self.backend {
+            Some(backend) => backend,
+            None => return Ok(Vec::new()),
+        };
+        let addresses = backend.db.borrow().addresses()?;
+        Ok(addresses
+            .into_iter()
+            .filter(|address| {
+                !backend.loaded.contains(address) && !self.accounts.contains_key(address)
+            })
+            .collect())
+    }
+
+    /// The account to change, loaded or created. Its code is set by
+    /// `set_account_code` and its slots by `set_stored_value`.
+    pub fn account_mut(&mut self, address: &Address) -> &mut AccountData {
+        self.load_account(address);
+        if let Some(backend) = self.backend.as_mut() {
+            backend.dirty.insert(address.clone());
+            if !self.accounts.contains_key(address) {
+                // A new account has no slots in the backend
+                backend.complete_storage.insert(address.clone());
+            }
+        }
+        self.accounts
+            .entry(address.clone())
+            .or_insert_with(|| AccountData::new(address.clone()))
+    }
+
+    /// Set the code of the account, created if not exists
+    pub fn set_account_code(&mut self, address: &Address, code: Option<JsonBytes>) {
+        self.account_mut(address).code = code;
+        if let Some(backend) = self.backend.as_mut() {
+            backend.dirty_code.insert(address.clone());
+        }
+    }
+
+    /// Set the slot of the account, created if not exists
+    pub fn set_stored_value(&mut self, address: &Address, key: Bytes32, value: Value) {
+        self.load_account(address);
+        if !self.accounts.contains_key(address) {
+            self.account_mut(address);
+        }
+        if let Some(account) = self.accounts.get_mut(address) {
+            account.storage.insert(key.clone(), value);
+        }
+        if let Some(backend) = self.backend.as_mut() {
+            let slot = (address.clone(), key);
+            backend.loaded_slots.insert(slot.clone());
+            backend.dirty_slots.insert(slot);
+        }
+    }
+
+    /// Remove the account with its code and storage
+    pub fn remove_account(&mut self, address: &Address) -> Option<AccountData> {
+        self.load_account(address);
+        if let Some(backend) = self.backend.as_mut() {
+            backend.removed.insert(address.clone());
+            backend.complete_storage.insert(address.clone());
+            backend.dirty.remove(address);
+            backend.dirty_code.remove(address);
+            backend
+                .dirty_slots
+                .retain(|(slot_address, _)| slot_address != address);
+        }
+        self.accounts.remove(address)
+    }
+
+    /// Write the changes since the last save to the backend
+    pub fn save_changes(&mut self) -> Result<(), String> {
+        // Without its backend, the context only visits its own accounts
+        let mut backend = match self.backend.take() {
+            Some(backend) => backend,
+            None => return Ok(()),
+        };
+        let result = backend.save(self);
+        self.backend = Some(backend);
+        result
+    }
+
+    /// The path of the backend, if it has one
+    pub fn backend_path(&self) -> Option<&str> {
+        self.backend
+            .as_ref()
+            .and_then(|backend| backend.path.as_deref())
+    }
+}
+
+/// Open the backend by the extension of the path
+pub fn open(path: &str) -> Result<Box<dyn StateDb>, String> {
+    if is_kv(path) {
+        Ok(Box::new(KvDb::open(path)?))
+    } else {
+        Ok(Box::new(JsonFileDb::open(path)?))
+    }
+}
+
+/// The context over the state at the path, created if not exists
+pub fn open_state(path: &str) -> Result<TestHostContext, String> {
+    TestHostContext::with_db(open(path)?, Some(path))
+}
+
+/// Load the context, the state must exist
+pub fn load_state(path: &str) -> Result<TestHostContext, String> {
+    if !Path::new(path).exists() {
+        return Err(format!("{}: No such file or directory", path));
+    }
+    open_state(path)
+}
+
+/// Save the changes of the context over the state at the path, or the whole
+/// state to another path
+pub fn save_state(path: &str, context: &mut TestHostContext) -> Result<(), String> {
+    let own_path = context
+        .backend
+        .as_ref()
+        .and_then(|backend| backend.path.as_deref());
+    if own_path.map(|own_path| same_path(own_path, path)) == Some(true) {
+        return context.save_changes();
+    }
+    // The JSON file is rewritten as a whole, no need to read it
+    let mut db: Box<dyn StateDb> = if is_kv(path) {
+        Box::new(KvDb::open(path)?)
+    } else {
+        Box::new(JsonFileDb::new(path))
+    };
+    write_all(&mut *db, context)?;
+    db.flush()
+}
+
+fn same_path(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+// Write the whole state of the context, the entries of the backend not in it
+// are removed
+fn write_all(db: &mut dyn StateDb, context: &TestHostContext) -> Result<(), String> {
+    let mut addresses = HashSet::new();
+    let mut result = Ok(());
+    context.visit_accounts(|address, account| {
+        addresses.insert(address.clone());
+        if result.is_ok() {
+            result = write_account(db, address, account);
+        }
+    })?;
+    result?;
+    for address in db.addresses()? {
+        if !addresses.contains(&address) {
+            db.remove_account(&address)?;
+        }
+    }
+    if context.receipts.len() < db.receipt_count()? {
+        db.set_receipt(context.receipts.len(), None)?;
+    }
+    for (index, receipt) in context.receipts.iter().enumerate() {
+        db.set_receipt(index, Some(receipt))?;
+    }
+    db.set_environment(&environment_json(context))
+}
+
+fn write_account(
+    db: &mut dyn StateDb,
+    address: &Address,
+    account: &AccountData,
+) -> Result<(), String> {
+    db.set_account(address, &AccountInfo::of(account))?;
+    db.set_code(address, account.code.as_ref().map(|code| &code.0[..]))?;
+    for key in db.storage_keys(address)? {
+        if !account.storage.contains_key(&key) {
+            db.set_storage(address, &key, None)?;
+        }
+    }
+    for (key, value) in &account.storage {
+        db.set_storage(address, key, Some(value))?;
+    }
+    Ok(())
+}
+
+fn is_kv(path: &str) -> bool {
+    Path::new(path).extension().and_then(|ext| ext.to_str()) == Some(KV_EXTENSION)
+}
+
+fn environment_json(context: &TestHostContext) -> Vec<u8> {
+    let environment = json!({
+        "depth": context.depth,
+        "current_account": context.current_account,
+        "accounts": {},
+        "destructed_accounts": context.destructed_accounts,
+        "block": context.block,
+    });
+    serde_json::to_vec(&environment).unwrap()
+}
+
+// Set the receipt in the list, the ones from the index are removed if None
+fn set_receipt_in(
+    receipts: &mut Vec<TransactionReceipt>,
+    index: usize,
+    receipt: Option<&TransactionReceipt>,
+) -> Result<(), String> {
+    match receipt {
+        Some(receipt) if index < receipts.len() => receipts[index] = receipt.clone(),
+        Some(receipt) if index == receipts.len() => receipts.push(receipt.clone()),
+        Some(_) => return Err(format!("Receipt index {} out of order", index)),
+        None => receipts.truncate(index),
+    }
+    Ok(())
+}
+
+/// The state in memory, nothing is persisted
+#[derive(Default)]
+pub struct MemoryDb {
+    accounts: HashMap<Address, AccountInfo>,
+    code: HashMap<Address, Vec<u8>>,
+    storage: HashMap<Address, HashMap<Bytes32, Value>>,
+    receipts: Vec<TransactionReceipt>,
+    environment: Option<Vec<u8>>,
+}
+
+impl StateDb for MemoryDb {
+    fn account(&self, address: &Address) -> Result<Option<AccountInfo>, String> {
+        Ok(self.accounts.get(address).cloned())
+    }
+
+    fn set_account(&mut self, address: &Address, info: &AccountInfo) -> Result<(), String> {
+        self.accounts.insert(address.clone(), info.clone());
+        Ok(())
+    }
+
+    fn remove_account(&mut self, address: &Address) -> Result<(), String> {
+        self.accounts.remove(address);
+        self.code.remove(address);
+        self.storage.remove(address);
+        Ok(())
+    }
+
+    fn addresses(&self) -> Result<Vec<Address>, String> {
+        Ok(self.accounts.keys().cloned().collect())
+    }
+
+    fn code(&self, address: &Address) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.code.get(address).cloned())
+    }
+
+    fn set_code(&mut self, address: &Address, code: Option<&[u8]>) -> Result<(), String> {
+        match code {
+            Some(code) => self.code.insert(address.clone(), code.to_vec()),
+            None => self.code.remove(address),
+        };
+        Ok(())
+    }
+
+    fn storage(&self, address: &Address, key: &Bytes32) -> Result<Option<Value>, String> {
+        Ok(self
+            .storage
+            .get(address)
+            .and_then(|storage| storage.get(key))
+            .cloned())
+    }
+
+    fn set_storage(
+        &mut self,
+        address: &Address,
+        key: &Bytes32,
+        value: Option<&Value>,
+    ) -> Result<(), String> {
+        let storage = self.storage.entry(address.clone()).or_default();
+        match value {
+            Some(value) => storage.insert(key.clone(), value.clone()),
+            None => storage.remove(key),
+        };
+        Ok(())
+    }
+
+    fn storage_keys(&self, address: &Address) -> Result<Vec<Bytes32>, String> {
+        Ok(self
+            .storage
+            .get(address)
+            .map(|storage| storage.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn receipt_count(&self) -> Result<usize, String> {
+        Ok(self.receipts.len())
+    }
+
+    fn receipts(&self) -> Result<Vec<TransactionReceipt>, String> {
+        Ok(self.receipts.clone())
+    }
+
+    fn set_receipt(
+        &mut self,
+        index: usize,
+        receipt: Option<&TransactionReceipt>,
+    ) -> Result<(), String> {
+        set_receipt_in(&mut self.receipts, index, receipt)
+    }
+
+    fn environment(&self) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.environment.clone())
+    }
+
+    fn set_environment(&mut self, data: &[u8]) -> Result<(), String> {
+        self.environment = Some(data.to_vec());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// The whole context in a JSON file, read at once and rewritten on flush
+pub struct JsonFileDb {
+    path: String,
+    context: TestHostContext,
+}
+
+impl JsonFileDb {
+    /// An empty state, the file is not read
+    pub fn new(path: &str) -> JsonFileDb {
+        JsonFileDb {
+            path: path.to_string(),
+            context: TestHostContext::new(0, Address::default()),
+        }
+    }
+
+    /// Read the file if it exists
+    pub fn open(path: &str) -> Result<JsonFileDb, String> {
+        let mut db = JsonFileDb::new(path);
+        if Path::new(path).exists() {
+            let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+            db.context = serde_json::from_slice(&data).map_err(|err| err.to_string())?;
+        }
+        Ok(db)
+    }
+
+    fn account_mut(&mut self, address: &Address) -> &mut AccountData {
+        self.context
+            .accounts
+            .entry(address.clone())
+            .or_insert_with(|| AccountData::new(address.clone()))
+    }
+}
+
+impl StateDb for JsonFileDb {
+    fn account(&self, address: &Address) -> Result<Option<AccountInfo>, String> {
+        Ok(self
+            .context
+            .accounts
+            .get(address)
+            .map(|account| AccountInfo {
+                nonce: account.nonce,
+                balance: account.balance.clone(),
+                logs: account.logs.clone(),
+            }))
+    }
+
+    fn set_account(&mut self, address: &Address, info: &AccountInfo) -> Result<(), String> {
+        let account = self.account_mut(address);
+        account.nonce = info.nonce;
+        account.balance = info.balance.clone();
+        account.logs = info.logs.clone();
+        Ok(())
+    }
+
+    fn remove_account(&mut self, address: &Address) -> Result<(), String> {
+        self.context.accounts.remove(address);
+        Ok(())
+    }
+
+    fn addresses(&self) -> Result<Vec<Address>, String> {
+        Ok(self.context.accounts.keys().cloned().collect())
+    }
+
+    fn code(&self, address: &Address) -> Result<Option<Vec<u8>>, String> {
+        Ok(self
+            .context
+            .accounts
+            .get(address)
+            .and_then(|account| account.code.as_ref())
+            .map(|code| code.0.clone()))
+    }
+
+    fn set_code(&mut self, address: &Address, code: Option<&[u8]>) -> Result<(), String> {
+        self.account_mut(address).code = code.map(|code| JsonBytes(code.to_vec()));
+        Ok(())
+    }
+
+    fn storage(&self, address: &Address, key: &Bytes32) -> Result<Option<Value>, String> {
+        Ok(self
+            .context
+            .accounts
+            .get(address)
+            .and_then(|account| account.storage.get(key))
+            .cloned())
+    }
+
+    fn set_storage(
+        &mut self,
+        address: &Address,
+        key: &Bytes32,
+        value: Option<&Value>,
+    ) -> Result<(), String> {
+        let storage = &mut self.account_mut(address).storage;
+        match value {
+            Some(value) => storage.insert(key.clone(), value.clone()),
+            None => storage.remove(key),
+        };
+        Ok(())
+    }
+
+    fn storage_keys(&self, address: &Address) -> Result<Vec<Bytes32>, String> {
+        Ok(self
+            .context
+            .accounts
+            .get(address)
+            .map(|account| account.storage.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn receipt_count(&self) -> Result<usize, String> {
+        Ok(self.context.receipts.len())
+    }
+
+    fn receipts(&self) -> Result<Vec<TransactionReceipt>, String> {
+        Ok(self.context.receipts.clone())
+    }
+
+    fn set_receipt(
+        &mut self,
+        index: usize,
+        receipt: Option<&TransactionReceipt>,
+    ) -> Result<(), String> {
+        set_receipt_in(&mut self.context.receipts, index, receipt)
+    }
+
+    fn environment(&self) -> Result<Option<Vec<u8>>, String> {
+        Ok(Some(environment_json(&self.context)))
+    }
+
+    fn set_environment(&mut self, data: &[u8]) -> Result<(), String> {
+        let accounts = std::mem::take(&mut self.context.accounts);
+        let receipts = std::mem::take(&mut self.context.receipts);
+        self.context = serde_json::from_slice(data).map_err(|err| err.to_string())?;
+        self.context.accounts = accounts;
+        self.context.receipts = receipts;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        let data = serde_json::to_string_pretty(&self.context).unwrap();
+        fs::write(&self.path, data.as_bytes()).map_err(|err| format!("{}: {}", self.path, err))
+    }
+}
+
+// The keys of the entries in the key-value store
+const ENVIRONMENT_KEY: &[u8] = b"e";
+const ACCOUNT_PREFIX: u8 = b'a';
+const CODE_PREFIX: u8 = b'c';
+const STORAGE_PREFIX: u8 = b's';
+const RECEIPT_PREFIX: u8 = b'r';
+
+/// The state in an embedded key-value store, one entry for each account, code,
+/// storage slot and receipt, so only the changed entries are written
+pub struct KvDb {
+    store: KvStore,
+}
+
+impl KvDb {
+    pub fn open(path: &str) -> Result<KvDb, String> {
+        Ok(KvDb {
+            store: KvStore::open(path)?,
+        })
+    }
+}
+
+fn account_key(prefix: u8, address: &Address) -> Vec<u8> {
+    let mut key = vec![prefix];
+    key.extend_from_slice(&address.0);
+    key
+}
+
+fn storage_key(address: &Address, key: &Bytes32) -> Vec<u8> {
+    let mut storage_key = account_key(STORAGE_PREFIX, address);
+    storage_key.extend_from_slice(&key.0);
+    storage_key
+}
+
+// The index in big endian, the receipts are listed in order
+fn receipt_key(index: usize) -> Vec<u8> {
+    let mut key = vec![RECEIPT_PREFIX];
+    key.extend_from_slice(&(index as u64).to_be_bytes());
+    key
+}
+
+// The data and the modify time (u64, little endian)
+fn encode_value(value: &Value) -> Vec<u8> {
+    let mut data = value.data.0.to_vec();
+    data.extend_from_slice(&(value.modify_time as u64).to_le_bytes());
+    data
+}
+
+fn decode_value(data: &[u8]) -> Result<Value, String> {
+    if data.len() != 40 {
+        return Err(format!("Invalid storage value: {} bytes", data.len()));
+    }
+    let mut value = Value::default();
+    value.data.0.copy_from_slice(&data[..32]);
+    let mut modify_time = [0u8; 8];
+    modify_time.copy_from_slice(&data[32..]);
+    value.modify_time = u64::from_le_bytes(modify_time) as usize;
+    Ok(value)
+}
+
+impl StateDb for KvDb {
+    fn account(&self, address: &Address) -> Result<Option<AccountInfo>, String> {
+        match self.store.get(&account_key(ACCOUNT_PREFIX, address))? {
+            Some(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .map_err(|err| err.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    fn set_account(&mut self, address: &Address, info: &AccountInfo) -> Result<(), String> {
+        let data = serde_json::to_vec(info).unwrap();
+        self.store.put(&account_key(ACCOUNT_PREFIX, address), &data)
+    }
+
+    fn remove_account(&mut self, address: &Address) -> Result<(), String> {
+        for key in self
+            .store
+            .keys_with_prefix(&account_key(STORAGE_PREFIX, address))
+        {
+            self.store.delete(&key)?;
+        }
+        self.store.delete(&account_key(CODE_PREFIX, address))?;
+        self.store.delete(&account_key(ACCOUNT_PREFIX, address))
+    }
+
+    fn addresses(&self) -> Result<Vec<Address>, String> {
+        Ok(self
+            .store
+            .keys_with_prefix(&[ACCOUNT_PREFIX])
+            .iter()
+            .map(|key| {
+                let mut address = Address::default();
+                address.0.copy_from_slice(&key[1..]);
+                address
+            })
+            .collect())
+    }
+
+    fn code(&self, address: &Address) -> Result<Option<Vec<u8>>, String> {
+        self.store.get(&account_key(CODE_PREFIX, address))
+    }
+
+    fn set_code(&mut self, address: &Address, code: Option<&[u8]>) -> Result<(), String> {
+        let key = account_key(CODE_PREFIX, address);
+        match code {
+            Some(code) => self.store.put(&key, code),
+            None => self.store.delete(&key),
+        }
+    }
+
+    fn storage(&self, address: &Address, key: &Bytes32) -> Result<Option<Value>, String> {
+        match self.store.get(&storage_key(address, key))? {
+            Some(data) => decode_value(&data).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn set_storage(
+        &mut self,
+        address: &Address,
+        key: &Bytes32,
+        value: Option<&Value>,
+    ) -> Result<(), String> {
+        let key = storage_key(address, key);
+        match value {
+            Some(value) => self.store.put(&key, &encode_value(value)),
+            None => self.store.delete(&key),
+        }
+    }
+
+    fn storage_keys(&self, address: &Address) -> Result<Vec<Bytes32>, String> {
+        let prefix = account_key(STORAGE_PREFIX, address);
+        Ok(self
+            .store
+            .keys_with_prefix(&prefix)
+            .iter()
+            .map(|key| {
+                let mut slot = Bytes32::default();
+                slot.0.copy_from_slice(&key[prefix.len()..]);
+                slot
+            })
+            .collect())
+    }
+
+    fn receipt_count(&self) -> Result<usize, String> {
+        Ok(self.store.keys_with_prefix(&[RECEIPT_PREFIX]).len())
+    }
+
+    fn receipts(&self) -> Result<Vec<TransactionReceipt>, String> {
+        let mut receipts = Vec::new();
+        for key in self.store.keys_with_prefix(&[RECEIPT_PREFIX]) {
+            if let Some(data) = self.store.get(&key)? {
+                receipts.push(serde_json::from_slice(&data).map_err(|err| err.to_string())?);
+            }
+        }
+        Ok(receipts)
+    }
+
+    fn set_receipt(
+        &mut self,
+        index: usize,
+        receipt: Option<&TransactionReceipt>,
+    ) -> Result<(), String> {
+        match receipt {
+            Some(receipt) => {
+                let data = serde_json::to_vec(receipt).unwrap();
+                self.store.put(&receipt_key(index), &data)
+            }
+            None => {
+                for index in index..self.receipt_count()? {
+                    self.store.delete(&receipt_key(index))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn environment(&self) -> Result<Option<Vec<u8>>, String> {
+        self.store.get(ENVIRONMENT_KEY)
+    }
+
+    fn set_environment(&mut self, data: &[u8]) -> Result<(), String> {
+        self.store.put(ENVIRONMENT_KEY, data)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.store.flush()
+    }
+}
+
+/// The backend of a node with snapshots: the entries written since each
+/// snapshot are journaled with their previous values, a revert writes them
+/// back. The copies share the backend and the journals.
+#[derive(Clone)]
+pub struct JournalDb(Rc<RefCell<Journaled>>);
+
+struct Journaled {
+    db: Box<dyn StateDb>,
+    // The journal of each snapshot, a write is recorded in the last one
+    journals: Vec<Journal>,
+}
+
+// The entries before they were first written since the snapshot
+#[derive(Default)]
+struct Journal {
+    accounts: HashMap<Address, Option<AccountInfo>>,
+    code: HashMap<Address, Option<Vec<u8>>>,
+    storage: HashMap<(Address, Bytes32), Option<Value>>,
+    receipt_count: usize,
+    // The receipts from the first index written below the count
+    receipts: Option<(usize, Vec<TransactionReceipt>)>,
+    environment: Option<Option<Vec<u8>>>,
+}
+
+impl Journal {
+    fn restore(self, db: &mut dyn StateDb) -> Result<(), String> {
+        // The accounts first, a removed one loses its code and storage
+        for (address, info) in &self.accounts {
+            match info {
+                Some(info) => db.set_account(address, info)?,
+                None => db.remove_account(address)?,
+            }
+        }
+        for (address, code) in &self.code {
+            db.set_code(address, code.as_deref())?;
+        }
+        for ((address, key), value) in &self.storage {
+            db.set_storage(address, key, value.as_ref())?;
+        }
+        let receipt_count = self.receipt_count;
+        let (from, receipts) = self.receipts.unwrap_or_else(|| (receipt_count, Vec::new()));
+        if db.receipt_count()? > from {
+            db.set_receipt(from, None)?;
+        }
+        for (index, receipt) in receipts.iter().enumerate() {
+            db.set_receipt(from + index, Some(receipt))?;
+        }
+        if let Some(Some(environment)) = &self.environment {
+            db.set_environment(environment)?;
+        }
+        Ok(())
+    }
+}
+
+impl JournalDb {
+    pub fn new(db: Box<dyn StateDb>) -> JournalDb {
+        JournalDb(Rc::new(RefCell::new(Journaled {
+            db,
+            journals: Vec::new(),
+        })))
+    }
+
+    /// Start the journal of a snapshot, return the number of the journals
+    /// before it to revert to
+    pub fn snapshot(&self) -> Result<usize, String> {
+        let mut journaled = self.0.borrow_mut();
+        let journal = Journal {
+            receipt_count: journaled.db.receipt_count()?,
+            ..Default::default()
+        };
+        journaled.journals.push(journal);
+        Ok(journaled.journals.len() - 1)
+    }
+
+    /// Restore the entries as they were when the journal was started, the
+    /// later journals are dropped
+    pub fn revert(&self, journal_count: usize) -> Result<(), String> {
+        let mut journaled = self.0.borrow_mut();
+        let Journaled { db, journals } = &mut *journaled;
+        while journals.len() > journal_count {
+            // The last journal first, an earlier one has the older values
+            journals.pop().unwrap().restore(&mut **db)?;
+        }
+        db.flush()
+    }
+}
+
+impl Journaled {
+    fn journal_account(&mut self, address: &Address) -> Result<(), String> {
+        if let Some(journal) = self.journals.last_mut() {
+            if let Entry::Vacant(entry) = journal.accounts.entry(address.clone()) {
+                entry.insert(self.db.account(address)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn journal_code(&mut self, address: &Address) -> Result<(), String> {
+        if let Some(journal) = self.journals.last_mut() {
+            if let Entry::Vacant(entry) = journal.code.entry(address.clone()) {
+                entry.insert(self.db.code(address)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn journal_slot(&mut self, address: &Address, key: &Bytes32) -> Result<(), String> {
+        if let Some(journal) = self.journals.last_mut() {
+            let slot = (address.clone(), key.clone());
+            if let Entry::Vacant(entry) = journal.storage.entry(slot) {
+                entry.insert(self.db.storage(address, key)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StateDb for JournalDb {
+    fn account(&self, address: &Address) -> Result<Option<AccountInfo>, String> {
+        self.0.borrow().db.account(address)
+    }
+
+    fn set_account(&mut self, address: &Address, info: &AccountInfo) -> Result<(), String> {
+        let mut journaled = self.0.borrow_mut();
+        journaled.journal_account(address)?;
+        journaled.db.set_account(address, info)
+    }
+
+    fn remove_account(&mut self, address: &Address) -> Result<(), String> {
+        let mut journaled = self.0.borrow_mut();
+        journaled.journal_account(address)?;
+        journaled.journal_code(address)?;
+        for key in journaled.db.storage_keys(address)? {
+            journaled.journal_slot(address, &key)?;
+        }
+        journaled.db.remove_account(address)
+    }
+
+    fn addresses(&self) -> Result<Vec<Address>, String> {
+        self.0.borrow().db.addresses()
+    }
+
+    fn code(&self, address: &Address) -> Result<Option<Vec<u8>>, String> {
+        self.0.borrow().db.code(address)
+    }
+
+    fn set_code(&mut self, address: &Address, code: Option<&[u8]>) -> Result<(), String> {
+        let mut journaled = self.0.borrow_mut();
+        journaled.journal_code(address)?;
+        journaled.db.set_code(address, code)
+    }
+
+    fn storage(&self, address: &Address, key: &Bytes32) -> Result<Option<Value>, String> {
+        self.0.borrow().db.storage(address, key)
+    }
+
+    fn set_storage(
+        &mut self,
+        address: &Address,
+        key: &Bytes32,
+        value: Option<&Value>,
+    ) -> Result<(), String> {
+        let mut journaled = self.0.borrow_mut();
+        journaled.journal_slot(address, key)?;
+        journaled.db.set_storage(address, key, value)
+    }
+
+    fn storage_keys(&self, address: &Address) -> Result<Vec<Bytes32>, String> {
+        self.0.borrow().db.storage_keys(address)
+    }
+
+    fn receipt_count(&self) -> Result<usize, String> {
+        self.0.borrow().db.receipt_count()
+    }
+
+    fn receipts(&self) -> Result<Vec<TransactionReceipt>, String> {
+        self.0.borrow().db.receipts()
+    }
+
+    fn set_receipt(
+        &mut self,
+        index: usize,
+        receipt: Option<&TransactionReceipt>,
+    ) -> Result<(), String> {
+        let mut journaled = self.0.borrow_mut();
+        let Journaled { db, journals } = &mut *journaled;
+        if let Some(journal) = journals.last_mut() {
+            let saved_from = journal.receipts.as_ref().map(|(from, _)| *from);
+            if index < journal.receipt_count && saved_from.is_none_or(|from| index < from) {
+                let receipts = db.receipts()?;
+                let count = journal.receipt_count.min(receipts.len());
+                journal.receipts = Some((index, receipts[index..count].to_vec()));
+            }
+        }
+        db.set_receipt(index, receipt)
+    }
+
+    fn environment(&self) -> Result<Option<Vec<u8>>, String> {
+        self.0.borrow().db.environment()
+    }
+
+    fn set_environment(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut journaled = self.0.borrow_mut();
+        let Journaled { db, journals } = &mut *journaled;
+        if let Some(journal) = journals.last_mut() {
+            if journal.environment.is_none() {
+                journal.environment = Some(db.environment()?);
+            }
+        }
+        db.set_environment(data)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.0.borrow_mut().db.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evmc::{CallKind, EvmcVm, ExecutionMessage, HostContext, StatusCode};
+    use crate::transaction::{u256_from_bytes, u256_to_bytes};
+    use ethereum_types::U256;
+    use evmc_sys as ffi;
+
+    fn temp_path(name: &str, extension: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "play-evmone-{}-{}.{}",
+            name,
+            std::process::id(),
+            extension
+        ));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().to_string()
+    }
+
+    fn word(byte: u8) -> Bytes32 {
+        let mut word = Bytes32::default();
+        word.0[31] = byte;
+        word
+    }
+
+    // The reads of the slots fail
+    #[derive(Default)]
+    struct FailingStorage(MemoryDb);
+
+    impl StateDb for FailingStorage {
+        fn account(&self, address: &Address) -> Result<Option<AccountInfo>, String> {
+            self.0.account(address)
+        }
+        fn set_account(&mut self, address: &Address, info: &AccountInfo) -> Result<(), String> {
+            self.0.set_account(address, info)
+        }
+        fn remove_account(&mut self, address: &Address) -> Result<(), String> {
+            self.0.remove_account(address)
+        }
+        fn addresses(&self) -> Result<Vec<Address>, String> {
+            self.0.addresses()
+        }
+        fn code(&self, address: &Address) -> Result<Option<Vec<u8>>, String> {
+            self.0.code(address)
+        }
+        fn set_code(&mut self, address: &Address, code: Option<&[u8]>) -> Result<(), String> {
+            self.0.set_code(address, code)
+        }
+        fn storage(&self, _address: &Address, _key: &Bytes32) -> Result<Option<Value>, String> {
+            Err("Storage read failed".to_string())
+        }
+        fn set_storage(
+            &mut self,
+            address: &Address,
+            key: &Bytes32,
+            value: Option<&Value>,
+        ) -> Result<(), String> {
+            self.0.set_storage(address, key, value)
+        }
+        fn storage_keys(&self, address: &Address) -> Result<Vec<Bytes32>, String> {
+            self.0.storage_keys(address)
+        }
+        fn receipt_count(&self) -> Result<usize, String> {
+            self.0.receipt_count()
+        }
+        fn receipts(&self) -> Result<Vec<TransactionReceipt>, String> {
+            self.0.receipts()
+        }
+        fn set_receipt(
+            &mut self,
+            index: usize,
+            receipt: Option<&TransactionReceipt>,
+        ) -> Result<(), String> {
+            self.0.set_receipt(index, receipt)
+        }
+        fn environment(&self) -> Result<Option<Vec<u8>>, String> {
+            self.0.environment()
+        }
+        fn set_environment(&mut self, data: &[u8]) -> Result<(), String> {
+            self.0.set_environment(data)
+        }
+        fn flush(&mut self) -> Result<(), String> {
+            self.0.flush()
+        }
+    }
+
+    // A VM reading the slot 1 of the called account, it succeeds with all the
+    // gas left
+    unsafe extern "C" fn sload_execute(
+        _vm: *mut ffi::evmc_vm,
+        host: *const ffi::evmc_host_interface,
+        context: *mut ffi::evmc_host_context,
+        _revision: ffi::evmc_revision,
+        message: *const ffi::evmc_message,
+        _code: *const u8,
+        _code_size: usize,
+    ) -> ffi::evmc_result {
+        let get_storage = (*host).get_storage.unwrap();
+        get_storage(context, &(*message).destination, &word(1).into());
+        ffi::evmc_result {
+            status_code: StatusCode::EVMC_SUCCESS,
+            gas_left: (*message).gas,
+            output_data: [].as_ptr(),
+            output_size: 0,
+            release: None,
+            create_address: Address::default().into(),
+            padding: [0u8; 4],
+        }
+    }
+
+    #[test]
+    fn fail_nested_call_on_read_error() {
+        let alice = Address([1u8; 20]);
+        let bob = Address([2u8; 20]);
+        let mut db = FailingStorage::default();
+        db.set_account(&bob, &AccountInfo::default()).unwrap();
+        db.set_code(&bob, Some(&[0x00])).unwrap();
+        let mut context = TestHostContext::with_db(Box::new(db), None).unwrap();
+        context.vm = Some(Rc::new(EvmcVm::new(Box::into_raw(Box::new(
+            ffi::evmc_vm {
+                abi_version: 7,
+                name: std::ptr::null(),
+                version: std::ptr::null(),
+                destroy: None,
+                execute: Some(sload_execute),
+                get_capabilities: None,
+                set_option: None,
+            },
+        )))));
+        context.account_mut(&alice).balance = Uint256(u256_to_bytes(U256::from(100)));
+        context.push_frame();
+        let raw_message = ffi::evmc_message {
+            kind: CallKind::EVMC_CALL,
+            flags: 0,
+            depth: 1,
+            gas: 100_000,
+            destination: bob.clone().into(),
+            sender: alice.clone().into(),
+            input_data: [].as_ptr(),
+            input_size: 0,
+            value: Uint256(u256_to_bytes(U256::from(30))).into(),
+            create2_salt: Bytes32::default().into(),
+        };
+        let result = context.call(ExecutionMessage::from(&raw_message));
+        assert_eq!(result.status_code, StatusCode::EVMC_INTERNAL_ERROR);
+        assert_eq!(result.gas_left, 0);
+        // The value stays with the caller
+        context.pop_frame(true);
+        assert_eq!(
+            u256_from_bytes(&context.account_balance(&alice).0),
+            U256::from(100)
+        );
+        assert!(context.check_reads().is_err());
+    }
+
+    #[test]
+    fn save_changes_and_load_lazily() {
+        let path = temp_path("state-db", KV_EXTENSION);
+        let alice = Address([1u8; 20]);
+        let bob = Address([2u8; 20]);
+        let root = {
+            let mut context = open_state(&path).unwrap();
+            context.account_mut(&alice).nonce = 1;
+            context.set_stored_value(&alice, word(1), Value::new(word(10)));
+            context.set_stored_value(&alice, word(2), Value::new(word(20)));
+            context.set_account_code(&bob, Some(JsonBytes(vec![0x00])));
+            save_state(&path, &mut context).unwrap();
+            crate::trie::state_root(&context).unwrap()
+        };
+        {
+            let mut context = load_state(&path).unwrap();
+            assert!(context.accounts.is_empty());
+            assert_eq!(
+                context
+                    .stored_value(&alice, &word(2))
+                    .map(|value| value.data),
+                Some(word(20))
+            );
+            assert_eq!(crate::trie::state_root(&context).unwrap(), root);
+            context.load_slot(&alice, &word(1));
+            context.set_stored_value(&alice, word(1), Value::new(word(11)));
+            context.remove_account(&bob);
+            save_state(&path, &mut context).unwrap();
+            // Only the changed slot is loaded
+            assert_eq!(context.accounts[&alice].storage.len(), 1);
+        }
+        let mut context = load_state(&path).unwrap();
+        context.load_all().unwrap();
+        assert!(!context.accounts.contains_key(&bob));
+        let account = &context.accounts[&alice];
+        assert_eq!(account.nonce, 1);
+        assert_eq!(account.storage[&word(1)].data, word(11));
+        assert_eq!(account.storage[&word(2)].data, word(20));
+        drop(context);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn same_state_in_json_and_kv() {
+        let json_path = temp_path("state-db-json", "json");
+        let kv_path = temp_path("state-db-json", KV_EXTENSION);
+        let alice = Address([1u8; 20]);
+        let bob = Address([2u8; 20]);
+        let root = {
+            let mut context = open_state(&json_path).unwrap();
+            context.account_mut(&alice).nonce = 3;
+            context.set_stored_value(&alice, word(1), Value::new(word(10)));
+            context.set_account_code(&bob, Some(JsonBytes(vec![0x60, 0x00])));
+            save_state(&json_path, &mut context).unwrap();
+            crate::trie::state_root(&context).unwrap()
+        };
+        let mut context = load_state(&json_path).unwrap();
+        save_state(&kv_path, &mut context).unwrap();
+        let mut json_context = load_state(&json_path).unwrap();
+        let mut kv_context = load_state(&kv_path).unwrap();
+        assert_eq!(crate::trie::state_root(&kv_context).unwrap(), root);
+        json_context.load_all().unwrap();
+        kv_context.load_all().unwrap();
+        let mut addresses: Vec<_> = kv_context.accounts.keys().cloned().collect();
+        addresses.sort_by_key(|address| address.0);
+        assert_eq!(addresses, vec![alice.clone(), bob.clone()]);
+        for address in &addresses {
+            let (json, kv) = (
+                &json_context.accounts[address],
+                &kv_context.accounts[address],
+            );
+            assert_eq!(json.nonce, kv.nonce);
+            assert_eq!(json.code, kv.code);
+            assert_eq!(json.storage.len(), kv.storage.len());
+            for (key, value) in &json.storage {
+                assert_eq!(kv.storage[key].data, value.data);
+            }
+        }
+
+        // The root cached for the unchanged accounts follows the saved ones
+        drop(kv_context);
+        let mut context = load_state(&kv_path).unwrap();
+        assert_eq!(crate::trie::state_root(&context).unwrap(), root);
+        context.account_mut(&alice).nonce = 4;
+        save_state(&kv_path, &mut context).unwrap();
+        let changed = crate::trie::state_root(&context).unwrap();
+        assert_ne!(changed, root);
+        drop(context);
+        assert_eq!(
+            crate::trie::state_root(&load_state(&kv_path).unwrap()).unwrap(),
+            changed
+        );
+        drop(json_context);
+        fs::remove_file(&json_path).unwrap();
+        fs::remove_file(&kv_path).unwrap();
+    }
+}
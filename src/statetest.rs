@@ -0,0 +1,452 @@
+//! Runner of the GeneralStateTests fixtures from ethereum/tests
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use clap::{App, Arg, ArgMatches};
+use keccak_hash::keccak;
+use rlp::RlpStream;
+use serde::Deserialize;
+
+use crate::evmc::{Address, Bytes32, EvmcVm, Revision, Uint256};
+use crate::loader::create_vm;
+use crate::receipt::append_logs;
+use crate::report::ReportLog;
+use crate::transaction::{
+    apply_transaction, parse_u256, secret_key_address, u256_to_bytes, Transaction,
+};
+use crate::trie::state_root;
+use crate::{AccountData, BlockContext, JsonBytes, TestHostContext, Value};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Env {
+    pub current_coinbase: String,
+    pub current_difficulty: String,
+    pub current_gas_limit: String,
+    pub current_number: String,
+    pub current_timestamp: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreAccount {
+    pub balance: String,
+    pub code: String,
+    pub nonce: String,
+    pub storage: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessListItem {
+    pub address: String,
+    pub storage_keys: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TestTransaction {
+    data: Vec<String>,
+    gas_limit: Vec<String>,
+    #[serde(default)]
+    gas_price: Option<String>,
+    nonce: String,
+    secret_key: String,
+    #[serde(default)]
+    sender: Option<String>,
+    to: String,
+    value: Vec<String>,
+    #[serde(default)]
+    access_lists: Vec<Option<Vec<AccessListItem>>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Indexes {
+    data: usize,
+    gas: usize,
+    value: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PostState {
+    hash: String,
+    logs: String,
+    indexes: Indexes,
+    #[serde(default)]
+    expect_exception: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StateTest {
+    env: Env,
+    pre: BTreeMap<String, PreAccount>,
+    transaction: TestTransaction,
+    post: BTreeMap<String, Vec<PostState>>,
+}
+
+/// The revision of the fork name used in the fixtures
+pub fn fork_revision(fork: &str) -> Option<Revision> {
+    match fork {
+        "Frontier" => Some(Revision::EVMC_FRONTIER),
+        "Homestead" => Some(Revision::EVMC_HOMESTEAD),
+        "EIP150" => Some(Revision::EVMC_TANGERINE_WHISTLE),
+        "EIP158" => Some(Revision::EVMC_SPURIOUS_DRAGON),
+        "Byzantium" => Some(Revision::EVMC_BYZANTIUM),
+        "Constantinople" => Some(Revision::EVMC_CONSTANTINOPLE),
+        "ConstantinopleFix" | "Petersburg" => Some(Revision::EVMC_PETERSBURG),
+        "Istanbul" => Some(Revision::EVMC_ISTANBUL),
+        "Berlin" => Some(Revision::EVMC_BERLIN),
+        _ => None,
+    }
+}
+
+/// The sub command to run the state tests
+pub fn sub_command(name: &'static str) -> App<'static, 'static> {
+    App::new(name)
+        .about("Run the GeneralStateTests fixtures (ethereum/tests) and compare the post state")
+        .arg(
+            Arg::with_name("path")
+                .long("path")
+                .short("p")
+                .takes_value(true)
+                .required(true)
+                .help("The fixture file or a directory of fixtures"),
+        )
+        .arg(
+            Arg::with_name("fork")
+                .long("fork")
+                .takes_value(true)
+                .multiple(true)
+                .help("Only run the forks (like Istanbul, Berlin)"),
+        )
+        .arg(
+            Arg::with_name("test")
+                .long("test")
+                .short("t")
+                .takes_value(true)
+                .help("Only run the tests whose name contains the text"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .short("v")
+                .help("Print the passed cases too"),
+        )
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+pub fn process(sub_matches: &ArgMatches) -> Result<(), String> {
+    let forks: Option<Vec<&str>> = sub_matches.values_of("fork").map(|values| values.collect());
+    let filter = sub_matches.value_of("test");
+    let verbose = sub_matches.is_present("verbose");
+    let mut files = Vec::new();
+    collect_files(Path::new(sub_matches.value_of("path").unwrap()), &mut files)?;
+
+    let vm = Rc::new(create_vm(sub_matches.value_of("vm"))?);
+    let mut summary = Summary::default();
+    for file in files {
+        let data = fs::read(&file).map_err(|err| format!("{}: {}", file.display(), err))?;
+        let tests: BTreeMap<String, StateTest> = match serde_json::from_slice(&data) {
+            Ok(tests) => tests,
+            Err(err) => {
+                println!("[SKIP] {}: {}", file.display(), err);
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        for (name, test) in tests {
+            if filter.map(|filter| !name.contains(filter)).unwrap_or(false) {
+                continue;
+            }
+            for (fork, posts) in &test.post {
+                if forks
+                    .as_ref()
+                    .map(|forks| !forks.contains(&fork.as_str()))
+                    .unwrap_or(false)
+                {
+                    continue;
+                }
+                let revision = match fork_revision(fork) {
+                    Some(revision) => revision,
+                    None => {
+                        summary.skipped += posts.len();
+                        continue;
+                    }
+                };
+                for post in posts {
+                    let case = format!(
+                        "{} {} d{}g{}v{}",
+                        name, fork, post.indexes.data, post.indexes.gas, post.indexes.value
+                    );
+                    match run_case(&vm, &test, revision, post) {
+                        Ok(errors) if errors.is_empty() => {
+                            summary.passed += 1;
+                            if verbose {
+                                println!("[PASS] {}", case);
+                            }
+                        }
+                        Ok(errors) => {
+                            summary.failed += 1;
+                            println!("[FAIL] {}", case);
+                            for error in errors {
+                                println!("       {}", error);
+                            }
+                        }
+                        Err(err) => {
+                            summary.failed += 1;
+                            println!("[FAIL] {}\n       {}", case, err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    println!(
+        "{} passed, {} failed, {} skipped",
+        summary.passed, summary.failed, summary.skipped
+    );
+    if summary.failed > 0 {
+        return Err(format!("{} cases failed", summary.failed));
+    }
+    Ok(())
+}
+
+pub fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    if path.is_dir() {
+        let mut entries: Vec<PathBuf> = fs::read_dir(path)
+            .map_err(|err| format!("{}: {}", path.display(), err))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()
+            .map_err(|err| err.to_string())?;
+        entries.sort();
+        for entry in entries {
+            collect_files(&entry, files)?;
+        }
+    } else if path.extension().and_then(|ext| ext.to_str()) == Some("json") {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+// Run the transaction selected by the indexes, return the mismatches
+fn run_case(
+    vm: &Rc<EvmcVm>,
+    test: &StateTest,
+    revision: Revision,
+    post: &PostState,
+) -> Result<Vec<String>, String> {
+    let mut context = pre_state(&test.pre)?;
+    context.block = block_context(&test.env)?;
+    context.vm = Some(vm.clone());
+    let tx = test_transaction(&test.transaction, &post.indexes)?;
+
+    let mut errors = Vec::new();
+    let (logs, outcome) = match apply_transaction(vm, &mut context, revision, &tx) {
+        Ok(result) => {
+            if let Some(exception) = post.expect_exception.as_ref() {
+                errors.push(format!(
+                    "expected exception {}, the transaction is valid",
+                    exception
+                ));
+            }
+            let mut outcome = format!(
+                "status: {:?}, gas used: {}, output: 0x{}",
+                result.status,
+                result.gas_used,
+                hex::encode(&result.output)
+            );
+            if let Some(address) = result.created_address.as_ref() {
+                outcome.push_str(&format!(", created: 0x{}", hex::encode(address.0)));
+            }
+            (result.logs, outcome)
+        }
+        Err(err) => {
+            if post.expect_exception.is_none() {
+                errors.push(format!("invalid transaction: {}", err));
+            }
+            (Vec::new(), format!("invalid transaction: {}", err))
+        }
+    };
+
+    let root = format!("0x{}", hex::encode(state_root(&context)?));
+    if root != post.hash.to_lowercase() {
+        errors.push(format!("state root: expected {}, got {}", post.hash, root));
+    }
+    let logs_hash = format!("0x{}", hex::encode(logs_hash(&logs)));
+    if logs_hash != post.logs.to_lowercase() {
+        errors.push(format!(
+            "logs hash: expected {}, got {}",
+            post.logs, logs_hash
+        ));
+    }
+    if !errors.is_empty() {
+        errors.push(outcome);
+    }
+    Ok(errors)
+}
+
+/// keccak256(rlp(logs)), each log is rlp([address, [topics], data])
+pub fn logs_hash(logs: &[ReportLog]) -> [u8; 32] {
+    let mut stream = RlpStream::new();
+    append_logs(&mut stream, logs);
+    keccak(stream.out()).0
+}
+
+pub fn parse_address(value: &str) -> Result<Address, String> {
+    let bytes = parse_hex(value)?;
+    if bytes.len() != 20 {
+        return Err(format!("Invalid address: {}", value));
+    }
+    let mut address = Address::default();
+    address.0.copy_from_slice(&bytes);
+    Ok(address)
+}
+
+pub fn access_list(items: &[AccessListItem]) -> Result<Vec<(Address, Vec<Bytes32>)>, String> {
+    items
+        .iter()
+        .map(|item| {
+            let keys = item
+                .storage_keys
+                .iter()
+                .map(|key| parse_word(key).map(Bytes32))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok((parse_address(&item.address)?, keys))
+        })
+        .collect()
+}
+
+pub fn parse_hex(value: &str) -> Result<Vec<u8>, String> {
+    hex::decode(value.trim_start_matches("0x")).map_err(|err| format!("{}: {}", value, err))
+}
+
+pub fn parse_u64(value: &str) -> Result<u64, String> {
+    let number = parse_u256(value)?;
+    if number.bits() > 64 {
+        return Err(format!("Number too large: {}", value));
+    }
+    Ok(number.low_u64())
+}
+
+pub fn parse_word(value: &str) -> Result<[u8; 32], String> {
+    Ok(u256_to_bytes(parse_u256(value)?))
+}
+
+pub fn pre_state(pre: &BTreeMap<String, PreAccount>) -> Result<TestHostContext, String> {
+    let mut accounts = HashMap::new();
+    for (address, account) in pre {
+        let address = parse_address(address)?;
+        let mut data = AccountData::new(address.clone());
+        data.nonce = parse_u64(&account.nonce)?;
+        data.balance = Uint256(parse_word(&account.balance)?);
+        let code = parse_hex(&account.code)?;
+        if !code.is_empty() {
+            data.code = Some(JsonBytes(code));
+        }
+        for (key, value) in &account.storage {
+            data.storage.insert(
+                Bytes32(parse_word(key)?),
+                Value::new(Bytes32(parse_word(value)?)),
+            );
+        }
+        accounts.insert(address, data);
+    }
+    let mut context = TestHostContext::new(0, Address::default());
+    context.accounts = accounts;
+    Ok(context)
+}
+
+pub fn block_context(env: &Env) -> Result<BlockContext, String> {
+    let number = parse_u64(&env.current_number)?;
+    Ok(BlockContext {
+        number,
+        timestamp: parse_u64(&env.current_timestamp)?,
+        coinbase: parse_address(&env.current_coinbase)?,
+        gas_limit: parse_u64(&env.current_gas_limit)?,
+        difficulty: Uint256(parse_word(&env.current_difficulty)?),
+        chain_id: 1,
+        base_fee: None,
+        // The state tests use keccak256 of the decimal block number as the block hash
+        block_hashes: (number.saturating_sub(256)..number)
+            .map(|number| (number, Bytes32(keccak(number.to_string()).0)))
+            .collect(),
+    })
+}
+
+fn test_transaction(tx: &TestTransaction, indexes: &Indexes) -> Result<Transaction, String> {
+    let sender = match tx.sender.as_ref() {
+        Some(sender) => parse_address(sender)?,
+        None => secret_key_address(&parse_hex(&tx.secret_key)?)?,
+    };
+    let to = if tx.to.is_empty() {
+        None
+    } else {
+        Some(parse_address(&tx.to)?)
+    };
+    let select = |values: &[String], index: usize| {
+        values
+            .get(index)
+            .cloned()
+            .ok_or_else(|| format!("Index {} out of range", index))
+    };
+    let gas_price = tx
+        .gas_price
+        .as_ref()
+        .ok_or_else(|| "Only the transactions with gasPrice are supported".to_string())?;
+    let access_list = match tx.access_lists.get(indexes.data) {
+        Some(Some(items)) => access_list(items)?,
+        _ => Vec::new(),
+    };
+    Ok(Transaction {
+        sender,
+        to,
+        nonce: parse_u64(&tx.nonce)?,
+        gas_limit: parse_u64(&select(&tx.gas_limit, indexes.gas)?)?,
+        gas_price: parse_u256(gas_price)?,
+        value: parse_u256(&select(&tx.value, indexes.value)?)?,
+        data: parse_hex(&select(&tx.data, indexes.data)?)?,
+        access_list,
+        priority_fee: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_transfer_fixture() {
+        let tests: BTreeMap<String, StateTest> = serde_json::from_str(include_str!(
+            "../testdata/GeneralStateTests/transferToNewAccount.json"
+        ))
+        .unwrap();
+        let vm = Rc::new(create_vm(None).unwrap());
+        let mut cases = 0;
+        for test in tests.values() {
+            for (fork, posts) in &test.post {
+                let revision = fork_revision(fork).unwrap();
+                for post in posts {
+                    let errors = run_case(&vm, test, revision, post).unwrap();
+                    assert!(
+                        errors.is_empty(),
+                        "{} {:?}: {:?}",
+                        fork,
+                        post.indexes,
+                        errors
+                    );
+                    cases += 1;
+                }
+            }
+        }
+        assert_eq!(cases, 5);
+    }
+}
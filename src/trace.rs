@@ -0,0 +1,565 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::evmc::{Address, EvmcVm, ExecutionContext, ExecutionMessage, ExecutionResult, Revision};
+use crate::host_trace::HostEvent;
+use crate::loader::create_vm;
+use crate::replay::{
+    apply_memory_write, call_output_range, write_memory, FrameData, OP_CALL, OP_CALLCODE,
+    OP_CREATE, OP_CREATE2, OP_DELEGATECALL, OP_SLOAD, OP_SSTORE, OP_STATICCALL,
+};
+
+/// One instruction step in EIP-3155 format
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceStep {
+    pub pc: u64,
+    pub op: u8,
+    pub gas: String,
+    pub gas_cost: String,
+    pub mem_size: u64,
+    pub stack: Vec<String>,
+    pub depth: u32,
+    pub refund: u64,
+    pub op_name: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub storage: Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub memory: Option<String>,
+}
+
+/// A line written by the VM tracer
+#[derive(Debug, Clone)]
+pub enum TraceLine {
+    Step(TraceStep),
+    /// The frame start/end lines, kept as they are
+    Frame(Value),
+    /// Anything else written to stderr during the execution
+    Raw(String),
+}
+
+impl TraceLine {
+    pub fn to_line(&self) -> String {
+        match self {
+            TraceLine::Step(step) => serde_json::to_string(step).unwrap(),
+            TraceLine::Frame(value) => value.to_string(),
+            TraceLine::Raw(line) => line.clone(),
+        }
+    }
+}
+
+/// Parse the trace written by the VM (evmone writes it as JSON lines). The
+/// host events written to stderr have a named `op`, they are kept as raw
+/// lines and do not start call frames.
+pub fn parse_trace(output: &str) -> Vec<TraceLine> {
+    output
+        .lines()
+        .map(|line| {
+            if let Ok(step) = serde_json::from_str::<TraceStep>(line) {
+                TraceLine::Step(step)
+            } else if let Ok(value @ Value::Object(_)) = serde_json::from_str::<Value>(line) {
+                if value.get("op").is_some() {
+                    TraceLine::Raw(line.to_string())
+                } else {
+                    TraceLine::Frame(value)
+                }
+            } else {
+                TraceLine::Raw(line.to_string())
+            }
+        })
+        .collect()
+}
+
+// A call frame entered or left, or an instruction step in the current frame
+enum FrameEvent<'a> {
+    Enter,
+    Exit,
+    Step(&'a mut TraceStep),
+}
+
+/// Walk the steps frame by frame: a frame is entered and left at the start and
+/// end lines of the VM tracer, or where the step depth changes if there are none
+fn walk_frames<F: FnMut(FrameEvent)>(lines: &mut [TraceLine], mut visit: F) {
+    let mut depth = 0;
+    for line in lines.iter_mut() {
+        match line {
+            TraceLine::Frame(value) if value.get("pc").is_none() => {
+                if value.get("output").is_some() || value.get("gasUsed").is_some() {
+                    if depth > 0 {
+                        depth -= 1;
+                        visit(FrameEvent::Exit);
+                    }
+                } else if value.get("depth").is_some() {
+                    depth += 1;
+                    visit(FrameEvent::Enter);
+                }
+            }
+            TraceLine::Step(step) => {
+                // The depth of the outermost frame is 1
+                let step_depth = (step.depth as usize).max(1);
+                while depth < step_depth {
+                    depth += 1;
+                    visit(FrameEvent::Enter);
+                }
+                while depth > step_depth {
+                    depth -= 1;
+                    visit(FrameEvent::Exit);
+                }
+                visit(FrameEvent::Step(step));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Fill in the storage of the executing contract on the steps it changed.
+///
+/// The VM tracer does not report the storage, it is rebuilt from the stack:
+/// SSTORE takes the key and value from the stack top, the value of SLOAD is
+/// on the stack top of the next step in the same frame.
+pub fn fill_storage(lines: &mut [TraceLine]) {
+    // The storage seen by each open call frame, and its pending SLOAD key
+    let mut frames: Vec<(BTreeMap<String, String>, Option<String>)> = Vec::new();
+    walk_frames(lines, |event| match event {
+        FrameEvent::Enter => frames.push(Default::default()),
+        FrameEvent::Exit => {
+            frames.pop();
+        }
+        FrameEvent::Step(step) => {
+            let (storage, pending_load) = match frames.last_mut() {
+                Some(frame) => frame,
+                None => return,
+            };
+            if let Some(key) = pending_load.take() {
+                if let Some(value) = step.stack.last() {
+                    storage.insert(key, value.clone());
+                    step.storage = Some(storage.clone());
+                }
+            }
+            let stack_len = step.stack.len();
+            if step.op == OP_SLOAD && stack_len >= 1 {
+                *pending_load = Some(step.stack[stack_len - 1].clone());
+            } else if step.op == OP_SSTORE && stack_len >= 2 {
+                let key = step.stack[stack_len - 1].clone();
+                let value = step.stack[stack_len - 2].clone();
+                storage.insert(key, value);
+                step.storage = Some(storage.clone());
+            }
+        }
+    });
+}
+
+// The memory of a call frame being rebuilt
+#[derive(Default)]
+struct MemoryFrame {
+    memory: Vec<u8>,
+    code: Vec<u8>,
+    input: Vec<u8>,
+    return_data: Vec<u8>,
+    is_create: bool,
+    // The previous step, its memory writes are applied before the next one
+    pending: Option<TraceStep>,
+    // The return data of the returned sub call
+    call_output: Option<Vec<u8>>,
+}
+
+impl MemoryFrame {
+    fn apply<F: Fn(&Address) -> Vec<u8>>(&mut self, step: &TraceStep, code_of: F) {
+        let call_output = self.call_output.take();
+        match step.op {
+            OP_CALL | OP_CALLCODE | OP_DELEGATECALL | OP_STATICCALL => {
+                self.return_data = call_output.unwrap_or_default();
+                if let (Some(offset), Some(size)) = call_output_range(step) {
+                    let size = size.min(self.return_data.len());
+                    write_memory(&mut self.memory, Some(offset), &self.return_data[..size]);
+                }
+            }
+            OP_CREATE | OP_CREATE2 => self.return_data = call_output.unwrap_or_default(),
+            _ => {
+                let data = FrameData {
+                    code: &self.code,
+                    input: &self.input,
+                    return_data: &self.return_data,
+                };
+                apply_memory_write(&mut self.memory, step, &data, code_of);
+            }
+        }
+    }
+}
+
+/// Fill in the memory (before the instruction) on every step.
+///
+/// The memory is rebuilt (see `replay`) with the input and code of each call
+/// frame taken from the `CALL_ENTER` host events, and the call outputs from
+/// the `CALL_EXIT` host events.
+pub fn fill_memory<F: Fn(&Address) -> Vec<u8>>(
+    lines: &mut [TraceLine],
+    events: &[HostEvent],
+    code_of: F,
+) {
+    let mut calls = events.iter().filter_map(|event| match event {
+        HostEvent::CallEnter {
+            kind,
+            destination,
+            input,
+            ..
+        } => Some((kind.contains("CREATE"), destination, &input.0)),
+        _ => None,
+    });
+    let mut outputs = events.iter().filter_map(|event| match event {
+        HostEvent::CallExit { status, output, .. } => Some((status, &output.0)),
+        _ => None,
+    });
+    // The frames are left in the order of the call exits
+    let mut frames: Vec<MemoryFrame> = Vec::new();
+    walk_frames(lines, |event| match event {
+        FrameEvent::Enter => {
+            let mut frame = MemoryFrame::default();
+            match calls.next() {
+                Some((true, _, input)) => {
+                    frame.code = input.clone();
+                    frame.is_create = true;
+                }
+                Some((false, destination, input)) => {
+                    frame.code = code_of(destination);
+                    frame.input = input.clone();
+                }
+                None => {}
+            }
+            frames.push(frame);
+        }
+        FrameEvent::Exit => {
+            let frame = frames.pop();
+            // The return data of a create is its output if it reverted only
+            let output = outputs.next().map(|(status, output)| {
+                match frame.as_ref().map(|frame| frame.is_create) {
+                    Some(true) if status != "EVMC_REVERT" => Vec::new(),
+                    _ => output.clone(),
+                }
+            });
+            if let Some(caller) = frames.last_mut() {
+                caller.call_output = output;
+            }
+        }
+        FrameEvent::Step(step) => {
+            let frame = match frames.last_mut() {
+                Some(frame) => frame,
+                None => return,
+            };
+            let mem_size = step.mem_size as usize;
+            if frame.memory.len() < mem_size {
+                frame.memory.resize(mem_size, 0);
+            }
+            if let Some(previous) = frame.pending.take() {
+                frame.apply(&previous, &code_of);
+            }
+            step.memory = Some(format!("0x{}", hex::encode(&frame.memory)));
+            frame.pending = Some(step.clone());
+        }
+    });
+}
+
+/// A new instance of the VM with its tracer on, the shared VM is left as it is.
+///
+/// EVMC has no tracing interface, the VM must accept the `trace` option and
+/// write the EIP-3155 lines to stderr. The evmone of the EVMC 7 ABI linked in
+/// has no tracer, a VM supporting it is loaded with `--vm`.
+pub fn tracing_vm(config: Option<&str>) -> Result<Rc<EvmcVm>, String> {
+    let vm = create_vm(config)?;
+    vm.set_option("trace", "1").map_err(|err| {
+        format!(
+            "{}: the VM does not support tracing, load one accepting the trace option with --vm",
+            err
+        )
+    })?;
+    Ok(Rc::new(vm))
+}
+
+/// Execute the code with a tracing VM (see `tracing_vm`), and collect the
+/// trace of every call frame
+pub fn execute_captured(
+    vm: &EvmcVm,
+    revision: Revision,
+    code: &[u8],
+    message: &ExecutionMessage,
+    context: &mut ExecutionContext,
+) -> Result<(ExecutionResult, Vec<TraceLine>), String> {
+    // evmone writes the trace to stderr
+    let capture = TraceCapture::start()?;
+    let result = vm.execute(revision, code, message, context);
+    let lines = parse_trace(&capture.finish()?);
+    Ok((result, lines))
+}
+
+/// Redirect stderr into an unlinked temporary file while the VM is writing
+/// the trace, stderr is restored when finished or dropped
+pub struct TraceCapture {
+    saved_fd: Option<i32>,
+    file: File,
+}
+
+impl TraceCapture {
+    pub fn start() -> Result<TraceCapture, String> {
+        let file = create_temp_file()?;
+        io::stderr().flush().map_err(|err| err.to_string())?;
+        let saved_fd = unsafe { libc::dup(libc::STDERR_FILENO) };
+        if saved_fd < 0 {
+            return Err(format!(
+                "Redirect stderr failed: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        let capture = TraceCapture {
+            saved_fd: Some(saved_fd),
+            file,
+        };
+        if unsafe { libc::dup2(capture.file.as_raw_fd(), libc::STDERR_FILENO) } < 0 {
+            return Err(format!(
+                "Redirect stderr failed: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        Ok(capture)
+    }
+
+    /// Restore stderr and return everything written to it
+    pub fn finish(mut self) -> Result<String, String> {
+        self.restore();
+        let mut output = Vec::new();
+        self.file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| self.file.read_to_end(&mut output))
+            .map_err(|err| err.to_string())?;
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    fn restore(&mut self) {
+        if let Some(saved_fd) = self.saved_fd.take() {
+            let _ = io::stderr().flush();
+            unsafe {
+                // std::clog of the VM goes through the C stdio
+                libc::fflush(std::ptr::null_mut());
+                libc::dup2(saved_fd, libc::STDERR_FILENO);
+                libc::close(saved_fd);
+            }
+        }
+    }
+}
+
+impl Drop for TraceCapture {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+// A new file readable by the user only, removed at once so no other process
+// can open it. An existing file or symlink of the name is never followed.
+fn create_temp_file() -> Result<File, String> {
+    let dir = std::env::temp_dir();
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.subsec_nanos())
+        .unwrap_or_default();
+    for attempt in 0..16 {
+        let path = dir.join(format!(
+            "play-evmone-trace-{}-{}-{}",
+            std::process::id(),
+            nanos,
+            attempt
+        ));
+        match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+        {
+            Ok(file) => {
+                fs::remove_file(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+                return Ok(file);
+            }
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(format!("{}: {}", path.display(), err)),
+        }
+    }
+    Err("Create the trace file failed: too many attempts".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evmc::Uint256;
+    use crate::JsonBytes;
+
+    fn step(pc: u64, op: u8, name: &str, stack: &[&str], depth: u32, mem_size: u64) -> String {
+        format!(
+            r#"{{"pc":{},"op":{},"gas":"0x100","gasCost":"0x3","memSize":{},"stack":{:?},"depth":{},"refund":0,"opName":"{}"}}"#,
+            pc, op, mem_size, stack, depth, name
+        )
+    }
+
+    fn steps(lines: &[TraceLine]) -> Vec<&TraceStep> {
+        lines
+            .iter()
+            .filter_map(|line| match line {
+                TraceLine::Step(step) => Some(step),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_steps_frames_and_raw_lines() {
+        let output = [
+            r#"{"depth":1,"rev":"Berlin","static":false}"#.to_string(),
+            step(0, 0x60, "PUSH1", &[], 1, 0),
+            r#"{"op":"SLOAD","depth":0}"#.to_string(),
+            "warning: not a trace line".to_string(),
+            r#"{"error":null,"gas":"0x0","gasUsed":"0x3","output":""}"#.to_string(),
+        ]
+        .join("\n");
+        let lines = parse_trace(&output);
+        assert_eq!(lines.len(), 5);
+        assert!(matches!(&lines[0], TraceLine::Frame(value) if value["depth"] == 1));
+        assert!(matches!(&lines[1], TraceLine::Step(step) if step.op_name == "PUSH1"));
+        // The host events have a named op
+        assert!(matches!(&lines[2], TraceLine::Raw(_)));
+        assert!(matches!(&lines[3], TraceLine::Raw(line) if line.starts_with("warning")));
+        assert!(matches!(&lines[4], TraceLine::Frame(_)));
+        assert_eq!(lines[1].to_line(), step(0, 0x60, "PUSH1", &[], 1, 0));
+    }
+
+    #[test]
+    fn walk_frames_by_lines_or_depth() {
+        let walk = |output: &str| {
+            let mut lines = parse_trace(output);
+            let mut events = Vec::new();
+            walk_frames(&mut lines, |event| {
+                events.push(match event {
+                    FrameEvent::Enter => "enter".to_string(),
+                    FrameEvent::Exit => "exit".to_string(),
+                    FrameEvent::Step(step) => step.pc.to_string(),
+                })
+            });
+            events
+        };
+        // The frame lines of a call with no instruction executed
+        let with_lines = [
+            step(0, 0xf1, "CALL", &[], 1, 0),
+            r#"{"depth":2,"rev":"Berlin","static":false}"#.to_string(),
+            r#"{"error":null,"gas":"0x0","gasUsed":"0x0","output":""}"#.to_string(),
+            step(1, 0x00, "STOP", &[], 1, 0),
+        ]
+        .join("\n");
+        assert_eq!(walk(&with_lines), vec!["enter", "0", "enter", "exit", "1"]);
+        let by_depth = [
+            step(0, 0xf1, "CALL", &[], 1, 0),
+            step(0, 0xf1, "CALL", &[], 2, 0),
+            step(0, 0x00, "STOP", &[], 3, 0),
+            step(1, 0x00, "STOP", &[], 1, 0),
+        ]
+        .join("\n");
+        assert_eq!(
+            walk(&by_depth),
+            vec!["enter", "0", "enter", "0", "enter", "0", "exit", "exit", "1"]
+        );
+    }
+
+    #[test]
+    fn fill_storage_of_each_frame() {
+        let output = [
+            // SSTORE(1, 0x2a)
+            step(0, OP_SSTORE, "SSTORE", &["0x2a", "0x1"], 1, 0),
+            // SLOAD(2) loads 0x7 in the next step
+            step(1, OP_SLOAD, "SLOAD", &["0x2"], 1, 0),
+            step(2, 0xf1, "CALL", &["0x7"], 1, 0),
+            step(0, OP_SSTORE, "SSTORE", &["0x3", "0x1"], 2, 0),
+            step(3, 0x00, "STOP", &["0x1"], 1, 0),
+        ]
+        .join("\n");
+        let mut lines = parse_trace(&output);
+        fill_storage(&mut lines);
+        let storage: Vec<Option<Vec<(String, String)>>> = steps(&lines)
+            .iter()
+            .map(|step| {
+                step.storage
+                    .as_ref()
+                    .map(|storage| storage.clone().into_iter().collect())
+            })
+            .collect();
+        let entry = |key: &str, value: &str| (key.to_string(), value.to_string());
+        assert_eq!(
+            storage,
+            vec![
+                Some(vec![entry("0x1", "0x2a")]),
+                None,
+                Some(vec![entry("0x1", "0x2a"), entry("0x2", "0x7")]),
+                // The callee has its own storage
+                Some(vec![entry("0x1", "0x3")]),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn fill_memory_with_call_output() {
+        let callee = Address([0xbb; 20]);
+        let output = [
+            // MSTORE(0, 0x2a)
+            step(0, 0x52, "MSTORE", &["0x2a", "0x0"], 1, 0),
+            // CALL writing 2 bytes of its output at 0x1e
+            step(
+                1,
+                OP_CALL,
+                "CALL",
+                &["0x2", "0x1e", "0x0", "0x0", "0x0", "0xbb", "0xffff"],
+                1,
+                32,
+            ),
+            step(0, 0x00, "STOP", &[], 2, 0),
+            step(2, 0x00, "STOP", &["0x1"], 1, 32),
+        ]
+        .join("\n");
+        let events = vec![
+            HostEvent::CallEnter {
+                depth: 1,
+                kind: "EVMC_CALL".to_string(),
+                sender: Address([0xaa; 20]),
+                destination: callee.clone(),
+                gas: 0xffff,
+                value: Uint256::default(),
+                input: JsonBytes(Vec::new()),
+            },
+            HostEvent::CallExit {
+                depth: 1,
+                status: "EVMC_SUCCESS".to_string(),
+                gas_left: 0,
+                gas_used: 0xffff,
+                output: JsonBytes(vec![0x11, 0x22, 0x33]),
+                revert_reason: None,
+            },
+        ];
+        let mut lines = parse_trace(&output);
+        fill_memory(&mut lines, &events, |address| {
+            assert_eq!(address, &callee);
+            vec![0x00]
+        });
+        let memory: Vec<String> = steps(&lines)
+            .iter()
+            .map(|step| step.memory.clone().unwrap())
+            .collect();
+        let word = |last: &str| format!("0x{}{}", "00".repeat(32 - last.len() / 2), last);
+        assert_eq!(
+            memory,
+            vec!["0x".to_string(), word("2a"), "0x".to_string(), word("1122"),]
+        );
+    }
+}
@@ -0,0 +1,1160 @@
+//! Signed transaction decoding and the transaction level execution:
+//! validation, intrinsic gas, gas purchase and refund, value transfer,
+//! contract creation and the miner reward.
+//!
+//! The SSTORE refunds are counted from the storage writes of the call frames
+//! that succeeded, with the rules of the revision (EIP-1283, EIP-2200 and
+//! EIP-2929).
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use ethereum_types::U256;
+use evmc_sys as ffi;
+use keccak_hash::keccak;
+use rlp::{Rlp, RlpStream};
+
+use crate::evmc::{
+    Address, Bytes32, CallKind, EvmcVm, ExecutionMessage, Revision, StatusCode, Uint256,
+};
+use crate::host_trace::{HostEvent, HostTracer};
+use crate::report::ReportLog;
+use crate::trie::trim_zeros;
+use crate::{JsonBytes, LogEntry, TestHostContext, TxEnv};
+
+const MAX_CODE_SIZE: usize = 24576;
+
+/// A transaction with the sender known
+#[derive(Debug, Clone, Default)]
+pub struct Transaction {
+    pub sender: Address,
+    /// None to create a contract
+    pub to: Option<Address>,
+    pub nonce: u64,
+    pub gas_limit: u64,
+    /// The gas price, the max fee per gas of EIP-1559 transactions
+    pub gas_price: U256,
+    pub value: U256,
+    pub data: Vec<u8>,
+    pub access_list: Vec<(Address, Vec<Bytes32>)>,
+    /// The max priority fee per gas of EIP-1559 transactions
+    pub priority_fee: Option<U256>,
+}
+
+impl Transaction {
+    /// The price paid per gas under the base fee
+    pub fn effective_gas_price(&self, base_fee: U256) -> U256 {
+        match self.priority_fee {
+            Some(priority_fee) => self.gas_price.min(base_fee.saturating_add(priority_fee)),
+            None => self.gas_price,
+        }
+    }
+}
+
+/// The result of an executed (valid) transaction
+#[derive(Debug, Clone)]
+pub struct TransactionResult {
+    pub status: StatusCode,
+    pub gas_used: u64,
+    pub output: Vec<u8>,
+    pub logs: Vec<ReportLog>,
+    pub created_address: Option<Address>,
+}
+
+pub fn u256_from_bytes(bytes: &[u8]) -> U256 {
+    U256::from_big_endian(bytes)
+}
+
+pub fn u256_to_bytes(value: U256) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    bytes
+}
+
+/// Parse a 0x prefixed hex or a decimal number
+pub fn parse_u256(value: &str) -> Result<U256, String> {
+    match value.strip_prefix("0x") {
+        Some(digits) => {
+            let digits = digits.trim_start_matches('0');
+            if digits.len() > 64 {
+                return Err(format!("Number too large: {}", value));
+            }
+            let digits = format!("{:0>64}", digits);
+            let bytes = hex::decode(&digits).map_err(|err| format!("{}: {}", value, err))?;
+            Ok(u256_from_bytes(&bytes))
+        }
+        None => U256::from_dec_str(value).map_err(|err| format!("{}: {:?}", value, err)),
+    }
+}
+
+pub fn balance_of(context: &TestHostContext, address: &Address) -> U256 {
+    context
+        .account(address)
+        .map(|account| u256_from_bytes(&account.balance.0))
+        .unwrap_or_else(U256::zero)
+}
+
+pub fn set_balance(context: &mut TestHostContext, address: &Address, balance: U256) {
+    context.account_mut(address).balance = Uint256(u256_to_bytes(balance));
+}
+
+pub fn nonce_of(context: &TestHostContext, address: &Address) -> u64 {
+    context
+        .account(address)
+        .map(|account| account.nonce)
+        .unwrap_or(0)
+}
+
+/// The address of the contract created by the sender with the nonce
+pub fn create_address(sender: &Address, nonce: u64) -> Address {
+    let mut stream = RlpStream::new_list(2);
+    stream.append(&&sender.0[..]);
+    stream.append(&nonce);
+    let mut address = Address::default();
+    address.0.copy_from_slice(&keccak(stream.out()).0[12..]);
+    address
+}
+
+/// secp256k1n / 2, the signatures with a higher s are invalid from Homestead (EIP-2)
+const HALF_CURVE_ORDER: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0,
+];
+
+/// A decoded signed transaction, the sender is recovered from the signature
+#[derive(Debug, Clone)]
+pub struct SignedTransaction {
+    /// 0 for legacy transactions, 1 for EIP-2930, 2 for EIP-1559
+    pub tx_type: u8,
+    /// None for legacy transactions before EIP-155
+    pub chain_id: Option<u64>,
+    pub transaction: Transaction,
+    /// The s value of the signature, the r and v are only used to recover the sender
+    pub s: [u8; 32],
+    /// keccak256 of the encoded transaction
+    pub hash: [u8; 32],
+    /// The encoded transaction as it appears in the transactions trie
+    pub raw: Vec<u8>,
+}
+
+impl SignedTransaction {
+    /// Check the transaction type and the signature are allowed in the revision,
+    /// return the warning of an EIP-1559 transaction executed with the latest
+    /// EVMC revision
+    pub fn validate(&self, revision: Revision, chain_id: u64) -> Result<Option<String>, String> {
+        let revision = revision as u32;
+        if self.tx_type == 1 && revision < Revision::EVMC_BERLIN as u32 {
+            return Err("EIP-2930 transactions are not supported before Berlin".to_string());
+        }
+        // London is not in the EVMC revisions, the latest one (Berlin) is the
+        // closest
+        let mut warning = None;
+        if self.tx_type == 2 {
+            if revision < Revision::EVMC_MAX_REVISION as u32 {
+                return Err(format!(
+                    "EIP-1559 transactions are not supported before London, they are executed with {:?} (the latest EVMC revision) only",
+                    Revision::EVMC_MAX_REVISION
+                ));
+            }
+            warning = Some(format!(
+                "EIP-1559 transactions are executed with the {:?} semantics, London is not supported by EVMC {}: no BASEFEE opcode, no EIP-3529 refund reduction",
+                Revision::EVMC_MAX_REVISION,
+                ffi::EVMC_ABI_VERSION
+            ));
+        }
+        if self.tx_type == 0
+            && self.chain_id.is_some()
+            && revision < Revision::EVMC_SPURIOUS_DRAGON as u32
+        {
+            return Err(
+                "EIP-155 transactions are not supported before Spurious Dragon".to_string(),
+            );
+        }
+        if let Some(id) = self.chain_id {
+            if id != chain_id {
+                return Err(format!(
+                    "Invalid chain id: expected {}, got {}",
+                    chain_id, id
+                ));
+            }
+        }
+        if revision >= Revision::EVMC_HOMESTEAD as u32 && self.s > HALF_CURVE_ORDER {
+            return Err("Invalid signature: s is in the upper half of the curve order".to_string());
+        }
+        Ok(warning)
+    }
+}
+
+/// Decode the transaction as it appears in the transactions trie: rlp list for
+/// legacy transactions, the type byte and the rlp payload for typed ones
+pub fn decode_transaction(raw: &[u8]) -> Result<SignedTransaction, String> {
+    match raw.first() {
+        Some(byte) if *byte >= 0xc0 => decode_legacy(raw),
+        Some(0x01) | Some(0x02) => decode_typed(raw),
+        Some(byte) => Err(format!("Unsupported transaction type: {}", byte)),
+        None => Err("Empty transaction".to_string()),
+    }
+}
+
+fn decode_legacy(raw: &[u8]) -> Result<SignedTransaction, String> {
+    let rlp = top_level_list(raw, 9)?;
+    let v = rlp_u64(&rlp, 6)?;
+    let (chain_id, recovery_id) = match v {
+        27 | 28 => (None, (v - 27) as u8),
+        v if v >= 35 => (Some((v - 35) / 2), ((v - 35) % 2) as u8),
+        v => return Err(format!("Invalid signature v: {}", v)),
+    };
+    let mut stream = RlpStream::new_list(if chain_id.is_some() { 9 } else { 6 });
+    for index in 0..6 {
+        stream.append_raw(rlp_at(&rlp, index)?.as_raw(), 1);
+    }
+    if let Some(chain_id) = chain_id {
+        stream.append(&chain_id);
+        stream.append_empty_data();
+        stream.append_empty_data();
+    }
+    let signing_hash = keccak(stream.out()).0;
+    let r = rlp_word(&rlp, 7)?;
+    let s = rlp_word(&rlp, 8)?;
+    let transaction = Transaction {
+        sender: recover_sender(&signing_hash, recovery_id, &r, &s)?,
+        to: rlp_to(&rlp, 3)?,
+        nonce: rlp_u64(&rlp, 0)?,
+        gas_limit: rlp_u64(&rlp, 2)?,
+        gas_price: u256_from_bytes(&rlp_word(&rlp, 1)?),
+        value: u256_from_bytes(&rlp_word(&rlp, 4)?),
+        data: rlp_bytes(&rlp, 5)?,
+        access_list: Vec::new(),
+        priority_fee: None,
+    };
+    Ok(SignedTransaction {
+        tx_type: 0,
+        chain_id,
+        transaction,
+        s,
+        hash: keccak(raw).0,
+        raw: raw.to_vec(),
+    })
+}
+
+// The type byte and rlp([chain id, nonce, gas price, gas, to, value, data,
+// access list, y parity, r, s]), EIP-1559 transactions have the max priority
+// fee and the max fee instead of the gas price
+fn decode_typed(raw: &[u8]) -> Result<SignedTransaction, String> {
+    let tx_type = raw[0];
+    let fields = if tx_type == 1 { 8 } else { 9 };
+    let rlp = top_level_list(&raw[1..], fields + 3)?;
+    let v = rlp_u64(&rlp, fields)?;
+    if v > 1 {
+        return Err(format!("Invalid signature y parity: {}", v));
+    }
+    let mut stream = RlpStream::new_list(fields);
+    for index in 0..fields {
+        stream.append_raw(rlp_at(&rlp, index)?.as_raw(), 1);
+    }
+    let mut payload = vec![tx_type];
+    payload.extend(stream.out());
+    let signing_hash = keccak(payload).0;
+    let r = rlp_word(&rlp, fields + 1)?;
+    let s = rlp_word(&rlp, fields + 2)?;
+    // The index of the fields after the gas price
+    let at = |index: usize| index + fields - 8;
+    let (gas_price, priority_fee) = if tx_type == 1 {
+        (u256_from_bytes(&rlp_word(&rlp, 2)?), None)
+    } else {
+        let priority_fee = u256_from_bytes(&rlp_word(&rlp, 2)?);
+        (u256_from_bytes(&rlp_word(&rlp, 3)?), Some(priority_fee))
+    };
+    let transaction = Transaction {
+        sender: recover_sender(&signing_hash, v as u8, &r, &s)?,
+        to: rlp_to(&rlp, at(4))?,
+        nonce: rlp_u64(&rlp, 1)?,
+        gas_limit: rlp_u64(&rlp, at(3))?,
+        gas_price,
+        value: u256_from_bytes(&rlp_word(&rlp, at(5))?),
+        data: rlp_bytes(&rlp, at(6))?,
+        access_list: decode_access_list_items(&rlp_at(&rlp, at(7))?)?,
+        priority_fee,
+    };
+    Ok(SignedTransaction {
+        tx_type,
+        chain_id: Some(rlp_u64(&rlp, 0)?),
+        transaction,
+        s,
+        hash: keccak(raw).0,
+        raw: raw.to_vec(),
+    })
+}
+
+/// The payload hashed for the signature: the rlp of the fields (with the chain
+/// id, 0 and 0 for EIP-155 legacy transactions), prefixed by the type byte for
+/// typed transactions
+pub fn signing_payload(tx_type: u8, chain_id: Option<u64>, tx: &Transaction) -> Vec<u8> {
+    let legacy_chain_id = if tx_type == 0 { chain_id } else { None };
+    let extra = if legacy_chain_id.is_some() { 3 } else { 0 };
+    let mut stream = RlpStream::new_list(field_count(tx_type) + extra);
+    append_fields(&mut stream, tx_type, chain_id, tx);
+    if let Some(chain_id) = legacy_chain_id {
+        stream.append(&chain_id);
+        stream.append_empty_data();
+        stream.append_empty_data();
+    }
+    typed_payload(tx_type, stream.out())
+}
+
+/// Sign the transaction with the private key, return the encoded transaction
+/// as it appears in the transactions trie
+pub fn sign_transaction(
+    tx_type: u8,
+    chain_id: Option<u64>,
+    tx: &Transaction,
+    secret_key: &[u8],
+) -> Result<Vec<u8>, String> {
+    let secret_key =
+        libsecp256k1::SecretKey::parse_slice(secret_key).map_err(|err| format!("{:?}", err))?;
+    let hash = keccak(signing_payload(tx_type, chain_id, tx)).0;
+    let (signature, recovery_id) =
+        libsecp256k1::sign(&libsecp256k1::Message::parse(&hash), &secret_key);
+    let signature = signature.serialize();
+    Ok(encode_signed(
+        tx_type,
+        chain_id,
+        tx,
+        recovery_id.serialize(),
+        &signature[..32],
+        &signature[32..],
+    ))
+}
+
+/// The transaction of an impersonated sender: it is not signed by the sender,
+/// the r of the fake signature is the sender address to keep the hash unique
+pub fn impersonated_transaction(
+    tx_type: u8,
+    chain_id: Option<u64>,
+    tx: &Transaction,
+) -> SignedTransaction {
+    let mut s = [0u8; 32];
+    s[31] = 1;
+    let raw = encode_signed(tx_type, chain_id, tx, 0, &tx.sender.0, &s);
+    SignedTransaction {
+        tx_type,
+        chain_id,
+        transaction: tx.clone(),
+        s,
+        hash: keccak(&raw).0,
+        raw,
+    }
+}
+
+fn encode_signed(
+    tx_type: u8,
+    chain_id: Option<u64>,
+    tx: &Transaction,
+    recovery_id: u8,
+    r: &[u8],
+    s: &[u8],
+) -> Vec<u8> {
+    let recovery_id = u64::from(recovery_id);
+    let v = match (tx_type, chain_id) {
+        (0, Some(chain_id)) => chain_id * 2 + 35 + recovery_id,
+        (0, None) => 27 + recovery_id,
+        _ => recovery_id,
+    };
+    let mut stream = RlpStream::new_list(field_count(tx_type) + 3);
+    append_fields(&mut stream, tx_type, chain_id, tx);
+    stream.append(&v);
+    stream.append(&trim_zeros(r));
+    stream.append(&trim_zeros(s));
+    typed_payload(tx_type, stream.out())
+}
+
+// The number of the fields before the signature
+fn field_count(tx_type: u8) -> usize {
+    match tx_type {
+        0 => 6,
+        1 => 8,
+        _ => 9,
+    }
+}
+
+fn append_fields(stream: &mut RlpStream, tx_type: u8, chain_id: Option<u64>, tx: &Transaction) {
+    let append_u256 = |stream: &mut RlpStream, value: U256| {
+        stream.append(&trim_zeros(&u256_to_bytes(value)));
+    };
+    if tx_type != 0 {
+        stream.append(&chain_id.unwrap_or_default());
+    }
+    stream.append(&tx.nonce);
+    if tx_type == 2 {
+        append_u256(stream, tx.priority_fee.unwrap_or_else(U256::zero));
+    }
+    append_u256(stream, tx.gas_price);
+    stream.append(&tx.gas_limit);
+    match tx.to.as_ref() {
+        Some(to) => stream.append(&&to.0[..]),
+        None => stream.append_empty_data(),
+    };
+    append_u256(stream, tx.value);
+    stream.append(&tx.data);
+    if tx_type != 0 {
+        stream.begin_list(tx.access_list.len());
+        for (address, keys) in &tx.access_list {
+            stream.begin_list(2);
+            stream.append(&&address.0[..]);
+            stream.begin_list(keys.len());
+            for key in keys {
+                stream.append(&&key.0[..]);
+            }
+        }
+    }
+}
+
+fn typed_payload(tx_type: u8, rlp: Vec<u8>) -> Vec<u8> {
+    let mut payload = Vec::new();
+    if tx_type != 0 {
+        payload.push(tx_type);
+    }
+    payload.extend(rlp);
+    payload
+}
+
+fn decode_access_list_items(rlp: &Rlp) -> Result<Vec<(Address, Vec<Bytes32>)>, String> {
+    let mut items = Vec::new();
+    for item in rlp.iter() {
+        if item.item_count().map_err(|err| err.to_string())? != 2 {
+            return Err("Invalid access list item".to_string());
+        }
+        let address = rlp_to(&item, 0)?.ok_or_else(|| "Invalid access list address".to_string())?;
+        let mut keys = Vec::new();
+        for key in rlp_at(&item, 1)?.iter() {
+            let data = key.data().map_err(|err| err.to_string())?;
+            if data.len() != 32 {
+                return Err("Invalid access list storage key".to_string());
+            }
+            let mut bytes = [0u8; 32];
+            bytes.copy_from_slice(data);
+            keys.push(Bytes32(bytes));
+        }
+        items.push((address, keys));
+    }
+    Ok(items)
+}
+
+/// The rlp list with the exact item count, without trailing bytes
+pub fn top_level_list(raw: &[u8], count: usize) -> Result<Rlp<'_>, String> {
+    let rlp = Rlp::new(raw);
+    let total = rlp.payload_info().map_err(|err| err.to_string())?.total();
+    if !rlp.is_list() || total != raw.len() {
+        return Err("Invalid rlp list".to_string());
+    }
+    let item_count = rlp.item_count().map_err(|err| err.to_string())?;
+    if item_count != count {
+        return Err(format!("Expected {} rlp items, got {}", count, item_count));
+    }
+    Ok(rlp)
+}
+
+pub fn rlp_at<'a>(rlp: &Rlp<'a>, index: usize) -> Result<Rlp<'a>, String> {
+    rlp.at(index).map_err(|err| err.to_string())
+}
+
+pub fn rlp_u64(rlp: &Rlp, index: usize) -> Result<u64, String> {
+    rlp.val_at(index).map_err(|err| err.to_string())
+}
+
+pub fn rlp_bytes(rlp: &Rlp, index: usize) -> Result<Vec<u8>, String> {
+    let item = rlp_at(rlp, index)?;
+    if item.is_list() {
+        return Err("Expected rlp data, got a list".to_string());
+    }
+    item.as_val().map_err(|err| err.to_string())
+}
+
+/// The number up to 256 bits as a big endian word
+pub fn rlp_word(rlp: &Rlp, index: usize) -> Result<[u8; 32], String> {
+    let data = rlp_bytes(rlp, index)?;
+    if data.len() > 32 || data.first() == Some(&0) {
+        return Err("Invalid rlp number".to_string());
+    }
+    let mut word = [0u8; 32];
+    word[32 - data.len()..].copy_from_slice(&data);
+    Ok(word)
+}
+
+fn rlp_to(rlp: &Rlp, index: usize) -> Result<Option<Address>, String> {
+    let data = rlp_bytes(rlp, index)?;
+    match data.len() {
+        0 => Ok(None),
+        20 => {
+            let mut address = Address::default();
+            address.0.copy_from_slice(&data);
+            Ok(Some(address))
+        }
+        _ => Err("Invalid address".to_string()),
+    }
+}
+
+fn public_key_address(public_key: &libsecp256k1::PublicKey) -> Address {
+    let mut address = Address::default();
+    address
+        .0
+        .copy_from_slice(&keccak(&public_key.serialize()[1..]).0[12..]);
+    address
+}
+
+/// The address of the private key
+pub fn secret_key_address(secret_key: &[u8]) -> Result<Address, String> {
+    let secret_key =
+        libsecp256k1::SecretKey::parse_slice(secret_key).map_err(|err| format!("{:?}", err))?;
+    Ok(public_key_address(
+        &libsecp256k1::PublicKey::from_secret_key(&secret_key),
+    ))
+}
+
+/// Recover the signer of the hash
+pub fn recover_sender(
+    hash: &[u8; 32],
+    recovery_id: u8,
+    r: &[u8; 32],
+    s: &[u8; 32],
+) -> Result<Address, String> {
+    if r == &[0u8; 32] || s == &[0u8; 32] {
+        return Err("Invalid signature: zero r or s".to_string());
+    }
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(r);
+    signature[32..].copy_from_slice(s);
+    let signature = libsecp256k1::Signature::parse_standard(&signature)
+        .map_err(|err| format!("Invalid signature: {:?}", err))?;
+    let recovery_id = libsecp256k1::RecoveryId::parse(recovery_id)
+        .map_err(|err| format!("Invalid recovery id: {:?}", err))?;
+    let public_key = libsecp256k1::recover(
+        &libsecp256k1::Message::parse(hash),
+        &signature,
+        &recovery_id,
+    )
+    .map_err(|err| format!("Invalid signature: {:?}", err))?;
+    Ok(public_key_address(&public_key))
+}
+
+/// The gas charged before the execution
+pub fn intrinsic_gas(tx: &Transaction, revision: Revision) -> u64 {
+    let revision = revision as u32;
+    let mut gas = 21000;
+    if tx.to.is_none() && revision >= Revision::EVMC_HOMESTEAD as u32 {
+        gas += 32000;
+    }
+    let nonzero_cost = if revision >= Revision::EVMC_ISTANBUL as u32 {
+        16
+    } else {
+        68
+    };
+    for byte in &tx.data {
+        gas += if *byte == 0 { 4 } else { nonzero_cost };
+    }
+    if revision >= Revision::EVMC_BERLIN as u32 {
+        for (_, keys) in &tx.access_list {
+            gas += 2400 + 1900 * keys.len() as u64;
+        }
+    }
+    gas
+}
+
+/// Check the transaction can be included against the current state
+pub fn validate_transaction(
+    context: &TestHostContext,
+    tx: &Transaction,
+    revision: Revision,
+) -> Result<(), String> {
+    let nonce = nonce_of(context, &tx.sender);
+    if tx.nonce != nonce {
+        return Err(format!(
+            "Invalid nonce: expected {}, got {}",
+            nonce, tx.nonce
+        ));
+    }
+    if tx.gas_limit > context.block.gas_limit {
+        return Err(format!(
+            "Gas limit {} exceeds the block gas limit {}",
+            tx.gas_limit, context.block.gas_limit
+        ));
+    }
+    let intrinsic = intrinsic_gas(tx, revision);
+    if tx.gas_limit < intrinsic {
+        return Err(format!(
+            "Intrinsic gas too low: {} < {}",
+            tx.gas_limit, intrinsic
+        ));
+    }
+    if let Some(priority_fee) = tx.priority_fee {
+        if priority_fee > tx.gas_price {
+            return Err("Max priority fee per gas is higher than the max fee".to_string());
+        }
+    }
+    let base_fee = base_fee(context);
+    if tx.gas_price < base_fee {
+        return Err(format!(
+            "Max fee per gas {} is less than the block base fee {}",
+            tx.gas_price, base_fee
+        ));
+    }
+    let cost = U256::from(tx.gas_limit)
+        .checked_mul(tx.gas_price)
+        .and_then(|fee| fee.checked_add(tx.value))
+        .ok_or_else(|| "Transaction cost overflows".to_string())?;
+    if balance_of(context, &tx.sender) < cost {
+        return Err("Insufficient balance for gas * price + value".to_string());
+    }
+    if context
+        .account(&tx.sender)
+        .map(|account| {
+            account
+                .code
+                .as_ref()
+                .map(|code| !code.0.is_empty())
+                .unwrap_or(false)
+        })
+        .unwrap_or(false)
+    {
+        return Err("Sender is not an externally owned account".to_string());
+    }
+    Ok(())
+}
+
+/// The base fee of the block, zero before London
+pub fn base_fee(context: &TestHostContext) -> U256 {
+    context
+        .block
+        .base_fee
+        .as_ref()
+        .map(|fee| u256_from_bytes(&fee.0))
+        .unwrap_or_else(U256::zero)
+}
+
+/// Validate and execute the transaction, the state is unchanged for invalid transactions
+pub fn apply_transaction(
+    vm: &EvmcVm,
+    context: &mut TestHostContext,
+    revision: Revision,
+    tx: &Transaction,
+) -> Result<TransactionResult, String> {
+    validate_transaction(context, tx, revision)?;
+    let revision_number = revision as u32;
+    let base_fee = base_fee(context);
+    let gas_price = tx.effective_gas_price(base_fee);
+
+    // Buy the gas and increase the nonce
+    let fee = U256::from(tx.gas_limit) * gas_price;
+    let balance = balance_of(context, &tx.sender);
+    set_balance(context, &tx.sender, balance - fee);
+    context.account_mut(&tx.sender).nonce += 1;
+    context.destructed_accounts.clear();
+    context.tx = TxEnv {
+        origin: tx.sender.clone(),
+        gas_price: Uint256(u256_to_bytes(gas_price)),
+    };
+    context.revision = Some(revision);
+    let sink = context.tracer.sink;
+    let tracer = Rc::new(HostTracer::recording(sink));
+    context.tracer = tracer.clone();
+
+    // The changes of the execution are reverted with the frame of the
+    // transaction, the gas is bought and the nonce increased below it
+    context.push_frame();
+    let gas = tx.gas_limit - intrinsic_gas(tx, revision);
+    let (destination, created_address) = match tx.to.as_ref() {
+        Some(to) => (to.clone(), None),
+        None => {
+            let address = create_address(&tx.sender, tx.nonce);
+            (address.clone(), Some(address))
+        }
+    };
+
+    // Transfer the value, the called account is created even if empty before
+    // Spurious Dragon
+    if revision_number < Revision::EVMC_SPURIOUS_DRAGON as u32 {
+        context.touch_account(&destination);
+    }
+    context.transfer(&tx.sender, &destination, &Uint256(u256_to_bytes(tx.value)));
+
+    let (status, mut gas_left, output) = match tx.to.as_ref() {
+        Some(to) => {
+            let code = context
+                .account(to)
+                .and_then(|account| account.code.as_ref().map(|code| code.0.clone()))
+                .unwrap_or_default();
+            execute_message(
+                vm,
+                context,
+                revision,
+                CallKind::EVMC_CALL,
+                tx,
+                &destination,
+                gas,
+                &code,
+            )
+        }
+        None => {
+            let collision = context
+                .account(&destination)
+                .map(|account| {
+                    account.nonce != 0
+                        || account
+                            .code
+                            .as_ref()
+                            .map(|code| !code.0.is_empty())
+                            .unwrap_or(false)
+                })
+                .unwrap_or(false);
+            if collision {
+                (StatusCode::EVMC_FAILURE, 0, Vec::new())
+            } else {
+                if revision_number >= Revision::EVMC_SPURIOUS_DRAGON as u32 {
+                    context.write_nonce(&destination, 1);
+                }
+                let (status, gas_left, output) = execute_message(
+                    vm,
+                    context,
+                    revision,
+                    CallKind::EVMC_CREATE,
+                    tx,
+                    &destination,
+                    gas,
+                    &tx.data,
+                );
+                deposit_code(context, revision, &destination, status, gas_left, output)
+            }
+        }
+    };
+
+    let success = status == StatusCode::EVMC_SUCCESS;
+    // The state below the frame has the original values
+    let sstore_refund = if success {
+        sstore_refund(context, &tracer, revision)
+    } else {
+        0
+    };
+    context.pop_frame(success);
+    if !success && status != StatusCode::EVMC_REVERT {
+        gas_left = 0;
+    }
+
+    // Refund the gas and reward the miner
+    let mut gas_used = tx.gas_limit - gas_left as u64;
+    if success {
+        let destructed: HashSet<&Address> = context.destructed_accounts.iter().collect();
+        let refund = sstore_refund + 24000 * destructed.len() as i64;
+        gas_used -= (refund.max(0) as u64).min(gas_used / 2);
+    }
+    let balance = balance_of(context, &tx.sender);
+    set_balance(
+        context,
+        &tx.sender,
+        balance + U256::from(tx.gas_limit - gas_used) * gas_price,
+    );
+    let coinbase = context.block.coinbase.clone();
+    let balance = balance_of(context, &coinbase);
+    set_balance(
+        context,
+        &coinbase,
+        balance + U256::from(gas_used) * (gas_price - base_fee),
+    );
+
+    // The value transfer and the call frames touch accounts only if succeeded
+    let mut touched = vec![tx.sender.clone(), coinbase];
+    let logs = if success {
+        touched.push(destination);
+        touched.extend(touched_accounts(&tracer));
+        finish_selfdestructs(context);
+        collect_logs(&tracer)
+    } else {
+        Vec::new()
+    };
+    if revision_number >= Revision::EVMC_SPURIOUS_DRAGON as u32 {
+        remove_empty_accounts(context, &touched);
+    }
+    // The events of the transaction are dropped with its tracer
+    context.tracer = Rc::new(HostTracer::new(sink));
+    Ok(TransactionResult {
+        status,
+        gas_used,
+        output,
+        logs,
+        created_address: if success { created_address } else { None },
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_message(
+    vm: &EvmcVm,
+    context: &mut TestHostContext,
+    revision: Revision,
+    kind: CallKind,
+    tx: &Transaction,
+    destination: &Address,
+    gas: u64,
+    code: &[u8],
+) -> (StatusCode, i64, Vec<u8>) {
+    if code.is_empty() {
+        return (StatusCode::EVMC_SUCCESS, gas as i64, Vec::new());
+    }
+    let input: &[u8] = if kind == CallKind::EVMC_CREATE {
+        &[]
+    } else {
+        &tx.data
+    };
+    let raw_message = ffi::evmc_message {
+        kind,
+        flags: 0,
+        depth: 0,
+        gas: gas as i64,
+        destination: destination.clone().into(),
+        sender: tx.sender.clone().into(),
+        input_data: if input.is_empty() {
+            std::ptr::null()
+        } else {
+            input.as_ptr()
+        },
+        input_size: input.len(),
+        value: Uint256(u256_to_bytes(tx.value)).into(),
+        create2_salt: Default::default(),
+    };
+    let message = ExecutionMessage::from(&raw_message);
+    let mut host_context = std::mem::take(context);
+    host_context.current_account = destination.clone();
+    let (result, host_context) = host_context.execute(vm, revision, code, &message);
+    *context = host_context;
+    (result.status_code, result.gas_left, result.output_data)
+}
+
+// Store the code returned by the init code, charge 200 gas per byte
+fn deposit_code(
+    context: &mut TestHostContext,
+    revision: Revision,
+    address: &Address,
+    status: StatusCode,
+    gas_left: i64,
+    output: Vec<u8>,
+) -> (StatusCode, i64, Vec<u8>) {
+    if status != StatusCode::EVMC_SUCCESS {
+        return (status, gas_left, output);
+    }
+    let revision = revision as u32;
+    if revision >= Revision::EVMC_SPURIOUS_DRAGON as u32 && output.len() > MAX_CODE_SIZE {
+        return (StatusCode::EVMC_OUT_OF_GAS, 0, Vec::new());
+    }
+    let cost = 200 * output.len() as i64;
+    if cost > gas_left {
+        if revision >= Revision::EVMC_HOMESTEAD as u32 {
+            return (StatusCode::EVMC_OUT_OF_GAS, 0, Vec::new());
+        }
+        // Frontier: the contract is created without code
+        return (status, gas_left, Vec::new());
+    }
+    context.update_code(address.clone(), output.clone());
+    (status, gas_left - cost, Vec::new())
+}
+
+// Remove the destructed accounts, their balance moved to the beneficiaries
+// in the frames
+fn finish_selfdestructs(context: &mut TestHostContext) {
+    for address in context.destructed_accounts.clone() {
+        context.remove_account(&address);
+    }
+}
+
+/// The logs emitted by the call frames that succeeded
+pub fn collect_logs(tracer: &HostTracer) -> Vec<ReportLog> {
+    tracer
+        .committed_events()
+        .into_iter()
+        .filter_map(|event| match event {
+            HostEvent::Log {
+                address,
+                topics,
+                data,
+                ..
+            } => Some(ReportLog {
+                address,
+                log: LogEntry {
+                    data: JsonBytes(data.0),
+                    topics,
+                },
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+// The accounts touched by the call frames that succeeded: the senders and
+// destinations of the calls, and the beneficiaries of the selfdestructs
+fn touched_accounts(tracer: &HostTracer) -> Vec<Address> {
+    let mut touched = Vec::new();
+    let mut calls = Vec::new();
+    for event in tracer.committed_events() {
+        match event {
+            HostEvent::CallEnter {
+                sender,
+                destination,
+                ..
+            } => calls.push((sender, destination)),
+            HostEvent::CallExit { status, .. } => {
+                if let Some((sender, destination)) = calls.pop() {
+                    if status == "EVMC_SUCCESS" {
+                        touched.push(sender);
+                        touched.push(destination);
+                    }
+                }
+            }
+            HostEvent::Selfdestruct { beneficiary, .. } => touched.push(beneficiary),
+            _ => {}
+        }
+    }
+    touched
+}
+
+// EIP-161: the touched accounts are removed if empty
+fn remove_empty_accounts(context: &mut TestHostContext, touched: &[Address]) {
+    for address in touched {
+        let empty = context
+            .account(address)
+            .map(|account| {
+                account.nonce == 0
+                    && account.balance.0 == [0u8; 32]
+                    && account
+                        .code
+                        .as_ref()
+                        .map(|code| code.0.is_empty())
+                        .unwrap_or(true)
+            })
+            .unwrap_or(false);
+        if empty {
+            context.remove_account(address);
+        }
+    }
+}
+
+/// The refund counter of the storage writes in the call frames that
+/// succeeded, it can be negative
+pub fn sstore_refund(original: &TestHostContext, tracer: &HostTracer, revision: Revision) -> i64 {
+    const CLEAR_REFUND: i64 = 15000;
+    const SET_GAS: i64 = 20000;
+    let (sload_gas, reset_gas) = match revision {
+        Revision::EVMC_CONSTANTINOPLE => (200, 5000),
+        Revision::EVMC_ISTANBUL => (800, 5000),
+        // EIP-2929: the slot is warm after the first access, the cold cost
+        // is not refunded
+        Revision::EVMC_BERLIN => (100, 5000 - 2100),
+        // No net gas metering
+        _ => (0, 0),
+    };
+    let net_metering = sload_gas > 0;
+    let zero = Bytes32::default();
+    let mut current: HashMap<(Address, Bytes32), Bytes32> = HashMap::new();
+    let mut refund = 0;
+    for event in tracer.committed_events() {
+        let (address, key, value) = match event {
+            HostEvent::Sstore {
+                address,
+                key,
+                value,
+                ..
+            } => (address, key, value),
+            _ => continue,
+        };
+        let original_value = original
+            .stored_value(&address, &key)
+            .map(|value| value.data)
+            .unwrap_or_default();
+        let slot = (address, key);
+        let current_value = current
+            .get(&slot)
+            .cloned()
+            .unwrap_or_else(|| original_value.clone());
+        if !net_metering {
+            if current_value != zero && value == zero {
+                refund += CLEAR_REFUND;
+            }
+        } else if current_value != value {
+            if original_value == current_value {
+                if original_value != zero && value == zero {
+                    refund += CLEAR_REFUND;
+                }
+            } else {
+                if original_value != zero {
+                    if current_value == zero {
+                        refund -= CLEAR_REFUND;
+                    } else if value == zero {
+                        refund += CLEAR_REFUND;
+                    }
+                }
+                if original_value == value {
+                    refund += if original_value == zero {
+                        SET_GAS - sload_gas
+                    } else {
+                        reset_gas - sload_gas
+                    };
+                }
+            }
+        }
+        current.insert(slot, value);
+    }
+    refund
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host_trace::TraceSink;
+    use crate::{AccountData, Value};
+
+    fn word(byte: u8) -> Bytes32 {
+        let mut word = Bytes32::default();
+        word.0[31] = byte;
+        word
+    }
+
+    fn sstore(tracer: &HostTracer, address: &Address, key: u8, value: u8) {
+        tracer.record(HostEvent::Sstore {
+            depth: 0,
+            address: address.clone(),
+            key: word(key),
+            value: word(value),
+            status: String::new(),
+        });
+    }
+
+    fn call(tracer: &HostTracer, address: &Address, status: &str, writes: &[(u8, u8)]) {
+        tracer.record(HostEvent::CallEnter {
+            depth: 1,
+            kind: "EVMC_CALL".to_string(),
+            sender: address.clone(),
+            destination: address.clone(),
+            gas: 0,
+            value: Uint256::default(),
+            input: JsonBytes(Vec::new()),
+        });
+        for (key, value) in writes {
+            sstore(tracer, address, *key, *value);
+        }
+        tracer.record(HostEvent::CallExit {
+            depth: 1,
+            status: status.to_string(),
+            gas_left: 0,
+            gas_used: 0,
+            output: JsonBytes(Vec::new()),
+            revert_reason: None,
+        });
+    }
+
+    // Slot 1 is 1 and slot 2 is 0 originally
+    fn original(address: &Address) -> TestHostContext {
+        let mut context = TestHostContext::new(0, address.clone());
+        let mut account = AccountData::new(address.clone());
+        account.storage.insert(
+            word(1),
+            Value {
+                data: word(1),
+                modify_time: 0,
+            },
+        );
+        context.accounts.insert(address.clone(), account);
+        context
+    }
+
+    fn refund(revision: Revision, writes: &[(u8, u8)]) -> i64 {
+        let address = Address([0xaa; 20]);
+        let tracer = HostTracer::recording(TraceSink::Quiet);
+        for (key, value) in writes {
+            sstore(&tracer, &address, *key, *value);
+        }
+        sstore_refund(&original(&address), &tracer, revision)
+    }
+
+    #[test]
+    fn sstore_refund_clear() {
+        assert_eq!(refund(Revision::EVMC_PETERSBURG, &[(1, 0)]), 15000);
+        assert_eq!(refund(Revision::EVMC_BERLIN, &[(1, 0)]), 15000);
+        // Not net metered: every clear is refunded
+        assert_eq!(
+            refund(Revision::EVMC_PETERSBURG, &[(1, 0), (1, 2), (1, 0)]),
+            30000
+        );
+    }
+
+    #[test]
+    fn sstore_refund_restore_original() {
+        // Set and clear a fresh slot
+        assert_eq!(refund(Revision::EVMC_ISTANBUL, &[(2, 1), (2, 0)]), 19200);
+        assert_eq!(refund(Revision::EVMC_BERLIN, &[(2, 1), (2, 0)]), 19900);
+        // Change and restore a dirty slot
+        assert_eq!(
+            refund(Revision::EVMC_CONSTANTINOPLE, &[(1, 2), (1, 1)]),
+            4800
+        );
+        assert_eq!(refund(Revision::EVMC_BERLIN, &[(1, 2), (1, 1)]), 2800);
+        // Clear then restore: the clear refund is taken back
+        assert_eq!(refund(Revision::EVMC_BERLIN, &[(1, 0), (1, 1)]), 2800);
+        assert_eq!(refund(Revision::EVMC_BERLIN, &[(1, 0), (1, 2)]), 0);
+    }
+
+    #[test]
+    fn sstore_refund_without_reverted_frames() {
+        let address = Address([0xaa; 20]);
+        let tracer = HostTracer::recording(TraceSink::Quiet);
+        call(&tracer, &address, "EVMC_REVERT", &[(1, 0)]);
+        call(&tracer, &address, "EVMC_SUCCESS", &[(1, 2)]);
+        call(&tracer, &address, "EVMC_SUCCESS", &[(1, 0)]);
+        let context = original(&address);
+        assert_eq!(
+            sstore_refund(&context, &tracer, Revision::EVMC_BERLIN),
+            15000
+        );
+    }
+
+    #[test]
+    fn validate_returns_the_london_warning() {
+        let tx = Transaction {
+            priority_fee: Some(U256::one()),
+            ..Default::default()
+        };
+        let dynamic_fee = impersonated_transaction(2, Some(1), &tx);
+        let warning = dynamic_fee
+            .validate(Revision::EVMC_MAX_REVISION, 1)
+            .unwrap()
+            .unwrap();
+        assert!(warning.starts_with("EIP-1559 transactions are executed with the"));
+        assert!(dynamic_fee.validate(Revision::EVMC_ISTANBUL, 1).is_err());
+        assert_eq!(
+            dynamic_fee.validate(Revision::EVMC_MAX_REVISION, 5),
+            Err("Invalid chain id: expected 5, got 1".to_string())
+        );
+
+        let legacy = impersonated_transaction(0, Some(1), &tx);
+        assert_eq!(legacy.validate(Revision::EVMC_MAX_REVISION, 1), Ok(None));
+    }
+
+    #[test]
+    fn remove_touched_empty_accounts() {
+        let touched = Address([1; 20]);
+        let untouched = Address([2; 20]);
+        let called = Address([3; 20]);
+        let reverted = Address([4; 20]);
+        let mut context = TestHostContext::new(0, touched.clone());
+        for address in &[&touched, &untouched, &called, &reverted] {
+            context
+                .accounts
+                .insert((*address).clone(), AccountData::new((*address).clone()));
+        }
+        let tracer = HostTracer::recording(TraceSink::Quiet);
+        call(&tracer, &called, "EVMC_SUCCESS", &[]);
+        call(&tracer, &reverted, "EVMC_REVERT", &[]);
+        let mut addresses = touched_accounts(&tracer);
+        addresses.push(touched.clone());
+        remove_empty_accounts(&mut context, &addresses);
+        assert!(!context.accounts.contains_key(&touched));
+        assert!(!context.accounts.contains_key(&called));
+        assert!(context.accounts.contains_key(&untouched));
+        assert!(context.accounts.contains_key(&reverted));
+    }
+}
@@ -0,0 +1,587 @@
+//! Merkle Patricia Trie root calculation (as defined in the yellow paper, appendix D)
+
+use std::collections::HashMap;
+
+use keccak_hash::keccak;
+use rlp::{Rlp, RlpStream};
+
+use crate::{AccountData, TestHostContext};
+
+/// keccak256(rlp("")), the root of an empty trie
+pub const EMPTY_ROOT: [u8; 32] = [
+    0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6, 0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+    0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0, 0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
+];
+
+/// The (key, value) items of a trie
+pub type Items = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// The root hash of the trie contains all the (key, value) items
+pub fn trie_root(items: Vec<(Vec<u8>, Vec<u8>)>) -> [u8; 32] {
+    let mut items: Vec<(Vec<u8>, Vec<u8>)> = items
+        .into_iter()
+        .map(|(key, value)| (to_nibbles(&key), value))
+        .collect();
+    items.sort();
+    items.dedup_by(|a, b| a.0 == b.0);
+    keccak(encode_node(&items, 0)).0
+}
+
+/// The root hash of the trie with keccak256 hashed keys
+pub fn secure_trie_root(items: Vec<(Vec<u8>, Vec<u8>)>) -> [u8; 32] {
+    trie_root(
+        items
+            .into_iter()
+            .map(|(key, value)| (keccak(&key).0.to_vec(), value))
+            .collect(),
+    )
+}
+
+/// The root hash of the trie keyed by rlp(index), used by transactions and receipts
+pub fn ordered_trie_root(values: Vec<Vec<u8>>) -> [u8; 32] {
+    trie_root(
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(index, value)| (rlp::encode(&(index as u64)).to_vec(), value))
+            .collect(),
+    )
+}
+
+/// The (slot, rlp(value)) items of the storage trie, zero values are not in the trie
+pub fn storage_items(account: &AccountData) -> Vec<(Vec<u8>, Vec<u8>)> {
+    account
+        .storage
+        .iter()
+        .filter(|(_, value)| value.data.0 != [0u8; 32])
+        .map(|(key, value)| (key.0.to_vec(), rlp_bytes(trim_zeros(&value.data.0))))
+        .collect()
+}
+
+/// The storage root of the account
+pub fn storage_root(account: &AccountData) -> [u8; 32] {
+    secure_trie_root(storage_items(account))
+}
+
+pub fn code_hash(account: &AccountData) -> [u8; 32] {
+    let code: &[u8] = account.code.as_ref().map(|code| &code.0[..]).unwrap_or(&[]);
+    keccak(code).0
+}
+
+/// rlp([nonce, balance, storage_root, code_hash])
+pub fn account_rlp(account: &AccountData) -> Vec<u8> {
+    let mut stream = RlpStream::new_list(4);
+    stream.append(&account.nonce);
+    stream.append(&trim_zeros(&account.balance.0));
+    stream.append(&&storage_root(account)[..]);
+    stream.append(&&code_hash(account)[..]);
+    stream.out()
+}
+
+/// The (address, rlp(account)) items of the world state trie, the accounts
+/// not loaded are read from the backend of the context (once, their items are
+/// kept until they are written)
+pub fn state_items(context: &TestHostContext) -> Result<Items, String> {
+    let mut items = Vec::new();
+    context.visit_loaded_accounts(|address, account| {
+        items.push((address.0.to_vec(), account_rlp(account)))
+    })?;
+    for (address, encoded) in context.encoded_backend_accounts(account_rlp)? {
+        items.push((address.0.to_vec(), encoded));
+    }
+    Ok(items)
+}
+
+/// The world state root of all the accounts
+pub fn state_root(context: &TestHostContext) -> Result<[u8; 32], String> {
+    Ok(secure_trie_root(state_items(context)?))
+}
+
+/// The nodes on the path of the key from the root, the nodes embedded in
+/// their parents are not listed (as eth_getProof)
+pub fn trie_proof(items: Vec<(Vec<u8>, Vec<u8>)>, key: &[u8]) -> Vec<Vec<u8>> {
+    let mut items: Vec<(Vec<u8>, Vec<u8>)> = items
+        .into_iter()
+        .map(|(key, value)| (to_nibbles(&key), value))
+        .collect();
+    items.sort();
+    items.dedup_by(|a, b| a.0 == b.0);
+    let mut proof = Vec::new();
+    if !items.is_empty() {
+        collect_proof(&items, 0, &to_nibbles(key), &mut proof);
+    }
+    proof
+}
+
+/// The proof of the keccak256 hashed key
+pub fn secure_trie_proof(items: Vec<(Vec<u8>, Vec<u8>)>, key: &[u8]) -> Vec<Vec<u8>> {
+    trie_proof(
+        items
+            .into_iter()
+            .map(|(key, value)| (keccak(&key).0.to_vec(), value))
+            .collect(),
+        &keccak(key).0,
+    )
+}
+
+/// Verify the proof of the keccak256 hashed key
+pub fn verify_secure_proof(
+    root: &[u8; 32],
+    key: &[u8],
+    proof: &[Vec<u8>],
+) -> Result<Option<Vec<u8>>, String> {
+    verify_proof(root, &keccak(key).0, proof)
+}
+
+/// Verify the proof of the key, return the value or None if the proof shows
+/// the key is not in the trie
+pub fn verify_proof(
+    root: &[u8; 32],
+    key: &[u8],
+    proof: &[Vec<u8>],
+) -> Result<Option<Vec<u8>>, String> {
+    if root == &EMPTY_ROOT {
+        return Ok(None);
+    }
+    let nodes: HashMap<[u8; 32], &[u8]> = proof
+        .iter()
+        .map(|node| (keccak(node).0, &node[..]))
+        .collect();
+    let key = to_nibbles(key);
+    let mut node: Vec<u8> = nodes
+        .get(root)
+        .ok_or_else(|| "The root node is missing in the proof".to_string())?
+        .to_vec();
+    let mut depth = 0;
+    loop {
+        let rlp = Rlp::new(&node);
+        let child = match rlp.item_count().map_err(|err| err.to_string())? {
+            17 => {
+                if depth == key.len() {
+                    let value: Vec<u8> = rlp.val_at(16).map_err(|err| err.to_string())?;
+                    return Ok(if value.is_empty() { None } else { Some(value) });
+                }
+                depth += 1;
+                rlp.at(key[depth - 1] as usize)
+                    .map_err(|err| err.to_string())?
+            }
+            2 => {
+                let path: Vec<u8> = rlp.val_at(0).map_err(|err| err.to_string())?;
+                let (nibbles, leaf) = decode_hex_prefix(&path)?;
+                if leaf {
+                    return if key[depth..] == nibbles[..] {
+                        Ok(Some(rlp.val_at(1).map_err(|err| err.to_string())?))
+                    } else {
+                        Ok(None)
+                    };
+                }
+                if !key[depth..].starts_with(&nibbles) {
+                    return Ok(None);
+                }
+                depth += nibbles.len();
+                rlp.at(1).map_err(|err| err.to_string())?
+            }
+            count => return Err(format!("Invalid trie node with {} items", count)),
+        };
+        node = if child.is_list() {
+            child.as_raw().to_vec()
+        } else {
+            let hash = child.data().map_err(|err| err.to_string())?;
+            if hash.is_empty() {
+                return Ok(None);
+            }
+            if hash.len() != 32 {
+                return Err("Invalid trie node reference".to_string());
+            }
+            let mut reference = [0u8; 32];
+            reference.copy_from_slice(hash);
+            nodes
+                .get(&reference)
+                .ok_or_else(|| format!("The node 0x{} is missing in the proof", hex::encode(hash)))?
+                .to_vec()
+        };
+    }
+}
+
+/// The big endian number without the leading zeros, as rlp encodes integers
+pub fn trim_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|byte| *byte != 0)
+        .unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+fn rlp_bytes(bytes: &[u8]) -> Vec<u8> {
+    let mut stream = RlpStream::new();
+    stream.append(&bytes);
+    stream.out()
+}
+
+fn to_nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter()
+        .flat_map(|byte| vec![byte >> 4, byte & 0x0f])
+        .collect()
+}
+
+/// Hex prefix encoding of the nibbles (appendix C)
+fn hex_prefix(nibbles: &[u8], leaf: bool) -> Vec<u8> {
+    let flag = if leaf { 2 } else { 0 };
+    let mut encoded = Vec::with_capacity(nibbles.len() / 2 + 1);
+    let rest = if nibbles.len() % 2 == 1 {
+        encoded.push(((flag + 1) << 4) | nibbles[0]);
+        &nibbles[1..]
+    } else {
+        encoded.push(flag << 4);
+        nibbles
+    };
+    for pair in rest.chunks(2) {
+        encoded.push((pair[0] << 4) | pair[1]);
+    }
+    encoded
+}
+
+// The rlp of the node contains the sorted items, the first `depth` nibbles of
+// the keys are consumed by the parent nodes
+fn encode_node(items: &[(Vec<u8>, Vec<u8>)], depth: usize) -> Vec<u8> {
+    if items.is_empty() {
+        return rlp_bytes(&[]);
+    }
+    if items.len() == 1 {
+        let (key, value) = &items[0];
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&hex_prefix(&key[depth..], true));
+        stream.append(value);
+        return stream.out();
+    }
+
+    // The items are sorted, the common prefix of all is the one of the first and last
+    let first = &items[0].0;
+    let last = &items[items.len() - 1].0;
+    let prefix_len = first[depth..]
+        .iter()
+        .zip(last[depth..].iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if prefix_len > 0 {
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&hex_prefix(&first[depth..depth + prefix_len], false));
+        append_child(&mut stream, encode_node(items, depth + prefix_len));
+        return stream.out();
+    }
+
+    let mut stream = RlpStream::new_list(17);
+    let mut value = None;
+    let mut start = 0;
+    if items[0].0.len() == depth {
+        value = Some(&items[0].1);
+        start = 1;
+    }
+    for nibble in 0..16u8 {
+        let end = start
+            + items[start..]
+                .iter()
+                .take_while(|(key, _)| key[depth] == nibble)
+                .count();
+        if start == end {
+            stream.append_empty_data();
+        } else {
+            append_child(&mut stream, encode_node(&items[start..end], depth + 1));
+        }
+        start = end;
+    }
+    match value {
+        Some(value) => stream.append(value),
+        None => stream.append_empty_data(),
+    };
+    stream.out()
+}
+
+// Push the node of the items and the nodes down the path of the key
+fn collect_proof(items: &[(Vec<u8>, Vec<u8>)], depth: usize, key: &[u8], proof: &mut Vec<Vec<u8>>) {
+    let node = encode_node(items, depth);
+    if depth == 0 || node.len() >= 32 {
+        proof.push(node);
+    }
+    if items.len() <= 1 {
+        return;
+    }
+    let first = &items[0].0;
+    let last = &items[items.len() - 1].0;
+    let prefix_len = first[depth..]
+        .iter()
+        .zip(last[depth..].iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if prefix_len > 0 {
+        if key[depth..].starts_with(&first[depth..depth + prefix_len]) {
+            collect_proof(items, depth + prefix_len, key, proof);
+        }
+        return;
+    }
+    if key.len() == depth {
+        return;
+    }
+    let children: Vec<(Vec<u8>, Vec<u8>)> = items
+        .iter()
+        .filter(|(item_key, _)| item_key.len() > depth && item_key[depth] == key[depth])
+        .cloned()
+        .collect();
+    if !children.is_empty() {
+        collect_proof(&children, depth + 1, key, proof);
+    }
+}
+
+fn decode_hex_prefix(encoded: &[u8]) -> Result<(Vec<u8>, bool), String> {
+    let first = *encoded
+        .first()
+        .ok_or_else(|| "Empty hex prefix path".to_string())?;
+    let flag = first >> 4;
+    if flag > 3 {
+        return Err("Invalid hex prefix path".to_string());
+    }
+    let mut nibbles = Vec::new();
+    if flag % 2 == 1 {
+        nibbles.push(first & 0x0f);
+    }
+    nibbles.extend(to_nibbles(&encoded[1..]));
+    Ok((nibbles, flag >= 2))
+}
+
+// Nodes shorter than 32 bytes are embedded in the parent, others are referenced by hash
+fn append_child(stream: &mut RlpStream, node: Vec<u8>) {
+    if node.len() < 32 {
+        stream.append_raw(&node, 1);
+    } else {
+        stream.append(&&keccak(&node).0[..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evmc::{Address, Bytes32};
+    use crate::Value;
+
+    fn root_of(items: &[(&str, &str)]) -> String {
+        let items = items
+            .iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect();
+        hex::encode(trie_root(items))
+    }
+
+    // The trieanyorder cases of ethereum/tests
+    #[test]
+    fn trie_root_any_order() {
+        assert_eq!(root_of(&[]), hex::encode(EMPTY_ROOT));
+        assert_eq!(
+            root_of(&[("A", &"a".repeat(50))]),
+            "d23786fb4a010da3ce639d66d5e904a11dbc02746d1ce25029e53290cabf28ab"
+        );
+        assert_eq!(
+            root_of(&[
+                ("doe", "reindeer"),
+                ("dog", "puppy"),
+                ("dogglesworth", "cat")
+            ]),
+            "8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3"
+        );
+        assert_eq!(
+            root_of(&[
+                ("do", "verb"),
+                ("horse", "stallion"),
+                ("doge", "coin"),
+                ("dog", "puppy")
+            ]),
+            "5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84"
+        );
+        assert_eq!(
+            root_of(&[("foo", "bar"), ("food", "bass")]),
+            "17beaa1648bafa633cda809c90c04af50fc8aed3cb40d16efbddee6fdf63c4c3"
+        );
+        assert_eq!(
+            root_of(&[("be", "e"), ("dog", "puppy"), ("bed", "d")]),
+            "3f67c7a47520f79faa29255d2d3c084a7a6df0453116ed7232ff10277a8be68b"
+        );
+        assert_eq!(
+            root_of(&[("test", "test"), ("te", "testy")]),
+            "8452568af70d8d140f58d941338542f645fcca50094b20f3c3d8c3df49337928"
+        );
+    }
+
+    #[test]
+    fn trie_root_hex_keys() {
+        let items = vec![
+            (vec![0x00, 0x45], hex::decode("0123456789").unwrap()),
+            (vec![0x45, 0x00], hex::decode("9876543210").unwrap()),
+        ];
+        assert_eq!(
+            hex::encode(trie_root(items)),
+            "285505fcabe84badc8aa310e2aae17eddc7d120aabec8a476902c8184b3a3503"
+        );
+    }
+
+    #[test]
+    fn trie_root_ignores_order() {
+        let items: Vec<(Vec<u8>, Vec<u8>)> = (0..64u8)
+            .map(|index| (vec![index, index / 3], vec![index; index as usize + 1]))
+            .collect();
+        let mut reversed = items.clone();
+        reversed.reverse();
+        assert_eq!(trie_root(items), trie_root(reversed));
+    }
+
+    #[test]
+    fn empty_roots() {
+        assert_eq!(ordered_trie_root(Vec::new()), EMPTY_ROOT);
+        assert_eq!(secure_trie_root(Vec::new()), EMPTY_ROOT);
+        assert_eq!(
+            state_root(&TestHostContext::new(0, Address::default())).unwrap(),
+            EMPTY_ROOT
+        );
+    }
+
+    #[test]
+    fn state_root_of_accounts() {
+        let address = Address([0x11; 20]);
+        let mut account = AccountData::new(address.clone());
+        // rlp([0, 0, EMPTY_ROOT, keccak256("")])
+        assert_eq!(
+            hex::encode(account_rlp(&account)),
+            "f8448080a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421\
+             a0c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+
+        // The zero values are not in the storage trie
+        let mut key = Bytes32::default();
+        key.0[31] = 1;
+        let mut one = Bytes32::default();
+        one.0[31] = 1;
+        for (key, data) in [(key.clone(), one), (Bytes32([2; 32]), Bytes32::default())] {
+            account.storage.insert(
+                key,
+                Value {
+                    data,
+                    modify_time: 0,
+                },
+            );
+        }
+        account.nonce = 1;
+        let expected_root = secure_trie_root(vec![(key.0.to_vec(), vec![0x01])]);
+        assert_eq!(storage_root(&account), expected_root);
+
+        let mut stream = RlpStream::new_list(4);
+        stream.append(&1u64);
+        stream.append(&"");
+        stream.append(&&expected_root[..]);
+        stream.append(&&keccak([]).0[..]);
+        let mut context = TestHostContext::new(0, address.clone());
+        context.accounts.insert(address.clone(), account);
+        assert_eq!(
+            state_root(&context).unwrap(),
+            secure_trie_root(vec![(address.0.to_vec(), stream.out().to_vec())])
+        );
+    }
+
+    fn items(pairs: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    fn prove(items: &[(Vec<u8>, Vec<u8>)], key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let root = trie_root(items.to_vec());
+        verify_proof(&root, key, &trie_proof(items.to_vec(), key))
+    }
+
+    #[test]
+    fn proof_of_present_keys() {
+        let items = items(&[
+            ("do", "verb"),
+            ("dog", "puppy"),
+            ("doge", "coin"),
+            ("horse", "stallion"),
+            ("dogglesworth", &"cat".repeat(20)),
+        ]);
+        for (key, value) in &items {
+            assert_eq!(prove(&items, key).unwrap().as_ref(), Some(value));
+        }
+    }
+
+    #[test]
+    fn proof_of_absent_keys() {
+        let items = items(&[("do", "verb"), ("dog", "puppy"), ("horse", "stallion")]);
+        // Ending in a branch without value, an extension, a leaf, or an empty child
+        for key in &["d", "", "dogs", "hors", "horses", "cat", "doe"] {
+            assert_eq!(prove(&items, key.as_bytes()).unwrap(), None, "{}", key);
+        }
+    }
+
+    #[test]
+    fn proof_with_embedded_nodes() {
+        // The extension of the common nibble 6 and the branch, the short
+        // leaves are embedded in the branch
+        let items = items(&[("a", "1"), ("b", "2"), ("c", &"3".repeat(40))]);
+        let proof = trie_proof(items.clone(), b"a");
+        assert_eq!(proof.len(), 2);
+        assert_eq!(prove(&items, b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(prove(&items, b"b").unwrap(), Some(b"2".to_vec()));
+        // The long leaf is referenced by hash
+        assert_eq!(trie_proof(items.clone(), b"c").len(), 3);
+        assert_eq!(
+            prove(&items, b"c").unwrap(),
+            Some("3".repeat(40).into_bytes())
+        );
+        assert_eq!(prove(&items, b"d").unwrap(), None);
+
+        // The whole trie is shorter than 32 bytes
+        let items = vec![(b"k".to_vec(), b"v".to_vec())];
+        assert_eq!(prove(&items, b"k").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(prove(&items, b"x").unwrap(), None);
+    }
+
+    #[test]
+    fn secure_proof_round_trip() {
+        let items: Vec<(Vec<u8>, Vec<u8>)> = (0..200u32)
+            .map(|index| (index.to_be_bytes().to_vec(), rlp::encode(&index).to_vec()))
+            .collect();
+        let root = secure_trie_root(items.clone());
+        for index in (0..400u32).step_by(7) {
+            let key = index.to_be_bytes();
+            let proof = secure_trie_proof(items.clone(), &key);
+            let expected = if index < 200 {
+                Some(rlp::encode(&index).to_vec())
+            } else {
+                None
+            };
+            assert_eq!(verify_secure_proof(&root, &key, &proof).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn proof_rejected() {
+        let items: Vec<(Vec<u8>, Vec<u8>)> = (0..50u32)
+            .map(|index| (index.to_be_bytes().to_vec(), vec![1; 40]))
+            .collect();
+        let root = secure_trie_root(items.clone());
+        let key = 7u32.to_be_bytes();
+        let proof = secure_trie_proof(items.clone(), &key);
+        assert!(proof.len() > 1);
+        // Another root, a missing node, a modified node
+        assert!(verify_secure_proof(&[1; 32], &key, &proof).is_err());
+        assert!(verify_secure_proof(&root, &key, &proof[..proof.len() - 1]).is_err());
+        let mut modified = proof.clone();
+        let last = modified.last_mut().unwrap();
+        let end = last.len() - 1;
+        last[end] ^= 1;
+        assert!(verify_secure_proof(&root, &key, &modified).is_err());
+    }
+
+    #[test]
+    fn empty_trie_proof() {
+        assert!(secure_trie_proof(Vec::new(), b"key").is_empty());
+        assert_eq!(verify_secure_proof(&EMPTY_ROOT, b"key", &[]).unwrap(), None);
+    }
+}
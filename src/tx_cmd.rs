@@ -0,0 +1,420 @@
+//! Build and sign legacy, access list (EIP-2930) and dynamic fee (EIP-1559)
+//! transactions
+
+use std::fs;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use keccak_hash::keccak;
+use serde_json::json;
+
+use crate::abi::{constructor_encode_input, contract_encode_input, load_contract};
+use crate::load_binary;
+use crate::statetest::{access_list, parse_address, parse_hex, AccessListItem};
+use crate::transaction::{
+    decode_transaction, parse_u256, sign_transaction, signing_payload, Transaction,
+};
+
+/// Transaction sub command
+pub fn sub_command(name: &'static str) -> App<'static, 'static> {
+    let args = vec![
+        Arg::with_name("type")
+            .long("type")
+            .takes_value(true)
+            .possible_values(&["legacy", "access-list", "dynamic-fee"])
+            .default_value("legacy")
+            .help("The transaction type"),
+        Arg::with_name("chain-id")
+            .long("chain-id")
+            .takes_value(true)
+            .default_value("1")
+            .help("The chain id, 0 for a legacy transaction without EIP-155"),
+        Arg::with_name("nonce")
+            .long("nonce")
+            .takes_value(true)
+            .default_value("0")
+            .help("The nonce of the sender"),
+        Arg::with_name("gas")
+            .long("gas")
+            .takes_value(true)
+            .default_value("21000")
+            .help("The gas limit"),
+        Arg::with_name("gas-price")
+            .long("gas-price")
+            .takes_value(true)
+            .conflicts_with_all(&["max-fee", "priority-fee"])
+            .help("The gas price (wei) of legacy and access list transactions"),
+        Arg::with_name("max-fee")
+            .long("max-fee")
+            .takes_value(true)
+            .help("The max fee per gas (wei) of dynamic fee transactions"),
+        Arg::with_name("priority-fee")
+            .long("priority-fee")
+            .takes_value(true)
+            .help("The max priority fee per gas (wei) of dynamic fee transactions"),
+        Arg::with_name("to")
+            .long("to")
+            .takes_value(true)
+            .help("The recipient, create a contract if not given"),
+        Arg::with_name("value")
+            .long("value")
+            .takes_value(true)
+            .default_value("0")
+            .help("The value (wei) to transfer"),
+        Arg::with_name("data")
+            .long("data")
+            .takes_value(true)
+            .conflicts_with_all(&["function", "code"])
+            .help("The calldata (or init code) in hex"),
+        Arg::with_name("code")
+            .long("code")
+            .takes_value(true)
+            .conflicts_with("to")
+            .help("The init code path, the constructor arguments are encoded by --abi"),
+        Arg::with_name("abi")
+            .long("abi")
+            .takes_value(true)
+            .help("The ABI json file to encode the function call or the constructor"),
+        Arg::with_name("function")
+            .long("function")
+            .takes_value(true)
+            .requires_all(&["abi", "to"])
+            .help("The function to call"),
+        Arg::with_name("param")
+            .long("param")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .allow_hyphen_values(true)
+            .value_name("value")
+            .help("The function (or constructor) parameters"),
+        Arg::with_name("access-list")
+            .long("access-list")
+            .takes_value(true)
+            .help("The access list json file: [{\"address\": .., \"storageKeys\": [..]}]"),
+        Arg::with_name("json")
+            .long("json")
+            .help("Print the result as json"),
+    ];
+    App::new(name)
+        .about("Build and sign transactions")
+        .subcommand(
+            SubCommand::with_name("build")
+                .about("Build the unsigned transaction, print the payload to sign and its hash")
+                .args(&args),
+        )
+        .subcommand(
+            SubCommand::with_name("sign")
+                .about("Sign the transaction, print the raw transaction and its hash")
+                .args(&args)
+                .arg(
+                    Arg::with_name("private-key")
+                        .long("private-key")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The private key (hex) of the sender"),
+                ),
+        )
+}
+
+pub fn process(sub_matches: &ArgMatches) -> Result<(), String> {
+    match sub_matches.subcommand() {
+        ("build", Some(m)) => {
+            let (tx_type, chain_id, tx) = transaction(m)?;
+            let payload = signing_payload(tx_type, chain_id, &tx);
+            let hash = keccak(&payload).0;
+            if m.is_present("json") {
+                let output = json!({
+                    "type": tx_type,
+                    "payload": format!("0x{}", hex::encode(&payload)),
+                    "signing_hash": format!("0x{}", hex::encode(hash)),
+                });
+                println!("{}", serde_json::to_string_pretty(&output).unwrap());
+            } else {
+                println!("Payload: 0x{}", hex::encode(&payload));
+                println!("Signing hash: 0x{}", hex::encode(hash));
+            }
+        }
+        ("sign", Some(m)) => {
+            let (tx_type, chain_id, tx) = transaction(m)?;
+            let secret_key = parse_hex(m.value_of("private-key").unwrap())?;
+            let raw = sign_transaction(tx_type, chain_id, &tx, &secret_key)?;
+            // Decode it back to recover the sender
+            let signed = decode_transaction(&raw)?;
+            if m.is_present("json") {
+                let output = json!({
+                    "type": tx_type,
+                    "from": signed.transaction.sender,
+                    "raw": format!("0x{}", hex::encode(&raw)),
+                    "hash": format!("0x{}", hex::encode(signed.hash)),
+                });
+                println!("{}", serde_json::to_string_pretty(&output).unwrap());
+            } else {
+                println!("From: 0x{:?}", signed.transaction.sender);
+                println!("Raw: 0x{}", hex::encode(&raw));
+                println!("Hash: 0x{}", hex::encode(signed.hash));
+            }
+        }
+        _ => {
+            return Err(sub_matches.usage().to_owned());
+        }
+    }
+    Ok(())
+}
+
+// The type, the chain id and the transaction (without the sender) from the arguments
+fn transaction(m: &ArgMatches) -> Result<(u8, Option<u64>, Transaction), String> {
+    let tx_type = match m.value_of("type").unwrap() {
+        "legacy" => 0,
+        "access-list" => 1,
+        _ => 2,
+    };
+    let chain_id = m
+        .value_of("chain-id")
+        .unwrap()
+        .parse::<u64>()
+        .map_err(|err| format!("Invalid chain id: {}", err))?;
+    let chain_id = match (tx_type, chain_id) {
+        (0, 0) => None,
+        (_, 0) => return Err("The chain id is required by typed transactions".to_string()),
+        (_, chain_id) => Some(chain_id),
+    };
+    let number = |name: &str| -> Result<u64, String> {
+        m.value_of(name)
+            .unwrap()
+            .parse::<u64>()
+            .map_err(|err| format!("Invalid {}: {}", name, err))
+    };
+    let (gas_price, priority_fee) = if tx_type == 2 {
+        let max_fee = m
+            .value_of("max-fee")
+            .ok_or_else(|| "<max-fee> is required by dynamic fee transactions".to_string())?;
+        let priority_fee = m.value_of("priority-fee").unwrap_or("0");
+        (parse_u256(max_fee)?, Some(parse_u256(priority_fee)?))
+    } else {
+        if m.is_present("max-fee") || m.is_present("priority-fee") {
+            return Err(
+                "<max-fee> and <priority-fee> are only for dynamic fee transactions".to_string(),
+            );
+        }
+        (parse_u256(m.value_of("gas-price").unwrap_or("0"))?, None)
+    };
+    let access_list = match m.value_of("access-list") {
+        Some(path) => {
+            if tx_type == 0 {
+                return Err("Legacy transactions have no access list".to_string());
+            }
+            let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+            let items: Vec<AccessListItem> =
+                serde_json::from_slice(&data).map_err(|err| err.to_string())?;
+            access_list(&items)?
+        }
+        None => Vec::new(),
+    };
+    let to = m.value_of("to").map(parse_address).transpose()?;
+    Ok((
+        tx_type,
+        chain_id,
+        Transaction {
+            sender: Default::default(),
+            to,
+            nonce: number("nonce")?,
+            gas_limit: number("gas")?,
+            gas_price,
+            value: parse_u256(m.value_of("value").unwrap())?,
+            data: calldata(m)?,
+            access_list,
+            priority_fee,
+        },
+    ))
+}
+
+// The raw data, the encoded function call, or the init code with the encoded
+// constructor arguments
+fn calldata(m: &ArgMatches) -> Result<Vec<u8>, String> {
+    if let Some(data) = m.value_of("data") {
+        return parse_hex(data);
+    }
+    let params: Vec<String> = match m.values_of("param") {
+        Some(values) => values.map(|value| value.to_owned()).collect(),
+        None => Vec::new(),
+    };
+    let contract = match m.value_of("abi") {
+        Some(path) => {
+            let abi = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+            Some(load_contract(&abi)?)
+        }
+        None => None,
+    };
+    let encoded = match (
+        m.value_of("function"),
+        m.value_of("code"),
+        contract.as_ref(),
+    ) {
+        (Some(function), _, Some(contract)) => {
+            contract_encode_input(contract, function, &params, true)?
+        }
+        (None, Some(path), Some(contract)) if contract.constructor.is_some() => {
+            constructor_encode_input(contract, &hex::encode(load_binary(path)), &params, true)?
+        }
+        (None, Some(path), _) if params.is_empty() => hex::encode(load_binary(path)),
+        (None, None, _) if params.is_empty() => String::new(),
+        _ => return Err("The parameters are given without a function or constructor".to_string()),
+    };
+    hex::decode(encoded.trim_start_matches("0x")).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The private key of a94f5374fce5edbc8e2a8697c15331677e6ebf0b
+    const SECRET_KEY: &str = "45a915e4d060149eb4365960e6a7a45f334393093061116b197e3240065ff2d8";
+
+    fn parse(args: &[&str]) -> (u8, Option<u64>, Transaction) {
+        let matches = sub_command("tx").get_matches_from([&["tx", "build"][..], args].concat());
+        transaction(matches.subcommand_matches("build").unwrap()).unwrap()
+    }
+
+    // The signing hash, the raw transaction and its hash
+    fn sign(args: &[&str], secret_key: &str) -> (String, String, String) {
+        let (tx_type, chain_id, tx) = parse(args);
+        let secret_key = hex::decode(secret_key).unwrap();
+        let raw = sign_transaction(tx_type, chain_id, &tx, &secret_key).unwrap();
+        (
+            hex::encode(keccak(signing_payload(tx_type, chain_id, &tx)).0),
+            hex::encode(&raw),
+            hex::encode(decode_transaction(&raw).unwrap().hash),
+        )
+    }
+
+    // The example of EIP-155
+    #[test]
+    fn sign_eip155_legacy() {
+        let args = [
+            "--nonce=9",
+            "--gas-price=20000000000",
+            "--gas=21000",
+            "--to=0x3535353535353535353535353535353535353535",
+            "--value=1000000000000000000",
+        ];
+        let (tx_type, chain_id, tx) = parse(&args);
+        assert_eq!(
+            hex::encode(signing_payload(tx_type, chain_id, &tx)),
+            "ec098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a764000080018080"
+        );
+        let (signing_hash, raw, hash) = sign(&args, &"46".repeat(32));
+        assert_eq!(
+            signing_hash,
+            "daf5a779ae972f972197303d7b574746c7ef83eadac0f2791ad23db92e4c8e53"
+        );
+        assert_eq!(
+            raw,
+            "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025\
+             a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276\
+             a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"
+        );
+        assert_eq!(
+            hash,
+            "33469b22e9f636356c4160a87eb19df52b7412e8eac32a4a55ffe88ea8350788"
+        );
+    }
+
+    #[test]
+    fn sign_access_list() {
+        let path = std::env::temp_dir().join(format!(
+            "play-evmone-tx-access-list-{}.json",
+            std::process::id()
+        ));
+        fs::write(
+            &path,
+            r#"[{"address": "0xbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb", "storageKeys": ["0x01"]}]"#,
+        )
+        .unwrap();
+        let access_list = format!("--access-list={}", path.to_string_lossy());
+        let args = [
+            "--type=access-list",
+            "--gas-price=10",
+            "--gas=30000",
+            "--to=0xbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb",
+            "--value=1",
+            "--data=0x1234",
+            &access_list,
+        ];
+        let (signing_hash, raw, hash) = sign(&args, SECRET_KEY);
+        let _ = fs::remove_file(&path);
+        assert_eq!(
+            signing_hash,
+            "0f6917553f26224b22f3730588b64b9450a86595c36f084019690644731438bb"
+        );
+        assert_eq!(
+            raw,
+            "01f89c01800a82753094bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb01821234f838f794bbbbbbbb\
+             bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbe1a000000000000000000000000000000000000000000000000000\
+             0000000000000180a056766511ad27c2c7ac02e85481763e9f7934acefcd5a122cf515ae3b82e9e659\
+             a011f08aea9337f16516bfcf718d73557fea2ee5213ff1ab27270a49b87e925eab"
+        );
+        assert_eq!(
+            hash,
+            "761cba4d0aa3c81963a44f234626e17976d36481b343a1e4f3663d9878089168"
+        );
+    }
+
+    #[test]
+    fn sign_dynamic_fee() {
+        let args = [
+            "--type=dynamic-fee",
+            "--nonce=1",
+            "--max-fee=100",
+            "--priority-fee=2",
+            "--to=0xbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb",
+            "--value=1000",
+        ];
+        let (signing_hash, raw, hash) = sign(&args, SECRET_KEY);
+        assert_eq!(
+            signing_hash,
+            "dd0ab3ebfac4ca60def2d2f9da49dd97637c0a334d86a71bf29f0fc47023e314"
+        );
+        assert_eq!(
+            raw,
+            "02f8640101026482520894bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb8203e880c001\
+             a08568b73c417142d6f255250b576693361738af529b464bc25f0a12ada2f97b8b\
+             a02f240f9ba6e4100cdb7e7247b6f8c1d9e8301f72f9190fd04da6483a94fc9548"
+        );
+        assert_eq!(
+            hash,
+            "9e0c8881d16d76310bf1ae83d59440a12e45e619427b7cb3cac7843f747439de"
+        );
+
+        let raw = hex::decode(raw).unwrap();
+        let signed = decode_transaction(&raw).unwrap();
+        assert_eq!(
+            hex::encode(signed.transaction.sender.0),
+            "a94f5374fce5edbc8e2a8697c15331677e6ebf0b"
+        );
+        assert_eq!(signed.transaction.priority_fee, Some(2.into()));
+    }
+
+    #[test]
+    fn reject_invalid_fields() {
+        let matches = |args: &[&str]| {
+            sub_command("tx").get_matches_from([&["tx", "build"][..], args].concat())
+        };
+        let error = |args: &[&str]| {
+            transaction(matches(args).subcommand_matches("build").unwrap()).unwrap_err()
+        };
+        assert_eq!(
+            error(&["--type=dynamic-fee"]),
+            "<max-fee> is required by dynamic fee transactions"
+        );
+        assert_eq!(
+            error(&["--type=access-list", "--chain-id=0"]),
+            "The chain id is required by typed transactions"
+        );
+        assert_eq!(
+            error(&["--max-fee=1"]),
+            "<max-fee> and <priority-fee> are only for dynamic fee transactions"
+        );
+        // No EIP-155 with the chain id 0
+        assert_eq!(parse(&["--chain-id=0"]).1, None);
+    }
+}
@@ -0,0 +1,291 @@
+//! A minimal WebSocket (RFC 6455) server side: the opening handshake over the
+//! HTTP request, and the text frames over a non-blocking stream
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use base64::Engine;
+
+use crate::http::Request;
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+// A frame of the maximum size with the longest header (length and mask)
+const MAX_BUFFER_SIZE: usize = MAX_MESSAGE_SIZE + 14;
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+// The close status of a frame breaking the protocol
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+pub fn is_upgrade(request: &Request) -> bool {
+    request
+        .header("upgrade")
+        .map(|value| value.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false)
+}
+
+/// The Sec-WebSocket-Accept of the key: base64(sha1(key + GUID))
+pub fn accept_key(key: &str) -> String {
+    let digest = sha1_smol::Sha1::from(format!("{}{}", key, ACCEPT_GUID)).digest();
+    base64::engine::general_purpose::STANDARD.encode(digest.bytes())
+}
+
+/// A WebSocket connection after the handshake
+pub struct Connection {
+    stream: TcpStream,
+    // The bytes received but not parsed as a complete frame yet
+    buffer: Vec<u8>,
+    // The opcode and the payload of the fragmented message
+    fragments: Option<(u8, Vec<u8>)>,
+    pub closed: bool,
+}
+
+impl Connection {
+    /// Answer the upgrade request, the stream is non-blocking afterwards and
+    /// the writes time out
+    pub fn accept(mut stream: TcpStream, request: &Request) -> Result<Connection, String> {
+        let key = request
+            .header("sec-websocket-key")
+            .ok_or_else(|| "Sec-WebSocket-Key is missing".to_string())?;
+        let head = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        );
+        stream
+            .write_all(head.as_bytes())
+            .and_then(|_| stream.set_nonblocking(true))
+            .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+            .map_err(|err| err.to_string())?;
+        Ok(Connection {
+            stream,
+            buffer: Vec::new(),
+            fragments: None,
+            closed: false,
+        })
+    }
+
+    /// Read the available bytes, return the complete text messages. Pings are
+    /// answered, and the close frame is echoed. The reading stops at one
+    /// frame of the maximum size, the rest is read by the next call. The
+    /// connection is closed on a frame breaking the protocol.
+    pub fn read_messages(&mut self) -> Result<Vec<String>, String> {
+        let mut chunk = [0u8; 4096];
+        while self.buffer.len() < MAX_BUFFER_SIZE {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                }
+                Ok(size) => self.buffer.extend_from_slice(&chunk[..size]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => {
+                    self.closed = true;
+                    return Err(err.to_string());
+                }
+            }
+        }
+
+        let mut messages = Vec::new();
+        while let Some((fin, opcode, payload)) = self.next_frame().map_err(|err| self.fail(err))? {
+            match opcode {
+                OPCODE_PING => self.send_frame(OPCODE_PONG, &payload)?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    self.send_frame(OPCODE_CLOSE, &payload[..payload.len().min(2)])?;
+                    self.closed = true;
+                    break;
+                }
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    let (opcode, mut data) = match (opcode, self.fragments.take()) {
+                        (OPCODE_CONTINUATION, Some(fragments)) => fragments,
+                        (OPCODE_CONTINUATION, None) => {
+                            return Err(
+                                self.fail("Continuation frame without a message".to_string())
+                            )
+                        }
+                        (opcode, _) => (opcode, Vec::new()),
+                    };
+                    data.extend(payload);
+                    if data.len() > MAX_MESSAGE_SIZE {
+                        return Err(self.fail(format!("Message too large: {} bytes", data.len())));
+                    }
+                    if !fin {
+                        self.fragments = Some((opcode, data));
+                    } else if opcode == OPCODE_TEXT {
+                        let text =
+                            String::from_utf8(data).map_err(|err| self.fail(err.to_string()))?;
+                        messages.push(text);
+                    }
+                }
+                opcode => return Err(self.fail(format!("Unknown opcode: {:#x}", opcode))),
+            }
+        }
+        Ok(messages)
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), String> {
+        self.send_frame(OPCODE_TEXT, text.as_bytes())
+    }
+
+    // Send the close frame of the protocol error and close the connection
+    fn fail(&mut self, err: String) -> String {
+        if !self.closed {
+            let _ = self.send_frame(OPCODE_CLOSE, &CLOSE_PROTOCOL_ERROR.to_be_bytes());
+            self.closed = true;
+        }
+        err
+    }
+
+    // Parse the first frame in the buffer: fin, opcode and the unmasked
+    // payload. The frames of the client must be masked (RFC 6455 5.1).
+    fn next_frame(&mut self) -> Result<Option<(bool, u8, Vec<u8>)>, String> {
+        let buffer = &self.buffer;
+        if buffer.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buffer[0] & 0x80 != 0;
+        let opcode = buffer[0] & 0x0f;
+        if buffer[1] & 0x80 == 0 {
+            return Err("Unmasked frame from the client".to_string());
+        }
+        let (length, mut offset) = match buffer[1] & 0x7f {
+            126 if buffer.len() >= 4 => (u16::from_be_bytes([buffer[2], buffer[3]]) as usize, 4),
+            127 if buffer.len() >= 10 => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&buffer[2..10]);
+                (u64::from_be_bytes(bytes) as usize, 10)
+            }
+            126 | 127 => return Ok(None),
+            length => (length as usize, 2),
+        };
+        if length > MAX_MESSAGE_SIZE {
+            return Err(format!("Frame too large: {} bytes", length));
+        }
+        if buffer.len() < offset + 4 + length {
+            return Ok(None);
+        }
+        let mask = &buffer[offset..offset + 4];
+        let mut payload = buffer[offset + 4..offset + 4 + length].to_vec();
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+        offset += 4 + length;
+        self.buffer.drain(..offset);
+        Ok(Some((fin, opcode, payload)))
+    }
+
+    // The frames of the server are not masked
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), String> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            length if length < 126 => frame.push(length as u8),
+            length if length <= 0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        // Block until the whole frame is written or the write times out
+        self.stream
+            .set_nonblocking(false)
+            .and_then(|_| self.stream.write_all(&frame))
+            .and_then(|_| self.stream.set_nonblocking(true))
+            .map_err(|err| {
+                self.closed = true;
+                err.to_string()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    // The server side connection and the client stream
+    fn connect() -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let connection = Connection {
+            stream,
+            buffer: Vec::new(),
+            fragments: None,
+            closed: false,
+        };
+        (connection, client)
+    }
+
+    // A frame of the client, masked
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![
+            (if fin { 0x80 } else { 0 }) | opcode,
+            0x80 | payload.len() as u8,
+        ];
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(index, byte)| byte ^ mask[index % 4]),
+        );
+        frame
+    }
+
+    #[test]
+    fn accept_key_of_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn read_fragmented_message() {
+        let (mut connection, _client) = connect();
+        let mut frames = client_frame(false, OPCODE_TEXT, b"hel");
+        frames.extend(client_frame(true, OPCODE_CONTINUATION, b"lo"));
+        frames.extend(client_frame(true, OPCODE_TEXT, b"world"));
+        // The second message is not complete
+        let split = frames.len() - 3;
+        connection.buffer.extend_from_slice(&frames[..split]);
+        assert_eq!(connection.read_messages().unwrap(), vec!["hello"]);
+        connection.buffer.extend_from_slice(&frames[split..]);
+        assert_eq!(connection.read_messages().unwrap(), vec!["world"]);
+        assert!(!connection.closed);
+
+        connection
+            .buffer
+            .extend(client_frame(true, OPCODE_CONTINUATION, b"x"));
+        assert!(connection.read_messages().is_err());
+        assert!(connection.closed);
+    }
+
+    #[test]
+    fn close_on_unmasked_frame() {
+        let (mut connection, mut client) = connect();
+        connection
+            .buffer
+            .extend_from_slice(&[0x81, 0x02, b'h', b'i']);
+        assert!(connection.read_messages().is_err());
+        assert!(connection.closed);
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).unwrap();
+        assert_eq!(close, [0x88, 0x02, 0x03, 0xea]);
+    }
+}